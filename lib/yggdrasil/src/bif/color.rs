@@ -0,0 +1,155 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    bif::{returns, Arity, COLOR, INTEGER, NUMBER},
+    color::{self, Color, BHS, RGB},
+    value::{Value, ValueData, ValueType},
+};
+use failure::{bail, ensure, Fallible};
+
+pure_nif!(Rgb, Arity::Exactly(3), rgb, |name, args| {
+    returns(name, args, &[INTEGER], ValueType::Color)
+});
+pure_nif!(Bhs, Arity::Exactly(3), bhs, |name, args| {
+    returns(name, args, &[INTEGER], ValueType::Color)
+});
+pure_nif!(Mired, Arity::Exactly(1), mired, |name, args| {
+    returns(name, args, &[INTEGER], ValueType::Color)
+});
+pure_nif!(Kelvin, Arity::Exactly(1), kelvin, |name, args| {
+    returns(name, args, &[INTEGER], ValueType::Color)
+});
+pure_nif!(Mix, Arity::Exactly(3), mix, |name, args| {
+    returns(name, args, &[COLOR, COLOR, NUMBER], ValueType::Color)
+});
+
+fn component(value: &Value, name: &str, max: i64) -> Fallible<i64> {
+    let v = value.as_integer()?;
+    ensure!(
+        (0..=max).contains(&v),
+        "runtime error: {} {} is out of range 0-{}",
+        name,
+        v,
+        max
+    );
+    Ok(v)
+}
+
+fn rgb(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_color(Color::RGB(RGB::new(
+        component(&args[0], "red", 255)? as u8,
+        component(&args[1], "green", 255)? as u8,
+        component(&args[2], "blue", 255)? as u8,
+    )?)))
+}
+
+fn bhs(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_color(Color::BHS(BHS::new(
+        component(&args[0], "brightness", 255)? as u8,
+        component(&args[1], "hue", 65535)? as u16,
+        component(&args[2], "saturation", 255)? as u8,
+    )?)))
+}
+
+fn mired(args: &[Value]) -> Fallible<Value> {
+    let ct = component(&args[0], "color temp", 255)?;
+    Ok(Value::from_color(Color::Mired(color::Mired::new(
+        ct as u8,
+    )?)))
+}
+
+fn kelvin(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_color(Color::Mired(color::Mired::from_kelvin(
+        args[0].as_integer()?,
+    )?)))
+}
+
+fn mix(args: &[Value]) -> Fallible<Value> {
+    let t = match args[2].data {
+        ValueData::Integer(i) => i as f64,
+        ValueData::Float(f) => f.value,
+        _ => bail!("runtime error: mix factor must be a number"),
+    };
+    Ok(Value::from_color(
+        args[0].as_color()?.mix(&args[1].as_color()?, t)?,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{path::ConcretePath, tree::TreeBuilder};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_color_constructors() -> Fallible<()> {
+        let s = r#"
+a <- rgb(206, 92, 0)
+b <- bhs(254, 34495, 254)
+c <- mired(154)
+d <- kelvin(6500)
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        for (path, expect) in &[
+            ("/a", "rgb(206, 92, 0)"),
+            ("/b", "bhs(254, 34495, 254)"),
+            ("/c", "mired(154)"),
+            ("/d", "mired(154)"),
+        ] {
+            assert_eq!(
+                tree.lookup(path)?.compute(&tree)?,
+                Value::from_color(Color::parse(expect)?)
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_color_validated_at_build() {
+        for s in &[
+            "a <- rgb(256, 0, 0)",
+            "a <- rgb(0, -1, 0)",
+            "a <- bhs(0, 65536, 0)",
+            "a <- mired(201)",
+            "a <- kelvin(2700)",
+            "a <- rgb(0, 0, 0.5)",
+            "a <- mix(rgb(0, 0, 0), mired(100), 0.5)",
+            "a <- bsh(254, 0, 254)",
+        ] {
+            assert!(TreeBuilder::default().build_from_str(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_color_arithmetic() -> Fallible<()> {
+        let s = r#"
+level ^level
+    default <- 0.5
+a <- bhs(254, 100, 254) * /level
+b <- 2 * rgb(10, 20, 30)
+c <- mix(rgb(0, 0, 0), rgb(100, 200, 250), /level)
+d $sink <- rgb(10, 20, 30) * /level
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(
+            tree.lookup("/a")?.compute(&tree)?,
+            Value::from_color(Color::parse("bhs(127, 100, 254)")?)
+        );
+        assert_eq!(
+            tree.lookup("/b")?.compute(&tree)?,
+            Value::from_color(Color::parse("rgb(20, 40, 60)")?)
+        );
+        assert_eq!(
+            tree.lookup("/c")?.compute(&tree)?,
+            Value::from_color(Color::parse("rgb(50, 100, 125)")?)
+        );
+        let updates =
+            tree.handle_event(&ConcretePath::from_str("/level")?, Value::from_integer(0))?;
+        assert_eq!(
+            updates["sink"][0].1,
+            Value::from_color(Color::parse("rgb(0, 0, 0)")?)
+        );
+        Ok(())
+    }
+}
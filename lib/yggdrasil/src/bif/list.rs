@@ -0,0 +1,137 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    bif::{returns, Arity, ANY, LIST, SEQUENCE},
+    tokenizer::Token,
+    value::{Value, ValueType},
+};
+use failure::{ensure, Fallible};
+
+// List literals are compiled down to a call to list.
+pure_nif!(List, Arity::AtLeast(0), list, |name, args| {
+    returns(name, args, &[ANY], ValueType::List)
+});
+pure_nif!(Len, Arity::Exactly(1), len, |name, args| {
+    returns(name, args, &[SEQUENCE], ValueType::Integer)
+});
+pure_nif!(Any, Arity::Exactly(1), any, |name, args| {
+    returns(name, args, &[LIST], ValueType::Boolean)
+});
+pure_nif!(All, Arity::Exactly(1), all, |name, args| {
+    returns(name, args, &[LIST], ValueType::Boolean)
+});
+pure_nif!(Count, Arity::Exactly(1), count, |name, args| {
+    returns(name, args, &[LIST], ValueType::Integer)
+});
+pure_nif!(Sum, Arity::Exactly(1), sum, |name, args| {
+    returns(name, args, &[LIST], ValueType::Any)
+});
+
+/// The items of a list, or the arguments themselves if they are not a list,
+/// so that aggregates like min can be given either.
+pub(super) fn items(args: &[Value]) -> Fallible<&[Value]> {
+    if args.len() == 1 && args[0].is_list() {
+        return args[0].as_list();
+    }
+    Ok(args)
+}
+
+fn list(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_list(args.to_vec()))
+}
+
+fn len(args: &[Value]) -> Fallible<Value> {
+    let len = if args[0].is_list() {
+        args[0].as_list()?.len()
+    } else {
+        args[0].as_string()?.chars().count()
+    };
+    Ok(Value::from_integer(len as i64))
+}
+
+fn booleans(value: &Value) -> Fallible<Vec<bool>> {
+    value.as_list()?.iter().map(Value::as_boolean).collect()
+}
+
+fn any(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_boolean(booleans(&args[0])?.contains(&true)))
+}
+
+fn all(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_boolean(!booleans(&args[0])?.contains(&false)))
+}
+
+// The number of items that are true.
+fn count(args: &[Value]) -> Fallible<Value> {
+    let count = booleans(&args[0])?.iter().filter(|b| **b).count();
+    Ok(Value::from_integer(count as i64))
+}
+
+// Integers are summed as integers until a float is seen.
+fn sum(args: &[Value]) -> Fallible<Value> {
+    let mut total = Value::from_integer(0);
+    for item in args[0].as_list()? {
+        ensure!(
+            item.is_integer() || item.is_float(),
+            "runtime error: sum expects a list of numbers, but found {}",
+            item
+        );
+        total = total.apply(&Token::Add, item)?;
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bif::NativeFunc, float::Float, tree::TreeBuilder};
+
+    fn i(v: i64) -> Value {
+        Value::from_integer(v)
+    }
+
+    fn b(v: bool) -> Value {
+        Value::from_boolean(v)
+    }
+
+    fn l(items: &[Value]) -> Value {
+        Value::from_list(items.to_vec())
+    }
+
+    fn call(nif: &dyn NativeFunc, args: &[Value]) -> Fallible<Value> {
+        assert!(nif.arity().accepts(args.len()));
+        nif.compute(args, &TreeBuilder::empty())
+    }
+
+    #[test]
+    fn test_list() -> Fallible<()> {
+        assert_eq!(call(&List, &[])?, l(&[]));
+        assert_eq!(call(&List, &[i(1), b(true)])?, l(&[i(1), b(true)]));
+        assert_eq!(call(&Len, &[l(&[i(1), i(2)])])?, i(2));
+        assert_eq!(call(&Len, &[Value::new_str("abc")])?, i(3));
+        assert!(call(&Len, &[i(1)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_aggregates() -> Fallible<()> {
+        let bools = [l(&[b(true), b(false), b(true)])];
+        assert_eq!(call(&Any, &bools)?, b(true));
+        assert_eq!(call(&All, &bools)?, b(false));
+        assert_eq!(call(&Count, &bools)?, i(2));
+        assert_eq!(call(&Any, &[l(&[])])?, b(false));
+        assert_eq!(call(&All, &[l(&[])])?, b(true));
+        assert!(call(&Any, &[l(&[i(1)])]).is_err());
+
+        assert_eq!(call(&Sum, &[l(&[i(1), i(2), i(3)])])?, i(6));
+        assert_eq!(
+            call(&Sum, &[l(&[i(1), Value::from_float(Float::new(0.5)?)])])?,
+            Value::from_float(Float::new(1.5)?)
+        );
+        assert_eq!(call(&Sum, &[l(&[])])?, i(0));
+        assert!(call(&Sum, &[l(&[b(true)])]).is_err());
+        assert!(call(&Sum, &[l(&[i(i64::MAX), i(1)])]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    bif::{list::items, numeric, numeric_or_list, returns, Arity, NUMBER},
+    float::Float,
+    value::{Value, ValueData, ValueType},
+};
+use failure::{bail, ensure, err_msg, Fallible};
+
+pure_nif!(Min, Arity::AtLeast(1), min, numeric_or_list);
+pure_nif!(Max, Arity::AtLeast(1), max, numeric_or_list);
+pure_nif!(Abs, Arity::Exactly(1), abs, numeric);
+pure_nif!(Round, Arity::Exactly(1), round, |name, args| {
+    returns(name, args, &[NUMBER], ValueType::Integer)
+});
+pure_nif!(Floor, Arity::Exactly(1), floor, |name, args| {
+    returns(name, args, &[NUMBER], ValueType::Integer)
+});
+pure_nif!(Ceil, Arity::Exactly(1), ceil, |name, args| {
+    returns(name, args, &[NUMBER], ValueType::Integer)
+});
+pure_nif!(Clamp, Arity::Exactly(3), clamp, numeric);
+pure_nif!(Int, Arity::Exactly(1), int, |name, args| {
+    let accepts = [
+        ValueType::Integer,
+        ValueType::Float,
+        ValueType::Boolean,
+        ValueType::String,
+    ];
+    returns(name, args, &[&accepts], ValueType::Integer)
+});
+pure_nif!(ToFloat, Arity::Exactly(1), float, |name, args| {
+    let accepts = [ValueType::Integer, ValueType::Float, ValueType::String];
+    returns(name, args, &[&accepts], ValueType::Float)
+});
+pure_nif!(Lerp, Arity::Exactly(3), lerp, |name, args| {
+    returns(name, args, &[NUMBER], ValueType::Float)
+});
+
+fn as_number(value: &Value) -> Fallible<f64> {
+    Ok(match value.data {
+        ValueData::Integer(i) => i as f64,
+        ValueData::Float(f) => f.value,
+        _ => bail!(
+            "runtime error: expected a number, but found {}",
+            value.to_string()
+        ),
+    })
+}
+
+fn all_integers(args: &[Value]) -> Fallible<bool> {
+    let mut integers = true;
+    for arg in args {
+        as_number(arg)?;
+        integers &= arg.is_integer();
+    }
+    Ok(integers)
+}
+
+// i64::MAX is not representable as a float; it rounds up to 2^63, which is
+// already out of range, so the upper bound is exclusive.
+fn float_to_integer(f: f64) -> Fallible<Value> {
+    ensure!(
+        f >= i64::MIN as f64 && f < i64::MAX as f64,
+        "runtime error: {} is out of range for an integer",
+        f
+    );
+    Ok(Value::from_integer(f as i64))
+}
+
+// Pick from a list of numbers. If any argument is a float, the result is too.
+fn select(args: &[Value], pick: fn(f64, f64) -> bool) -> Fallible<Value> {
+    ensure!(
+        !args.is_empty(),
+        "runtime error: cannot pick from an empty list"
+    );
+    let integers = all_integers(args)?;
+    let mut best = &args[0];
+    for arg in &args[1..] {
+        if pick(as_number(arg)?, as_number(best)?) {
+            best = arg;
+        }
+    }
+    if integers {
+        return Ok(Value::from_integer(best.as_integer()?));
+    }
+    Ok(Value::from_float(Float::new(as_number(best)?)?))
+}
+
+fn min(args: &[Value]) -> Fallible<Value> {
+    select(items(args)?, |a, b| a < b)
+}
+
+fn max(args: &[Value]) -> Fallible<Value> {
+    select(items(args)?, |a, b| a > b)
+}
+
+fn abs(args: &[Value]) -> Fallible<Value> {
+    Ok(match args[0].data {
+        ValueData::Integer(i) => Value::from_integer(
+            i.checked_abs()
+                .ok_or_else(|| err_msg("runtime error: overflow in abs"))?,
+        ),
+        _ => Value::from_float(Float::new(as_number(&args[0])?.abs())?),
+    })
+}
+
+// Rounding functions produce integers, so that their results can be used
+// directly as path components and in device properties.
+fn round(args: &[Value]) -> Fallible<Value> {
+    float_to_integer(as_number(&args[0])?.round())
+}
+
+fn floor(args: &[Value]) -> Fallible<Value> {
+    float_to_integer(as_number(&args[0])?.floor())
+}
+
+fn ceil(args: &[Value]) -> Fallible<Value> {
+    float_to_integer(as_number(&args[0])?.ceil())
+}
+
+fn clamp(args: &[Value]) -> Fallible<Value> {
+    ensure!(
+        as_number(&args[1])? <= as_number(&args[2])?,
+        "runtime error: clamp lower bound {} is above upper bound {}",
+        args[1],
+        args[2]
+    );
+    max(&[
+        args[1].to_owned(),
+        min(&[args[0].to_owned(), args[2].to_owned()])?,
+    ])
+}
+
+fn int(args: &[Value]) -> Fallible<Value> {
+    Ok(match args[0].data {
+        ValueData::Integer(i) => Value::from_integer(i),
+        ValueData::Float(f) => float_to_integer(f.value.trunc())?,
+        ValueData::Boolean(b) => Value::from_integer(if b { 1 } else { 0 }),
+        ValueData::String(ref s) => Value::from_integer(s.trim().parse::<i64>().map_err(|_| {
+            err_msg(format!(
+                "runtime error: cannot convert \"{}\" to an integer",
+                s
+            ))
+        })?),
+        _ => bail!("runtime error: cannot convert {} to an integer", args[0]),
+    })
+}
+
+fn float(args: &[Value]) -> Fallible<Value> {
+    Ok(match args[0].data {
+        ValueData::Integer(i) => Value::from_float(Float::new(i as f64)?),
+        ValueData::Float(f) => Value::from_float(f),
+        ValueData::String(ref s) => {
+            Value::from_float(Float::new(s.trim().parse::<f64>().map_err(|_| {
+                err_msg(format!(
+                    "runtime error: cannot convert \"{}\" to a float",
+                    s
+                ))
+            })?)?)
+        }
+        _ => bail!("runtime error: cannot convert {} to a float", args[0]),
+    })
+}
+
+fn lerp(args: &[Value]) -> Fallible<Value> {
+    let a = as_number(&args[0])?;
+    let b = as_number(&args[1])?;
+    let t = as_number(&args[2])?;
+    Ok(Value::from_float(Float::new(a + (b - a) * t)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bif::NativeFunc, tree::TreeBuilder};
+
+    fn i(v: i64) -> Value {
+        Value::from_integer(v)
+    }
+
+    fn f(v: f64) -> Value {
+        Value::from_float(Float::new(v).unwrap())
+    }
+
+    fn call(nif: &dyn NativeFunc, args: &[Value]) -> Fallible<Value> {
+        assert!(nif.arity().accepts(args.len()));
+        nif.compute(args, &TreeBuilder::empty())
+    }
+
+    #[test]
+    fn test_min_max() -> Fallible<()> {
+        assert_eq!(call(&Min, &[i(3), i(-1), i(2)])?, i(-1));
+        assert_eq!(call(&Max, &[i(3), i(-1), i(2)])?, i(3));
+        assert_eq!(call(&Min, &[f(3.5), f(-1.5)])?, f(-1.5));
+        assert_eq!(call(&Max, &[f(3.5), f(-1.5)])?, f(3.5));
+        assert_eq!(call(&Min, &[i(3), f(2.5)])?, f(2.5));
+        assert_eq!(call(&Max, &[i(3), f(2.5)])?, f(3.0));
+        assert_eq!(call(&Min, &[i(7)])?, i(7));
+        assert!(call(&Min, &[i(1), Value::new_str("a")]).is_err());
+        let list = [Value::from_list(vec![i(3), f(2.5), i(4)])];
+        assert_eq!(call(&Min, &list)?, f(2.5));
+        assert_eq!(call(&Max, &list)?, f(4.0));
+        assert!(call(&Max, &[Value::from_list(vec![])]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_abs() -> Fallible<()> {
+        assert_eq!(call(&Abs, &[i(-3)])?, i(3));
+        assert_eq!(call(&Abs, &[i(3)])?, i(3));
+        assert_eq!(call(&Abs, &[f(-3.5)])?, f(3.5));
+        assert!(call(&Abs, &[i(i64::MIN)]).is_err());
+        assert!(call(&Abs, &[Value::from_boolean(true)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rounding() -> Fallible<()> {
+        assert_eq!(call(&Round, &[i(3)])?, i(3));
+        assert_eq!(call(&Round, &[f(2.5)])?, i(3));
+        assert_eq!(call(&Round, &[f(-2.4)])?, i(-2));
+        assert_eq!(call(&Floor, &[i(3)])?, i(3));
+        assert_eq!(call(&Floor, &[f(2.9)])?, i(2));
+        assert_eq!(call(&Floor, &[f(-2.1)])?, i(-3));
+        assert_eq!(call(&Ceil, &[i(3)])?, i(3));
+        assert_eq!(call(&Ceil, &[f(2.1)])?, i(3));
+        assert_eq!(call(&Ceil, &[f(-2.9)])?, i(-2));
+        assert!(call(&Round, &[f(1e300)]).is_err());
+        assert!(call(&Round, &[f(2f64.powi(63))]).is_err());
+        assert_eq!(call(&Round, &[f(-(2f64.powi(63)))])?, i(i64::MIN));
+        Ok(())
+    }
+
+    #[test]
+    fn test_clamp() -> Fallible<()> {
+        assert_eq!(call(&Clamp, &[i(70000), i(0), i(65535)])?, i(65535));
+        assert_eq!(call(&Clamp, &[i(-5), i(0), i(65535)])?, i(0));
+        assert_eq!(call(&Clamp, &[i(5), i(0), i(65535)])?, i(5));
+        assert_eq!(call(&Clamp, &[f(1.5), f(0.0), f(1.0)])?, f(1.0));
+        assert_eq!(call(&Clamp, &[f(0.5), f(0.0), f(1.0)])?, f(0.5));
+        assert_eq!(call(&Clamp, &[f(0.5), i(0), i(1)])?, f(0.5));
+        assert!(call(&Clamp, &[i(5), i(10), i(0)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_conversions() -> Fallible<()> {
+        assert_eq!(call(&Int, &[i(3)])?, i(3));
+        assert_eq!(call(&Int, &[f(3.9)])?, i(3));
+        assert_eq!(call(&Int, &[f(-3.9)])?, i(-3));
+        assert_eq!(call(&Int, &[Value::from_boolean(true)])?, i(1));
+        assert_eq!(call(&Int, &[Value::new_str(" 42 ")])?, i(42));
+        assert!(call(&Int, &[Value::new_str("4x2")]).is_err());
+        assert_eq!(call(&ToFloat, &[i(3)])?, f(3.0));
+        assert_eq!(call(&ToFloat, &[f(3.5)])?, f(3.5));
+        assert_eq!(call(&ToFloat, &[Value::new_str("0.25")])?, f(0.25));
+        assert!(call(&ToFloat, &[Value::from_boolean(true)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lerp() -> Fallible<()> {
+        assert_eq!(call(&Lerp, &[i(0), i(10), f(0.5)])?, f(5.0));
+        assert_eq!(call(&Lerp, &[f(1.0), f(3.0), f(0.25)])?, f(1.5));
+        assert_eq!(call(&Lerp, &[i(10), i(0), i(1)])?, f(0.0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generation() -> Fallible<()> {
+        let mut a = i(1);
+        a.set_generation(4);
+        let mut b = i(2);
+        b.set_generation(7);
+        assert_eq!(call(&Max, &[a, b])?.generation(), 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_math_in_tree() -> Fallible<()> {
+        let s = r#"
+tic <- 60
+hue <- "bhs(254, " + str(clamp(round(float(/tic) * 1092.5), 0, 65535)) + ", 254)"
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(
+            tree.lookup("/hue")?.compute(&tree)?,
+            Value::new_str("bhs(254, 65535, 254)")
+        );
+        Ok(())
+    }
+}
@@ -1,21 +1,121 @@
 // This Source Code Form is subject to the terms of the GNU General Public
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+// Most builtins are pure functions of their arguments, so they share the
+// same plumbing: the result inherits the newest generation of any input.
+// The type of the result is given by a function of the function's name and
+// the argument types, usually built with `returns`.
+macro_rules! pure_nif {
+    ($name:ident, $arity:expr, $compute:ident, $value_type:expr) => {
+        #[derive(Clone, Debug)]
+        pub(crate) struct $name;
+
+        impl $crate::bif::NativeFunc for $name {
+            fn arity(&self) -> $crate::bif::Arity {
+                $arity
+            }
+
+            fn compute(
+                &self,
+                args: &[$crate::value::Value],
+                _tree: &$crate::tree::Tree,
+            ) -> failure::Fallible<$crate::value::Value> {
+                let generation = args
+                    .iter()
+                    .map($crate::value::Value::generation)
+                    .max()
+                    .unwrap_or(0);
+                Ok($compute(args)?.with_generation(generation))
+            }
+
+            fn value_type(
+                &self,
+                arg_types: &[$crate::value::ValueType],
+                _tree: &$crate::tree::Tree,
+            ) -> failure::Fallible<$crate::value::ValueType> {
+                let value_type: fn(
+                    &str,
+                    &[$crate::value::ValueType],
+                ) -> failure::Fallible<$crate::value::ValueType> = $value_type;
+                value_type(stringify!($compute), arg_types)
+            }
+
+            fn box_clone(&self) -> Box<dyn $crate::bif::NativeFunc + Send + Sync> {
+                Box::new((*self).clone())
+            }
+
+            fn is_pure(&self) -> bool {
+                true
+            }
+        }
+    };
+}
+
+pub(super) mod color;
+pub(super) mod list;
+pub(super) mod math;
+pub(super) mod string;
+pub(super) mod time;
 pub(super) mod tostr;
 
-use crate::{path::ConcretePath, tree::Tree, value::Value};
-use failure::Fallible;
-use std::fmt;
+use crate::{
+    path::ConcretePath,
+    tree::Tree,
+    value::{Value, ValueType},
+};
+use failure::{ensure, Fallible};
+use std::{collections::HashMap, fmt};
+
+/// The number of arguments that a NativeFunc will accept.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Arity {
+    Exactly(usize),
+    AtLeast(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Exactly(n) => count == n,
+            Arity::AtLeast(n) => count >= n,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Arity::Exactly(1) => write!(f, "1 argument"),
+            Arity::Exactly(n) => write!(f, "{} arguments", n),
+            Arity::AtLeast(1) => write!(f, "at least 1 argument"),
+            Arity::AtLeast(n) => write!(f, "at least {} arguments", n),
+        }
+    }
+}
 
 pub trait NativeFunc {
-    fn compute(&self, value: Value, tree: &Tree) -> Fallible<Value>;
-    fn find_all_possible_inputs(
-        &self,
-        value_type: (),
-        tree: &Tree,
-        out: &mut Vec<ConcretePath>,
-    ) -> Fallible<()>;
+    fn arity(&self) -> Arity;
+    fn compute(&self, args: &[Value], tree: &Tree) -> Fallible<Value>;
     fn box_clone(&self) -> Box<dyn NativeFunc + Send + Sync>;
+
+    /// Any inputs that the function reads from the tree, other than through
+    /// its arguments.
+    fn find_all_possible_inputs(&self, _tree: &Tree, _out: &mut Vec<ConcretePath>) -> Fallible<()> {
+        Ok(())
+    }
+
+    /// The type of the result, given the types of the arguments. This is
+    /// checked when the tree is built, so should fail for any arguments that
+    /// `compute` would always reject.
+    fn value_type(&self, _arg_types: &[ValueType], _tree: &Tree) -> Fallible<ValueType> {
+        Ok(ValueType::Any)
+    }
+
+    /// Pure functions depend only on their arguments. Calls to them with
+    /// constant arguments are evaluated, and thus validated, at build time.
+    fn is_pure(&self) -> bool {
+        false
+    }
 }
 
 impl Clone for Box<dyn NativeFunc + Send + Sync> {
@@ -29,3 +129,110 @@ impl fmt::Debug for Box<dyn NativeFunc + Send + Sync> {
         write!(f, "<Unknown NativeFunc>")
     }
 }
+
+pub(crate) const ANY: &[ValueType] = &[ValueType::Any];
+pub(crate) const COLOR: &[ValueType] = &[ValueType::Color];
+pub(crate) const DURATION: &[ValueType] = &[ValueType::Duration];
+pub(crate) const INTEGER: &[ValueType] = &[ValueType::Integer];
+pub(crate) const LIST: &[ValueType] = &[ValueType::List];
+pub(crate) const NUMBER: &[ValueType] = &[ValueType::Integer, ValueType::Float];
+pub(crate) const SEQUENCE: &[ValueType] = &[ValueType::List, ValueType::String];
+pub(crate) const STRING: &[ValueType] = &[ValueType::String];
+pub(crate) const TIMESTAMP: &[ValueType] = &[ValueType::Timestamp];
+
+// Check each argument against the types accepted in its position, then give
+// the result type. The last position covers any further arguments.
+pub(crate) fn returns(
+    name: &str,
+    arg_types: &[ValueType],
+    accepts: &[&[ValueType]],
+    result: ValueType,
+) -> Fallible<ValueType> {
+    for (i, arg_type) in arg_types.iter().enumerate() {
+        let allowed = accepts[i.min(accepts.len() - 1)];
+        ensure!(
+            allowed.iter().any(|t| t.accepts(*arg_type)),
+            "type error: argument {} to {} must be {}, not {}",
+            i + 1,
+            name,
+            allowed
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>()
+                .join(" or "),
+            arg_type
+        );
+    }
+    Ok(result)
+}
+
+// Aggregates over numbers can be given the numbers or a list of them. The
+// type of the items of a list is not known.
+pub(crate) fn numeric_or_list(name: &str, arg_types: &[ValueType]) -> Fallible<ValueType> {
+    if arg_types == [ValueType::List] {
+        return Ok(ValueType::Any);
+    }
+    numeric(name, arg_types)
+}
+
+// Arithmetic on numbers gives an integer only if every argument is one.
+pub(crate) fn numeric(name: &str, arg_types: &[ValueType]) -> Fallible<ValueType> {
+    returns(name, arg_types, &[NUMBER], ValueType::Any)?;
+    Ok(if arg_types.contains(&ValueType::Any) {
+        ValueType::Any
+    } else if arg_types.contains(&ValueType::Float) {
+        ValueType::Float
+    } else {
+        ValueType::Integer
+    })
+}
+
+/// Add the standard library of native functions to `nifs`. Functions that
+/// were already registered by the embedder take precedence over builtins.
+pub(crate) fn add_builtins(nifs: &mut HashMap<String, Box<dyn NativeFunc + Send + Sync>>) {
+    let builtins: Vec<(&str, Box<dyn NativeFunc + Send + Sync>)> = vec![
+        ("str", Box::new(tostr::ToStr)),
+        ("min", Box::new(math::Min)),
+        ("max", Box::new(math::Max)),
+        ("abs", Box::new(math::Abs)),
+        ("round", Box::new(math::Round)),
+        ("floor", Box::new(math::Floor)),
+        ("ceil", Box::new(math::Ceil)),
+        ("clamp", Box::new(math::Clamp)),
+        ("int", Box::new(math::Int)),
+        ("float", Box::new(math::ToFloat)),
+        ("lerp", Box::new(math::Lerp)),
+        ("rgb", Box::new(color::Rgb)),
+        ("bhs", Box::new(color::Bhs)),
+        ("mired", Box::new(color::Mired)),
+        ("kelvin", Box::new(color::Kelvin)),
+        ("mix", Box::new(color::Mix)),
+        ("seconds", Box::new(time::Seconds)),
+        ("minutes", Box::new(time::Minutes)),
+        ("hours", Box::new(time::Hours)),
+        ("days", Box::new(time::Days)),
+        ("in_seconds", Box::new(time::InSeconds)),
+        ("in_minutes", Box::new(time::InMinutes)),
+        ("in_hours", Box::new(time::InHours)),
+        ("duration", Box::new(time::ToDuration)),
+        ("timestamp", Box::new(time::ToTimestamp)),
+        ("unix", Box::new(time::Unix)),
+        ("upper", Box::new(string::Upper)),
+        ("lower", Box::new(string::Lower)),
+        ("contains", Box::new(string::Contains)),
+        ("starts_with", Box::new(string::StartsWith)),
+        ("replace", Box::new(string::Replace)),
+        ("split", Box::new(string::Split)),
+        ("nth", Box::new(string::Nth)),
+        ("format", Box::new(string::Format)),
+        ("list", Box::new(list::List)),
+        ("len", Box::new(list::Len)),
+        ("any", Box::new(list::Any)),
+        ("all", Box::new(list::All)),
+        ("count", Box::new(list::Count)),
+        ("sum", Box::new(list::Sum)),
+    ];
+    for (name, nif) in builtins {
+        nifs.entry(name.to_owned()).or_insert(nif);
+    }
+}
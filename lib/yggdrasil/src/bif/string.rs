@@ -0,0 +1,274 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    bif::{returns, Arity, ANY, INTEGER, SEQUENCE, STRING},
+    value::{Value, ValueData, ValueType},
+};
+use failure::{bail, ensure, err_msg, Fallible};
+
+pure_nif!(Upper, Arity::Exactly(1), upper, |name, args| {
+    returns(name, args, &[STRING], ValueType::String)
+});
+pure_nif!(Lower, Arity::Exactly(1), lower, |name, args| {
+    returns(name, args, &[STRING], ValueType::String)
+});
+pure_nif!(Contains, Arity::Exactly(2), contains, |name, args| {
+    match args[0] {
+        ValueType::String => returns(name, args, &[STRING], ValueType::Boolean),
+        _ => returns(name, &args[..1], &[SEQUENCE], ValueType::Boolean),
+    }
+});
+pure_nif!(StartsWith, Arity::Exactly(2), starts_with, |name, args| {
+    returns(name, args, &[STRING], ValueType::Boolean)
+});
+pure_nif!(Replace, Arity::Exactly(3), replace, |name, args| {
+    returns(name, args, &[STRING], ValueType::String)
+});
+pure_nif!(Split, Arity::Exactly(3), split, |name, args| {
+    returns(name, args, &[STRING, STRING, INTEGER], ValueType::String)
+});
+pure_nif!(Nth, Arity::Exactly(2), nth, |name, args| {
+    match args[0] {
+        ValueType::String => returns(name, args, &[STRING, INTEGER], ValueType::String),
+        _ => returns(name, args, &[SEQUENCE, INTEGER], ValueType::Any),
+    }
+});
+pure_nif!(Format, Arity::AtLeast(1), format, |name, args| {
+    returns(name, args, &[STRING, ANY], ValueType::String)
+});
+
+fn upper(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_string(args[0].as_string()?.to_uppercase()))
+}
+
+fn lower(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_string(args[0].as_string()?.to_lowercase()))
+}
+
+// Searches a string for a substring or a list for an item.
+fn contains(args: &[Value]) -> Fallible<Value> {
+    if args[0].is_list() {
+        return Ok(Value::from_boolean(args[0].as_list()?.contains(&args[1])));
+    }
+    Ok(Value::from_boolean(
+        args[0].as_string()?.contains(&args[1].as_string()?),
+    ))
+}
+
+fn starts_with(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_boolean(
+        args[0].as_string()?.starts_with(&args[1].as_string()?),
+    ))
+}
+
+fn replace(args: &[Value]) -> Fallible<Value> {
+    let from = args[1].as_string()?;
+    ensure!(
+        !from.is_empty(),
+        "runtime error: replace requires a non-empty pattern"
+    );
+    Ok(Value::from_string(
+        args[0].as_string()?.replace(&from, &args[2].as_string()?),
+    ))
+}
+
+fn index(value: &Value, len: usize) -> Fallible<usize> {
+    let i = value.as_integer()?;
+    ensure!(
+        i >= 0 && (i as usize) < len,
+        "runtime error: index {} out of range for {} items",
+        i,
+        len
+    );
+    Ok(i as usize)
+}
+
+// Returns the field at the given index after splitting on the separator.
+fn split(args: &[Value]) -> Fallible<Value> {
+    let s = args[0].as_string()?;
+    let sep = args[1].as_string()?;
+    ensure!(
+        !sep.is_empty(),
+        "runtime error: split requires a non-empty separator"
+    );
+    let fields = s.split(&sep).collect::<Vec<_>>();
+    Ok(Value::new_str(fields[index(&args[2], fields.len())?]))
+}
+
+// Returns the item of a list or the character of a string at the given
+// index. Indexing with [] is compiled down to a call to nth.
+fn nth(args: &[Value]) -> Fallible<Value> {
+    if args[0].is_list() {
+        let items = args[0].as_list()?;
+        return Ok(items[index(&args[1], items.len())?].to_owned());
+    }
+    let chars = args[0].as_string()?.chars().collect::<Vec<_>>();
+    Ok(Value::from_string(
+        chars[index(&args[1], chars.len())?].to_string(),
+    ))
+}
+
+fn text_of(value: &Value) -> Fallible<String> {
+    Ok(match &value.data {
+        ValueData::String(s) => s.to_owned(),
+        ValueData::Integer(i) => i.to_string(),
+        ValueData::Float(f) => f.to_string(),
+        ValueData::Boolean(b) => b.to_string(),
+        ValueData::Color(c) => c.to_string(),
+        ValueData::Duration(d) => d.to_string(),
+        ValueData::Timestamp(t) => t.to_string(),
+        ValueData::List(items) => format!(
+            "[{}]",
+            items
+                .iter()
+                .map(text_of)
+                .collect::<Fallible<Vec<_>>>()?
+                .join(", ")
+        ),
+        _ => bail!("runtime error: cannot format {}", value),
+    })
+}
+
+// Each {} in the format string is replaced by the next argument. Literal
+// braces are written as {{ and }}. Interpolated string literals are compiled
+// down to a call to format, so both share the same syntax.
+fn format(args: &[Value]) -> Fallible<Value> {
+    let fmt = args[0].as_string()?;
+    let mut rest = args[1..].iter();
+    let mut out = String::new();
+    let mut chars = fmt.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                out.push(c);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                let arg = rest.next().ok_or_else(|| {
+                    err_msg(format!(
+                        "runtime error: too few arguments for format \"{}\"",
+                        fmt
+                    ))
+                })?;
+                out.push_str(&text_of(arg)?);
+            }
+            ('{', _) | ('}', _) => bail!(
+                "runtime error: unmatched {} in format \"{}\"; use {{{{ or }}}} for literal braces",
+                c,
+                fmt
+            ),
+            _ => out.push(c),
+        }
+    }
+    ensure!(
+        rest.next().is_none(),
+        "runtime error: too many arguments for format \"{}\"",
+        fmt
+    );
+    Ok(Value::from_string(out))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bif::NativeFunc, float::Float, tree::TreeBuilder};
+
+    fn s(v: &str) -> Value {
+        Value::new_str(v)
+    }
+
+    fn i(v: i64) -> Value {
+        Value::from_integer(v)
+    }
+
+    fn call(nif: &dyn NativeFunc, args: &[Value]) -> Fallible<Value> {
+        assert!(nif.arity().accepts(args.len()));
+        nif.compute(args, &TreeBuilder::empty())
+    }
+
+    #[test]
+    fn test_case() -> Fallible<()> {
+        assert_eq!(call(&Upper, &[s("Hue Light")])?, s("HUE LIGHT"));
+        assert_eq!(call(&Lower, &[s("Hue Light")])?, s("hue light"));
+        assert!(call(&Upper, &[i(1)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_search() -> Fallible<()> {
+        assert_eq!(
+            call(&Contains, &[s("bhs(1, 2, 3)"), s("2,")])?,
+            Value::from_boolean(true)
+        );
+        assert_eq!(
+            call(&Contains, &[s("bhs(1, 2, 3)"), s("rgb")])?,
+            Value::from_boolean(false)
+        );
+        assert_eq!(
+            call(&StartsWith, &[s("bhs(1, 2, 3)"), s("bhs(")])?,
+            Value::from_boolean(true)
+        );
+        assert_eq!(
+            call(&StartsWith, &[s("bhs(1, 2, 3)"), s("rgb(")])?,
+            Value::from_boolean(false)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace() -> Fallible<()> {
+        assert_eq!(call(&Replace, &[s("a-b-c"), s("-"), s("/")])?, s("a/b/c"));
+        assert!(call(&Replace, &[s("a-b-c"), s(""), s("/")]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_nth() -> Fallible<()> {
+        assert_eq!(call(&Split, &[s("a, b, c"), s(", "), i(1)])?, s("b"));
+        assert_eq!(call(&Split, &[s("abc"), s(","), i(0)])?, s("abc"));
+        assert!(call(&Split, &[s("a,b"), s(","), i(2)]).is_err());
+        assert!(call(&Split, &[s("a,b"), s(","), i(-1)]).is_err());
+        assert_eq!(call(&Nth, &[s("abc"), i(2)])?, s("c"));
+        assert!(call(&Nth, &[s("abc"), i(3)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_format() -> Fallible<()> {
+        assert_eq!(
+            call(
+                &Format,
+                &[
+                    s("bhs({}, {}, {})"),
+                    i(254),
+                    Value::from_float(Float::new(0.5)?),
+                    Value::from_boolean(true)
+                ]
+            )?,
+            s("bhs(254, 0.5, true)")
+        );
+        assert_eq!(call(&Format, &[s("{{{}}}"), s("x")])?, s("{x}"));
+        assert!(call(&Format, &[s("{}")]).is_err());
+        assert!(call(&Format, &[s("{}"), i(1), i(2)]).is_err());
+        assert!(call(&Format, &[s("{"), i(1)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_strings_in_tree() -> Fallible<()> {
+        let s = r#"
+name <- "Bedroom"
+upper <- upper(/name)
+field <- split("a:b:c", ":", 2)
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(
+            tree.lookup("/upper")?.compute(&tree)?,
+            Value::new_str("BEDROOM")
+        );
+        assert_eq!(tree.lookup("/field")?.compute(&tree)?, Value::new_str("c"));
+        Ok(())
+    }
+}
@@ -0,0 +1,233 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    bif::{returns, Arity, DURATION, NUMBER, TIMESTAMP},
+    float::Float,
+    time::{Duration, Timestamp},
+    value::{Value, ValueData, ValueType},
+};
+use failure::{bail, Fallible};
+
+pure_nif!(Seconds, Arity::Exactly(1), seconds, |name, args| {
+    returns(name, args, &[NUMBER], ValueType::Duration)
+});
+pure_nif!(Minutes, Arity::Exactly(1), minutes, |name, args| {
+    returns(name, args, &[NUMBER], ValueType::Duration)
+});
+pure_nif!(Hours, Arity::Exactly(1), hours, |name, args| {
+    returns(name, args, &[NUMBER], ValueType::Duration)
+});
+pure_nif!(Days, Arity::Exactly(1), days, |name, args| {
+    returns(name, args, &[NUMBER], ValueType::Duration)
+});
+pure_nif!(InSeconds, Arity::Exactly(1), in_seconds, |name, args| {
+    returns(name, args, &[DURATION], ValueType::Float)
+});
+pure_nif!(InMinutes, Arity::Exactly(1), in_minutes, |name, args| {
+    returns(name, args, &[DURATION], ValueType::Float)
+});
+pure_nif!(InHours, Arity::Exactly(1), in_hours, |name, args| {
+    returns(name, args, &[DURATION], ValueType::Float)
+});
+pure_nif!(ToDuration, Arity::Exactly(1), duration, |name, args| {
+    let accepts = [ValueType::Duration, ValueType::String];
+    returns(name, args, &[&accepts], ValueType::Duration)
+});
+pure_nif!(ToTimestamp, Arity::Exactly(1), timestamp, |name, args| {
+    let accepts = [
+        ValueType::Timestamp,
+        ValueType::Integer,
+        ValueType::Float,
+        ValueType::String,
+    ];
+    returns(name, args, &[&accepts], ValueType::Timestamp)
+});
+pure_nif!(Unix, Arity::Exactly(1), unix, |name, args| {
+    returns(name, args, &[TIMESTAMP], ValueType::Integer)
+});
+
+fn as_number(value: &Value) -> Fallible<f64> {
+    Ok(match value.data {
+        ValueData::Integer(i) => i as f64,
+        ValueData::Float(f) => f.value,
+        _ => bail!("runtime error: expected a number, but found {}", value),
+    })
+}
+
+fn seconds(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_duration(Duration::from_secs_f64(as_number(
+        &args[0],
+    )?)?))
+}
+
+fn minutes(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_duration(Duration::from_secs_f64(
+        as_number(&args[0])? * 60.0,
+    )?))
+}
+
+fn hours(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_duration(Duration::from_secs_f64(
+        as_number(&args[0])? * 60.0 * 60.0,
+    )?))
+}
+
+fn days(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_duration(Duration::from_secs_f64(
+        as_number(&args[0])? * 60.0 * 60.0 * 24.0,
+    )?))
+}
+
+fn in_seconds(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_float(Float::new(
+        args[0].as_duration()?.as_secs_f64(),
+    )?))
+}
+
+fn in_minutes(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_float(Float::new(
+        args[0].as_duration()?.as_secs_f64() / 60.0,
+    )?))
+}
+
+fn in_hours(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_float(Float::new(
+        args[0].as_duration()?.as_secs_f64() / (60.0 * 60.0),
+    )?))
+}
+
+fn duration(args: &[Value]) -> Fallible<Value> {
+    Ok(match args[0].data {
+        ValueData::Duration(d) => Value::from_duration(d),
+        ValueData::String(ref s) => Value::from_duration(Duration::parse(s.trim())?),
+        _ => bail!("runtime error: cannot convert {} to a duration", args[0]),
+    })
+}
+
+// Timestamps are made from seconds since the unix epoch or an RFC 3339 string.
+fn timestamp(args: &[Value]) -> Fallible<Value> {
+    Ok(match args[0].data {
+        ValueData::Timestamp(t) => Value::from_timestamp(t),
+        ValueData::Integer(_) | ValueData::Float(_) => {
+            let millis = Duration::from_secs_f64(as_number(&args[0])?)?.as_millis();
+            Value::from_timestamp(Timestamp::from_unix_millis(millis))
+        }
+        ValueData::String(ref s) => Value::from_timestamp(Timestamp::parse(s.trim())?),
+        _ => bail!("runtime error: cannot convert {} to a timestamp", args[0]),
+    })
+}
+
+fn unix(args: &[Value]) -> Fallible<Value> {
+    Ok(Value::from_integer(
+        args[0].as_timestamp()?.as_unix_millis().div_euclid(1000),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{path::ConcretePath, tree::TreeBuilder};
+    use std::str::FromStr;
+
+    fn d(s: &str) -> Value {
+        Value::from_duration(Duration::parse(s).unwrap())
+    }
+
+    fn f(v: f64) -> Value {
+        Value::from_float(Float::new(v).unwrap())
+    }
+
+    #[test]
+    fn test_time_conversions() -> Fallible<()> {
+        let s = r#"
+a <- seconds(90)
+b <- minutes(1.5)
+c <- hours(2)
+e <- days(1)
+g <- in_seconds(1m30s)
+h <- in_minutes(90s)
+i <- in_hours(30m)
+j <- duration("1h30m")
+k <- unix(timestamp("2020-01-31T18:00:00Z"))
+l <- timestamp(1580493600) == timestamp("2020-01-31T18:00:00Z")
+m <- str(timestamp(1580493600) + 90m)
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        for (path, expect) in &[
+            ("/a", d("1m30s")),
+            ("/b", d("1m30s")),
+            ("/c", d("2h")),
+            ("/e", d("24h")),
+            ("/g", f(90.0)),
+            ("/h", f(1.5)),
+            ("/i", f(0.5)),
+            ("/j", d("90m")),
+            ("/k", Value::from_integer(1_580_493_600)),
+            ("/l", Value::from_boolean(true)),
+            ("/m", Value::new_str("2020-01-31T19:30:00Z")),
+        ] {
+            assert_eq!(&tree.lookup(path)?.compute(&tree)?, expect, "{}", path);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_validated_at_build() {
+        for s in &[
+            "a <- duration(\"5 minutes\")",
+            "a <- timestamp(\"yesterday\")",
+            "a <- in_seconds(5)",
+            "a <- unix(5)",
+        ] {
+            assert!(TreeBuilder::default().build_from_str(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_time_arithmetic() -> Fallible<()> {
+        let s = r#"
+now ^clock
+    default <- timestamp(0)
+last-motion ^motion
+    default <- timestamp(0)
+idle $sink <- /now - /last-motion > 10m
+a <- 2 * 5m + 30s
+b <- 10m / 4
+c <- 10m / 4m
+e <- 10m % 4m
+f <- 1h - 90m
+g <- 5m <= 300s
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        for (path, expect) in &[
+            ("/a", d("10m30s")),
+            ("/b", d("2m30s")),
+            ("/c", f(2.5)),
+            ("/e", d("2m")),
+            ("/f", d("-30m")),
+            ("/g", Value::from_boolean(true)),
+        ] {
+            assert_eq!(&tree.lookup(path)?.compute(&tree)?, expect, "{}", path);
+        }
+
+        let now = ConcretePath::from_str("/now")?;
+        let t0 = Timestamp::parse("2020-01-31T18:00:00Z")?;
+        let updates = tree.handle_event(
+            &ConcretePath::from_str("/last-motion")?,
+            Value::from_timestamp(t0),
+        )?;
+        assert_eq!(updates["sink"][0].1, Value::from_boolean(false));
+        let updates = tree.handle_event(
+            &now,
+            Value::from_timestamp(t0.checked_add(Duration::parse("5m")?)?),
+        )?;
+        assert!(updates.is_empty());
+        let updates = tree.handle_event(
+            &now,
+            Value::from_timestamp(t0.checked_add(Duration::parse("11m")?)?),
+        )?;
+        assert_eq!(updates["sink"][0].1, Value::from_boolean(true));
+        Ok(())
+    }
+}
@@ -2,10 +2,9 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use crate::{
-    bif::NativeFunc,
-    path::ConcretePath,
+    bif::{Arity, NativeFunc},
     tree::Tree,
-    value::{Value, ValueData},
+    value::{Value, ValueData, ValueType},
 };
 use failure::{bail, Fallible};
 
@@ -13,27 +12,35 @@ use failure::{bail, Fallible};
 pub(crate) struct ToStr;
 
 impl NativeFunc for ToStr {
-    fn compute(&self, value: Value, tree: &Tree) -> Fallible<Value> {
-        Ok(Value::from_string(match value.data {
-            ValueData::String(s) => s,
+    fn arity(&self) -> Arity {
+        Arity::Exactly(1)
+    }
+
+    fn compute(&self, args: &[Value], tree: &Tree) -> Fallible<Value> {
+        Ok(Value::from_string(match &args[0].data {
+            ValueData::String(s) => s.to_owned(),
             ValueData::Integer(i) => format!("{}", i),
             ValueData::Float(f) => format!("{}", f),
             ValueData::Boolean(b) => format!("{}", b),
-            ValueData::Path(p) => {
-                let (noderef, _gen) = tree.lookup_dynamic_path(0, &p)?;
-                self.compute(noderef.compute(tree)?, tree)?.as_string()?
-            }
+            ValueData::Color(c) => format!("{}", c),
+            ValueData::Duration(d) => format!("{}", d),
+            ValueData::Timestamp(t) => format!("{}", t),
+            ValueData::List(items) => format!(
+                "[{}]",
+                items
+                    .iter()
+                    .map(|item| self.compute(&[item.to_owned()], tree)?.as_string())
+                    .collect::<Fallible<Vec<_>>>()?
+                    .join(", ")
+            ),
+            ValueData::Path(_) => self.compute(&[args[0].compute(tree)?], tree)?.as_string()?,
             ValueData::InputFlag => bail!("runtime error: InputFlag in ToStr"),
-        }))
+        })
+        .with_generation(args[0].generation()))
     }
 
-    fn find_all_possible_inputs(
-        &self,
-        _value_type: (),
-        _tree: &Tree,
-        _out: &mut Vec<ConcretePath>,
-    ) -> Fallible<()> {
-        Ok(())
+    fn value_type(&self, _arg_types: &[ValueType], _tree: &Tree) -> Fallible<ValueType> {
+        Ok(ValueType::String)
     }
 
     fn box_clone(&self) -> Box<dyn NativeFunc + Send + Sync> {
@@ -0,0 +1,239 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use failure::{bail, err_msg, Fallible};
+use std::{fs, path::PathBuf, process, str::FromStr};
+use structopt::StructOpt;
+use yggdrasil::{
+    has_comments, Color, ConcretePath, Duration, Float, Timestamp, Tree, TreeBuilder, Value,
+    ValueType,
+};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "ygg", about = "Check, format and evaluate .ygg configurations")]
+struct Opt {
+    #[structopt(
+        short = "t",
+        long = "source-type",
+        number_of_values = 1,
+        help = "Declare the value type of a source kind, as kind=type"
+    )]
+    source_types: Vec<String>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Build the tree and report any errors and warnings.
+    #[structopt(name = "check")]
+    Check {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+
+    /// Rewrite the file in the canonical layout.
+    #[structopt(name = "fmt")]
+    Fmt {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        #[structopt(
+            long = "check",
+            help = "Fail if the file is not formatted, without writing"
+        )]
+        check: bool,
+
+        #[structopt(
+            long = "drop-comments",
+            help = "Format even though comments will be lost"
+        )]
+        drop_comments: bool,
+    },
+
+    /// Compute the value at a path, after setting sources as given.
+    #[structopt(name = "eval")]
+    Eval {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+
+        path: String,
+
+        #[structopt(
+            short = "s",
+            long = "set",
+            number_of_values = 1,
+            help = "Send an event to a source before computing, as path=value"
+        )]
+        set: Vec<String>,
+    },
+
+    /// List every sink and its kind.
+    #[structopt(name = "sinks")]
+    Sinks {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
+fn main() {
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Fallible<()> {
+    let mut builder = TreeBuilder::default();
+    for declaration in &opt.source_types {
+        let (kind, value_type) = split_assignment(declaration)?;
+        builder = builder.declare_source_type(kind, parse_value_type(value_type)?)?;
+    }
+    match opt.command {
+        Command::Check { file } => {
+            let tree = builder.build_from_file(&file)?;
+            for warning in tree.warnings() {
+                println!("warning: {}", warning);
+            }
+        }
+        Command::Fmt {
+            file,
+            check,
+            drop_comments,
+        } => {
+            let contents = fs::read_to_string(&file)?;
+            let formatted = builder.build_from_file(&file)?.to_ygg_string();
+            if check {
+                if formatted != contents {
+                    bail!("{} is not formatted", file.display());
+                }
+                return Ok(());
+            }
+            // Comments are stripped by the tokenizer, so we cannot put them back.
+            if has_comments(&contents) && !drop_comments {
+                bail!(
+                    "{} has comments, which fmt would drop; pass --drop-comments to format anyway",
+                    file.display()
+                );
+            }
+            if formatted != contents {
+                fs::write(&file, formatted)?;
+            }
+        }
+        Command::Eval { file, path, set } => {
+            let mut tree = builder.build_from_file(&file)?;
+            for assignment in &set {
+                let (source, value) = split_assignment(assignment)?;
+                tree.handle_event(&ConcretePath::from_str(source)?, parse_value(value)?)?;
+            }
+            println!("{}", eval(&tree, &path)?.literal());
+        }
+        Command::Sinks { file } => {
+            let tree = builder.build_from_file(&file)?;
+            for (path, kind) in tree.sinks() {
+                println!("{} {}", path, kind);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn eval(tree: &Tree, path: &str) -> Fallible<Value> {
+    tree.lookup(path)?.compute(tree)
+}
+
+fn split_assignment(s: &str) -> Fallible<(&str, &str)> {
+    match s.find('=') {
+        Some(offset) => Ok((&s[..offset], &s[offset + 1..])),
+        None => bail!("expected name=value, but got {}", s),
+    }
+}
+
+fn parse_value_type(s: &str) -> Fallible<ValueType> {
+    [
+        ValueType::Boolean,
+        ValueType::Color,
+        ValueType::Duration,
+        ValueType::Float,
+        ValueType::Integer,
+        ValueType::List,
+        ValueType::String,
+        ValueType::Timestamp,
+    ]
+    .iter()
+    .find(|t| t.to_string() == s)
+    .copied()
+    .ok_or_else(|| err_msg(format!("unknown value type {}", s)))
+}
+
+// Values on the command line are taken to be the first type they parse as.
+// Quote a value to force it to be a string.
+fn parse_value(s: &str) -> Fallible<Value> {
+    if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+        return Ok(Value::new_str(&s[1..s.len() - 1]));
+    }
+    Ok(match s {
+        "true" => Value::from_boolean(true),
+        "false" => Value::from_boolean(false),
+        _ => {
+            if let Ok(i) = s.parse::<i64>() {
+                Value::from_integer(i)
+            } else if let Ok(f) = s.parse::<f64>() {
+                Value::from_float(Float::new(f)?)
+            } else if let Ok(d) = Duration::parse(s) {
+                Value::from_duration(d)
+            } else if let Ok(t) = Timestamp::parse(s) {
+                Value::from_timestamp(t)
+            } else if let Ok(c) = Color::parse(s) {
+                Value::from_color(c)
+            } else {
+                Value::new_str(s)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_value() -> Fallible<()> {
+        assert_eq!(parse_value("true")?, Value::from_boolean(true));
+        assert_eq!(parse_value("-3")?, Value::from_integer(-3));
+        assert_eq!(parse_value("0.5")?, Value::from_float(Float::new(0.5)?));
+        assert_eq!(
+            parse_value("1h30m")?,
+            Value::from_duration(Duration::from_millis(5_400_000))
+        );
+        assert_eq!(
+            parse_value("rgb(255, 0, 0)")?,
+            Value::from_color(Color::parse("rgb(255, 0, 0)")?)
+        );
+        assert_eq!(parse_value("night")?, Value::new_str("night"));
+        assert_eq!(parse_value("\"true\"")?, Value::new_str("true"));
+        assert_eq!(split_assignment("/a/b=x=y")?, ("/a/b", "x=y"));
+        assert!(split_assignment("/a/b").is_err());
+        assert_eq!(parse_value_type("integer")?, ValueType::Integer);
+        assert!(parse_value_type("any").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval() -> Fallible<()> {
+        let mut tree = TreeBuilder::default().build_from_str(
+            r#"
+mode ^switch
+    default <- "day"
+light $hue <- /mode == "night"
+"#,
+        )?;
+        assert_eq!(eval(&tree, "/light")?, Value::from_boolean(false));
+        tree.handle_event(&ConcretePath::from_str("/mode")?, parse_value("night")?)?;
+        assert_eq!(eval(&tree, "/light")?, Value::from_boolean(true));
+        assert_eq!(eval(&tree, "/mode")?.literal(), "\"night\"");
+        Ok(())
+    }
+}
@@ -1,12 +1,15 @@
 // This Source Code Form is subject to the terms of the GNU General Public
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+#![allow(clippy::upper_case_acronyms)]
+
 use approx::relative_eq;
 use failure::{bail, ensure, Fallible};
 use lazy_static::lazy_static;
 use regex::Regex;
+use std::fmt;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct BHS {
     pub brightness: u8,
     pub hue: u16,
@@ -29,7 +32,7 @@ impl BHS {
         let max = r.max(g).max(b);
         let min = r.min(g).min(b);
 
-        let hue = if relative_eq!(max, min) {
+        let sextant = if relative_eq!(max, min) {
             0.0
         } else if relative_eq!(max, r) {
             0.0 + (g - b) / (max - min)
@@ -41,20 +44,21 @@ impl BHS {
             unreachable!()
         };
 
+        // Hue is measured in sixths of the circle above; the hub wants the
+        // full circle spread over 0..65536.
+        let hue = (sextant / 6.0).rem_euclid(1.0);
         let saturation = if max == 0.0 { 0.0 } else { (max - min) / max };
         let v = max;
 
-        let bhs = Self {
+        Self {
             brightness: (v * 255.0) as u8,
             hue: (hue * 65536.0) as u16,
             saturation: (saturation * 255.0) as u8,
-        };
-
-        bhs
+        }
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct RGB {
     pub red: u8,
     pub green: u8,
@@ -67,7 +71,7 @@ impl RGB {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct Mired {
     pub color_temp: u8,
 }
@@ -84,9 +88,19 @@ impl Mired {
         );
         Ok(Self { color_temp })
     }
+
+    pub fn from_kelvin(kelvin: i64) -> Fallible<Self> {
+        ensure!(kelvin > 0, "kelvin: color temp must be positive");
+        let mired = (1_000_000.0 / kelvin as f64).round();
+        ensure!(
+            (40.0..=200.0).contains(&mired),
+            "kelvin: color temp must be between 5000 and 25000"
+        );
+        Self::new(mired as u8)
+    }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Color {
     BHS(BHS),
     RGB(RGB),
@@ -119,6 +133,85 @@ impl Color {
         }
         bail!("color: not a color: '{}'", s)
     }
+
+    /// Scale the brightness of the color, as for `color * 0.5` in a script.
+    pub fn scale(&self, factor: f64) -> Fallible<Color> {
+        ensure!(
+            factor >= 0.0,
+            "color: cannot scale by a negative factor {}",
+            factor
+        );
+        let scale = |c: u8| (f64::from(c) * factor).round().min(255.0) as u8;
+        Ok(match self {
+            Color::BHS(bhs) => Color::BHS(BHS {
+                brightness: scale(bhs.brightness),
+                ..*bhs
+            }),
+            Color::RGB(rgb) => {
+                Color::RGB(RGB::new(scale(rgb.red), scale(rgb.green), scale(rgb.blue))?)
+            }
+            Color::Mired(_) => bail!("color: cannot scale the brightness of a color temperature"),
+        })
+    }
+
+    /// Blend from this color (at t = 0) to the other color (at t = 1). Colors
+    /// of different kinds are blended in BHS, taking the short way around the
+    /// hue circle.
+    pub fn mix(&self, other: &Color, t: f64) -> Fallible<Color> {
+        ensure!(
+            (0.0..=1.0).contains(&t),
+            "color: mix factor {} is not between 0 and 1",
+            t
+        );
+        let lerp = |a: u8, b: u8| (f64::from(a) + (f64::from(b) - f64::from(a)) * t).round() as u8;
+        Ok(match (self, other) {
+            (Color::RGB(a), Color::RGB(b)) => Color::RGB(RGB::new(
+                lerp(a.red, b.red),
+                lerp(a.green, b.green),
+                lerp(a.blue, b.blue),
+            )?),
+            (Color::Mired(a), Color::Mired(b)) => {
+                Color::Mired(Mired::new(lerp(a.color_temp, b.color_temp))?)
+            }
+            (Color::Mired(_), _) | (_, Color::Mired(_)) => {
+                bail!("color: cannot mix a color temperature with a color")
+            }
+            _ => {
+                let a = self.to_bhs();
+                let b = other.to_bhs();
+                let mut delta = f64::from(b.hue) - f64::from(a.hue);
+                if delta > 32768.0 {
+                    delta -= 65536.0;
+                } else if delta < -32768.0 {
+                    delta += 65536.0;
+                }
+                let hue = (f64::from(a.hue) + delta * t).round().rem_euclid(65536.0);
+                Color::BHS(BHS::new(
+                    lerp(a.brightness, b.brightness),
+                    hue as u16,
+                    lerp(a.saturation, b.saturation),
+                )?)
+            }
+        })
+    }
+
+    fn to_bhs(self) -> BHS {
+        match self {
+            Color::BHS(bhs) => bhs,
+            Color::RGB(rgb) => BHS::from_rgb(&rgb),
+            Color::Mired(_) => unreachable!("mired colors have no hue"),
+        }
+    }
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Color::BHS(c) => write!(f, "bhs({}, {}, {})", c.brightness, c.hue, c.saturation),
+            Color::RGB(c) => write!(f, "rgb({}, {}, {})", c.red, c.green, c.blue),
+            Color::Mired(c) => write!(f, "mired({})", c.color_temp),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -191,6 +284,60 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_kelvin() -> Fallible<()> {
+        assert_eq!(Mired::from_kelvin(6500)?, Mired::new(154)?);
+        assert_eq!(Mired::from_kelvin(25000)?, Mired::new(40)?);
+        assert!(Mired::from_kelvin(2700).is_err());
+        assert!(Mired::from_kelvin(0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_round_trip() -> Fallible<()> {
+        for s in &["bhs(254, 34495, 254)", "rgb(206, 92, 0)", "mired(154)"] {
+            assert_eq!(&Color::parse(s)?.to_string(), s);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_scale() -> Fallible<()> {
+        assert_eq!(
+            Color::parse("bhs(254, 100, 200)")?.scale(0.5)?,
+            Color::parse("bhs(127, 100, 200)")?
+        );
+        assert_eq!(
+            Color::parse("rgb(200, 100, 0)")?.scale(2.0)?,
+            Color::parse("rgb(255, 200, 0)")?
+        );
+        assert!(Color::parse("mired(154)")?.scale(0.5).is_err());
+        assert!(Color::parse("rgb(1, 1, 1)")?.scale(-1.0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_mix() -> Fallible<()> {
+        let a = Color::parse("rgb(0, 100, 200)")?;
+        let b = Color::parse("rgb(200, 100, 0)")?;
+        assert_eq!(a.mix(&b, 0.0)?, a);
+        assert_eq!(a.mix(&b, 1.0)?, b);
+        assert_eq!(a.mix(&b, 0.5)?, Color::parse("rgb(100, 100, 100)")?);
+        assert_eq!(
+            Color::parse("mired(40)")?.mix(&Color::parse("mired(200)")?, 0.25)?,
+            Color::parse("mired(80)")?
+        );
+
+        // Hue takes the short way around the circle.
+        let c = Color::parse("bhs(100, 65000, 0)")?;
+        let d = Color::parse("bhs(200, 1000, 254)")?;
+        assert_eq!(c.mix(&d, 0.5)?, Color::parse("bhs(150, 232, 127)")?);
+
+        assert!(a.mix(&Color::parse("mired(40)")?, 0.5).is_err());
+        assert!(a.mix(&b, 1.5).is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_bhs_to_rgb() -> Fallible<()> {
         assert_eq!(
@@ -198,7 +345,7 @@ mod test {
                 red: 128,
                 green: 0,
                 blue: 0,
-            })?,
+            }),
             BHS {
                 brightness: 127,
                 hue: 0,
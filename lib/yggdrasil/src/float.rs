@@ -8,7 +8,7 @@ use std::{
     ops::{Add, Div, Mul, Neg, Sub},
 };
 
-#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Float {
     pub value: f64,
 }
@@ -25,6 +25,7 @@ impl Float {
     }
 
     pub fn checked_div(self, rhs: Float) -> Fallible<Float> {
+        ensure!(rhs.value != 0.0, "numerical error: division by zero");
         Float::new(self.value / rhs.value)
     }
 
@@ -32,6 +33,11 @@ impl Float {
         Float::new(self.value * rhs.value)
     }
 
+    pub fn checked_rem(self, rhs: Float) -> Fallible<Float> {
+        ensure!(rhs.value != 0.0, "numerical error: remainder by zero");
+        Float::new(self.value % rhs.value)
+    }
+
     pub fn checked_neg(self) -> Fallible<Float> {
         Float::new(-self.value)
     }
@@ -73,7 +79,14 @@ impl Neg for Float {
 
 impl Ord for Float {
     fn cmp(&self, other: &Float) -> Ordering {
-        self.partial_cmp(other).unwrap()
+        // Float::new rejects NaN, so all values are comparable.
+        self.value.partial_cmp(&other.value).unwrap()
+    }
+}
+
+impl PartialOrd for Float {
+    fn partial_cmp(&self, other: &Float) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
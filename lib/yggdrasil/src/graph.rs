@@ -3,24 +3,22 @@
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use crate::tree::NodeRef;
 use failure::{ensure, Fallible};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 /// A simplified graph that we can use to find paths from all inputs to the outputs they affect.
+/// Edges point from the node that is read to the node that reads it.
 pub struct Graph {
     nodes: HashMap<String, NodeRef>,
-    edges: Vec<Edge>,
-}
-
-struct Edge {
-    start: String,
-    end: String,
+    outputs: HashMap<String, HashSet<String>>,
+    inputs: HashMap<String, HashSet<String>>,
 }
 
 impl Graph {
     pub fn new_empty() -> Self {
         Self {
             nodes: HashMap::new(),
-            edges: Vec::new(),
+            outputs: HashMap::new(),
+            inputs: HashMap::new(),
         }
     }
 
@@ -33,26 +31,149 @@ impl Graph {
     }
 
     pub fn add_edge(&mut self, src_node: &NodeRef, tgt_node: &NodeRef) {
-        self.edges.push(Edge {
-            start: src_node.path_str(),
-            end: tgt_node.path_str(),
-        })
+        let start = src_node.path_str();
+        let end = tgt_node.path_str();
+        self.outputs
+            .entry(start.clone())
+            .or_default()
+            .insert(end.clone());
+        self.inputs.entry(end).or_default().insert(start);
     }
 
-    pub fn invert(mut self) -> Fallible<Self> {
-        let mut next_edges = Vec::new();
-        for edge in self.edges.drain(..) {
-            next_edges.push(Edge {
-                start: edge.end,
-                end: edge.start,
-            });
-        }
+    pub fn invert(self) -> Fallible<Self> {
         Ok(Self {
             nodes: self.nodes,
-            edges: next_edges,
+            outputs: self.inputs,
+            inputs: self.outputs,
         })
     }
 
+    /// Replace all edges into the given node with edges from the given inputs.
+    pub fn set_inputs(&mut self, node: &NodeRef, inputs: &[NodeRef]) {
+        let path = node.path_str();
+        if let Some(prior) = self.inputs.remove(&path) {
+            for input in &prior {
+                if let Some(outputs) = self.outputs.get_mut(input) {
+                    outputs.remove(&path);
+                }
+            }
+        }
+        self.add_node(node);
+        for input in inputs {
+            self.add_node(input);
+            self.add_edge(input, node);
+        }
+    }
+
+    /// The nodes that read directly from the given node.
+    pub fn dependents_of(&self, node: &NodeRef) -> Vec<NodeRef> {
+        match self.outputs.get(&node.path_str()) {
+            Some(outputs) => outputs
+                .iter()
+                .filter_map(|path| self.nodes.get(path))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Find every cycle in the graph. Each is given as the paths along the
+    /// cycle, starting and ending with the same path.
+    pub fn find_cycles(&self) -> Vec<Vec<String>> {
+        let mut state = Tarjan::default();
+        let mut paths = self.nodes.keys().collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            if !state.index.contains_key(path) {
+                self._strong_connect(path, &mut state);
+            }
+        }
+        state
+            .components
+            .iter()
+            .filter(|scc| scc.len() > 1 || self.outputs_of(&scc[0]).any(|end| end == &scc[0]))
+            .map(|scc| self._cycle_through(scc))
+            .collect()
+    }
+
+    fn outputs_of<'a>(&'a self, path: &str) -> impl Iterator<Item = &'a String> {
+        let mut outputs = self
+            .outputs
+            .get(path)
+            .map(|outputs| outputs.iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+        outputs.sort();
+        outputs.into_iter()
+    }
+
+    // Tarjan's algorithm: each strongly connected component is a set of nodes
+    // that can all reach each other, so any with more than one node is a cycle.
+    fn _strong_connect(&self, path: &str, state: &mut Tarjan) {
+        let index = state.index.len();
+        state.index.insert(path.to_owned(), index);
+        state.lowlink.insert(path.to_owned(), index);
+        state.stack.push(path.to_owned());
+        state.on_stack.insert(path.to_owned());
+
+        for end in self.outputs_of(path) {
+            if !state.index.contains_key(end) {
+                self._strong_connect(end, state);
+                let low = state.lowlink[path].min(state.lowlink[end]);
+                state.lowlink.insert(path.to_owned(), low);
+            } else if state.on_stack.contains(end) {
+                let low = state.lowlink[path].min(state.index[end]);
+                state.lowlink.insert(path.to_owned(), low);
+            }
+        }
+
+        if state.lowlink[path] == state.index[path] {
+            let mut component = Vec::new();
+            while let Some(member) = state.stack.pop() {
+                state.on_stack.remove(&member);
+                let done = member == path;
+                component.push(member);
+                if done {
+                    break;
+                }
+            }
+            component.sort();
+            state.components.push(component);
+        }
+    }
+
+    // Find the shortest cycle through the first node of a strongly connected
+    // component, staying inside the component.
+    fn _cycle_through(&self, scc: &[String]) -> Vec<String> {
+        let start = &scc[0];
+        let members = scc.iter().collect::<HashSet<_>>();
+        let mut parent: HashMap<&String, &String> = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back(start);
+        while let Some(current) = queue.pop_front() {
+            for end in self.outputs_of(current) {
+                if !members.contains(end) {
+                    continue;
+                }
+                if end == start {
+                    let mut cycle = vec![start.to_owned()];
+                    let mut at = current;
+                    while at != start {
+                        cycle.push(at.to_owned());
+                        at = parent[at];
+                    }
+                    cycle.push(start.to_owned());
+                    cycle.reverse();
+                    return cycle;
+                }
+                if !parent.contains_key(end) {
+                    parent.insert(end, current);
+                    queue.push_back(end);
+                }
+            }
+        }
+        unreachable!("strongly connected component without a cycle")
+    }
+
     pub fn connected_nodes(&self, from: &NodeRef, to: &[NodeRef]) -> Fallible<Vec<NodeRef>> {
         let mut found = HashSet::new();
         let mut visited = HashSet::new();
@@ -89,15 +210,14 @@ impl Graph {
         }
 
         // Find all targets of current.
-        for edge in &self.edges {
-            if edge.start == current_path {
-                // FIXME: is there a way to check for existance up front? Otherwise we need to propogate this error.
+        if let Some(outputs) = self.outputs.get(&current_path) {
+            for end in outputs {
                 ensure!(
-                    self.nodes.contains_key(&edge.end),
+                    self.nodes.contains_key(end),
                     "dataflow error: source node {} does not exist",
-                    &edge.end
+                    end
                 );
-                let next = &self.nodes[&edge.end];
+                let next = &self.nodes[end];
                 self._connected_at(next, targets, visited, out)?;
             }
         }
@@ -105,3 +225,12 @@ impl Graph {
         Ok(())
     }
 }
+
+#[derive(Default)]
+struct Tarjan {
+    index: HashMap<String, usize>,
+    lowlink: HashMap<String, usize>,
+    stack: Vec<String>,
+    on_stack: HashSet<String>,
+    components: Vec<Vec<String>>,
+}
@@ -2,18 +2,26 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 mod bif;
+mod color;
 mod float;
 mod graph;
 mod parser;
 mod path;
 mod physical;
 mod script;
+mod source;
+mod state;
+mod time;
 mod tokenizer;
 mod tree;
 mod value;
 
-pub use self::bif::NativeFunc;
+pub use self::bif::{Arity, NativeFunc};
+pub use self::color::{Color, Mired, BHS, RGB};
 pub use self::float::Float;
 pub use self::path::ConcretePath;
-pub use self::tree::{Tree, TreeBuilder};
-pub use self::value::Value;
+pub use self::source::SourceError;
+pub use self::time::{Duration, Timestamp};
+pub use self::tokenizer::has_comments;
+pub use self::tree::{Tree, TreeBuilder, TreeChanges};
+pub use self::value::{Value, ValueType};
@@ -4,19 +4,148 @@
 use crate::{
     bif::NativeFunc,
     script::Script,
-    tokenizer::{Token, TreeTokenizer},
+    source::{SourceError, SourceFile, SourceLocation},
+    state::Stateful,
+    tokenizer::{Spanned, Token, TreeTokenizer},
     tree::{NodeRef, Tree},
 };
-use failure::{bail, ensure, format_err, Fallible};
-use std::collections::HashMap;
+use failure::{bail, ensure, format_err, Error, Fallible};
+use std::{cell::Cell, collections::HashMap, fs, path::Path, sync::Arc};
 use tracing::trace;
 
+// A template body, kept as tokens, and the parameters that must be bound by
+// every use of the template.
+#[derive(Clone, Debug)]
+struct Template {
+    params: Vec<String>,
+    body: Vec<Spanned>,
+}
+
+impl Template {
+    fn new(name: &str, params: Vec<String>, body: Vec<Spanned>) -> Fallible<Self> {
+        for spanned in &body {
+            Self::map_params(&spanned.token, &mut |param| {
+                ensure!(
+                    params.iter().any(|p| p == param),
+                    "parse error: unknown parameter ${{{}}} in template {}",
+                    param,
+                    name
+                );
+                Ok(Token::PathTerm(format!("${{{}}}", param)))
+            })
+            .map_err(|e| spanned.location.annotate(e))?;
+        }
+        Ok(Self { params, body })
+    }
+
+    // Produce the body with every ${param} replaced by the bound argument.
+    fn bind(&self, name: &str, args: &[(String, Token)]) -> Fallible<Vec<Spanned>> {
+        let mut bound = HashMap::new();
+        for (param, value) in args {
+            ensure!(
+                self.params.contains(param),
+                "parse error: unknown argument {} to template {}",
+                param,
+                name
+            );
+            ensure!(
+                bound.insert(param.as_str(), value).is_none(),
+                "parse error: argument {} given twice to template {}",
+                param,
+                name
+            );
+        }
+        for param in &self.params {
+            ensure!(
+                bound.contains_key(param.as_str()),
+                "parse error: missing argument {} to template {}",
+                param,
+                name
+            );
+        }
+        self.body
+            .iter()
+            .map(|spanned| {
+                Ok(Spanned {
+                    token: Self::map_params(&spanned.token, &mut |param| {
+                        Ok(bound[param].to_owned())
+                    })
+                    .map_err(|e| spanned.location.annotate(e))?,
+                    location: spanned.location.to_owned(),
+                })
+            })
+            .collect()
+    }
+
+    // Rewrite a single token, passing every parameter reference in it through `f`.
+    // A path that consists of only a parameter is replaced by the argument token
+    // wholesale, so that parameters can stand in for values as well as paths.
+    fn map_params(token: &Token, f: &mut dyn FnMut(&str) -> Fallible<Token>) -> Fallible<Token> {
+        Ok(match token {
+            Token::PathTerm(path) if path.contains("${") => {
+                if path.starts_with("${") && path.find('}') == Some(path.len() - 1) {
+                    return f(&path[2..path.len() - 1]);
+                }
+                let mut out = String::new();
+                let mut rest = path.as_str();
+                while let Some(start) = rest.find("${") {
+                    let end = start
+                        + rest[start..].find('}').ok_or_else(|| {
+                            format_err!("parse error: unterminated parameter in {}", path)
+                        })?;
+                    out.push_str(&rest[..start]);
+                    out.push_str(&Self::path_text(&f(&rest[start + 2..end])?)?);
+                    rest = &rest[end + 1..];
+                }
+                out.push_str(rest);
+                Token::PathTerm(out)
+            }
+            Token::UseTemplate(name, args) => Token::UseTemplate(
+                name.to_owned(),
+                args.iter()
+                    .map(|(param, value)| Ok((param.to_owned(), Self::map_params(value, f)?)))
+                    .collect::<Fallible<Vec<_>>>()?,
+            ),
+            Token::FormatTerm(fmt, parts) => Token::FormatTerm(
+                fmt.to_owned(),
+                parts
+                    .iter()
+                    .map(|part| part.iter().map(|t| Self::map_params(t, f)).collect())
+                    .collect::<Fallible<Vec<_>>>()?,
+            ),
+            t => t.to_owned(),
+        })
+    }
+
+    // The text of an argument when spliced into a larger path.
+    fn path_text(token: &Token) -> Fallible<String> {
+        Ok(match token {
+            Token::PathTerm(s) | Token::NameTerm(s) | Token::StringTerm(s) => s.to_owned(),
+            Token::IntegerTerm(i) => i.to_string(),
+            Token::BooleanTerm(b) => b.to_string(),
+            _ => bail!(
+                "parse error: {:?} cannot be used as a path component",
+                token
+            ),
+        })
+    }
+}
+
 pub struct TreeParser<'a> {
     nifs: &'a HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
     import_interceptors: &'a HashMap<String, Tree>,
-    templates: HashMap<String, NodeRef>,
-    tokens: Vec<Token>,
+    templates: HashMap<String, Template>,
+    template_stack: Vec<String>,
+    tokens: Vec<Spanned>,
     position: usize,
+
+    // The last token we looked at, which is where errors get reported.
+    last: Cell<usize>,
+
+    // Whether to record what was written on the nodes, for writing the tree
+    // back out. Imported files and template bodies are written as the
+    // import or the use of the template, so their parsers do not.
+    record_layout: bool,
 }
 
 impl<'a> TreeParser<'a> {
@@ -37,33 +166,60 @@ impl<'a> TreeParser<'a> {
     //
     pub fn from_str(
         tree: Tree,
+        name: &str,
         s: &str,
         nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
         import_interceptors: &HashMap<String, Tree>,
     ) -> Fallible<Tree> {
-        let sanitized = s.replace('\t', "    ");
-
-        {
-            let tokens = TreeTokenizer::tokenize(&sanitized)?;
-            let mut parser = TreeParser {
-                nifs,
-                import_interceptors,
-                templates: HashMap::new(),
-                tokens,
-                position: 0,
-            };
-            parser.consume_root(&tree.root())?;
-        }
-
+        let file = SourceFile::new(name, &s.replace('\t', "    "));
+        let mut parser = TreeParser::new(&file, nifs, import_interceptors)?;
+        parser
+            .consume_root(&tree.root())
+            .map_err(|e| parser.annotate(e))?;
         Ok(tree)
     }
 
+    fn new(
+        file: &Arc<SourceFile>,
+        nifs: &'a HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
+        import_interceptors: &'a HashMap<String, Tree>,
+    ) -> Fallible<Self> {
+        Ok(TreeParser {
+            nifs,
+            import_interceptors,
+            templates: HashMap::new(),
+            template_stack: Vec::new(),
+            tokens: TreeTokenizer::tokenize_source(file)?,
+            position: 0,
+            last: Cell::new(0),
+            record_layout: true,
+        })
+    }
+
+    fn location(&self) -> Option<&SourceLocation> {
+        self.tokens
+            .get(self.last.get())
+            .or_else(|| self.tokens.last())
+            .map(|spanned| &spanned.location)
+    }
+
+    fn annotate(&self, error: Error) -> Error {
+        match self.location() {
+            Some(location) => location.annotate(error),
+            None => error,
+        }
+    }
+
     fn consume_root(&mut self, root: &NodeRef) -> Fallible<()> {
         while !self.out_of_input() {
             match self.peek()? {
                 Token::NameTerm(_n) => {
                     self.consume_tree(root)?;
                 }
+                Token::Template(name, params) => {
+                    self.pop()?;
+                    self.consume_template(&name, params, root)?;
+                }
                 Token::ImportTerm(filename) => {
                     self.do_import(&filename, root)?;
                     self.pop()?;
@@ -89,6 +245,9 @@ impl<'a> TreeParser<'a> {
             parent.name()
         );
         let child = parent.add_child(&name)?;
+        if self.record_layout {
+            parent.record_child(&name);
+        }
         self.consume_inline_suite(&child)?;
         if self.out_of_input() || self.peek()? != Token::Indent {
             trace!("finished tree {}", name);
@@ -98,11 +257,16 @@ impl<'a> TreeParser<'a> {
         // Next token is indent, so parse any body and any children.
         self.pop()?;
         self.consume_block_suite(&child)?;
+        self.consume_children(&child)
+    }
+
+    // After the block suite up to and including the dedent, or end of input.
+    fn consume_children(&mut self, parent: &NodeRef) -> Fallible<()> {
         while !self.out_of_input() {
             match self.peek()? {
-                Token::NameTerm(ref _s) => self.consume_tree(&child)?,
-                Token::BooleanTerm(ref _b) => self.consume_tree(&child)?,
-                Token::IntegerTerm(ref _i) => self.consume_tree(&child)?,
+                Token::NameTerm(ref _s) => self.consume_tree(parent)?,
+                Token::BooleanTerm(ref _b) => self.consume_tree(parent)?,
+                Token::IntegerTerm(ref _i) => self.consume_tree(parent)?,
                 Token::Dedent => {
                     self.pop()?;
                     return Ok(());
@@ -116,6 +280,105 @@ impl<'a> TreeParser<'a> {
         Ok(())
     }
 
+    // After `template name` up to and including the dedent. Templates are
+    // stored lexically so that relative paths in the body can be resolved
+    // against the node the template gets applied to.
+    fn consume_template(
+        &mut self,
+        name: &str,
+        params: Vec<String>,
+        root: &NodeRef,
+    ) -> Fallible<()> {
+        ensure!(
+            self.pop()? == Token::Newline,
+            "parse error: expected newline after template {}",
+            name
+        );
+        ensure!(
+            !self.out_of_input() && self.pop()? == Token::Indent,
+            "parse error: expected an indented body after template {}",
+            name
+        );
+        ensure!(
+            !self.templates.contains_key(name),
+            "parse error: template {} defined twice",
+            name
+        );
+        let end = self.find_next_matching_dedent();
+        let body_end = if self.tokens[end - 1].token == Token::Dedent {
+            end - 1
+        } else {
+            end
+        };
+        let body = self.tokens[self.position..body_end].to_vec();
+        trace!("template {} tokens: {:?}", name, body);
+        if self.record_layout {
+            root.record_template(
+                Token::Template(name.to_owned(), params.clone()),
+                body.iter()
+                    .map(|spanned| spanned.token.to_owned())
+                    .collect(),
+            );
+        }
+        self.templates
+            .insert(name.to_owned(), Template::new(name, params, body)?);
+        self.position = end;
+        Ok(())
+    }
+
+    fn apply_template(&self, name: &str, args: &[(String, Token)], node: &NodeRef) -> Fallible<()> {
+        let body = self
+            .templates
+            .get(name)
+            .ok_or_else(|| format_err!("parse error: unknown template: {}", name))?
+            .bind(name, args)?;
+        ensure!(
+            !self.template_stack.iter().any(|s| s == name),
+            "parse error: template {} applied recursively via {:?} @ {}",
+            name,
+            self.template_stack,
+            node.path_str()
+        );
+        let mut template_stack = self.template_stack.clone();
+        template_stack.push(name.to_owned());
+        let mut parser = TreeParser {
+            nifs: self.nifs,
+            import_interceptors: self.import_interceptors,
+            templates: self.templates.clone(),
+            template_stack,
+            tokens: body,
+            position: 0,
+            last: Cell::new(0),
+            record_layout: false,
+        };
+        let applied = parser
+            .consume_block_suite(node)
+            .and_then(|()| parser.consume_children(node))
+            .and_then(|()| {
+                ensure!(
+                    parser.out_of_input(),
+                    "parse error: unexpected dedent in template {}",
+                    name
+                );
+                Ok(())
+            });
+
+        // Errors are found in the template body, so also say where it was used.
+        applied.map_err(|e| {
+            let mut e = parser.annotate(e);
+            if let Some(location) = self.location() {
+                e = SourceError::add_note(
+                    e,
+                    format!("in template {} applied at {}", name, location),
+                );
+                for import in location.file().imported_from().iter().rev() {
+                    e = SourceError::add_note(e, format!("imported from {}", import));
+                }
+            }
+            e
+        })
+    }
+
     // After name up to the newline.
     fn consume_inline_suite(&mut self, node: &NodeRef) -> Fallible<()> {
         trace!(
@@ -161,7 +424,7 @@ impl<'a> TreeParser<'a> {
     fn find_next_token(&self, tok: &Token) -> Fallible<usize> {
         let mut i = self.position;
         while i < self.tokens.len() {
-            if &self.tokens[i] == tok {
+            if &self.tokens[i].token == tok {
                 return Ok(i);
             }
             i += 1;
@@ -173,10 +436,10 @@ impl<'a> TreeParser<'a> {
         self.position + Self::find_matching_dedent(&self.tokens[self.position..])
     }
 
-    pub(super) fn find_matching_dedent(tokens: &[Token]) -> usize {
+    pub(super) fn find_matching_dedent(tokens: &[Spanned]) -> usize {
         let mut level = 0;
-        for (i, token) in tokens.iter().enumerate() {
-            match token {
+        for (i, spanned) in tokens.iter().enumerate() {
+            match spanned.token {
                 Token::Indent => level += 1,
                 Token::Dedent => {
                     if level == 0 {
@@ -199,11 +462,12 @@ impl<'a> TreeParser<'a> {
             Token::Sink(ref s) => node.set_sink(s)?,
             Token::ComesFromInline => {
                 let end = self.find_next_token(&Token::Newline)?;
-                let s = Script::inline_from_tokens(
-                    node.path_str(),
-                    &self.tokens[self.position..end],
-                    self.nifs,
-                )?;
+                let tokens = &self.tokens[self.position..end];
+                if let Some(stateful) = Stateful::maybe_from_tokens(&node.path_str(), tokens)? {
+                    self.position = end;
+                    return node.set_state(stateful);
+                }
+                let s = Script::inline_from_tokens(node.path_str(), tokens, self.nifs)?;
                 self.position = end;
                 node.set_script(s)?
             }
@@ -218,27 +482,87 @@ impl<'a> TreeParser<'a> {
                 // Since this is parsed as a sigil, we expect to end with a newline, but since
                 // we were indented the Dedent happened after the closing Newline, so inject
                 // an extra one here.
-                self.tokens.insert(self.position, Token::Newline);
+                let location = self.tokens[self.position - 1].location.to_owned();
+                self.tokens.insert(
+                    self.position,
+                    Spanned {
+                        token: Token::Newline,
+                        location,
+                    },
+                );
                 node.set_script(s)?
             }
             Token::ImportTerm(filename) => self.do_import(&filename, node)?,
-            Token::UseTemplate(ref s) => {
-                let template: &NodeRef = self
-                    .templates
-                    .get(s)
-                    .ok_or_else(|| format_err!("parse error: unknown template: {}", s))?;
-                node.apply_template(template)?
-            }
+            Token::UseTemplate(ref s, ref args) => self.use_template(s, args, node)?,
             _ => bail!("parse error: expected to find a sigil-delimited token"),
         }
         Ok(())
     }
 
+    // The node remembers what each template use added to it, so that the
+    // tree can be written back out with the use in its place.
+    fn use_template(&self, name: &str, args: &[(String, Token)], node: &NodeRef) -> Fallible<()> {
+        if !self.record_layout {
+            return self.apply_template(name, args, node);
+        }
+        let existing = node.child_names();
+        let sigils = node.sigils();
+        self.apply_template(name, args, node)?;
+        let added = node
+            .child_names()
+            .into_iter()
+            .filter(|name| !existing.contains(name))
+            .collect();
+        node.record_template_use(
+            Token::UseTemplate(name.to_owned(), args.to_vec()),
+            added,
+            node.sigils().since(sigils),
+        );
+        Ok(())
+    }
+
+    // The parent remembers which of its children came from the import, so
+    // that the tree can be written back out with the import in their place.
     fn do_import(&mut self, filename: &str, parent: &NodeRef) -> Fallible<()> {
+        if !self.record_layout {
+            return self.read_import(filename, parent);
+        }
+        let existing = parent.child_names();
+        self.read_import(filename, parent)?;
+        let imported = parent
+            .child_names()
+            .into_iter()
+            .filter(|name| !existing.contains(name))
+            .collect();
+        parent.record_import(filename, imported);
+        Ok(())
+    }
+
+    // Imported files are found relative to the file that imports them and
+    // are parsed directly into the parent node, with the templates defined
+    // so far.
+    fn read_import(&mut self, filename: &str, parent: &NodeRef) -> Fallible<()> {
         if let Some(subtree) = self.import_interceptors.get(filename) {
             return parent.insert_subtree(&subtree.root());
         }
-        bail!("would import {} from file", filename)
+        let from = self.tokens[self.last.get()].location.to_owned();
+        let path = Path::new(from.file().name())
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(filename);
+        let name = path.to_string_lossy();
+        ensure!(
+            !from.file().is_imported_through(&name),
+            "parse error: import cycle: {} is already being imported",
+            name
+        );
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format_err!("parse error: cannot import {}: {}", name, e))?;
+        let file = SourceFile::imported(&name, &text.replace('\t', "    "), &from);
+        let mut parser = TreeParser::new(&file, self.nifs, self.import_interceptors)?;
+        parser.templates = self.templates.clone();
+        parser.record_layout = false;
+        parser.consume_root(parent).map_err(|e| parser.annotate(e))
     }
 
     fn consume_node_name(&mut self) -> Fallible<String> {
@@ -263,7 +587,8 @@ impl<'a> TreeParser<'a> {
 
     fn pop(&mut self) -> Fallible<Token> {
         ensure!(!self.out_of_input(), "parse error: no tokens to pop");
-        let out = self.tokens[self.position].clone();
+        let out = self.tokens[self.position].token.clone();
+        self.last.set(self.position);
         self.position += 1;
         Ok(out)
     }
@@ -273,7 +598,8 @@ impl<'a> TreeParser<'a> {
             self.position < self.tokens.len(),
             "parse error: enexpected end of input"
         );
-        Ok(self.tokens[self.position].clone())
+        self.last.set(self.position);
+        Ok(self.tokens[self.position].token.clone())
     }
 }
 
@@ -409,34 +735,213 @@ d @4x4";
         Ok(())
     }
 
-    //     #[test]
-    //     fn test_parse_tree_templates() {
-    //         let _ = TermLogger::init(LevelFilter::Trace, Config::default());
-    //         let s = "
-    // template foo [@1x1 <-/b]
-    // template bar [
-    //     @2x2
-    //     #comment
-    // ]
-    // a !foo
-    // b !bar
-    // ";
-    //         let tree = TreeBuilder::default().build_from_str(s)?;
-    //         assert_eq!(
-    //             tree.lookup("/a")?.location().unwrap(),
-    //             Dimension2::from_str("1x1")?
-    //         );
-    //         assert_eq!(
-    //             tree.lookup("/b")?.location().unwrap(),
-    //             Dimension2::from_str("2x2")?
-    //         );
-    //     }
+    #[test]
+    fn test_parse_tree_templates() -> Fallible<()> {
+        let s = r#"
+template foo
+    @1x1
+    <-/b
+template bar
+    <>2x2
+    # comment
+a !foo
+b !bar
+    <- 2
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(
+            tree.lookup("/a")?.location().unwrap(),
+            Dimension2::from_str("1x1")?
+        );
+        assert_eq!(
+            tree.lookup("/b")?.dimensions().unwrap(),
+            Dimension2::from_str("2x2")?
+        );
+        assert_eq!(tree.lookup("/a")?.compute(&tree)?, Value::from_integer(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_tree_template_children() -> Fallible<()> {
+        let s = r#"
+template light
+    $hue
+    <- /palette/{./color}/on
+    light
+        mode <- "on"
+palette
+    red
+        on <- "bhs(254, 0, 254)"
+    blue
+        on <- "bhs(254, 47000, 254)"
+room
+    color <- "red"
+    ceiling !light
+    desk
+        !light
+    lamp !light
+        light
+            mode <- "off"
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(
+            tree.lookup("/room/ceiling")?.compute(&tree)?,
+            Value::new_str("bhs(254, 0, 254)")
+        );
+        assert_eq!(
+            tree.lookup("/room/desk")?.compute(&tree)?,
+            Value::new_str("bhs(254, 0, 254)")
+        );
+        assert_eq!(tree.find_sinks("hue").len(), 3);
+        assert_eq!(
+            tree.lookup("/room/desk/light/mode")?.compute(&tree)?,
+            Value::new_str("on")
+        );
+        assert_eq!(
+            tree.lookup("/room/lamp/light/mode")?.compute(&tree)?,
+            Value::new_str("off")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_tree_template_nested() -> Fallible<()> {
+        let s = r#"
+template inner
+    <- 1
+template outer
+    a !inner
+    b !inner
+x !outer
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(tree.lookup("/x/a")?.compute(&tree)?, Value::from_integer(1));
+        assert_eq!(tree.lookup("/x/b")?.compute(&tree)?, Value::from_integer(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_tree_template_params() -> Fallible<()> {
+        let s = r#"
+template light(palette, control, level)
+    $hue
+    <- ${palette}/{${control}}/{./color} + ${level}
+palette
+    hue
+        on
+            red <- 1
+            blue <- 2
+        off
+            red <- 10
+            blue <- 20
+ctrl <- "on"
+ctrl-off <- "off"
+room
+    color <- "blue"
+    a !light(palette=/palette/hue, control=/ctrl, level=100)
+    b !light(level=1000, control=../ctrl, palette=/palette/hue)
+    c !light(palette=/palette/hue, control=/ctrl-off, level=0)
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(
+            tree.lookup("/room/a")?.compute(&tree)?,
+            Value::from_integer(102)
+        );
+        assert_eq!(
+            tree.lookup("/room/b")?.compute(&tree)?,
+            Value::from_integer(1002)
+        );
+        assert_eq!(
+            tree.lookup("/room/c")?.compute(&tree)?,
+            Value::from_integer(20)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_tree_template_nested_params() -> Fallible<()> {
+        let s = r#"
+template inner(v)
+    <- ${v}
+template outer(v)
+    a !inner(v=${v})
+x !outer(v="hello")
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(
+            tree.lookup("/x/a")?.compute(&tree)?,
+            Value::new_str("hello")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_tree_template_param_errors() {
+        for s in &[
+            "template t(a)\n    <- ${a}\nx !t",
+            "template t(a)\n    <- ${a}\nx !t(a=1, b=2)",
+            "template t(a)\n    <- ${a}\nx !t(a=1, a=2)",
+            "template t(a)\n    <- ${b}\nx !t(a=1)",
+            "template t(a, a)\n    <- ${a}",
+            "template t(a)\n    <- /x/${a}\nx !t(a=1.5)",
+            "template t(a)\n    <- ${a}\nx !t(a=+)",
+        ] {
+            assert!(TreeBuilder::default().build_from_str(s).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_tree_template_errors() {
+        for s in &[
+            "a !missing",
+            "template t\n    <- 1\ntemplate t\n    <- 2",
+            "template t\n    a !t\nb !t",
+            "template t\n    <- 1\na !t\n    <- 2",
+        ] {
+            assert!(TreeBuilder::default().build_from_str(s).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let s = "a\n    b <- 1 +\n    c <- 2";
+        let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            r#"parse error: unexpected end of expression
+ --> <string>:2:12
+  |
+2 |     b <- 1 +
+  |            ^"#
+        );
+    }
+
+    #[test]
+    fn test_parse_error_location_in_template() {
+        let s = r#"
+template light(level)
+    <- bhs(${level}, 0)
+a
+    !light(level=1)
+"#;
+        let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            r#"parse error: bhs takes 3 arguments, but 2 given
+ --> <string>:3:8
+  |
+3 |     <- bhs(${level}, 0)
+  |        ^^^
+  = in template light applied at <string>:5:5"#
+        );
+    }
 
     #[test]
     #[should_panic]
     fn test_parse_node_before_newline() {
         TreeParser::from_str(
             TreeBuilder::empty(),
+            "<string>",
             "a b",
             &HashMap::new(),
             &HashMap::new(),
@@ -1,7 +1,7 @@
 // This Source Code Form is subject to the terms of the GNU General Public
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
-use crate::tree::Tree;
+use crate::tree::{NodeRef, Tree};
 use failure::{bail, ensure, Error, Fallible};
 use std::{fmt, ops::Div, str::FromStr};
 use tracing::trace;
@@ -10,6 +10,22 @@ use tracing::trace;
 pub enum PathComponent {
     Name(String),
     Lookup(ScriptPath),
+
+    // Glob components, which make the path evaluate to a list: any one
+    // child (*), the node itself or any descendant (**), or any child that
+    // is a sink of the given kind ($kind).
+    Wildcard,
+    Recursive,
+    Sink(String),
+}
+
+impl PathComponent {
+    pub fn is_glob(&self) -> bool {
+        match self {
+            PathComponent::Name(_) | PathComponent::Lookup(_) => false,
+            PathComponent::Wildcard | PathComponent::Recursive | PathComponent::Sink(_) => true,
+        }
+    }
 }
 
 impl fmt::Display for PathComponent {
@@ -17,6 +33,9 @@ impl fmt::Display for PathComponent {
         match self {
             PathComponent::Name(name) => write!(f, "{}", name),
             PathComponent::Lookup(script_path) => write!(f, "{{{}}}", script_path),
+            PathComponent::Wildcard => write!(f, "*"),
+            PathComponent::Recursive => write!(f, "**"),
+            PathComponent::Sink(kind) => write!(f, "${}", kind),
         }
     }
 }
@@ -34,7 +53,7 @@ impl ScriptPath {
         let (start, mut components) = if s.starts_with('/') {
             (1, Vec::new())
         } else {
-            let mut comps = (&base_path[1..])
+            let mut comps = base_path[1..]
                 .split('/')
                 .map(|c| PathComponent::Name(c.to_owned()))
                 .collect::<Vec<PathComponent>>();
@@ -82,20 +101,49 @@ impl ScriptPath {
                     base_path,
                     components
                 );
+                ensure!(
+                    !components[components.len() - 1].is_glob(),
+                    "parse error: looked up parent dir (..) of a glob at '{}' in '{:?}'",
+                    base_path,
+                    components
+                );
                 components.pop();
                 Ok(false)
             }
+            "*" => {
+                components.push(PathComponent::Wildcard);
+                Ok(true)
+            }
+            "**" => {
+                components.push(PathComponent::Recursive);
+                Ok(true)
+            }
             s => {
                 if s.starts_with('{') && s.ends_with('}') {
-                    let c = PathComponent::Lookup(Self::from_str_at_path(
-                        base_path,
-                        &s[1..s.len() - 1],
-                    )?);
-                    components.push(c);
+                    let lookup = Self::from_str_at_path(base_path, &s[1..s.len() - 1])?;
+                    ensure!(
+                        !lookup.is_glob(),
+                        "parse error: a glob cannot be used as a path component, in {}",
+                        lookup
+                    );
+                    components.push(PathComponent::Lookup(lookup));
+                    Ok(true)
+                } else if let Some(kind) = s.strip_prefix('$') {
+                    ensure!(
+                        !kind.is_empty() && !kind.contains(['$', '{', '}']),
+                        "parse error: expected a sink kind after $ in path part '{}'",
+                        s
+                    );
+                    components.push(PathComponent::Sink(kind.to_owned()));
                     Ok(true)
                 } else {
                     ensure!(!s.contains('{'), "parse error: found { in path part");
                     ensure!(!s.contains('}'), "parse error: found } in path part");
+                    ensure!(
+                        !s.contains('*'),
+                        "parse error: * must be a whole path part, not '{}'",
+                        s
+                    );
                     let c = PathComponent::Name(s.to_owned());
                     components.push(c);
                     Ok(false)
@@ -111,11 +159,9 @@ impl ScriptPath {
         let mut parts = Vec::new();
         for c in s.chars() {
             match c {
-                '/' => {
-                    if brace_depth == 0 {
-                        parts.push(s[part_start..offset].chars().collect::<String>());
-                        part_start = offset + 1;
-                    }
+                '/' if brace_depth == 0 => {
+                    parts.push(s[part_start..offset].chars().collect::<String>());
+                    part_start = offset + 1;
                 }
                 '{' => {
                     brace_depth += 1;
@@ -140,14 +186,17 @@ impl ScriptPath {
         !self.dynamic
     }
 
+    /// True if the path may match any number of nodes, so evaluates to a list.
+    pub fn is_glob(&self) -> bool {
+        self.components.iter().any(PathComponent::is_glob)
+    }
+
     pub fn as_concrete(&self) -> ConcretePath {
         let mut concrete = Vec::new();
         for component in &self.components {
             match component {
                 PathComponent::Name(name) => concrete.push(name.clone()),
-                PathComponent::Lookup(_) => {
-                    panic!("path error: dynamic is set, but lookups in path")
-                }
+                _ => panic!("path error: dynamic is set, but lookups in path"),
             }
         }
         ConcretePath::from_components(concrete)
@@ -159,11 +208,8 @@ impl ScriptPath {
             return Ok(());
         }
         for component in &self.components {
-            match component {
-                PathComponent::Name(_) => {}
-                PathComponent::Lookup(path) => {
-                    path.find_concrete_inputs(inputs)?;
-                }
+            if let PathComponent::Lookup(path) = component {
+                path.find_concrete_inputs(inputs)?;
             }
         }
         Ok(())
@@ -178,6 +224,10 @@ impl ScriptPath {
             trace!("Path::devirtualize(concrete: {})", self);
             return Ok(vec![self.as_concrete()]);
         }
+        if self.is_glob() {
+            trace!("Path::devirtualize(glob: {})", self);
+            return Ok(self.explode_glob(tree)?.0);
+        }
         trace!("Path::devirtualize(dynamic: {})", self);
         let mut working_set = Vec::new();
         for component in &self.components {
@@ -189,6 +239,7 @@ impl ScriptPath {
                 PathComponent::Lookup(_script_path) => {
                     working_set = Self::explode_paths_2(working_set, tree)?;
                 }
+                _ => unreachable!("glob paths are exploded separately"),
             }
             trace!(
                 "Path::devirtualize: working set after {}: {:?}",
@@ -225,6 +276,38 @@ impl ScriptPath {
         }
         Ok(next_working_set)
     }
+
+    /// Match a glob against the tree as it is now, with lookups resolving to
+    /// any child. Returns the nodes matched, then every node whose children
+    /// were listed to find them: adding a node under any of those may change
+    /// what the glob matches.
+    pub fn explode_glob(&self, tree: &Tree) -> Fallible<(Vec<ConcretePath>, Vec<ConcretePath>)> {
+        let mut listed = Vec::new();
+        let mut matches = vec![tree.root()];
+        for component in &self.components {
+            let mut next = Vec::new();
+            for node in &matches {
+                listed.push(node.path());
+                match component {
+                    PathComponent::Name(name) => next.extend(node.child(name).ok()),
+                    PathComponent::Lookup(_) | PathComponent::Wildcard => {
+                        next.extend(node.children())
+                    }
+                    PathComponent::Recursive => node.find_descendants(&mut next),
+                    PathComponent::Sink(kind) => next.extend(
+                        node.children()
+                            .into_iter()
+                            .filter(|child| child.maybe_sink_kind().as_ref() == Some(kind)),
+                    ),
+                }
+            }
+            matches = NodeRef::sorted_unique(next);
+        }
+        listed.extend(matches.iter().map(NodeRef::path));
+        listed.sort_by_key(ConcretePath::to_string);
+        listed.dedup();
+        Ok((matches.iter().map(NodeRef::path).collect(), listed))
+    }
 }
 
 impl fmt::Display for ScriptPath {
@@ -352,6 +435,37 @@ mod test {
         })
     }
 
+    #[test]
+    fn test_parse_glob() -> Fallible<()> {
+        let path = ScriptPath::from_str_at_path("/", "/rooms/*/color")?;
+        assert_eq!(
+            path.components,
+            vec![n("rooms"), PathComponent::Wildcard, n("color")]
+        );
+        assert!(path.is_glob());
+        assert!(!path.is_concrete());
+        assert_eq!(path.to_string(), "/rooms/*/color");
+
+        let path = ScriptPath::from_str_at_path("/a/b", "./**/$hue")?;
+        assert_eq!(
+            path.components,
+            vec![
+                n("a"),
+                PathComponent::Recursive,
+                PathComponent::Sink("hue".to_owned())
+            ]
+        );
+        assert_eq!(path.to_string(), "/a/**/$hue");
+
+        let path = ScriptPath::from_str_at_path("/", "/a/{/b}")?;
+        assert!(!path.is_glob());
+
+        for bad in &["/a/*x", "/a/{/b/*}", "/a/*/..", "/a/$", "/a/**/../b"] {
+            assert!(ScriptPath::from_str_at_path("/", bad).is_err(), "{}", bad);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_parse_abs_deep_nest() {
         let path = ScriptPath::from_str_at_path("/", "/a/{/0/{/A/B}/2}/c").unwrap();
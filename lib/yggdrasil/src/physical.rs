@@ -3,6 +3,7 @@
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use approx::relative_eq;
 use failure::{ensure, Fallible};
+use std::fmt;
 
 #[derive(Clone, Copy, Debug)]
 pub enum Length {
@@ -41,6 +42,17 @@ impl Length {
         }
     }
 }
+// Lengths are written back the way they are parsed.
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Length::Meters(meters) => write!(f, "{}m", meters),
+            Length::Imperial(feet, 0.) => write!(f, "{}'", feet),
+            Length::Imperial(feet, inches) => write!(f, "{}'{}\"", feet, inches),
+        }
+    }
+}
+
 impl PartialEq for Length {
     fn eq(&self, other: &Length) -> bool {
         relative_eq!(self.meters(), other.meters())
@@ -70,6 +82,12 @@ impl Dimension2 {
     }
 }
 
+impl fmt::Display for Dimension2 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}x{}", self.x_len, self.y_len)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -159,4 +177,14 @@ mod test {
         assert_eq!(Dimension2::from_str("-2'-1.0\"x-1\"").unwrap(), d);
         assert_eq!(Dimension2::from_str("-2'-1.0000\"x-1\"").unwrap(), d);
     }
+
+    #[test]
+    fn test_display_round_trip() -> Fallible<()> {
+        for s in &["1mx-2.5m", "6'x3'", "2'1\"x1\"", "-2'-1\"x0.25m"] {
+            let d = Dimension2::from_str(s)?;
+            assert_eq!(Dimension2::from_str(&d.to_string())?, d);
+        }
+        assert_eq!(Dimension2::from_str("10'x1.5")?.to_string(), "10'x1.5m");
+        Ok(())
+    }
 }
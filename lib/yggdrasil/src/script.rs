@@ -2,26 +2,36 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use crate::{
-    bif::NativeFunc,
+    bif::{
+        list::List,
+        string::{Format, Nth},
+        NativeFunc,
+    },
     graph::Graph,
     parser::TreeParser,
     path::{ConcretePath, ScriptPath},
-    tokenizer::Token,
-    tree::{NodeRef, Tree},
-    value::Value,
+    source::SourceLocation,
+    state::StateKind,
+    tokenizer::{Spanned, Token},
+    tree::{NodeRef, Tree, TreeBuilder},
+    value::{Value, ValueData, ValueType},
 };
-use failure::{bail, ensure, err_msg, Fallible};
+use failure::{bail, ensure, err_msg, format_err, Error, Fallible};
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
 use tracing::trace;
 
 #[derive(Clone, Debug)]
 pub(super) enum Expr {
     Add(Box<Expr>, Box<Expr>),
     And(Box<Expr>, Box<Expr>),
-    Call(Box<dyn NativeFunc + Send + Sync>, Box<Expr>),
+    Call(Box<dyn NativeFunc + Send + Sync>, Vec<Expr>),
     Divide(Box<Expr>, Box<Expr>),
     Equal(Box<Expr>, Box<Expr>),
+    If(Box<Expr>, Box<Expr>, Box<Expr>),
     GreaterThan(Box<Expr>, Box<Expr>),
     GreaterThanOrEqual(Box<Expr>, Box<Expr>),
     LessThan(Box<Expr>, Box<Expr>),
@@ -29,15 +39,54 @@ pub(super) enum Expr {
     Modulo(Box<Expr>, Box<Expr>),
     Multiply(Box<Expr>, Box<Expr>),
     Negate(Box<Expr>),
+    Not(Box<Expr>),
     NotEqual(Box<Expr>, Box<Expr>),
     Or(Box<Expr>, Box<Expr>),
     Subtract(Box<Expr>, Box<Expr>),
     Latch(Box<Expr>, Box<Expr>),
     Value(Value),
+    Binding(Arc<Binding>),
+}
+
+// A name bound by let. Its expression is computed the first time the name is
+// used in each computation of the block that binds it, and every other use
+// gets the same value.
+#[derive(Debug)]
+pub(super) struct Binding {
+    expr: Expr,
+    value: Mutex<Option<Value>>,
 }
 
+impl Binding {
+    fn new(expr: Expr) -> Self {
+        Self {
+            expr,
+            value: Mutex::new(None),
+        }
+    }
+
+    fn compute(&self, tree: &Tree) -> Fallible<Value> {
+        if let Some(ref value) = *self.value.lock().unwrap() {
+            return Ok(value.to_owned());
+        }
+        let value = self.expr.compute(tree)?;
+        *self.value.lock().unwrap() = Some(value.to_owned());
+        Ok(value)
+    }
+
+    fn value_type(&self, tree: &Tree) -> Fallible<ValueType> {
+        self.expr.value_type(tree)
+    }
+
+    fn reset(&self) {
+        *self.value.lock().unwrap() = None;
+    }
+}
+
+// Conditionals are given the computed condition and both branches, so that
+// they can decide which branches to visit.
 macro_rules! map_values {
-    ($self:ident, $f:ident, $reduce:expr, $($args:ident),*) => {
+    ($self:ident, $f:ident, $reduce:expr, $unary:expr, $choose:expr, $($args:ident),*) => {
         match $self {
             Expr::Add(a, b) => {
                 $reduce(Token::Add, a.$f($($args),*)?, b.$f($($args),*)?)
@@ -45,8 +94,12 @@ macro_rules! map_values {
             Expr::And(a, b) => {
                 $reduce(Token::And, a.$f($($args),*)?, b.$f($($args),*)?)
             }
-            Expr::Call(fun, a) => {
-                fun.$f(a.$f($($args),*)?, $($args),*)
+            Expr::Call(fun, args) => {
+                let values = args
+                    .iter()
+                    .map(|a| a.$f($($args),*))
+                    .collect::<Fallible<Vec<_>>>()?;
+                fun.$f(&values, $($args),*)
             }
             Expr::Divide(a, b) => {
                 $reduce(Token::Divide, a.$f($($args),*)?, b.$f($($args),*)?)
@@ -54,6 +107,9 @@ macro_rules! map_values {
             Expr::Equal(a, b) => {
                 $reduce(Token::Equals, a.$f($($args),*)?, b.$f($($args),*)?)
             }
+            Expr::If(cond, a, b) => {
+                $choose(cond.$f($($args),*)?, a, b)
+            }
             Expr::GreaterThan(a, b) => {
                 $reduce(Token::GreaterThan, a.$f($($args),*)?, b.$f($($args),*)?)
             }
@@ -73,7 +129,10 @@ macro_rules! map_values {
                 $reduce(Token::Multiply, a.$f($($args),*)?, b.$f($($args),*)?)
             }
             Expr::Negate(a) => {
-                a.$f($($args),*)
+                $unary(Token::Subtract, a.$f($($args),*)?)
+            }
+            Expr::Not(a) => {
+                $unary(Token::Not, a.$f($($args),*)?)
             }
             Expr::NotEqual(a, b) => {
                 $reduce(Token::NotEquals, a.$f($($args),*)?, b.$f($($args),*)?)
@@ -90,6 +149,9 @@ macro_rules! map_values {
             Expr::Value(v) => {
                 v.$f($($args),*)
             }
+            Expr::Binding(binding) => {
+                binding.$f($($args),*)
+            }
         }
     };
 }
@@ -103,6 +165,14 @@ impl Expr {
                 trace!("compute: reduce {:?} {:?} {:?}", lhs, tok, rhs);
                 lhs.apply(&tok, &rhs)
             },
+            |tok, operand: Value| {
+                trace!("compute: unary {:?} {:?}", tok, operand);
+                operand.apply_unary(&tok)
+            },
+            |cond: Value, a: &Expr, b: &Expr| {
+                let branch = if cond.as_boolean()? { a } else { b };
+                Ok(branch.compute(tree)?.with_generation(cond.generation()))
+            },
             tree
         )
     }
@@ -113,14 +183,127 @@ impl Expr {
         out: &mut Vec<ConcretePath>,
     ) -> Fallible<()> {
         trace!("Expr::find_all_possible_inputs({:?})", self);
+        match self {
+            Expr::Call(fun, _) => fun.find_all_possible_inputs(tree, out)?,
+            Expr::Value(v) => v.find_all_possible_inputs(tree, out)?,
+            _ => {}
+        }
+        for operand in self.operands() {
+            operand.find_all_possible_inputs(tree, out)?;
+        }
+        Ok(())
+    }
+
+    pub fn value_type(&self, tree: &Tree) -> Fallible<ValueType> {
         map_values!(
             self,
-            find_all_possible_inputs,
-            |_tok, _a, _b| Ok(()),
-            tree,
-            out
+            value_type,
+            |tok, lhs, rhs| ValueType::apply(&tok, lhs, rhs),
+            |tok, operand| ValueType::apply_unary(&tok, operand),
+            |cond: ValueType, a: &Expr, b: &Expr| {
+                ensure!(
+                    ValueType::Boolean.accepts(cond),
+                    "type error: if expression conditions must be boolean, not {}",
+                    cond
+                );
+                Ok(a.value_type(tree)?.join(b.value_type(tree)?))
+            },
+            tree
         )
     }
+
+    // True if this expression calls a function that is not pure.
+    fn is_volatile(&self) -> bool {
+        match self {
+            Expr::Call(fun, _) if !fun.is_pure() => true,
+            _ => self.operands().iter().any(|e| e.is_volatile()),
+        }
+    }
+
+    // The inputs this expression reads regardless of the value of any
+    // computed path lookup: plain paths and the paths inside lookups.
+    fn find_definite_inputs(&self, out: &mut Vec<ConcretePath>) -> Fallible<()> {
+        if let Expr::Value(v) = self {
+            if let ValueData::Path(ref path) = v.data {
+                path.find_concrete_inputs(out)?;
+            }
+        }
+        for operand in self.operands() {
+            operand.find_definite_inputs(out)?;
+        }
+        Ok(())
+    }
+
+    // Every value this expression can take, where there are few enough to
+    // list: constants, booleans, and paths to nodes whose values are known.
+    fn possible_values(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<String>,
+    ) -> Fallible<Option<Vec<Value>>> {
+        if let Expr::Value(v) = self {
+            match v.data {
+                ValueData::Path(ref path) if path.is_concrete() => {
+                    let node = tree.lookup_path(&path.as_concrete())?;
+                    if let Some(values) = node.possible_values(tree, visiting)? {
+                        return Ok(Some(values));
+                    }
+                }
+                ValueData::Path(_) => {}
+                _ => return Ok(Some(vec![v.to_owned()])),
+            }
+        }
+        if let Expr::Binding(binding) = self {
+            return binding.expr.possible_values(tree, visiting);
+        }
+        if let Expr::If(_, a, b) = self {
+            let (a, b) = match (
+                a.possible_values(tree, visiting)?,
+                b.possible_values(tree, visiting)?,
+            ) {
+                (Some(a), Some(b)) => (a, b),
+                _ => return Ok(None),
+            };
+            let mut values = a;
+            values.extend(
+                b.into_iter()
+                    .filter(|v| !values.contains(v))
+                    .collect::<Vec<_>>(),
+            );
+            return Ok(Some(values));
+        }
+        if self.value_type(tree)? == ValueType::Boolean {
+            return Ok(Some(vec![
+                Value::from_boolean(true),
+                Value::from_boolean(false),
+            ]));
+        }
+        Ok(None)
+    }
+
+    fn operands(&self) -> Vec<&Expr> {
+        match self {
+            Expr::Call(_, args) => args.iter().collect(),
+            Expr::Negate(a) | Expr::Not(a) => vec![a],
+            Expr::Binding(binding) => vec![&binding.expr],
+            Expr::If(cond, a, b) => vec![cond, a, b],
+            Expr::Value(_) => vec![],
+            Expr::Add(a, b)
+            | Expr::And(a, b)
+            | Expr::Divide(a, b)
+            | Expr::Equal(a, b)
+            | Expr::GreaterThan(a, b)
+            | Expr::GreaterThanOrEqual(a, b)
+            | Expr::LessThan(a, b)
+            | Expr::LessThanOrEqual(a, b)
+            | Expr::Modulo(a, b)
+            | Expr::Multiply(a, b)
+            | Expr::NotEqual(a, b)
+            | Expr::Or(a, b)
+            | Expr::Subtract(a, b)
+            | Expr::Latch(a, b) => vec![a, b],
+        }
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -145,10 +328,10 @@ impl IfStatement {
                 let cond = e.compute(tree)?;
                 ensure!(cond.is_boolean(), "if statement conditions must be boolean");
                 if cond == Value::from_boolean(true) {
-                    return Ok(stmt.suite.compute(tree)?);
+                    return stmt.compute(tree);
                 }
             } else {
-                return Ok(stmt.compute(tree)?);
+                return stmt.compute(tree);
             }
         }
         bail!("reached end of if conditions without at statement")
@@ -168,6 +351,41 @@ impl IfStatement {
         Ok(())
     }
 
+    // Conditions must be boolean. Branches may differ in type, in which case
+    // the type of the whole statement is unknown.
+    fn value_type(&self, tree: &Tree) -> Fallible<ValueType> {
+        let mut value_type = None;
+        for (expr, stmt) in &self.cases {
+            if let Some(e) = expr {
+                let condition_type = e.value_type(tree)?;
+                ensure!(
+                    ValueType::Boolean.accepts(condition_type),
+                    "type error: if statement conditions must be boolean, not {}",
+                    condition_type
+                );
+            }
+            let branch_type = stmt.value_type(tree)?;
+            value_type = Some(value_type.map_or(branch_type, |t: ValueType| t.join(branch_type)));
+        }
+        Ok(value_type.unwrap_or(ValueType::Any))
+    }
+
+    fn is_volatile(&self) -> bool {
+        self.cases.iter().any(|(expr, stmt)| {
+            expr.as_ref().map(|e| e.is_volatile()).unwrap_or(false) || stmt.is_volatile()
+        })
+    }
+
+    fn find_definite_inputs(&self, out: &mut Vec<ConcretePath>) -> Fallible<()> {
+        for (expr, stmt) in &self.cases {
+            if let Some(e) = expr {
+                e.find_definite_inputs(out)?;
+            }
+            stmt.suite.find_definite_inputs(out)?;
+        }
+        Ok(())
+    }
+
     fn mark_ready(&mut self) {
         for (_, stmt) in self.cases.iter_mut() {
             stmt.mark_ready();
@@ -175,17 +393,212 @@ impl IfStatement {
     }
 }
 
+// One arm of a match: `pattern [if guard]: body`. A missing pattern is `_`,
+// which matches anything.
+#[derive(Debug)]
+struct MatchArm {
+    pattern: Option<Value>,
+    guard: Option<Expr>,
+    body: Script,
+    location: SourceLocation,
+}
+
+#[derive(Debug)]
+struct MatchStatement {
+    subject: Expr,
+    arms: Vec<MatchArm>,
+    location: Option<SourceLocation>,
+}
+
+impl MatchStatement {
+    pub fn compute(&self, tree: &Tree) -> Fallible<Value> {
+        let value = self.subject.compute(tree)?;
+        for arm in &self.arms {
+            if let Some(ref pattern) = arm.pattern {
+                if *pattern != value {
+                    continue;
+                }
+            }
+            if let Some(ref guard) = arm.guard {
+                let cond = guard.compute(tree)?;
+                ensure!(
+                    cond.is_boolean(),
+                    "runtime error: match guards must be boolean"
+                );
+                if !cond.as_boolean()? {
+                    continue;
+                }
+            }
+            return arm.body.compute(tree);
+        }
+        bail!("runtime error: no match arm for {}", value.literal())
+    }
+
+    pub fn find_all_possible_inputs(
+        &self,
+        tree: &Tree,
+        out: &mut Vec<ConcretePath>,
+    ) -> Fallible<()> {
+        self.subject.find_all_possible_inputs(tree, out)?;
+        for arm in &self.arms {
+            if let Some(ref guard) = arm.guard {
+                guard.find_all_possible_inputs(tree, out)?;
+            }
+            arm.body.suite.find_all_possible_inputs(tree, out)?;
+        }
+        Ok(())
+    }
+
+    fn value_type(&self, tree: &Tree) -> Fallible<ValueType> {
+        let subject_type = self.subject.value_type(tree)?;
+        let mut value_type = None;
+        for arm in &self.arms {
+            if let Some(ref pattern) = arm.pattern {
+                let pattern_type = ValueType::of(&pattern.data);
+                if !subject_type.accepts(pattern_type) {
+                    return Err(arm.location.annotate(format_err!(
+                        "type error: match pattern {} is a {}, but the value matched is a {}",
+                        pattern.literal(),
+                        pattern_type,
+                        subject_type
+                    )));
+                }
+            }
+            if let Some(ref guard) = arm.guard {
+                let guard_type = guard.value_type(tree)?;
+                if !ValueType::Boolean.accepts(guard_type) {
+                    return Err(arm.location.annotate(format_err!(
+                        "type error: match guards must be boolean, not {}",
+                        guard_type
+                    )));
+                }
+            }
+            let arm_type = arm.body.value_type(tree)?;
+            value_type = Some(value_type.map_or(arm_type, |t: ValueType| t.join(arm_type)));
+        }
+        self.check_coverage(tree)?;
+        Ok(value_type.unwrap_or(ValueType::Any))
+    }
+
+    // If we know every value that the subject can take, warn about arms that
+    // can never be reached and values that no arm matches. Arms with guards
+    // may always fail, so they do not count towards covering a value.
+    fn check_coverage(&self, tree: &Tree) -> Fallible<()> {
+        let values = self.subject.possible_values(tree, &mut Vec::new())?;
+        let mut covered = Vec::new();
+        let mut wildcard = false;
+        for arm in &self.arms {
+            let unreachable = wildcard
+                || match (&arm.pattern, &values) {
+                    (Some(pattern), _) if covered.contains(&pattern) => true,
+                    (Some(pattern), Some(values)) => !values.contains(pattern),
+                    (None, Some(values)) => values.iter().all(|v| covered.contains(&v)),
+                    _ => false,
+                };
+            if unreachable {
+                let pattern = arm.pattern.as_ref().map(Value::literal);
+                tree.warn(
+                    arm.location
+                        .annotate(format_err!(
+                            "match warning: arm {} is unreachable",
+                            pattern.as_deref().unwrap_or("_")
+                        ))
+                        .to_string(),
+                );
+            }
+            if arm.guard.is_none() {
+                match arm.pattern {
+                    Some(ref pattern) => covered.push(pattern),
+                    None => wildcard = true,
+                }
+            }
+        }
+        if let (false, Some(values)) = (wildcard, values) {
+            let missing = values
+                .iter()
+                .filter(|v| !covered.contains(v))
+                .map(Value::literal)
+                .collect::<Vec<_>>();
+            if !missing.is_empty() {
+                let warning = format_err!("match warning: no arm matches {}", missing.join(", "));
+                tree.warn(match self.location {
+                    Some(ref location) => location.annotate(warning).to_string(),
+                    None => warning.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn possible_values(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<String>,
+    ) -> Fallible<Option<Vec<Value>>> {
+        union_of(self.arms.iter().map(|arm| &arm.body), tree, visiting)
+    }
+
+    fn is_volatile(&self) -> bool {
+        self.subject.is_volatile()
+            || self.arms.iter().any(|arm| {
+                arm.guard.as_ref().map(|e| e.is_volatile()).unwrap_or(false)
+                    || arm.body.is_volatile()
+            })
+    }
+
+    fn find_definite_inputs(&self, out: &mut Vec<ConcretePath>) -> Fallible<()> {
+        self.subject.find_definite_inputs(out)?;
+        for arm in &self.arms {
+            if let Some(ref guard) = arm.guard {
+                guard.find_definite_inputs(out)?;
+            }
+            arm.body.suite.find_definite_inputs(out)?;
+        }
+        Ok(())
+    }
+
+    fn mark_ready(&mut self) {
+        for arm in self.arms.iter_mut() {
+            arm.body.mark_ready();
+        }
+    }
+}
+
+// The values of any of the given scripts, if all of them are known.
+fn union_of<'a>(
+    scripts: impl Iterator<Item = &'a Script>,
+    tree: &Tree,
+    visiting: &mut Vec<String>,
+) -> Fallible<Option<Vec<Value>>> {
+    let mut out = Vec::new();
+    for script in scripts {
+        match script.suite.possible_values(tree, visiting)? {
+            Some(values) => {
+                for value in values {
+                    if !out.contains(&value) {
+                        out.push(value);
+                    }
+                }
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(out))
+}
+
 #[derive(Debug)]
 enum Stmt {
-    ExprStmt(Expr),
-    IfStmt(IfStatement),
+    Expr(Expr),
+    If(IfStatement),
+    Match(MatchStatement),
 }
 
 impl Stmt {
     pub fn compute(&self, tree: &Tree) -> Fallible<Value> {
         match self {
-            Self::ExprStmt(e) => e.compute(tree),
-            Self::IfStmt(s) => s.compute(tree),
+            Self::Expr(e) => e.compute(tree),
+            Self::If(s) => s.compute(tree),
+            Self::Match(s) => s.compute(tree),
         }
     }
 
@@ -195,15 +608,53 @@ impl Stmt {
         out: &mut Vec<ConcretePath>,
     ) -> Fallible<()> {
         match self {
-            Self::ExprStmt(e) => e.find_all_possible_inputs(tree, out),
-            Self::IfStmt(s) => s.find_all_possible_inputs(tree, out),
+            Self::Expr(e) => e.find_all_possible_inputs(tree, out),
+            Self::If(s) => s.find_all_possible_inputs(tree, out),
+            Self::Match(s) => s.find_all_possible_inputs(tree, out),
+        }
+    }
+
+    fn value_type(&self, tree: &Tree) -> Fallible<ValueType> {
+        match self {
+            Self::Expr(e) => e.value_type(tree),
+            Self::If(s) => s.value_type(tree),
+            Self::Match(s) => s.value_type(tree),
+        }
+    }
+
+    fn possible_values(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<String>,
+    ) -> Fallible<Option<Vec<Value>>> {
+        match self {
+            Self::Expr(e) => e.possible_values(tree, visiting),
+            Self::If(s) => union_of(s.cases.iter().map(|(_, stmt)| stmt), tree, visiting),
+            Self::Match(s) => s.possible_values(tree, visiting),
+        }
+    }
+
+    fn is_volatile(&self) -> bool {
+        match self {
+            Self::Expr(e) => e.is_volatile(),
+            Self::If(s) => s.is_volatile(),
+            Self::Match(s) => s.is_volatile(),
+        }
+    }
+
+    fn find_definite_inputs(&self, out: &mut Vec<ConcretePath>) -> Fallible<()> {
+        match self {
+            Self::Expr(e) => e.find_definite_inputs(out),
+            Self::If(s) => s.find_definite_inputs(out),
+            Self::Match(s) => s.find_definite_inputs(out),
         }
     }
 
     fn mark_ready(&mut self) {
         match self {
-            Self::ExprStmt(_) => {}
-            Self::IfStmt(s) => s.mark_ready(),
+            Self::Expr(_) => {}
+            Self::If(s) => s.mark_ready(),
+            Self::Match(s) => s.mark_ready(),
         }
     }
 }
@@ -214,116 +665,353 @@ pub struct Script {
     suite: Stmt,
     phase: CompilationPhase,
     input_map: HashMap<ConcretePath, NodeRef>,
+
+    // The tokens of the script as written, after any template arguments
+    // were bound, and whether they were a block, for writing the tree back
+    // out. Only set on the script at the top of a node.
+    source: Vec<Token>,
+    block: bool,
+
+    // The let bindings made at the top of this block, to be recomputed each
+    // time the block is.
+    bindings: Vec<Arc<Binding>>,
+
+    // Where the script starts, for reporting link errors.
+    location: Option<SourceLocation>,
 }
 
 impl Script {
+    fn new(suite: Stmt, tokens: &[Spanned]) -> Self {
+        Script {
+            suite,
+            phase: CompilationPhase::NeedInputMap,
+            input_map: HashMap::new(),
+            source: Vec::new(),
+            block: false,
+            bindings: Vec::new(),
+            location: tokens.first().map(|t| t.location.to_owned()),
+        }
+    }
+
+    fn with_source(mut self, tokens: &[Spanned], block: bool) -> Self {
+        self.source = tokens.iter().map(|t| t.token.to_owned()).collect();
+        self.block = block;
+        self
+    }
+
+    pub(crate) fn source(&self) -> &[Token] {
+        &self.source
+    }
+
+    pub(crate) fn is_block(&self) -> bool {
+        self.block
+    }
+
     pub fn inline_from_tokens(
         path: String,
-        tokens: &[Token],
+        tokens: &[Spanned],
         nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
     ) -> Fallible<Self> {
         let mut parser = ExprParser::from_tokens(path, tokens, nifs);
         let expr = parser.eparser()?;
-        let script = Script {
-            suite: Stmt::ExprStmt(expr),
-            phase: CompilationPhase::NeedInputMap,
-            input_map: HashMap::new(),
-        };
-        Ok(script)
+        Ok(Script::new(Stmt::Expr(expr), tokens).with_source(tokens, false))
     }
 
     pub fn block_from_tokens(
         path: String,
-        tokens: &[Token],
+        tokens: &[Spanned],
         nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
     ) -> Fallible<Self> {
-        match tokens[0].maybe_name() {
-            Some("if") => Self::if_from_tokens(path, tokens, nifs),
+        Ok(
+            Self::block_with_bindings(path, tokens, nifs, &HashMap::new())?
+                .with_source(tokens, true),
+        )
+    }
+
+    // A block starts with any number of `let name = expr` lines, each of
+    // which can be used by the lines after it and by any nested blocks.
+    // Uses of a name all refer to one binding, so the expression is computed
+    // once; input discovery and type checking look through to the expression.
+    fn block_with_bindings(
+        path: String,
+        tokens: &[Spanned],
+        nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
+        bindings: &Bindings,
+    ) -> Fallible<Self> {
+        let mut bindings = bindings.to_owned();
+        let mut own_bindings = Vec::new();
+        let mut offset = 0;
+        while offset < tokens.len() && tokens[offset].token.maybe_name() == Some("let") {
+            let line_end = offset + Self::find_token(&tokens[offset..], &Token::Newline)?;
+            let (name, expr) =
+                Self::let_from_tokens(&path, &tokens[offset..line_end], nifs, &bindings)
+                    .map_err(|e| tokens[offset].location.annotate(e))?;
+            // Constants need no computing, so are used as they are.
+            let expr = match expr {
+                Expr::Value(ref v) if !v.is_path() => expr,
+                _ => {
+                    let binding = Arc::new(Binding::new(expr));
+                    own_bindings.push(binding.clone());
+                    Expr::Binding(binding)
+                }
+            };
+            bindings.insert(name, expr);
+            offset = line_end + 1;
+        }
+        let tokens = &tokens[offset..];
+        ensure!(
+            tokens
+                .iter()
+                .any(|t| ![Token::Newline, Token::Indent, Token::Dedent].contains(&t.token)),
+            "parse error: expected an expression after let"
+        );
+        let mut script = match tokens[0].token.maybe_name() {
+            Some("if") if Self::is_if_block(tokens) => {
+                Self::if_from_tokens(path, tokens, nifs, &bindings)?
+            }
+            Some("match") => Self::match_from_tokens(path, tokens, nifs, &bindings)?,
             _ => {
-                let mut parser = ExprParser::from_tokens(path, tokens, nifs);
+                let mut parser =
+                    ExprParser::from_tokens(path, tokens, nifs).with_bindings(&bindings);
                 let expr = parser.eparser()?;
-                let script = Script {
-                    suite: Stmt::ExprStmt(expr),
-                    phase: CompilationPhase::NeedInputMap,
-                    input_map: HashMap::new(),
-                };
-                Ok(script)
+                Script::new(Stmt::Expr(expr), tokens)
             }
-        }
+        };
+        script.bindings = own_bindings;
+        Ok(script)
     }
 
-    fn find_token(tokens: &[Token], end_token: &Token) -> Fallible<usize> {
-        for (i, token) in tokens.iter().enumerate() {
-            if token == end_token {
+    // let name = expr
+    fn let_from_tokens(
+        path: &str,
+        tokens: &[Spanned],
+        nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
+        bindings: &Bindings,
+    ) -> Fallible<(String, Expr)> {
+        let name = match tokens.get(1).map(|t| &t.token) {
+            Some(Token::NameTerm(name)) if !KEYWORDS.contains(&name.as_str()) => name.to_owned(),
+            _ => bail!("parse error: expected a name after let"),
+        };
+        ensure!(
+            tokens.len() > 3 && tokens[2].token == Token::Assign,
+            "parse error: expected = and an expression after let {}",
+            name
+        );
+        let expr = ExprParser::from_tokens(path.to_owned(), &tokens[3..], nifs)
+            .with_bindings(bindings)
+            .eparser()?;
+        Ok((name, expr))
+    }
+
+    fn find_token(tokens: &[Spanned], end_token: &Token) -> Fallible<usize> {
+        for (i, spanned) in tokens.iter().enumerate() {
+            if &spanned.token == end_token {
                 return Ok(i);
             }
         }
         bail!("did not find requested token: {:?}", end_token)
     }
 
-    fn find_start_of_block(tokens: &[Token]) -> Fallible<usize> {
+    // An if block has a : at the end of its first line; an if expression
+    // does not.
+    fn is_if_block(tokens: &[Spanned]) -> bool {
+        tokens
+            .iter()
+            .map(|t| &t.token)
+            .find(|t| **t == Token::StartOfBlock || **t == Token::Newline)
+            == Some(&Token::StartOfBlock)
+    }
+
+    fn find_start_of_block(tokens: &[Spanned]) -> Fallible<usize> {
         Self::find_token(tokens, &Token::StartOfBlock)
     }
 
     fn if_from_tokens(
         path: String,
-        tokens: &[Token],
+        tokens: &[Spanned],
         nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
+        bindings: &Bindings,
     ) -> Fallible<Self> {
         let mut cases: Vec<(Option<Expr>, Script)> = Vec::new();
 
         // if and block
         let cond_end = Self::find_start_of_block(tokens)?;
         let condition_tokens = &tokens[1..cond_end];
-        let if_condition =
-            ExprParser::from_tokens(path.clone(), condition_tokens, nifs).eparser()?;
-        ensure!(tokens[cond_end + 0] == Token::StartOfBlock, "expect SOB");
-        ensure!(tokens[cond_end + 1] == Token::Newline, "expect newline");
-        ensure!(tokens[cond_end + 2] == Token::Indent, "expect indent");
+        let if_condition = ExprParser::from_tokens(path.clone(), condition_tokens, nifs)
+            .with_bindings(bindings)
+            .eparser()?;
+        ensure!(tokens[cond_end].token == Token::StartOfBlock, "expect SOB");
+        ensure!(
+            tokens[cond_end + 1].token == Token::Newline,
+            "expect newline"
+        );
+        ensure!(tokens[cond_end + 2].token == Token::Indent, "expect indent");
         let cond_end = cond_end + 3;
         let block_end = cond_end + TreeParser::find_matching_dedent(&tokens[cond_end..]);
         let block_tokens = &tokens[cond_end..block_end];
-        let block_script = Script::block_from_tokens(path.clone(), block_tokens, nifs)?;
+        let block_script = Script::block_with_bindings(path.clone(), block_tokens, nifs, bindings)?;
         cases.push((Some(if_condition), block_script));
 
         // Elifs and blocks
         let mut offset = block_end;
-        while offset < tokens.len() && tokens[offset].maybe_name() == Some("elif") {
+        while offset < tokens.len() && tokens[offset].token.maybe_name() == Some("elif") {
             let cond_end = offset + 1 + Self::find_start_of_block(&tokens[offset + 1..])?;
             let condition_tokens = &tokens[offset + 1..cond_end];
-            let if_condition =
-                ExprParser::from_tokens(path.clone(), condition_tokens, nifs).eparser()?;
-            ensure!(tokens[cond_end + 0] == Token::StartOfBlock, "expect SOB");
-            ensure!(tokens[cond_end + 1] == Token::Newline, "expect newline");
-            ensure!(tokens[cond_end + 2] == Token::Indent, "expect indent");
+            let if_condition = ExprParser::from_tokens(path.clone(), condition_tokens, nifs)
+                .with_bindings(bindings)
+                .eparser()?;
+            ensure!(tokens[cond_end].token == Token::StartOfBlock, "expect SOB");
+            ensure!(
+                tokens[cond_end + 1].token == Token::Newline,
+                "expect newline"
+            );
+            ensure!(tokens[cond_end + 2].token == Token::Indent, "expect indent");
             let cond_end = cond_end + 3;
             let block_end = cond_end + TreeParser::find_matching_dedent(&tokens[cond_end..]);
             let block_tokens = &tokens[cond_end..block_end];
-            let block_script = Script::block_from_tokens(path.clone(), block_tokens, nifs)?;
+            let block_script =
+                Script::block_with_bindings(path.clone(), block_tokens, nifs, bindings)?;
             cases.push((Some(if_condition), block_script));
             offset = block_end;
         }
 
         ensure!(
-            tokens[offset].maybe_name() == Some("else"),
+            tokens[offset].token.maybe_name() == Some("else"),
             "if statements must have an else block"
         );
         offset += 1;
-        ensure!(tokens[offset + 0] == Token::StartOfBlock, "expect SOB");
-        ensure!(tokens[offset + 1] == Token::Newline, "expect newline");
-        ensure!(tokens[offset + 2] == Token::Indent, "expect indent");
+        ensure!(tokens[offset].token == Token::StartOfBlock, "expect SOB");
+        ensure!(tokens[offset + 1].token == Token::Newline, "expect newline");
+        ensure!(tokens[offset + 2].token == Token::Indent, "expect indent");
         offset += 3;
         let block_end = offset + TreeParser::find_matching_dedent(&tokens[offset..]);
         let block_tokens = &tokens[offset..block_end];
-        let block_script = Script::block_from_tokens(path.clone(), block_tokens, nifs)?;
+        let block_script = Script::block_with_bindings(path.clone(), block_tokens, nifs, bindings)?;
         cases.push((None, block_script));
 
-        let script = Script {
-            suite: Stmt::IfStmt(IfStatement::new(cases)),
-            phase: CompilationPhase::NeedInputMap,
-            input_map: HashMap::new(),
+        Ok(Script::new(Stmt::If(IfStatement::new(cases)), tokens))
+    }
+
+    // match subject:
+    //     pattern: expression
+    //     pattern if guard:
+    //         block
+    //     _: expression
+    fn match_from_tokens(
+        path: String,
+        tokens: &[Spanned],
+        nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
+        bindings: &Bindings,
+    ) -> Fallible<Self> {
+        let subject_end = Self::find_start_of_block(tokens)?;
+        let subject = ExprParser::from_tokens(path.clone(), &tokens[1..subject_end], nifs)
+            .with_bindings(bindings)
+            .eparser()?;
+        ensure!(
+            tokens.len() > subject_end + 2
+                && tokens[subject_end + 1].token == Token::Newline
+                && tokens[subject_end + 2].token == Token::Indent,
+            "parse error: expected an indented list of arms after match"
+        );
+        let mut offset = subject_end + 3;
+        let mut end = offset + TreeParser::find_matching_dedent(&tokens[offset..]);
+        if tokens[end - 1].token == Token::Dedent {
+            end -= 1;
+        }
+
+        let mut arms = Vec::new();
+        while offset < end {
+            let location = tokens[offset].location.to_owned();
+            let arm =
+                Self::match_arm_from_tokens(&path, &tokens[offset..end], nifs, bindings, location)
+                    .map_err(|e| tokens[offset].location.annotate(e))?;
+            arms.push(arm.0);
+            offset += arm.1;
+        }
+        ensure!(!arms.is_empty(), "parse error: match must have arms");
+
+        Ok(Script::new(
+            Stmt::Match(MatchStatement {
+                subject,
+                arms,
+                location: Some(tokens[0].location.to_owned()),
+            }),
+            tokens,
+        ))
+    }
+
+    // Parse one arm, returning it and the number of tokens it used.
+    fn match_arm_from_tokens(
+        path: &str,
+        tokens: &[Spanned],
+        nifs: &HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
+        bindings: &Bindings,
+        location: SourceLocation,
+    ) -> Fallible<(MatchArm, usize)> {
+        let pattern = match tokens[0].token {
+            Token::NameTerm(ref name) if name == "_" => None,
+            Token::BooleanTerm(b) => Some(Value::from_boolean(b)),
+            Token::IntegerTerm(i) => Some(Value::from_integer(i)),
+            Token::FloatTerm(f) => Some(Value::from_float(f)),
+            Token::DurationTerm(d) => Some(Value::from_duration(d)),
+            Token::StringTerm(ref s) => Some(Value::new_str(s)),
+            ref t => bail!(
+                "parse error: expected a literal or _ as a match pattern, not {:?}",
+                t
+            ),
         };
-        Ok(script)
+        let mut offset = 1;
+        let mut guard = None;
+        if tokens.len() > offset && tokens[offset].token.maybe_name() == Some("if") {
+            let guard_end = offset + Self::find_start_of_block(&tokens[offset..])?;
+            guard = Some(
+                ExprParser::from_tokens(path.to_owned(), &tokens[offset + 1..guard_end], nifs)
+                    .with_bindings(bindings)
+                    .eparser()?,
+            );
+            offset = guard_end;
+        }
+        ensure!(
+            tokens.len() > offset + 1 && tokens[offset].token == Token::StartOfBlock,
+            "parse error: expected : after match pattern"
+        );
+        offset += 1;
+
+        let body = if tokens[offset].token == Token::Newline {
+            ensure!(
+                tokens.len() > offset + 1 && tokens[offset + 1].token == Token::Indent,
+                "parse error: expected an expression or indented block after match pattern"
+            );
+            offset += 2;
+            let block_end = offset + TreeParser::find_matching_dedent(&tokens[offset..]);
+            let body = Script::block_with_bindings(
+                path.to_owned(),
+                &tokens[offset..block_end],
+                nifs,
+                bindings,
+            )?;
+            offset = block_end;
+            body
+        } else {
+            let line_end = offset + Self::find_token(&tokens[offset..], &Token::Newline)?;
+            let line = &tokens[offset..line_end];
+            let expr = ExprParser::from_tokens(path.to_owned(), line, nifs)
+                .with_bindings(bindings)
+                .eparser()?;
+            let body = Script::new(Stmt::Expr(expr), line);
+            offset = line_end + 1;
+            body
+        };
+
+        Ok((
+            MatchArm {
+                pattern,
+                guard,
+                body,
+                location,
+            },
+            offset,
+        ))
     }
 
     // Note that we have to have a separate build and install phase because otherwise we'd be borrowed
@@ -331,34 +1019,79 @@ impl Script {
     pub fn build_input_map(&self, tree: &Tree) -> Fallible<HashMap<ConcretePath, NodeRef>> {
         assert_eq!(self.phase, CompilationPhase::NeedInputMap);
         let mut inputs = Vec::new();
-        self.suite.find_all_possible_inputs(tree, &mut inputs)?;
+        self.suite
+            .find_all_possible_inputs(tree, &mut inputs)
+            .map_err(|e| self.annotate(e))?;
         let mut input_map = HashMap::new();
         for input in inputs.drain(..) {
-            let node = tree.lookup_path(&input)?;
+            let node = tree.lookup_path(&input).map_err(|e| self.annotate(e))?;
             input_map.insert(input, node);
         }
         Ok(input_map)
     }
 
+    /// The type of the value the script computes, checking that every
+    /// operation and call in it is given the types it needs.
+    pub fn value_type(&self, tree: &Tree) -> Fallible<ValueType> {
+        self.suite.value_type(tree).map_err(|e| self.annotate(e))
+    }
+
+    pub(crate) fn possible_values(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<String>,
+    ) -> Fallible<Option<Vec<Value>>> {
+        self.suite.possible_values(tree, visiting)
+    }
+
+    /// Pin an error that concerns the whole script to where it starts.
+    pub fn annotate(&self, error: Error) -> Error {
+        match &self.location {
+            Some(location) => location.annotate(error),
+            None => error,
+        }
+    }
+
     pub fn install_input_map(&mut self, input_map: HashMap<ConcretePath, NodeRef>) -> Fallible<()> {
         assert_eq!(self.phase, CompilationPhase::NeedInputMap);
         self.input_map = input_map;
-        self.suite.mark_ready();
         self.mark_ready();
         Ok(())
     }
 
+    // Nested blocks are scripts too, so they have to be marked as well.
     fn mark_ready(&mut self) {
+        self.suite.mark_ready();
         self.phase = CompilationPhase::Ready;
     }
 
-    pub fn populate_flow_graph(&self, tgt_node: &NodeRef, graph: &mut Graph) -> Fallible<()> {
+    // The flow graph gets every input that the script could possibly read.
+    // The definite graph only gets the inputs it reads no matter how its path
+    // lookups resolve, so that we can find cycles without false positives.
+    pub fn populate_flow_graph(
+        &self,
+        tgt_node: &NodeRef,
+        graph: &mut Graph,
+        definite: &mut Graph,
+    ) -> Fallible<()> {
         for src_node in self.input_map.values() {
             graph.add_edge(src_node, tgt_node);
         }
+        let mut definite_inputs = Vec::new();
+        self.suite.find_definite_inputs(&mut definite_inputs)?;
+        for path in &definite_inputs {
+            if let Some(src_node) = self.input_map.get(path) {
+                definite.add_edge(src_node, tgt_node);
+            }
+        }
         Ok(())
     }
 
+    /// Scripts that call impure functions must be recomputed on every event.
+    pub fn is_volatile(&self) -> bool {
+        self.suite.is_volatile()
+    }
+
     pub(super) fn compute(&self, tree: &Tree) -> Fallible<Value> {
         ensure!(
             self.phase == CompilationPhase::Ready,
@@ -366,6 +1099,9 @@ impl Script {
             self.phase,
             self.suite
         );
+        for binding in &self.bindings {
+            binding.reset();
+        }
         self.suite.compute(tree)
     }
 }
@@ -393,12 +1129,9 @@ impl Operator {
     }
 
     fn maybe_op(t: &Token, arity: usize) -> Option<&Operator> {
-        for i in OPERATORS.iter() {
-            if t == &i.token && arity == i.arity {
-                return Some(i);
-            }
-        }
-        None
+        OPERATORS
+            .iter()
+            .find(|op| t == &op.token && arity == op.arity)
     }
 
     fn op(t: &Token, arity: usize) -> &Operator {
@@ -419,49 +1152,45 @@ impl Operator {
 }
 
 lazy_static! {
-    static ref OPERATORS: Vec<Operator> = {
-        let mut v = Vec::new();
-        v.push(Operator::new(Token::Divide, 15, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Modulo, 15, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Multiply, 15, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Subtract, 14, 1, None));
-        v.push(Operator::new(Token::Subtract, 13, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Add, 13, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::GreaterThan, 12, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::LessThan, 12, 2, Some(Assoc::Left)));
-        v.push(Operator::new(
-            Token::GreaterThanOrEquals,
-            12,
-            2,
-            Some(Assoc::Left),
-        ));
-        v.push(Operator::new(
-            Token::LessThanOrEquals,
-            12,
-            2,
-            Some(Assoc::Left),
-        ));
-        v.push(Operator::new(Token::Equals, 11, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::NotEquals, 11, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::And, 10, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Or, 9, 2, Some(Assoc::Left)));
-        v.push(Operator::new(Token::Latch, 8, 2, Some(Assoc::Left)));
-        v
-    };
+    static ref OPERATORS: Vec<Operator> = vec![
+        Operator::new(Token::Divide, 15, 2, Some(Assoc::Left)),
+        Operator::new(Token::Modulo, 15, 2, Some(Assoc::Left)),
+        Operator::new(Token::Multiply, 15, 2, Some(Assoc::Left)),
+        Operator::new(Token::Subtract, 14, 1, None),
+        Operator::new(Token::Not, 14, 1, None),
+        Operator::new(Token::Subtract, 13, 2, Some(Assoc::Left)),
+        Operator::new(Token::Add, 13, 2, Some(Assoc::Left)),
+        Operator::new(Token::GreaterThan, 12, 2, Some(Assoc::Left)),
+        Operator::new(Token::LessThan, 12, 2, Some(Assoc::Left)),
+        Operator::new(Token::GreaterThanOrEquals, 12, 2, Some(Assoc::Left)),
+        Operator::new(Token::LessThanOrEquals, 12, 2, Some(Assoc::Left)),
+        Operator::new(Token::Equals, 11, 2, Some(Assoc::Left)),
+        Operator::new(Token::NotEquals, 11, 2, Some(Assoc::Left)),
+        Operator::new(Token::And, 10, 2, Some(Assoc::Left)),
+        Operator::new(Token::Or, 9, 2, Some(Assoc::Left)),
+        Operator::new(Token::Latch, 8, 2, Some(Assoc::Left)),
+    ];
 }
 
+// Names that cannot be bound by let.
+const KEYWORDS: &[&str] = &["elif", "else", "if", "let", "match", "not", "then"];
+
+// The bindings made by let, by name.
+type Bindings = HashMap<String, Expr>;
+
 struct ExprParser<'a> {
     path: String,
-    tokens: &'a [Token],
+    tokens: &'a [Spanned],
     offset: usize,
     nifs: &'a HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
+    bindings: Option<&'a Bindings>,
 }
 
 // Uses textbook precedence climbing.
 impl<'a> ExprParser<'a> {
     fn from_tokens(
         path: String,
-        tokens: &'a [Token],
+        tokens: &'a [Spanned],
         nifs: &'a HashMap<String, Box<dyn NativeFunc + Send + Sync>>,
     ) -> Self {
         Self {
@@ -469,40 +1198,62 @@ impl<'a> ExprParser<'a> {
             tokens,
             offset: 0,
             nifs,
+            bindings: None,
         }
     }
 
+    fn with_bindings(mut self, bindings: &'a Bindings) -> Self {
+        self.bindings = Some(bindings);
+        self
+    }
+
     fn eparser(&mut self) -> Fallible<Expr> {
-        let e = self.exp_p(0)?;
-        ensure!(
-            self.tokens[self.offset..].iter().all(|t| [
-                Token::Newline,
-                Token::Indent,
-                Token::Dedent
-            ]
-            .contains(t)),
-            "parse error: extra non-whitespace tokens after script"
-        );
+        let e = self.exp_p(0).map_err(|e| self.annotate(e))?;
+        if let Some(extra) = self.tokens[self.offset..]
+            .iter()
+            .find(|t| ![Token::Newline, Token::Indent, Token::Dedent].contains(&t.token))
+        {
+            return Err(extra.location.annotate(err_msg(
+                "parse error: extra non-whitespace tokens after script",
+            )));
+        }
         Ok(e)
     }
 
-    fn peek(&self) -> &Token {
-        &self.tokens[self.offset]
+    // Errors are reported at the last token we looked at.
+    fn annotate(&self, error: Error) -> Error {
+        match self
+            .tokens
+            .get(self.offset.saturating_sub(1))
+            .or_else(|| self.tokens.last())
+        {
+            Some(spanned) => spanned.location.annotate(error),
+            None => error,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.offset).map(|t| &t.token)
     }
 
-    fn pop(&mut self) -> Token {
-        let op = self.tokens[self.offset].clone();
+    fn pop(&mut self) -> Fallible<Token> {
+        let token = self
+            .tokens
+            .get(self.offset)
+            .ok_or_else(|| err_msg("parse error: unexpected end of expression"))?
+            .token
+            .clone();
         self.offset += 1;
-        op
+        Ok(token)
     }
 
     fn exp_p(&mut self, p: usize) -> Fallible<Expr> {
         let mut t = self.p()?;
-        while self.offset < self.tokens.len()
-            && Operator::is_bin_op(&self.tokens[self.offset])
-            && Operator::precedence_of(self.peek(), 2) >= p
-        {
-            let op = self.pop();
+        while let Some(op) = self.peek() {
+            if !Operator::is_bin_op(op) || Operator::precedence_of(op, 2) < p {
+                break;
+            }
+            let op = self.pop()?;
             let q = match Operator::assoc_of(&op) {
                 Assoc::Left => Operator::precedence_of(&op, 2) + 1,
                 //Assoc::Right => Operator::precedence_of(&op, 2),
@@ -530,21 +1281,135 @@ impl<'a> ExprParser<'a> {
         Ok(t)
     }
 
+    fn not(&mut self) -> Fallible<Expr> {
+        let q = Operator::op(&Token::Not, 1).precedence;
+        Ok(Expr::Not(Box::new(self.exp_p(q)?)))
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Fallible<()> {
+        ensure!(
+            self.peek().and_then(Token::maybe_name) == Some(keyword),
+            "parse error: expected {} in if expression",
+            keyword
+        );
+        self.pop()?;
+        Ok(())
+    }
+
+    // After the open bracket of a list, up to and including the close bracket.
+    fn list_items(&mut self) -> Fallible<Vec<Expr>> {
+        let mut items = Vec::new();
+        if self.peek() == Some(&Token::RightBracket) {
+            self.pop()?;
+            return Ok(items);
+        }
+        loop {
+            items.push(self.exp_p(0)?);
+            ensure!(self.peek().is_some(), "parse error: expected ] after list");
+            match self.pop()? {
+                Token::Comma => {}
+                Token::RightBracket => return Ok(items),
+                _ => bail!("parse error: expected , or ] in list"),
+            }
+        }
+    }
+
+    // After the open paren of a call, up to and including the close paren.
+    fn call_args(&mut self, name: &str) -> Fallible<Vec<Expr>> {
+        let mut args = Vec::new();
+        if self.peek() == Some(&Token::RightParen) {
+            self.pop()?;
+            return Ok(args);
+        }
+        loop {
+            args.push(self.exp_p(0)?);
+            ensure!(
+                self.peek().is_some(),
+                "parse error: expected right paren after call to {}",
+                name
+            );
+            match self.pop()? {
+                Token::Comma => {}
+                Token::RightParen => return Ok(args),
+                _ => bail!(
+                    "parse error: expected , or right paren in arguments to {}",
+                    name
+                ),
+            }
+        }
+    }
+
+    // Evaluate calls to pure functions with constant arguments up front, so
+    // that bad arguments are reported when the tree is built.
+    fn fold_call(nif: Box<dyn NativeFunc + Send + Sync>, args: Vec<Expr>) -> Fallible<Expr> {
+        let mut values = Vec::new();
+        for arg in &args {
+            match arg {
+                Expr::Value(v) if !v.is_path() => values.push(v.to_owned()),
+                _ => return Ok(Expr::Call(nif, args)),
+            }
+        }
+        if !nif.is_pure() {
+            return Ok(Expr::Call(nif, args));
+        }
+        Ok(Expr::Value(nif.compute(&values, &TreeBuilder::empty())?))
+    }
+
+    // A term followed by any number of [index] suffixes.
     fn p(&mut self) -> Fallible<Expr> {
-        Ok(match self.pop() {
+        let mut t = self.term()?;
+        while self.peek() == Some(&Token::LeftBracket) {
+            self.pop()?;
+            let index = self.exp_p(0)?;
+            ensure!(
+                self.peek() == Some(&Token::RightBracket),
+                "parse error: expected ] after index"
+            );
+            self.pop()?;
+            t = Self::fold_call(Box::new(Nth), vec![t, index])?;
+        }
+        Ok(t)
+    }
+
+    fn term(&mut self) -> Fallible<Expr> {
+        Ok(match self.pop()? {
             Token::BooleanTerm(b) => Expr::Value(Value::from_boolean(b)),
             Token::FloatTerm(f) => Expr::Value(Value::from_float(f)),
             Token::IntegerTerm(i) => Expr::Value(Value::from_integer(i)),
+            Token::DurationTerm(d) => Expr::Value(Value::from_duration(d)),
             Token::PathTerm(p) => Expr::Value(Value::from_path(ScriptPath::from_str_at_path(
                 &self.path, &p,
             )?)),
             Token::StringTerm(s) => Expr::Value(Value::from_string(s)),
+            Token::FormatTerm(fmt, parts) => {
+                // The embedded expressions are reported at the string.
+                let location = &self.tokens[self.offset - 1].location;
+                let mut args = vec![Expr::Value(Value::from_string(fmt))];
+                for part in &parts {
+                    let part = part
+                        .iter()
+                        .map(|token| Spanned {
+                            token: token.to_owned(),
+                            location: location.to_owned(),
+                        })
+                        .collect::<Vec<_>>();
+                    let mut parser = ExprParser::from_tokens(self.path.clone(), &part, self.nifs);
+                    parser.bindings = self.bindings;
+                    args.push(parser.eparser()?);
+                }
+                Expr::Call(Box::new(Format), args)
+            }
+            Token::LeftBracket => {
+                let items = self.list_items()?;
+                Self::fold_call(Box::new(List), items)?
+            }
             Token::LeftParen => {
                 let t = self.exp_p(0)?;
                 ensure!(
-                    self.pop() == Token::RightParen,
+                    self.peek() == Some(&Token::RightParen),
                     "parse error: expected right paren after sub-expression"
                 );
+                self.pop()?;
                 t
             }
             Token::Subtract => {
@@ -553,26 +1418,55 @@ impl<'a> ExprParser<'a> {
                 let t = self.exp_p(q)?;
                 Expr::Negate(Box::new(t))
             }
+            Token::Not => self.not()?,
+            Token::NameTerm(ref name) if name == "not" => self.not()?,
+            Token::NameTerm(ref name) if name == "if" => {
+                // The else branch extends as far as possible, as in Python.
+                let cond = self.exp_p(0)?;
+                self.expect_keyword("then")?;
+                let a = self.exp_p(0)?;
+                self.expect_keyword("else")?;
+                let b = self.exp_p(0)?;
+                Expr::If(Box::new(cond), Box::new(a), Box::new(b))
+            }
             Token::NameTerm(name) => {
+                let location = self.tokens[self.offset - 1].location.to_owned();
+                let is_call = self.peek() == Some(&Token::LeftParen);
+                if !is_call {
+                    if let Some(expr) = self.bindings.and_then(|b| b.get(&name)) {
+                        return Ok(expr.to_owned());
+                    }
+                }
                 ensure!(
-                    self.pop() == Token::LeftParen,
-                    "parse error: expected () in call to {}",
-                    name
-                );
-                let t = self.exp_p(0)?;
-                ensure!(
-                    self.pop() == Token::RightParen,
-                    "parse error: expected right paren after call to {}",
+                    is_call,
+                    "parse error: {} is not a let binding; expected () in call to {}",
+                    name,
                     name
                 );
+                self.pop()?;
                 let nif = self
                     .nifs
                     .get(&name)
-                    .ok_or_else(|| err_msg(format!("parse error: no such function {}", name)))?
+                    .ok_or_else(|| match StateKind::from_name(&name) {
+                        Some(kind) => format_err!(
+                            "parse error: {} keeps state, so must be the only thing after <- on its node",
+                            kind
+                        ),
+                        None => err_msg(format!("parse error: no such function {}", name)),
+                    })?
                     .clone();
-                Expr::Call(nif, Box::new(t))
+                let args = self.call_args(&name)?;
+                if !nif.arity().accepts(args.len()) {
+                    return Err(location.annotate(format_err!(
+                        "parse error: {} takes {}, but {} given",
+                        name,
+                        nif.arity(),
+                        args.len()
+                    )));
+                }
+                Self::fold_call(nif, args).map_err(|e| location.annotate(e))?
             }
-            t => panic!("parse error: unexpected token {:?}", t),
+            t => bail!("parse error: unexpected token {:?}", t),
         })
     }
 }
@@ -580,12 +1474,90 @@ impl<'a> ExprParser<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{float::Float, tokenizer::TreeTokenizer, tree::TreeBuilder};
+    use crate::{
+        bif::Arity, float::Float, source::SourceFile, tokenizer::TreeTokenizer, tree::TreeBuilder,
+    };
+    use std::{
+        str::FromStr,
+        sync::atomic::{AtomicI64, Ordering},
+    };
+
+    #[derive(Clone, Debug)]
+    struct Sum;
+
+    impl NativeFunc for Sum {
+        fn arity(&self) -> Arity {
+            Arity::AtLeast(1)
+        }
+
+        fn compute(&self, args: &[Value], _tree: &Tree) -> Fallible<Value> {
+            let mut total = 0;
+            for arg in args {
+                total += arg.as_integer()?;
+            }
+            Ok(Value::from_integer(total))
+        }
+
+        fn box_clone(&self) -> Box<dyn NativeFunc + Send + Sync> {
+            Box::new((*self).clone())
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    struct Second;
+
+    impl NativeFunc for Second {
+        fn arity(&self) -> Arity {
+            Arity::Exactly(2)
+        }
+
+        fn compute(&self, args: &[Value], _tree: &Tree) -> Fallible<Value> {
+            Ok(args[1].to_owned())
+        }
+
+        fn box_clone(&self) -> Box<dyn NativeFunc + Send + Sync> {
+            Box::new((*self).clone())
+        }
+    }
+
+    // Counts how many times it is called.
+    static COUNT: AtomicI64 = AtomicI64::new(0);
+
+    #[derive(Clone, Debug)]
+    struct Count;
+
+    impl NativeFunc for Count {
+        fn arity(&self) -> Arity {
+            Arity::Exactly(0)
+        }
+
+        fn compute(&self, _args: &[Value], _tree: &Tree) -> Fallible<Value> {
+            Ok(Value::from_integer(
+                COUNT.fetch_add(1, Ordering::SeqCst) + 1,
+            ))
+        }
+
+        fn box_clone(&self) -> Box<dyn NativeFunc + Send + Sync> {
+            Box::new((*self).clone())
+        }
+    }
+
+    fn test_nifs() -> HashMap<String, Box<dyn NativeFunc + Send + Sync>> {
+        let mut nifs: HashMap<String, Box<dyn NativeFunc + Send + Sync>> = HashMap::new();
+        nifs.insert("sum".to_owned(), Box::new(Sum));
+        nifs.insert("second".to_owned(), Box::new(Second));
+        nifs.insert("count".to_owned(), Box::new(Count));
+        nifs
+    }
+
+    fn tokenize(s: &str) -> Fallible<Vec<Spanned>> {
+        TreeTokenizer::tokenize_source(&SourceFile::new("<test>", s))
+    }
 
     fn do_compute(expr: &str) -> Fallible<Value> {
-        let tok = TreeTokenizer::tokenize(&format!("a <- {}", expr))?;
+        let tok = tokenize(&format!("a <- {}", expr))?;
         let mut script =
-            Script::inline_from_tokens("/a".to_owned(), &tok[2..tok.len() - 1], &HashMap::new())?;
+            Script::inline_from_tokens("/a".to_owned(), &tok[2..tok.len() - 1], &test_nifs())?;
         let tree = TreeBuilder::empty();
         let input_map = script.build_input_map(&tree)?;
         ensure!(
@@ -607,6 +1579,9 @@ mod test {
             ("-2", Value::from_integer(-2)),
             ("2 - 3", Value::from_integer(-1)),
             ("2 / 3", Value::from_float(Float::new(2f64 / 3f64)?)),
+            ("1 + 2.", Value::from_float(Float::new(3.0)?)),
+            ("-(2 + 3)", Value::from_integer(-5)),
+            ("-(1.5 * 2)", Value::from_float(Float::new(-3.0)?)),
         ];
         for (expr, value) in expect.iter() {
             assert_eq!(do_compute(expr)?, *value);
@@ -616,16 +1591,110 @@ mod test {
 
     #[test]
     fn test_script_failures() -> Fallible<()> {
-        let expect = vec!["1 + 2.", "true + false", r#" "2" - "3" "#];
+        let expect = [
+            "true + false",
+            r#" "2" - "3" "#,
+            "1 % 0",
+            "1. / 0",
+            "9223372036854775807 + 1",
+            "-(-9223372036854775807 - 1)",
+        ];
         for expr in expect.iter() {
             assert!(do_compute(expr).is_err());
         }
+
+        // Running out of tokens part way through is a parse error.
+        for expr in &[
+            "(1 + 2",
+            "(1",
+            "max(1, 2",
+            "[1, 2",
+            "[1][0",
+            "if true then 1",
+        ] {
+            assert!(do_compute(expr)
+                .unwrap_err()
+                .to_string()
+                .starts_with("parse error:"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_call() -> Fallible<()> {
+        let expect = [
+            ("sum(1)", Value::from_integer(1)),
+            ("sum(1, 2, 3)", Value::from_integer(6)),
+            ("sum(1, sum(2, 3) * 2)", Value::from_integer(11)),
+            ("second(1, 2 + 3)", Value::from_integer(5)),
+            ("second((1), (2))", Value::from_integer(2)),
+        ];
+        for (expr, value) in expect.iter() {
+            assert_eq!(do_compute(expr)?, *value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_call_arity() {
+        let expect = [
+            "sum()",
+            "second(1)",
+            "second(1, 2, 3)",
+            "second(1 2)",
+            "second(1,)",
+            "missing(1)",
+        ];
+        for expr in expect.iter() {
+            assert!(do_compute(expr).is_err());
+        }
+    }
+
+    #[test]
+    fn test_script_call_inputs() -> Fallible<()> {
+        let s = r#"
+a ^src
+    default <- 0
+b ^src
+    default <- 0
+c $sink <- second(/a, /b)
+"#;
+        let mut tree = TreeBuilder::default()
+            .add_native_function("second", Box::new(Second))?
+            .build_from_str(s)?;
+        tree.handle_event(&ConcretePath::from_str("/a")?, Value::from_integer(1))?;
+        let updates = tree.handle_event(&ConcretePath::from_str("/b")?, Value::from_integer(2))?;
+        assert_eq!(updates["sink"][0].1, Value::from_integer(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_interpolation() -> Fallible<()> {
+        let s = r#"
+meta
+    minute-tic ^clock
+        default <- 0
+    room ^room
+        default <- "bed"
+rooms
+    bed
+        level <- 254
+c $sink <- "bhs({/rooms/{/meta/room}/level}, {/meta/minute-tic * 1092}, 254)"
+"#;
+        let mut tree = TreeBuilder::default()
+            .without_builtins()?
+            .build_from_str(s)?;
+        let updates = tree.handle_event(
+            &ConcretePath::from_str("/meta/minute-tic")?,
+            Value::from_integer(2),
+        )?;
+        assert_eq!(updates["sink"][0].1, Value::new_str("bhs(254, 2184, 254)"));
         Ok(())
     }
 
     #[test]
     fn test_script_or() -> Fallible<()> {
-        let tok = TreeTokenizer::tokenize("a <- true || true")?;
+        let tok = tokenize("a <- true || true")?;
         ExprParser::from_tokens("/a".to_owned(), &tok[2..tok.len() - 1], &HashMap::new())
             .eparser()?;
         Ok(())
@@ -633,7 +1702,7 @@ mod test {
 
     #[test]
     fn test_script_inputs() -> Fallible<()> {
-        let tok = TreeTokenizer::tokenize("a <- /foo/bar/baz")?;
+        let tok = tokenize("a <- /foo/bar/baz")?;
         ExprParser::from_tokens("/a".to_owned(), &tok[2..tok.len() - 1], &HashMap::new())
             .eparser()?;
         Ok(())
@@ -641,9 +1710,327 @@ mod test {
 
     #[test]
     fn test_script_negate() -> Fallible<()> {
-        let tok = TreeTokenizer::tokenize("a <- -/foo/bar/baz")?;
+        let tok = tokenize("a <- -/foo/bar/baz")?;
         ExprParser::from_tokens("/a".to_owned(), &tok[2..tok.len() - 1], &HashMap::new())
             .eparser()?;
         Ok(())
     }
+
+    #[test]
+    fn test_script_match() -> Fallible<()> {
+        let s = r#"
+mode ^mode
+    default <- 0
+emer ^emer
+    default <- false
+light $sink <-\
+    match /mode:
+        0: "off"
+        1 if /emer: "bright"
+        1: "dim"
+        2:
+            if /emer:
+                "bright"
+            else:
+                "moon"
+        _: "unknown"
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(
+            tree.lookup("/light")?.compute(&tree)?,
+            Value::new_str("off")
+        );
+        let mode = ConcretePath::from_str("/mode")?;
+        let emer = ConcretePath::from_str("/emer")?;
+        for (path, value, expect) in [
+            (&mode, Value::from_integer(1), "dim"),
+            (&emer, Value::from_boolean(true), "bright"),
+            (&mode, Value::from_integer(0), "off"),
+            (&mode, Value::from_integer(2), "bright"),
+            (&emer, Value::from_boolean(false), "moon"),
+            (&mode, Value::from_integer(7), "unknown"),
+        ] {
+            let updates = tree.handle_event(path, value)?;
+            assert_eq!(updates["sink"][0].1, Value::new_str(expect));
+        }
+        assert!(tree.warnings().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_match_errors() -> Fallible<()> {
+        let s = "mode ^mode\n    default <- 0\nlight $sink <-\\\n    match /mode:\n        0: 1\n";
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        tree.handle_event(&ConcretePath::from_str("/mode")?, Value::from_integer(1))?;
+        let err = tree.lookup("/light")?.compute(&tree).err().unwrap();
+        assert_eq!(err.to_string(), "runtime error: no match arm for 1");
+
+        for (s, error) in &[
+            (
+                "a <- 1\nb <-\\\n    match /a:\n        \"on\": 1\n        _: 2",
+                "type error: match pattern \"on\" is a string, but the value matched is a integer",
+            ),
+            (
+                "a <- 1\nb <-\\\n    match /a:\n        1 if 2: 1\n        _: 2",
+                "type error: match guards must be boolean, not integer",
+            ),
+            (
+                "a <- 1\nb <-\\\n    match /a:\n        /a: 1",
+                "parse error: expected a literal or _ as a match pattern",
+            ),
+        ] {
+            let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+            assert!(err.to_string().starts_with(error), "{}: {}", s, err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_match_warnings() -> Fallible<()> {
+        let s = r#"
+dark ^dark
+    default <- false
+mode <-\
+    if /dark:
+        "night"
+    else:
+        "day"
+a <-\
+    match /mode:
+        "day": 1
+        "dusk": 2
+        _: 3
+        "night": 4
+b <-\
+    match /mode:
+        "day": 1
+c <-\
+    match /dark:
+        true: 1
+        false: 2
+        _: 3
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let warnings = tree
+            .warnings()
+            .iter()
+            .map(|w| w.lines().next().unwrap().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            warnings,
+            vec![
+                "match warning: arm \"dusk\" is unreachable",
+                "match warning: arm \"night\" is unreachable",
+                "match warning: no arm matches \"night\"",
+                "match warning: arm _ is unreachable",
+                "dataflow warning: source at /dark is not connected to any sinks",
+            ]
+        );
+        assert!(tree.warnings()[0].contains("--> <string>:12:9"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_not_and_if() -> Fallible<()> {
+        let expect = [
+            ("!true", Value::from_boolean(false)),
+            ("not false", Value::from_boolean(true)),
+            ("!(1 == 2) && true", Value::from_boolean(true)),
+            ("not true || true", Value::from_boolean(true)),
+            ("if true then 1 else 2", Value::from_integer(1)),
+            ("if 1 > 2 then 1 else 2 + 3", Value::from_integer(5)),
+            ("1 + (if !true then 1 else 2)", Value::from_integer(3)),
+            (
+                "if false then 1 else if true then 2 else 3",
+                Value::from_integer(2),
+            ),
+        ];
+        for (expr, value) in expect.iter() {
+            assert_eq!(do_compute(expr)?, *value, "{}", expr);
+        }
+        for expr in &[
+            "!1",
+            "if 1 then 2 else 3",
+            "if true then 1",
+            "!name",
+            "!sum(1)",
+        ] {
+            assert!(do_compute(expr).is_err(), "{}", expr);
+        }
+
+        let tree = TreeBuilder::default().build_from_str(
+            r#"
+a <- !contains("abc", "a")
+b <-\
+    !contains("abc", "d") && !/a
+"#,
+        )?;
+        assert_eq!(
+            tree.lookup("/a")?.compute(&tree)?,
+            Value::from_boolean(false)
+        );
+        assert_eq!(
+            tree.lookup("/b")?.compute(&tree)?,
+            Value::from_boolean(true)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_inline_if_generation() -> Fallible<()> {
+        let s = r#"
+dark ^dark
+    default <- false
+a ^a
+    default <- 1
+b ^b
+    default <- 2
+c $sink <- (if !/dark then /a else /b) :: /a
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let updates =
+            tree.handle_event(&ConcretePath::from_str("/dark")?, Value::from_boolean(true))?;
+        assert_eq!(updates["sink"][0].1, Value::from_integer(2));
+        let updates = tree.handle_event(&ConcretePath::from_str("/a")?, Value::from_integer(3))?;
+        assert_eq!(updates["sink"][0].1, Value::from_integer(3));
+        assert_eq!(tree.lookup("/c")?.value_type(&tree)?, ValueType::Integer);
+        let err = TreeBuilder::default()
+            .build_from_str("a <- 1\nb <- if /a then 1 else 2")
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("type error: if expression conditions must be boolean, not integer"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_list() -> Fallible<()> {
+        let list = |items: Vec<i64>| {
+            Value::from_list(items.into_iter().map(Value::from_integer).collect())
+        };
+        let expect = [
+            ("[]", list(vec![])),
+            ("[1, 2 + 1]", list(vec![1, 3])),
+            ("[1] + [2]", list(vec![1, 2])),
+            ("[1, 2, 3][1]", Value::from_integer(2)),
+            ("[[1, 2], [3]][0][1]", Value::from_integer(2)),
+            ("len([1, 2, 3])", Value::from_integer(3)),
+            ("sum([1, 2, 3]) * 2", Value::from_integer(12)),
+            ("max([1, 5, 3])", Value::from_integer(5)),
+            ("any([false, 1 > 0])", Value::from_boolean(true)),
+            ("all([true, false])", Value::from_boolean(false)),
+            ("count([true, false, true])", Value::from_integer(2)),
+            ("contains([1, 2], 2)", Value::from_boolean(true)),
+            ("[1, 2] == [1, 2]", Value::from_boolean(true)),
+            (r#""{[1, 2]}""#, Value::new_str("[1, 2]")),
+        ];
+        // Lists are mostly used with builtins, so compute them in a tree.
+        let compute = |expr: &str| -> Fallible<Value> {
+            let tree = TreeBuilder::default().build_from_str(&format!("a <- {}", expr))?;
+            tree.lookup("/a")?.compute(&tree)
+        };
+        for (expr, value) in expect.iter() {
+            assert_eq!(compute(expr)?, *value, "{}", expr);
+        }
+        for expr in &["[1, 2][2]", "[1, 2", "[1 2]", "any([1])", "[1] - [1]"] {
+            assert!(compute(expr).is_err(), "{}", expr);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_list_inputs() -> Fallible<()> {
+        let s = r#"
+rooms
+    bed ^room
+        default <- "off"
+    kitchen ^room
+        default <- "off"
+    office <- "off"
+lit $sink <- count([/rooms/bed == "on", /rooms/kitchen == "on", /rooms/office == "on"])
+levels $sink <- [/rooms/bed, /rooms/kitchen][1]
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let updates = tree.handle_event(
+            &ConcretePath::from_str("/rooms/kitchen")?,
+            Value::new_str("on"),
+        )?;
+        let mut values = updates["sink"]
+            .iter()
+            .map(|(_, v)| v.to_owned())
+            .collect::<Vec<_>>();
+        values.sort_by_key(|v| v.to_string());
+        assert_eq!(values, vec![Value::new_str("on"), Value::from_integer(1)]);
+        assert_eq!(tree.lookup("/lit")?.value_type(&tree)?, ValueType::Integer);
+        let err = TreeBuilder::default()
+            .build_from_str("a <- [1, 2]\nb <- upper(/a)")
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("type error: argument 1 to upper must be string, not list"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_let() -> Fallible<()> {
+        let s = r#"
+a ^a
+    default <- 1
+b ^b
+    default <- 2
+c $sink <-\
+    let x = /a + /b
+    let y = x * 2
+    if y > 10:
+        let z = x - 1
+        "big {z}"
+    else:
+        match x:
+            3: "three"
+            _: "small {y}"
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(tree.lookup("/c")?.compute(&tree)?, Value::new_str("three"));
+        let updates = tree.handle_event(&ConcretePath::from_str("/a")?, Value::from_integer(2))?;
+        assert_eq!(updates["sink"][0].1, Value::new_str("small 8"));
+        let updates = tree.handle_event(&ConcretePath::from_str("/b")?, Value::from_integer(5))?;
+        assert_eq!(updates["sink"][0].1, Value::new_str("big 6"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_let_computed_once() -> Fallible<()> {
+        let tok = tokenize(
+            "a <-\\\n    let x = count()\n    let y = x * 10\n    if x > 0:\n        let z = y + x\n        z + z + x\n    else:\n        0\n",
+        )?;
+        let mut script = Script::block_from_tokens("/a".to_owned(), &tok[4..], &test_nifs())?;
+        let tree = TreeBuilder::empty();
+        let input_map = script.build_input_map(&tree)?;
+        script.install_input_map(input_map)?;
+        assert_eq!(script.compute(&tree)?, Value::from_integer(23));
+        assert_eq!(COUNT.load(Ordering::SeqCst), 1);
+
+        // Each computation of the block computes the bindings afresh.
+        assert_eq!(script.compute(&tree)?, Value::from_integer(46));
+        assert_eq!(COUNT.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_script_let_errors() {
+        for (s, error) in &[
+            (
+                "a <-\\\n    let x = 1\n    if true:\n        let y = 2\n        y\n    else:\n        y",
+                "parse error: y is not a let binding",
+            ),
+            ("a <-\\\n    let = 1\n    2", "parse error: expected a name after let"),
+            ("a <-\\\n    let x 1\n    x", "parse error: expected = and an expression after let x"),
+            ("a <-\\\n    let x = 1\n", "parse error: expected an expression after let"),
+        ] {
+            let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+            assert!(err.to_string().starts_with(error), "{}: {}", s, err);
+        }
+    }
 }
@@ -0,0 +1,209 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use failure::{Error, Fail};
+use std::{fmt, sync::Arc};
+
+/// The text of a configuration file, kept so that errors can quote it.
+pub struct SourceFile {
+    name: String,
+    lines: Vec<String>,
+
+    // The import statements that led to this file, outermost first.
+    imported_from: Vec<SourceLocation>,
+}
+
+impl SourceFile {
+    pub fn new(name: &str, text: &str) -> Arc<Self> {
+        Arc::new(Self {
+            name: name.to_owned(),
+            lines: text.lines().map(|l| l.to_owned()).collect(),
+            imported_from: Vec::new(),
+        })
+    }
+
+    pub fn imported(name: &str, text: &str, from: &SourceLocation) -> Arc<Self> {
+        let mut imported_from = from.file.imported_from.clone();
+        imported_from.push(from.to_owned());
+        Arc::new(Self {
+            name: name.to_owned(),
+            lines: text.lines().map(|l| l.to_owned()).collect(),
+            imported_from,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+
+    /// The import statements that led to this file, outermost first.
+    pub fn imported_from(&self) -> &[SourceLocation] {
+        &self.imported_from
+    }
+
+    /// True if this file is the given one or was imported through it.
+    pub fn is_imported_through(&self, name: &str) -> bool {
+        self.name == name || self.imported_from.iter().any(|l| l.file.name == name)
+    }
+}
+
+/// A run of characters on one line. Lines and columns count from 1.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize, len: usize) -> Self {
+        Self { line, column, len }
+    }
+}
+
+/// A span in a specific file.
+#[derive(Clone)]
+pub struct SourceLocation {
+    file: Arc<SourceFile>,
+    span: Span,
+}
+
+impl SourceLocation {
+    pub fn new(file: &Arc<SourceFile>, span: Span) -> Self {
+        Self {
+            file: file.to_owned(),
+            span,
+        }
+    }
+
+    pub fn file(&self) -> &Arc<SourceFile> {
+        &self.file
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// Pin the error to this location. Errors that already have a location
+    /// are returned unchanged, since the innermost location is the most precise.
+    pub fn annotate(&self, error: Error) -> Error {
+        if error.downcast_ref::<SourceError>().is_some() {
+            return error;
+        }
+        SourceError {
+            message: error.to_string(),
+            location: self.to_owned(),
+            notes: Vec::new(),
+        }
+        .into()
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.file.name, self.span.line, self.span.column
+        )
+    }
+}
+
+// Tokens are traced with their locations, so keep this short.
+impl fmt::Debug for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+/// An error at a location in the source, displayed with the offending line.
+pub struct SourceError {
+    message: String,
+    location: SourceLocation,
+    notes: Vec<String>,
+}
+
+impl SourceError {
+    /// Add context to a located error, such as the template it came from.
+    /// Errors without a location are returned unchanged.
+    pub fn add_note(mut error: Error, note: String) -> Error {
+        if let Some(source_error) = error.downcast_mut::<SourceError>() {
+            source_error.notes.push(note);
+        }
+        error
+    }
+
+    pub fn location(&self) -> &SourceLocation {
+        &self.location
+    }
+}
+
+impl Fail for SourceError {}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = self.location.span;
+        let gutter = " ".repeat(span.line.to_string().len());
+        writeln!(f, "{}", self.message)?;
+        writeln!(f, "{}--> {}", gutter, self.location)?;
+        if let Some(line) = self.location.file.lines.get(span.line - 1) {
+            writeln!(f, "{} |", gutter)?;
+            writeln!(f, "{} | {}", span.line, line)?;
+            write!(
+                f,
+                "{} | {}{}",
+                gutter,
+                " ".repeat(span.column - 1),
+                "^".repeat(span.len.max(1))
+            )?;
+        }
+        for note in &self.notes {
+            write!(f, "\n{} = {}", gutter, note)?;
+        }
+        for import in self.location.file.imported_from.iter().rev() {
+            write!(f, "\n{} = imported from {}", gutter, import)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use failure::err_msg;
+
+    #[test]
+    fn test_source_error_display() {
+        let main = SourceFile::new("main.ygg", "a\n    import(rooms.ygg)\n");
+        let rooms = SourceFile::imported(
+            "rooms.ygg",
+            "bed\n    color <- rgb(1, 2)\n",
+            &SourceLocation::new(&main, Span::new(2, 5, 18)),
+        );
+        let error = SourceLocation::new(&rooms, Span::new(2, 14, 3))
+            .annotate(err_msg("parse error: rgb takes 3 arguments, but 2 given"));
+        let error = SourceError::add_note(error, "in template light".to_owned());
+        assert_eq!(
+            error.to_string(),
+            r#"parse error: rgb takes 3 arguments, but 2 given
+ --> rooms.ygg:2:14
+  |
+2 |     color <- rgb(1, 2)
+  |              ^^^
+  = in template light
+  = imported from main.ygg:2:5"#
+        );
+        assert!(rooms.is_imported_through("main.ygg"));
+        assert!(!main.is_imported_through("rooms.ygg"));
+    }
+}
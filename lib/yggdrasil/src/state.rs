@@ -0,0 +1,510 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::{
+    bif::Arity,
+    path::{ConcretePath, ScriptPath},
+    source::SourceLocation,
+    time::{Duration, Timestamp},
+    tokenizer::{Spanned, Token},
+    tree::Tree,
+    value::{Value, ValueType},
+};
+use failure::{bail, ensure, format_err, Error, Fallible};
+use std::fmt;
+
+/// The kinds of node that remember something between events.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StateKind {
+    // Flips between false and true each time the trigger fires.
+    Toggle,
+    // Counts the times the trigger has fired.
+    Counter,
+    // True from an event where a boolean trigger rises from false to true,
+    // until the trigger's next event.
+    Edge,
+    // Holds the value of its input as it was when the trigger last fired.
+    // Until the trigger first fires, the input is passed through.
+    SampleHold,
+
+    // Timers follow their input, but wait for it to stay the same for the
+    // delay before taking some changes. After delays rises of a boolean,
+    // hold delays falls, and debounce delays every change.
+    After,
+    Hold,
+    Debounce,
+}
+
+impl StateKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "toggle" => StateKind::Toggle,
+            "counter" => StateKind::Counter,
+            "edge" => StateKind::Edge,
+            "sample_hold" => StateKind::SampleHold,
+            "after" => StateKind::After,
+            "hold" => StateKind::Hold,
+            "debounce" => StateKind::Debounce,
+            _ => return None,
+        })
+    }
+
+    fn arity(self) -> Arity {
+        match self {
+            StateKind::Toggle | StateKind::Counter | StateKind::Edge => Arity::Exactly(1),
+            _ => Arity::Exactly(2),
+        }
+    }
+
+    pub fn is_timer(self) -> bool {
+        matches!(
+            self,
+            StateKind::After | StateKind::Hold | StateKind::Debounce
+        )
+    }
+
+    // Whether a timer waits out the delay before taking on this value.
+    fn delays(self, value: &Value) -> bool {
+        match self {
+            StateKind::After => *value == Value::from_boolean(true),
+            StateKind::Hold => *value == Value::from_boolean(false),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for StateKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            StateKind::Toggle => "toggle",
+            StateKind::Counter => "counter",
+            StateKind::Edge => "edge",
+            StateKind::SampleHold => "sample_hold",
+            StateKind::After => "after",
+            StateKind::Hold => "hold",
+            StateKind::Debounce => "debounce",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How long a timer waits: fixed, or read from a node when it starts waiting.
+#[derive(Debug)]
+pub enum Delay {
+    Fixed(Duration),
+    Path(ConcretePath),
+}
+
+/// A change that a timer will make once its deadline passes.
+#[derive(Clone, Debug)]
+pub struct Pending {
+    pub deadline: Timestamp,
+    pub value: Value,
+}
+
+// The arguments of a stateful node are paths or literal durations.
+enum Arg {
+    Path(ConcretePath),
+    Duration(Duration),
+}
+
+/// A node whose value is kept in the tree rather than computed afresh:
+/// `presses <- counter(/button)` or `occupied <- hold(/motion, 10m)`.
+///
+/// Most change only when their trigger source sees an event. An event fires
+/// the trigger unless its value is false, so that buttons that report being
+/// released are only counted once. Edge looks at every event, since it needs
+/// the falls as well as the rises.
+///
+/// Timers instead watch their input, which may be any node, and change when
+/// it does or when a deadline passes. Deadlines are checked by `Tree::tick`.
+#[derive(Debug)]
+pub struct Stateful {
+    kind: StateKind,
+    trigger: Option<ConcretePath>,
+
+    // What sample_hold samples, or what a timer watches.
+    input: Option<ConcretePath>,
+    delay: Option<Delay>,
+
+    // The tokens after the <-, for writing the tree back out.
+    source: Vec<Token>,
+    location: SourceLocation,
+}
+
+impl Stateful {
+    /// Parse the tokens after a <- as a stateful node, if they name one.
+    pub fn maybe_from_tokens(path: &str, tokens: &[Spanned]) -> Fallible<Option<Self>> {
+        let kind = match tokens.first().and_then(|t| t.token.maybe_name()) {
+            Some(name) => match StateKind::from_name(name) {
+                Some(kind) => kind,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        let location = tokens[0].location.to_owned();
+        let mut stateful = Self::from_tokens(kind, path, &tokens[1..], location.to_owned())
+            .map_err(|e| location.annotate(e))?;
+        stateful.source = tokens.iter().map(|t| t.token.to_owned()).collect();
+        Ok(Some(stateful))
+    }
+
+    fn from_tokens(
+        kind: StateKind,
+        path: &str,
+        tokens: &[Spanned],
+        location: SourceLocation,
+    ) -> Fallible<Self> {
+        ensure!(
+            tokens.first().map(|t| &t.token) == Some(&Token::LeftParen),
+            "parse error: expected () in call to {}",
+            kind
+        );
+        let mut args = Vec::new();
+        let mut offset = 1;
+        loop {
+            match tokens.get(offset).map(|t| &t.token) {
+                Some(Token::RightParen) if args.is_empty() => break,
+                Some(Token::PathTerm(p)) => {
+                    let arg = ScriptPath::from_str_at_path(path, p)?;
+                    ensure!(
+                        arg.is_concrete(),
+                        "parse error: the arguments to {} must be plain paths, not {}",
+                        kind,
+                        arg
+                    );
+                    args.push(Arg::Path(arg.as_concrete()));
+                }
+                Some(Token::DurationTerm(d)) => args.push(Arg::Duration(*d)),
+                _ => bail!("parse error: the arguments to {} must be paths", kind),
+            }
+            match tokens.get(offset + 1).map(|t| &t.token) {
+                Some(Token::Comma) => offset += 2,
+                Some(Token::RightParen) => break,
+                _ => bail!("parse error: expected , or ) in call to {}", kind),
+            }
+        }
+        offset += 2;
+        ensure!(
+            offset >= tokens.len(),
+            "parse error: {} keeps state, so must be the only thing after <- on its node",
+            kind
+        );
+        ensure!(
+            kind.arity().accepts(args.len()),
+            "parse error: {} takes {}, but {} given",
+            kind,
+            kind.arity(),
+            args.len()
+        );
+
+        let mut args = args.drain(..);
+        let first = match args.next() {
+            Some(Arg::Path(p)) => p,
+            _ => bail!("parse error: the arguments to {} must be paths", kind),
+        };
+        let second = args.next();
+        let mut stateful = Self {
+            kind,
+            trigger: None,
+            input: None,
+            delay: None,
+            source: Vec::new(),
+            location,
+        };
+        match (kind.is_timer(), second) {
+            (false, None) => stateful.trigger = Some(first),
+            (false, Some(Arg::Path(input))) => {
+                stateful.trigger = Some(first);
+                stateful.input = Some(input);
+            }
+            (false, Some(Arg::Duration(_))) => {
+                bail!("parse error: the arguments to {} must be paths", kind)
+            }
+            (true, second) => {
+                stateful.input = Some(first);
+                stateful.delay = Some(match second {
+                    Some(Arg::Duration(d)) => Delay::Fixed(d),
+                    Some(Arg::Path(p)) => Delay::Path(p),
+                    None => unreachable!("timers take 2 arguments"),
+                });
+            }
+        }
+        Ok(stateful)
+    }
+
+    pub fn kind(&self) -> StateKind {
+        self.kind
+    }
+
+    pub(crate) fn source(&self) -> &[Token] {
+        &self.source
+    }
+
+    /// The source whose events change the state. Timers have none.
+    pub fn trigger(&self) -> Option<&ConcretePath> {
+        self.trigger.as_ref()
+    }
+
+    pub fn input(&self) -> Option<&ConcretePath> {
+        self.input.as_ref()
+    }
+
+    /// Every node the state is made from.
+    pub fn inputs(&self) -> Vec<&ConcretePath> {
+        let delay = match self.delay {
+            Some(Delay::Path(ref p)) => Some(p),
+            _ => None,
+        };
+        self.trigger
+            .iter()
+            .chain(self.input.iter())
+            .chain(delay)
+            .collect()
+    }
+
+    /// The state before the trigger has fired. Timers start out with the
+    /// first value they see of their input.
+    pub fn initial(&self) -> Option<Value> {
+        match self.kind {
+            StateKind::Toggle | StateKind::Edge => Some(Value::from_boolean(false)),
+            StateKind::Counter => Some(Value::from_integer(0)),
+            _ => None,
+        }
+    }
+
+    pub fn value_type(&self, tree: &Tree) -> Fallible<ValueType> {
+        if let Some(Delay::Path(ref delay)) = self.delay {
+            let delay_type = tree.lookup_path(delay)?.value_type(tree)?;
+            ensure!(
+                ValueType::Duration.accepts(delay_type),
+                "type error: the delay of {} must be a duration, but {} is {}",
+                self.kind,
+                delay,
+                delay_type
+            );
+        }
+        let boolean_input = match self.kind {
+            StateKind::Toggle => return Ok(ValueType::Boolean),
+            StateKind::Counter => return Ok(ValueType::Integer),
+            StateKind::SampleHold | StateKind::Debounce => false,
+            StateKind::Edge | StateKind::After | StateKind::Hold => true,
+        };
+        let (what, path) = match (&self.trigger, &self.input) {
+            (Some(trigger), None) => ("trigger", trigger),
+            (_, Some(input)) => ("input", input),
+            (None, None) => unreachable!("stateful node without inputs"),
+        };
+        let input_type = tree.lookup_path(path)?.value_type(tree)?;
+        if !boolean_input {
+            return Ok(input_type);
+        }
+        ensure!(
+            ValueType::Boolean.accepts(input_type),
+            "type error: {} needs a boolean {}, but {} is {}",
+            self.kind,
+            what,
+            path,
+            input_type
+        );
+        Ok(ValueType::Boolean)
+    }
+
+    /// The state after the trigger sees the event. The previous value of the
+    /// trigger is given if it had one.
+    pub fn fire(
+        &self,
+        state: Option<&Value>,
+        previous: Option<&Value>,
+        event: &Value,
+        tree: &Tree,
+    ) -> Fallible<Option<Value>> {
+        let fires = *event != Value::from_boolean(false);
+        let next = match self.kind {
+            StateKind::Edge => {
+                let rising = event.as_boolean().map_err(|_| {
+                    format_err!(
+                        "runtime error: edge needs a boolean trigger, but {} sent {}",
+                        self.trigger.as_ref().unwrap(),
+                        event
+                    )
+                })? && previous != Some(&Value::from_boolean(true));
+                Value::from_boolean(rising)
+            }
+            _ if !fires => return Ok(state.cloned()),
+            StateKind::Toggle => Value::from_boolean(!state.map_or(Ok(false), Value::as_boolean)?),
+            StateKind::Counter => state
+                .cloned()
+                .unwrap_or_else(|| Value::from_integer(0))
+                .apply(&Token::Add, &Value::from_integer(1))?,
+            StateKind::SampleHold => match self.input {
+                Some(ref input) => tree.lookup_path(input)?.compute(tree)?,
+                None => unreachable!("sample_hold without an input"),
+            },
+            _ => unreachable!("timers do not have triggers"),
+        };
+        Ok(Some(next.with_generation(event.generation())))
+    }
+
+    /// Look at a timer's input after something upstream of it changed,
+    /// taking the new value at once or setting a deadline to take it at.
+    /// A deadline that is already set is kept if it is for the same value.
+    pub fn observe(
+        &self,
+        state: &mut Option<Value>,
+        pending: &mut Option<Pending>,
+        now: Timestamp,
+        tree: &Tree,
+    ) -> Fallible<()> {
+        let value = match self.input {
+            Some(ref input) => tree.lookup_path(input)?.compute(tree)?,
+            None => unreachable!("timer without an input"),
+        };
+        // After and hold are typed to take booleans, but sources may still
+        // send something else.
+        ensure!(
+            self.kind == StateKind::Debounce || value.is_boolean(),
+            "runtime error: {} needs a boolean input, but {} is {}",
+            self.kind,
+            self.input.as_ref().unwrap(),
+            value
+        );
+        let current = match state {
+            Some(current) => current,
+            None => {
+                *state = Some(value);
+                return Ok(());
+            }
+        };
+        if *current == value {
+            *pending = None;
+        } else if !self.kind.delays(&value) {
+            *state = Some(value);
+            *pending = None;
+        } else if pending.as_ref().map(|p| &p.value) != Some(&value) {
+            *pending = Some(Pending {
+                deadline: now.checked_add(self.delay(tree)?)?,
+                value,
+            });
+        }
+        Ok(())
+    }
+
+    fn delay(&self, tree: &Tree) -> Fallible<Duration> {
+        match self.delay {
+            Some(Delay::Fixed(d)) => Ok(d),
+            Some(Delay::Path(ref p)) => tree.lookup_path(p)?.compute(tree)?.as_duration(),
+            None => unreachable!("timer without a delay"),
+        }
+    }
+
+    pub fn annotate(&self, error: Error) -> Error {
+        self.location.annotate(error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{source::SourceFile, tokenizer::TreeTokenizer, tree::TreeBuilder};
+
+    fn parse(s: &str) -> Fallible<Option<Stateful>> {
+        let file = SourceFile::new("<test>", &format!("b <- {}", s));
+        let tokens = TreeTokenizer::tokenize_source(&file)?;
+        Stateful::maybe_from_tokens("/a/b", &tokens[2..tokens.len() - 1])
+    }
+
+    fn b(v: bool) -> Value {
+        Value::from_boolean(v)
+    }
+
+    #[test]
+    fn test_parse_stateful() -> Fallible<()> {
+        let toggle = parse("toggle(/button)")?.unwrap();
+        assert_eq!(toggle.kind(), StateKind::Toggle);
+        assert_eq!(toggle.trigger().unwrap().to_string(), "/button");
+        let hold = parse("sample_hold(./tick, ../level)")?.unwrap();
+        assert_eq!(hold.trigger().unwrap().to_string(), "/a/tick");
+        assert_eq!(hold.input().unwrap().to_string(), "/level");
+        let timer = parse("hold(/motion, 10m)")?.unwrap();
+        assert!(timer.kind().is_timer());
+        assert_eq!(timer.trigger(), None);
+        assert_eq!(timer.input().unwrap().to_string(), "/motion");
+        let debounce = parse("debounce(../scene, ../delay)")?.unwrap();
+        assert_eq!(debounce.inputs().len(), 2);
+        assert!(parse("rgb(1, 2, 3)")?.is_none());
+        assert!(parse("/button")?.is_none());
+
+        for (s, message) in &[
+            ("toggle", "parse error: expected () in call to toggle"),
+            (
+                "toggle()",
+                "parse error: toggle takes 1 argument, but 0 given",
+            ),
+            (
+                "sample_hold(/a)",
+                "parse error: sample_hold takes 2 arguments, but 1 given",
+            ),
+            (
+                "toggle(1)",
+                "parse error: the arguments to toggle must be paths",
+            ),
+            (
+                "sample_hold(/a, 1s)",
+                "parse error: the arguments to sample_hold must be paths",
+            ),
+            (
+                "debounce(1s, /a)",
+                "parse error: the arguments to debounce must be paths",
+            ),
+            (
+                "counter(/a/{/b})",
+                "parse error: the arguments to counter must be plain paths, not /a/{/b}",
+            ),
+            (
+                "edge(/a) && /b",
+                "parse error: edge keeps state, so must be the only thing after <- on its node",
+            ),
+        ] {
+            let error = parse(s).err().unwrap();
+            assert!(error.to_string().starts_with(message), "{}: {}", s, error);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fire() -> Fallible<()> {
+        let tree = TreeBuilder::empty();
+        let fire = |s: &Stateful, state: Option<Value>, previous: Option<Value>, event: Value| {
+            s.fire(state.as_ref(), previous.as_ref(), &event, &tree)
+        };
+
+        let toggle = parse("toggle(/button)")?.unwrap();
+        let state = fire(&toggle, toggle.initial(), None, b(true))?;
+        assert_eq!(state, Some(b(true)));
+        assert_eq!(fire(&toggle, state.clone(), None, b(false))?, Some(b(true)));
+        assert_eq!(
+            fire(&toggle, state, None, "pressed".into())?,
+            Some(b(false))
+        );
+
+        let counter = parse("counter(/button)")?.unwrap();
+        let mut state = counter.initial();
+        for event in &[b(true), b(false), Value::from_integer(0), b(true)] {
+            state = fire(&counter, state, None, event.to_owned())?;
+        }
+        assert_eq!(state, Some(Value::from_integer(3)));
+
+        let edge = parse("edge(/motion)")?.unwrap();
+        assert_eq!(fire(&edge, None, None, b(true))?, Some(b(true)));
+        assert_eq!(fire(&edge, None, Some(b(false)), b(true))?, Some(b(true)));
+        assert_eq!(fire(&edge, None, Some(b(true)), b(true))?, Some(b(false)));
+        assert_eq!(fire(&edge, None, Some(b(true)), b(false))?, Some(b(false)));
+        assert!(fire(&edge, None, None, Value::from_integer(1)).is_err());
+
+        let mut event = b(true);
+        event.set_generation(7);
+        assert_eq!(fire(&toggle, None, None, event)?.unwrap().generation(), 7);
+        Ok(())
+    }
+}
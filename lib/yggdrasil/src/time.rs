@@ -0,0 +1,271 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use chrono::{DateTime, SecondsFormat};
+use failure::{bail, ensure, err_msg, Fallible};
+use std::fmt;
+
+const UNITS: [(&str, i64); 5] = [
+    ("d", 24 * 60 * 60 * 1000),
+    ("h", 60 * 60 * 1000),
+    ("m", 60 * 1000),
+    ("s", 1000),
+    ("ms", 1),
+];
+
+/// A signed span of time, with millisecond resolution.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Duration {
+    millis: i64,
+}
+
+impl Duration {
+    pub fn from_millis(millis: i64) -> Self {
+        Duration { millis }
+    }
+
+    pub fn from_secs_f64(secs: f64) -> Fallible<Self> {
+        Self::from_millis_f64(secs * 1000.0)
+    }
+
+    fn from_millis_f64(millis: f64) -> Fallible<Self> {
+        ensure!(
+            millis.is_finite() && millis.abs() < i64::MAX as f64,
+            "numerical error: duration out of range"
+        );
+        Ok(Self::from_millis(millis.round() as i64))
+    }
+
+    /// Parse a duration literal: a number and unit, optionally followed by
+    /// more of the same, as in 500ms, 30s, 5m, 2h, 1d or 1h30m.
+    pub fn parse(s: &str) -> Fallible<Self> {
+        let (negative, mut rest) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        ensure!(!rest.is_empty(), "parse error: empty duration");
+        let mut millis = 0f64;
+        while !rest.is_empty() {
+            let number_end = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .ok_or_else(|| err_msg(format!("parse error: duration {} is missing a unit", s)))?;
+            let unit_end = rest[number_end..]
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .map(|offset| number_end + offset)
+                .unwrap_or_else(|| rest.len());
+            let number = rest[..number_end]
+                .parse::<f64>()
+                .map_err(|_| err_msg(format!("parse error: invalid number in duration {}", s)))?;
+            let unit = &rest[number_end..unit_end];
+            let scale = match UNITS.iter().find(|(name, _)| *name == unit) {
+                Some((_, scale)) => *scale,
+                None => bail!("parse error: unknown unit '{}' in duration {}", unit, s),
+            };
+            millis += number * scale as f64;
+            rest = &rest[unit_end..];
+        }
+        Self::from_millis_f64(if negative { -millis } else { millis })
+    }
+
+    pub fn as_millis(&self) -> i64 {
+        self.millis
+    }
+
+    pub fn as_secs_f64(&self) -> f64 {
+        self.millis as f64 / 1000.0
+    }
+
+    pub fn checked_add(self, rhs: Duration) -> Fallible<Duration> {
+        Ok(Self::from_millis(
+            self.millis
+                .checked_add(rhs.millis)
+                .ok_or_else(|| err_msg("numerical error: duration overflow"))?,
+        ))
+    }
+
+    pub fn checked_sub(self, rhs: Duration) -> Fallible<Duration> {
+        Ok(Self::from_millis(
+            self.millis
+                .checked_sub(rhs.millis)
+                .ok_or_else(|| err_msg("numerical error: duration overflow"))?,
+        ))
+    }
+
+    pub fn checked_rem(self, rhs: Duration) -> Fallible<Duration> {
+        ensure!(
+            rhs.millis != 0,
+            "numerical error: remainder by a zero duration"
+        );
+        Ok(Self::from_millis(self.millis % rhs.millis))
+    }
+
+    pub fn checked_mul(self, factor: f64) -> Fallible<Duration> {
+        Self::from_millis_f64(self.millis as f64 * factor)
+    }
+
+    pub fn checked_div(self, divisor: f64) -> Fallible<Duration> {
+        ensure!(
+            divisor != 0.0,
+            "numerical error: division of a duration by zero"
+        );
+        Self::from_millis_f64(self.millis as f64 / divisor)
+    }
+
+    /// How many times the other duration fits into this one.
+    pub fn ratio(self, rhs: Duration) -> Fallible<f64> {
+        ensure!(
+            rhs.millis != 0,
+            "numerical error: division by a zero duration"
+        );
+        Ok(self.millis as f64 / rhs.millis as f64)
+    }
+}
+
+// Formats as the shortest literal that parses back to the same duration.
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.millis == 0 {
+            return write!(f, "0s");
+        }
+        if self.millis < 0 {
+            write!(f, "-")?;
+        }
+        let mut rest = self.millis.unsigned_abs();
+        for (name, scale) in &UNITS {
+            let scale = *scale as u64;
+            if rest >= scale {
+                write!(f, "{}{}", rest / scale, name)?;
+                rest %= scale;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A point in time, with millisecond resolution.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Timestamp {
+    unix_millis: i64,
+}
+
+impl Timestamp {
+    pub fn from_unix_millis(unix_millis: i64) -> Self {
+        Timestamp { unix_millis }
+    }
+
+    /// Parse an RFC 3339 timestamp, such as 2020-01-31T18:00:00Z.
+    pub fn parse(s: &str) -> Fallible<Self> {
+        let dt = DateTime::parse_from_rfc3339(s)
+            .map_err(|e| err_msg(format!("parse error: invalid timestamp {}: {}", s, e)))?;
+        Ok(Self::from_unix_millis(dt.timestamp_millis()))
+    }
+
+    pub fn as_unix_millis(&self) -> i64 {
+        self.unix_millis
+    }
+
+    pub fn checked_add(self, rhs: Duration) -> Fallible<Timestamp> {
+        Ok(Self::from_unix_millis(
+            self.unix_millis
+                .checked_add(rhs.as_millis())
+                .ok_or_else(|| err_msg("numerical error: timestamp overflow"))?,
+        ))
+    }
+
+    pub fn checked_sub(self, rhs: Duration) -> Fallible<Timestamp> {
+        Ok(Self::from_unix_millis(
+            self.unix_millis
+                .checked_sub(rhs.as_millis())
+                .ok_or_else(|| err_msg("numerical error: timestamp overflow"))?,
+        ))
+    }
+
+    /// The duration from the other timestamp until this one.
+    pub fn since(self, earlier: Timestamp) -> Fallible<Duration> {
+        Ok(Duration::from_millis(
+            self.unix_millis
+                .checked_sub(earlier.unix_millis)
+                .ok_or_else(|| err_msg("numerical error: duration overflow"))?,
+        ))
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match DateTime::from_timestamp_millis(self.unix_millis) {
+            Some(dt) => write!(f, "{}", dt.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+            None => write!(f, "{}ms", self.unix_millis),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() -> Fallible<()> {
+        assert_eq!(Duration::parse("500ms")?, Duration::from_millis(500));
+        assert_eq!(Duration::parse("30s")?, Duration::from_millis(30_000));
+        assert_eq!(Duration::parse("5m")?, Duration::from_millis(300_000));
+        assert_eq!(Duration::parse("2h")?, Duration::from_millis(7_200_000));
+        assert_eq!(Duration::parse("1d")?, Duration::from_millis(86_400_000));
+        assert_eq!(Duration::parse("1h30m")?, Duration::from_millis(5_400_000));
+        assert_eq!(Duration::parse("1.5s")?, Duration::from_millis(1500));
+        assert_eq!(Duration::parse("-10m")?, Duration::from_millis(-600_000));
+        assert!(Duration::parse("5").is_err());
+        assert!(Duration::parse("5y").is_err());
+        assert!(Duration::parse("m").is_err());
+        assert!(Duration::parse("").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_duration() -> Fallible<()> {
+        for s in &[
+            "0s",
+            "500ms",
+            "30s",
+            "5m",
+            "2h",
+            "1d",
+            "1h30m",
+            "-10m",
+            "1d1h1m1s1ms",
+        ] {
+            assert_eq!(&Duration::parse(s)?.to_string(), s);
+        }
+        assert_eq!(Duration::parse("90m")?.to_string(), "1h30m");
+        Ok(())
+    }
+
+    #[test]
+    fn test_duration_arithmetic() -> Fallible<()> {
+        let a = Duration::parse("10m")?;
+        let b = Duration::parse("4m")?;
+        assert_eq!(a.checked_add(b)?, Duration::parse("14m")?);
+        assert_eq!(a.checked_sub(b)?, Duration::parse("6m")?);
+        assert_eq!(a.checked_rem(b)?, Duration::parse("2m")?);
+        assert_eq!(a.checked_mul(1.5)?, Duration::parse("15m")?);
+        assert_eq!(a.checked_div(4.0)?, Duration::parse("2m30s")?);
+        assert_eq!(a.ratio(b)?, 2.5);
+        assert!(a.checked_div(0.0).is_err());
+        assert!(a.ratio(Duration::from_millis(0)).is_err());
+        assert!(Duration::from_millis(i64::MAX).checked_add(a).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamp() -> Fallible<()> {
+        let t = Timestamp::parse("2020-01-31T18:00:00Z")?;
+        assert_eq!(t.as_unix_millis(), 1_580_493_600_000);
+        assert_eq!(t.to_string(), "2020-01-31T18:00:00Z");
+        let later = t.checked_add(Duration::parse("90m")?)?;
+        assert_eq!(later.to_string(), "2020-01-31T19:30:00Z");
+        assert_eq!(later.since(t)?, Duration::parse("1h30m")?);
+        assert_eq!(t.since(later)?, Duration::parse("-1h30m")?);
+        assert_eq!(later.checked_sub(Duration::parse("90m")?)?, t);
+        assert!(Timestamp::parse("yesterday").is_err());
+        Ok(())
+    }
+}
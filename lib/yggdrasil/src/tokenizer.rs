@@ -1,8 +1,14 @@
 // This Source Code Form is subject to the terms of the GNU General Public
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
-use crate::{float::Float, physical::Dimension2};
-use failure::{bail, ensure, Fallible};
+use crate::{
+    float::Float,
+    physical::Dimension2,
+    source::{SourceFile, SourceLocation, Span},
+    time::Duration,
+};
+use failure::{bail, ensure, format_err, Fallible};
+use std::{fmt, sync::Arc};
 use tracing::trace;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -11,17 +17,17 @@ pub enum Token {
     Newline,
     Indent,
     Dedent,
-    Template,     // template name [...]
-    StartOfBlock, // :
+    Template(String, Vec<String>), // template name(param, ...)
+    StartOfBlock,                  // :
 
     // Sigil-delimited
-    Location(Dimension2), // @
-    Size(Dimension2),     // <>
-    Source(String),       // ^
-    Sink(String),         // $
-    ComesFromInline,      // <-
-    ComesFromBlock,       // <-\
-    UseTemplate(String),  // !
+    Location(Dimension2),                      // @
+    Size(Dimension2),                          // <>
+    Source(String),                            // ^
+    Sink(String),                              // $
+    ComesFromInline,                           // <-
+    ComesFromBlock,                            // <-\
+    UseTemplate(String, Vec<(String, Token)>), // !name(param=value, ...)
 
     // Operators
     Add,                 // +
@@ -40,15 +46,22 @@ pub enum Token {
     Latch,               // ::
     LeftParen,           // (
     RightParen,          // )
+    LeftBracket,         // [
+    RightBracket,        // ]
+    Comma,               // ,
+    Assign,              // = in let bindings
+    Not,                 // ! shared with use-template and not-equals
 
     // Terminals
-    NameTerm(String),   // [a-zA-Z][a-zA-Z0-9]*
-    StringTerm(String), // ""
-    IntegerTerm(i64),   // -?[0-9]+
-    FloatTerm(Float),   // -?[0-9.]+
-    BooleanTerm(bool),  // true|false
-    PathTerm(String),   // (\.\.?)?(/identifier)+
-    ImportTerm(String), // import(file.ygg)
+    NameTerm(String),                    // [a-zA-Z_][a-zA-Z0-9]*
+    StringTerm(String),                  // ""
+    FormatTerm(String, Vec<Vec<Token>>), // "text {expr} text"
+    IntegerTerm(i64),                    // -?[0-9]+
+    FloatTerm(Float),                    // -?[0-9.]+
+    DurationTerm(Duration),              // -?([0-9.]+(ms|s|m|h|d))+
+    BooleanTerm(bool),                   // true|false
+    PathTerm(String),                    // (\.\.?)?(/identifier)+ or ${param}(/identifier)*
+    ImportTerm(String),                  // import(file.ygg)
 }
 
 impl Token {
@@ -65,17 +78,196 @@ impl Token {
         }
         None
     }
+    /// Lay out a run of tokens as source text: one line per newline, with
+    /// the nesting level of each line, as given by indents and dedents.
+    pub(crate) fn layout(tokens: &[Token]) -> Vec<(usize, String)> {
+        let mut lines = Vec::new();
+        let mut level = 0usize;
+        let mut line = String::new();
+        let mut prev: Option<&Token> = None;
+        for token in tokens {
+            match token {
+                Token::Newline => {
+                    if !line.is_empty() {
+                        lines.push((level, std::mem::take(&mut line)));
+                    }
+                    prev = None;
+                }
+                Token::Indent => level += 1,
+                Token::Dedent => level = level.saturating_sub(1),
+                _ => {
+                    if prev.is_some_and(|prev| Self::spaced(prev, token)) {
+                        line.push(' ');
+                    }
+                    line.push_str(&token.to_string());
+                    prev = Some(token);
+                }
+            }
+        }
+        if !line.is_empty() {
+            lines.push((level, line));
+        }
+        lines
+    }
+
+    // Tokens on a line are separated by a space, except where that would
+    // look odd. Every token that needs a space after it to keep its meaning,
+    // like division or not, gets one.
+    fn spaced(prev: &Token, next: &Token) -> bool {
+        !matches!(
+            (prev, next),
+            (
+                _,
+                Token::Comma | Token::RightParen | Token::RightBracket | Token::StartOfBlock
+            ) | (Token::LeftParen | Token::LeftBracket | Token::Not, _)
+                | (Token::NameTerm(_), Token::LeftParen)
+        )
+    }
+
+    // Literal text inside quotes, with the escapes the tokenizer undoes.
+    fn quote(f: &mut fmt::Formatter, text: &str, braces: bool) -> fmt::Result {
+        for c in text.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '{' | '}' if braces => write!(f, "{}{}", c, c)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Tokens display as the text they were read from, or text that reads back
+// as the same token.
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Newline => writeln!(f),
+            Token::Indent | Token::Dedent => Ok(()),
+            Token::Template(name, params) if params.is_empty() => write!(f, "template {}", name),
+            Token::Template(name, params) => write!(f, "template {}({})", name, params.join(", ")),
+            Token::StartOfBlock => write!(f, ":"),
+            Token::Location(dim) => write!(f, "@{}", dim),
+            Token::Size(dim) => write!(f, "<>{}", dim),
+            Token::Source(kind) => write!(f, "^{}", kind),
+            Token::Sink(kind) => write!(f, "${}", kind),
+            Token::ComesFromInline => write!(f, "<-"),
+            Token::ComesFromBlock => write!(f, "<-\\"),
+            Token::UseTemplate(name, args) if args.is_empty() => write!(f, "!{}", name),
+            Token::UseTemplate(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|(param, value)| format!("{}={}", param, value))
+                    .collect::<Vec<_>>();
+                write!(f, "!{}({})", name, args.join(", "))
+            }
+            Token::Add => write!(f, "+"),
+            Token::And => write!(f, "&&"),
+            Token::Subtract => write!(f, "-"),
+            Token::Divide => write!(f, "/"),
+            Token::Multiply => write!(f, "*"),
+            Token::Modulo => write!(f, "%"),
+            Token::Equals => write!(f, "=="),
+            Token::NotEquals => write!(f, "!="),
+            Token::LessThan => write!(f, "<"),
+            Token::LessThanOrEquals => write!(f, "<="),
+            Token::GreaterThan => write!(f, ">"),
+            Token::GreaterThanOrEquals => write!(f, ">="),
+            Token::Or => write!(f, "||"),
+            Token::Latch => write!(f, "::"),
+            Token::LeftParen => write!(f, "("),
+            Token::RightParen => write!(f, ")"),
+            Token::LeftBracket => write!(f, "["),
+            Token::RightBracket => write!(f, "]"),
+            Token::Comma => write!(f, ","),
+            Token::Assign => write!(f, "="),
+            Token::Not => write!(f, "!"),
+            Token::NameTerm(name) => write!(f, "{}", name),
+            Token::StringTerm(text) => {
+                write!(f, "\"")?;
+                Self::quote(f, text, true)?;
+                write!(f, "\"")
+            }
+            // The format already has its literal braces doubled.
+            Token::FormatTerm(format, parts) => {
+                write!(f, "\"")?;
+                let mut parts = parts.iter();
+                let mut rest = format.as_str();
+                while let Some(offset) = rest.find("{}") {
+                    let doubled = rest[..offset].matches('{').count() % 2 == 1;
+                    Self::quote(f, &rest[..offset], false)?;
+                    if doubled {
+                        write!(f, "{{")?;
+                        rest = &rest[offset + 1..];
+                        continue;
+                    }
+                    let part = parts.next().map(|p| p.as_slice()).unwrap_or_default();
+                    let line = Token::layout(part)
+                        .into_iter()
+                        .map(|(_, line)| line)
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    write!(f, "{{{}}}", line)?;
+                    rest = &rest[offset + 2..];
+                }
+                Self::quote(f, rest, false)?;
+                write!(f, "\"")
+            }
+            Token::IntegerTerm(i) => write!(f, "{}", i),
+            // A float must keep its point, or it would read back as an integer.
+            Token::FloatTerm(float) => {
+                let text = float.value.to_string();
+                if text.contains('.') {
+                    write!(f, "{}", text)
+                } else {
+                    write!(f, "{}.0", text)
+                }
+            }
+            Token::DurationTerm(duration) => write!(f, "{}", duration),
+            Token::BooleanTerm(b) => write!(f, "{}", b),
+            Token::PathTerm(path) => write!(f, "{}", path),
+            Token::ImportTerm(filename) => write!(f, "import({})", filename),
+        }
+    }
+}
+
+/// A token and where it was found.
+#[derive(Clone, Debug)]
+pub struct Spanned {
+    pub token: Token,
+    pub location: SourceLocation,
 }
 
 pub struct TreeTokenizer {}
 
+/// Whether the text has comments, which are dropped when it is tokenized.
+pub fn has_comments(text: &str) -> bool {
+    text.lines()
+        .any(|line| LineTokenizer::find_comment(line).is_some())
+}
+
 impl TreeTokenizer {
-    #[allow(clippy::comparison_chain)]
+    // Tokens without their locations, for tests.
+    #[cfg(test)]
     pub fn tokenize(s: &str) -> Fallible<Vec<Token>> {
+        let file = SourceFile::new("<string>", s);
+        Ok(Self::tokenize_source(&file)?
+            .into_iter()
+            .map(|spanned| spanned.token)
+            .collect())
+    }
+
+    #[allow(clippy::comparison_chain)]
+    pub fn tokenize_source(file: &Arc<SourceFile>) -> Fallible<Vec<Spanned>> {
         let mut tokens = Vec::new();
+        let at = |line: usize, column: usize, len: usize| {
+            SourceLocation::new(file, Span::new(line + 1, column + 1, len))
+        };
 
         let mut indent = vec![0];
-        for line_raw in s.lines() {
+        // The indent of the line that opened the block script we are in, if any.
+        let mut block_script: Option<usize> = None;
+        for (line_number, line_raw) in file.lines().iter().enumerate() {
             let line = LineTokenizer::trim_comment(line_raw);
             if line.is_empty() {
                 continue;
@@ -83,31 +275,54 @@ impl TreeTokenizer {
 
             let last_level = *indent.last().unwrap();
             let current_level = LineTokenizer::leading_whitespace(&line);
+            if block_script.is_some_and(|level| current_level <= level) {
+                block_script = None;
+            }
+            let layout = |token: Token| Spanned {
+                token,
+                location: at(line_number, current_level, 0),
+            };
             if current_level > last_level {
                 indent.push(current_level);
-                tokens.push(Token::Indent);
+                tokens.push(layout(Token::Indent));
             } else if current_level < last_level {
                 if let Ok(offset) = indent.binary_search(&current_level) {
                     let cnt = indent.len() - offset - 1;
                     for _ in 0..cnt {
                         indent.pop();
-                        tokens.push(Token::Dedent);
+                        tokens.push(layout(Token::Dedent));
                     }
                 } else {
-                    bail!("dedent not aligned with a prior indent level");
+                    return Err(at(line_number, 0, current_level).annotate(format_err!(
+                        "tokenize error: dedent not aligned with a prior indent level"
+                    )));
                 }
             }
 
             let mut lt = LineTokenizer {
                 chars: line.chars().collect::<Vec<char>>(),
                 offset: 0,
+                in_script: block_script.is_some(),
             };
             while !lt.is_empty() {
                 lt.skip_space();
-                let token = lt.tokenize_one()?;
-                tokens.push(token);
+                let start = lt.offset;
+                let token = lt.tokenize_one().map_err(|e| {
+                    let len = lt.offset.saturating_sub(start).max(1);
+                    at(line_number, start, len).annotate(e)
+                })?;
+                if token == Token::ComesFromBlock {
+                    block_script = Some(current_level);
+                }
+                tokens.push(Spanned {
+                    token,
+                    location: at(line_number, start, lt.offset - start),
+                });
             }
-            tokens.push(Token::Newline);
+            tokens.push(Spanned {
+                token: Token::Newline,
+                location: at(line_number, lt.offset, 0),
+            });
         }
 
         Ok(tokens)
@@ -117,6 +332,9 @@ impl TreeTokenizer {
 pub struct LineTokenizer {
     chars: Vec<char>,
     offset: usize,
+
+    // After <- or in the body of a block script, as opposed to sigil position.
+    in_script: bool,
 }
 
 impl LineTokenizer {
@@ -133,13 +351,13 @@ impl LineTokenizer {
     fn tokenize_one(&mut self) -> Fallible<Token> {
         let c = self.peek(0)?;
         let tok = match c {
-            'a'..='z' | 'A'..='Z' => self.tokenize_name_or_keyword(),
+            'a'..='z' | 'A'..='Z' | '_' => self.tokenize_name_or_keyword(),
             '0'..='9' => self.tokenize_int_or_float(),
             '/' => self.tokenize_absolute_path_or_division(),
             '.' => self.tokenize_path(),
             '^' => self.tokenize_source(),
-            '$' => self.tokenize_sink(),
-            '!' => self.tokenize_use_template_or_not_eq(),
+            '$' => self.tokenize_sink_or_param(),
+            '!' => self.tokenize_use_template_or_not(),
             '@' => self.tokenize_location(),
             '"' => self.tokenize_string(),
             '<' => self.tokenize_comes_from_or_less_than_or_size(),
@@ -155,6 +373,18 @@ impl LineTokenizer {
                 self.offset += 1;
                 Ok(Token::RightParen)
             }
+            '[' => {
+                self.offset += 1;
+                Ok(Token::LeftBracket)
+            }
+            ']' => {
+                self.offset += 1;
+                Ok(Token::RightBracket)
+            }
+            ',' => {
+                self.offset += 1;
+                Ok(Token::Comma)
+            }
             '+' => {
                 self.offset += 1;
                 Ok(Token::Add)
@@ -168,9 +398,12 @@ impl LineTokenizer {
                 Ok(Token::Modulo)
             }
             '=' => {
-                ensure!(self.peek(1)? == '=', "expected double == sign");
-                self.offset += 2;
-                Ok(Token::Equals)
+                if self.maybe_peek(1) == Some('=') {
+                    self.offset += 2;
+                    return Ok(Token::Equals);
+                }
+                self.offset += 1;
+                Ok(Token::Assign)
             }
             _ => bail!(
                 "tokenize error: expected a sigil or name, found: {}",
@@ -216,32 +449,60 @@ impl LineTokenizer {
 
     fn tokenize_template(&mut self) -> Fallible<Token> {
         self.skip_space();
-        let _name = self.tokenize_identifier()?;
-        self.skip_space();
+        let name = self.tokenize_identifier()?;
         ensure!(
-            self.peek(0)? == '[',
-            "expected template to be surrounded with []"
+            !name.is_empty(),
+            "tokenize error: expected a name after template"
         );
-        self.offset += 1;
-        let start_offset = self.offset;
-        while self.peek(0)? != ']' {
+        let mut params = Vec::new();
+        if self.maybe_peek(0) == Some('(') {
             self.offset += 1;
+            while self.tokenize_list_continues(params.is_empty())? {
+                let param = self.tokenize_identifier()?;
+                ensure!(
+                    !param.is_empty(),
+                    "tokenize error: expected a parameter name in template {}",
+                    name
+                );
+                ensure!(
+                    !params.contains(&param),
+                    "tokenize error: duplicate parameter {} in template {}",
+                    param,
+                    name
+                );
+                params.push(param);
+            }
         }
-        let template_chars = &self.chars[start_offset..self.offset];
-        let template_str = template_chars.iter().collect::<String>();
-        self.offset += 1;
-        let _template_tokens = TreeTokenizer::tokenize(&template_str)?;
-        Ok(Token::Template)
+        Ok(Token::Template(name, params))
+    }
+
+    // Skip to the next item of a parenthesized, comma separated list.
+    // Returns false after consuming the closing paren.
+    fn tokenize_list_continues(&mut self, first: bool) -> Fallible<bool> {
+        self.skip_space();
+        if self.peek(0)? == ')' {
+            self.offset += 1;
+            return Ok(false);
+        }
+        if !first {
+            ensure!(
+                self.peek(0)? == ',',
+                "tokenize error: expected , or ) in list"
+            );
+            self.offset += 1;
+            self.skip_space();
+        }
+        Ok(true)
     }
 
     fn tokenize_subtract_or_number(&mut self) -> Fallible<Token> {
-        if let Some(c) = self.maybe_peek(1) {
-            if c == ' ' || c == '/' || c == '.' {
+        match self.maybe_peek(1) {
+            Some(c) if c.is_ascii_digit() => self.tokenize_int_or_float(),
+            _ => {
                 self.offset += 1;
-                return Ok(Token::Subtract);
+                Ok(Token::Subtract)
             }
         }
-        self.tokenize_int_or_float()
     }
 
     fn tokenize_start_of_block_or_latch(&mut self) -> Fallible<Token> {
@@ -275,6 +536,9 @@ impl LineTokenizer {
                 _ => break,
             }
         }
+        if self.maybe_peek(0).map(|c| c.is_ascii_alphabetic()) == Some(true) {
+            return self.tokenize_duration(start, negative);
+        }
         let s = self.chars[start..self.offset].iter().collect::<String>();
         if contains_dot {
             return Ok(Token::FloatTerm(Float::new(
@@ -284,14 +548,35 @@ impl LineTokenizer {
         Ok(Token::IntegerTerm(negative * s.parse::<i64>()?))
     }
 
+    // A number directly followed by a unit, optionally repeated: 1h30m.
+    fn tokenize_duration(&mut self, start: usize, negative: i64) -> Fallible<Token> {
+        while !self.is_empty() {
+            match self.peek(0)? {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '.' => self.offset += 1,
+                _ => break,
+            }
+        }
+        let s = self.chars[start..self.offset].iter().collect::<String>();
+        let duration = Duration::parse(&s)?;
+        if negative < 0 {
+            return Ok(Token::DurationTerm(Duration::from_millis(
+                -duration.as_millis(),
+            )));
+        }
+        Ok(Token::DurationTerm(duration))
+    }
+
     fn tokenize_source(&mut self) -> Fallible<Token> {
         assert!(self.peek(0)? == '^');
         self.offset += 1;
         Ok(Token::Source(self.tokenize_identifier()?))
     }
 
-    fn tokenize_sink(&mut self) -> Fallible<Token> {
+    fn tokenize_sink_or_param(&mut self) -> Fallible<Token> {
         assert!(self.peek(0)? == '$');
+        if self.maybe_peek(1) == Some('{') {
+            return self.tokenize_path();
+        }
         self.offset += 1;
         Ok(Token::Sink(self.tokenize_identifier()?))
     }
@@ -309,13 +594,53 @@ impl LineTokenizer {
         Ok(Token::Divide)
     }
 
-    fn tokenize_use_template_or_not_eq(&mut self) -> Fallible<Token> {
-        if self.peek(1)? == '=' {
-            self.offset += 2;
-            return Ok(Token::NotEquals);
+    // In a script, ! is a logical not. Elsewhere, !name uses a template.
+    fn tokenize_use_template_or_not(&mut self) -> Fallible<Token> {
+        match self.maybe_peek(1) {
+            Some('=') => {
+                self.offset += 2;
+                return Ok(Token::NotEquals);
+            }
+            Some(c) if !self.in_script && (c.is_alphabetic() || c == '_') => {}
+            _ => {
+                self.offset += 1;
+                return Ok(Token::Not);
+            }
         }
         self.offset += 1;
-        Ok(Token::UseTemplate(self.tokenize_identifier()?))
+        let name = self.tokenize_identifier()?;
+        let mut args = Vec::new();
+        if self.maybe_peek(0) == Some('(') {
+            self.offset += 1;
+            while self.tokenize_list_continues(args.is_empty())? {
+                let param = self.tokenize_identifier()?;
+                self.skip_space();
+                ensure!(
+                    !param.is_empty() && self.peek(0)? == '=',
+                    "tokenize error: expected param=value in arguments to {}",
+                    name
+                );
+                self.offset += 1;
+                self.skip_space();
+                let value = self.tokenize_one()?;
+                match value {
+                    Token::PathTerm(_)
+                    | Token::NameTerm(_)
+                    | Token::StringTerm(_)
+                    | Token::IntegerTerm(_)
+                    | Token::FloatTerm(_)
+                    | Token::DurationTerm(_)
+                    | Token::BooleanTerm(_) => {}
+                    _ => bail!(
+                        "tokenize error: template argument {} to {} must be a path or value",
+                        param,
+                        name
+                    ),
+                }
+                args.push((param, value));
+            }
+        }
+        Ok(Token::UseTemplate(name, args))
     }
 
     fn tokenize_location(&mut self) -> Fallible<Token> {
@@ -347,9 +672,14 @@ impl LineTokenizer {
         Ok(Token::Size(Dimension2::from_str(&span)?))
     }
 
+    // Strings may embed expressions in braces: "bhs(254, {/hue}, 254)". These
+    // are emitted as a format string with {} in place of each expression,
+    // along with the tokens of each expression. Literal braces are doubled.
     fn tokenize_string(&mut self) -> Fallible<Token> {
         assert_eq!(self.peek(0)?, '"');
         let mut out = Vec::new();
+        let mut fmt = Vec::new();
+        let mut parts = Vec::new();
         self.offset += 1;
         while !self.is_empty() {
             match self.chars[self.offset] {
@@ -361,19 +691,79 @@ impl LineTokenizer {
 
                     // Skip the following quote.
                     out.push('"');
+                    fmt.push('"');
                     self.offset += 1;
                 }
                 '"' => {
                     self.offset += 1;
-                    return Ok(Token::StringTerm(out.iter().collect::<String>()));
+                    if parts.is_empty() {
+                        return Ok(Token::StringTerm(out.iter().collect::<String>()));
+                    }
+                    return Ok(Token::FormatTerm(fmt.iter().collect::<String>(), parts));
+                }
+                c @ '{' | c @ '}' if self.maybe_peek(1) == Some(c) => {
+                    out.push(c);
+                    fmt.extend([c, c]);
+                    self.offset += 1;
+                }
+                '{' => {
+                    parts.push(self.tokenize_interpolation()?);
+                    fmt.extend(['{', '}']);
+                    continue;
+                }
+                '}' => {
+                    bail!("tokenize error: unmatched } in string; use }} for a literal brace")
+                }
+                c => {
+                    out.push(c);
+                    fmt.push(c);
                 }
-                c => out.push(c),
             }
             self.offset += 1;
         }
         bail!("tokenize error: unmatched \"")
     }
 
+    // From an opening brace in a string, up to and including the matching brace.
+    fn tokenize_interpolation(&mut self) -> Fallible<Vec<Token>> {
+        assert_eq!(self.peek(0)?, '{');
+        let start = self.offset + 1;
+        let mut depth = 0;
+        let mut in_string = false;
+        while !self.is_empty() {
+            match self.chars[self.offset] {
+                '\\' if in_string => self.offset += 1,
+                '"' => in_string = !in_string,
+                '{' if !in_string => depth += 1,
+                '}' if !in_string => {
+                    depth -= 1;
+                    if depth == 0 {
+                        let mut inner = LineTokenizer {
+                            chars: self.chars[start..self.offset].to_vec(),
+                            offset: 0,
+                            in_script: true,
+                        };
+                        self.offset += 1;
+                        let mut tokens = Vec::new();
+                        inner.skip_space();
+                        while !inner.is_empty() {
+                            tokens.push(inner.tokenize_one()?);
+                            inner.skip_space();
+                        }
+                        ensure!(
+                            !tokens.is_empty(),
+                            "tokenize error: empty {} in string; use {{ for a literal brace"
+                        );
+                        return Ok(tokens);
+                    }
+                }
+                _ => {}
+            }
+            self.offset += 1;
+        }
+        bail!("tokenize error: unmatched { in string")
+    }
+
     fn tokenize_comes_from_or_less_than_or_size(&mut self) -> Fallible<Token> {
         match self.maybe_peek(1) {
             Some('-') => {
@@ -382,6 +772,7 @@ impl LineTokenizer {
                     return Ok(Token::ComesFromBlock);
                 }
                 self.offset += 2;
+                self.in_script = true;
                 Ok(Token::ComesFromInline)
             }
             Some('=') => {
@@ -402,9 +793,11 @@ impl LineTokenizer {
 
     fn tokenize_greater_than(&mut self) -> Fallible<Token> {
         assert!(self.peek(0)? == '>');
-        if self.peek(1)? == '=' {
+        if self.maybe_peek(1) == Some('=') {
+            self.offset += 2;
             return Ok(Token::GreaterThanOrEquals);
         }
+        self.offset += 1;
         Ok(Token::GreaterThan)
     }
 
@@ -430,7 +823,11 @@ impl LineTokenizer {
         let start = self.offset;
         while !self.is_empty() {
             match self.peek(0)? {
-                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '/' | '.' | '{' | '}' => {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '/' | '.' | '{' | '}' | '$' => {
+                    self.offset += 1
+                }
+                // A glob component; anywhere else this is a multiplication.
+                '*' if self.offset > start && matches!(self.chars[self.offset - 1], '/' | '*') => {
                     self.offset += 1
                 }
                 _ => break,
@@ -468,12 +865,43 @@ impl LineTokenizer {
 
     fn trim_comment(line_raw: &str) -> String {
         let mut line = line_raw.to_owned();
-        if let Some(offset) = line_raw.find('#') {
+        if let Some(offset) = Self::find_comment(line_raw) {
             line.truncate(offset);
         }
         line.trim_end().to_owned()
     }
 
+    // Where the comment on a line starts, if it has one. A # in a string is
+    // text, and strings can hold expressions in braces that hold strings.
+    fn find_comment(line: &str) -> Option<usize> {
+        // Each string or braced expression we are inside, innermost last.
+        let mut nesting = Vec::new();
+        let mut chars = line.char_indices().peekable();
+        while let Some((offset, c)) = chars.next() {
+            let in_string = nesting.last() == Some(&'"');
+            let in_braces = nesting.last() == Some(&'{');
+            match c {
+                '#' if !in_string => return Some(offset),
+                '\\' if in_string => {
+                    chars.next();
+                }
+                '"' if in_string => {
+                    nesting.pop();
+                }
+                '"' => nesting.push('"'),
+                '{' | '}' if in_string && chars.peek().map(|&(_, next)| next) == Some(c) => {
+                    chars.next();
+                }
+                '{' if in_string || in_braces => nesting.push('{'),
+                '}' if in_braces => {
+                    nesting.pop();
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
     fn leading_whitespace(s: &str) -> usize {
         let mut cnt = 0;
         for c in s.chars() {
@@ -488,7 +916,7 @@ impl LineTokenizer {
 
 #[cfg(test)]
 mod test {
-    use super::{Dimension2, Fallible, Float, Token, TreeTokenizer as TT};
+    use super::{has_comments, Dimension2, Duration, Fallible, Float, Token, TreeTokenizer as TT};
 
     #[test]
     fn test_tokenize_dedent1() {
@@ -572,6 +1000,74 @@ d";
         );
     }
 
+    #[test]
+    fn test_tokenize_string_braces() {
+        assert_eq!(
+            TT::tokenize(r#""{{a}}""#).unwrap(),
+            vec![Token::StringTerm("{a}".to_owned()), Token::Newline]
+        );
+        assert!(TT::tokenize(r#""a}""#).is_err());
+        assert!(TT::tokenize(r#""{a""#).is_err());
+        assert!(TT::tokenize(r#""{ }""#).is_err());
+    }
+
+    #[test]
+    fn test_tokenize_comments() {
+        assert_eq!(
+            TT::tokenize("a <- \"#{\"}}#\"}\" # \"comment\"").unwrap(),
+            vec![
+                Token::NameTerm("a".to_owned()),
+                Token::ComesFromInline,
+                Token::FormatTerm(
+                    "#{}".to_owned(),
+                    vec![vec![Token::StringTerm("}#".to_owned())]]
+                ),
+                Token::Newline
+            ]
+        );
+        assert!(has_comments("a\n    b <- 1 # one\n"));
+        assert!(has_comments("# a\n"));
+        assert!(!has_comments(
+            "a <- \"#\\\"#{\"#\"}\"\nb <- /palette/{./c}\n"
+        ));
+    }
+
+    #[test]
+    fn test_tokenize_string_interpolation() {
+        assert_eq!(
+            TT::tokenize(r#""bhs(254, {/meta/minute-tic * 1092}, 254)""#).unwrap(),
+            vec![
+                Token::FormatTerm(
+                    "bhs(254, {}, 254)".to_owned(),
+                    vec![vec![
+                        Token::PathTerm("/meta/minute-tic".to_owned()),
+                        Token::Multiply,
+                        Token::IntegerTerm(1092),
+                    ]]
+                ),
+                Token::Newline
+            ]
+        );
+        assert_eq!(
+            TT::tokenize(r#""{{{/a/{./b}}}}-{upper("x}}")}""#).unwrap(),
+            vec![
+                Token::FormatTerm(
+                    "{{{}}}-{}".to_owned(),
+                    vec![
+                        vec![Token::PathTerm("/a/{./b}".to_owned())],
+                        vec![
+                            Token::NameTerm("upper".to_owned()),
+                            Token::LeftParen,
+                            Token::StringTerm("x}".to_owned()),
+                            Token::RightParen,
+                        ],
+                    ]
+                ),
+                Token::Newline
+            ]
+        );
+    }
+
     #[test]
     fn test_tokenize_location() {
         assert_eq!(
@@ -614,7 +1110,27 @@ d";
     fn test_tokenize_use_template() {
         assert_eq!(
             TT::tokenize("!a-s-d-f").unwrap(),
-            vec![Token::UseTemplate("a-s-d-f".to_owned()), Token::Newline]
+            vec![
+                Token::UseTemplate("a-s-d-f".to_owned(), vec![]),
+                Token::Newline
+            ]
+        );
+        assert_eq!(
+            TT::tokenize("!light(palette=/palette/hue, mode = \"on\",level=2)").unwrap(),
+            vec![
+                Token::UseTemplate(
+                    "light".to_owned(),
+                    vec![
+                        (
+                            "palette".to_owned(),
+                            Token::PathTerm("/palette/hue".to_owned())
+                        ),
+                        ("mode".to_owned(), Token::StringTerm("on".to_owned())),
+                        ("level".to_owned(), Token::IntegerTerm(2)),
+                    ]
+                ),
+                Token::Newline
+            ]
         );
     }
 
@@ -627,9 +1143,52 @@ d";
     }
 
     #[test]
-    #[should_panic]
     fn test_tokenize_not() {
-        TT::tokenize("!").unwrap();
+        assert_eq!(TT::tokenize("!").unwrap(), vec![Token::Not, Token::Newline]);
+        assert_eq!(
+            TT::tokenize("a !t <- !/a !(b) !name !f(x) \"{!c}\"").unwrap(),
+            vec![
+                Token::NameTerm("a".to_owned()),
+                Token::UseTemplate("t".to_owned(), vec![]),
+                Token::ComesFromInline,
+                Token::Not,
+                Token::PathTerm("/a".to_owned()),
+                Token::Not,
+                Token::LeftParen,
+                Token::NameTerm("b".to_owned()),
+                Token::RightParen,
+                Token::Not,
+                Token::NameTerm("name".to_owned()),
+                Token::Not,
+                Token::NameTerm("f".to_owned()),
+                Token::LeftParen,
+                Token::NameTerm("x".to_owned()),
+                Token::RightParen,
+                Token::FormatTerm(
+                    "{}".to_owned(),
+                    vec![vec![Token::Not, Token::NameTerm("c".to_owned())]]
+                ),
+                Token::Newline
+            ]
+        );
+
+        // Block scripts run until the indent returns to the line that opened them.
+        assert_eq!(
+            TT::tokenize("a <-\\\n    !b\nc !d\n").unwrap(),
+            vec![
+                Token::NameTerm("a".to_owned()),
+                Token::ComesFromBlock,
+                Token::Newline,
+                Token::Indent,
+                Token::Not,
+                Token::NameTerm("b".to_owned()),
+                Token::Newline,
+                Token::Dedent,
+                Token::NameTerm("c".to_owned()),
+                Token::UseTemplate("d".to_owned(), vec![]),
+                Token::Newline
+            ]
+        );
     }
 
     #[test]
@@ -689,6 +1248,23 @@ d";
         );
     }
 
+    #[test]
+    fn test_tokenize_glob() {
+        assert_eq!(
+            TT::tokenize("/rooms/**/$hue").unwrap(),
+            vec![Token::PathTerm("/rooms/**/$hue".to_owned()), Token::Newline]
+        );
+        assert_eq!(
+            TT::tokenize("/a/*/b*2").unwrap(),
+            vec![
+                Token::PathTerm("/a/*/b".to_owned()),
+                Token::Multiply,
+                Token::IntegerTerm(2),
+                Token::Newline,
+            ]
+        );
+    }
+
     #[test]
     fn test_tokenize_add() {
         assert_eq!(
@@ -722,6 +1298,16 @@ d";
                 Token::Newline,
             ]
         );
+        assert_eq!(
+            TT::tokenize("-(1)").unwrap(),
+            vec![
+                Token::Subtract,
+                Token::LeftParen,
+                Token::IntegerTerm(1),
+                Token::RightParen,
+                Token::Newline,
+            ]
+        );
     }
 
     #[test]
@@ -761,6 +1347,63 @@ d";
         );
     }
 
+    #[test]
+    fn test_tokenize_duration() -> Fallible<()> {
+        assert_eq!(
+            TT::tokenize("5m")?,
+            vec![
+                Token::DurationTerm(Duration::from_millis(300_000)),
+                Token::Newline
+            ]
+        );
+        assert_eq!(
+            TT::tokenize("-1h30m")?,
+            vec![
+                Token::DurationTerm(Duration::from_millis(-5_400_000)),
+                Token::Newline
+            ]
+        );
+        assert_eq!(
+            TT::tokenize("/a>10m")?[1..],
+            [
+                Token::GreaterThan,
+                Token::DurationTerm(Duration::from_millis(600_000)),
+                Token::Newline
+            ]
+        );
+        assert!(TT::tokenize("5x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokenize_template() -> Fallible<()> {
+        assert_eq!(
+            TT::tokenize("template hue-light\n    $hue")?,
+            vec![
+                Token::Template("hue-light".to_owned(), vec![]),
+                Token::Newline,
+                Token::Indent,
+                Token::Sink("hue".to_owned()),
+                Token::Newline,
+            ]
+        );
+        assert_eq!(
+            TT::tokenize("template hue-light(palette, control)\n    <-${palette}/{${control}}")?,
+            vec![
+                Token::Template(
+                    "hue-light".to_owned(),
+                    vec!["palette".to_owned(), "control".to_owned()]
+                ),
+                Token::Newline,
+                Token::Indent,
+                Token::ComesFromInline,
+                Token::PathTerm("${palette}/{${control}}".to_owned()),
+                Token::Newline,
+            ]
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_tokenize_import() -> Fallible<()> {
         assert_eq!(
@@ -769,4 +1412,28 @@ d";
         );
         Ok(())
     }
+
+    #[test]
+    fn test_layout_round_trip() -> Fallible<()> {
+        for source in &[
+            "a @1mx2m <>6'x3' ^switch $hue <- -/a/b - -1 / 2.0 * !x",
+            r#"b <- "say \"hi\" {{to}} {name(/c, [1, 2.5])} now {/d * 2}""#,
+            r#"c <- fmt("{{}}", "{{x}}") :: 1h30m + 500ms - -2s"#,
+            "d <- sum(/rooms/**/$hue) >= 0 && (x != y || !(z <= 1))",
+            "template t(p, q)\n    !u(a=/x/y, b=\"s\", c=3) import(f.ygg)",
+            "e <-\\\n    let x = 1\n    if x > 0:\n        \"on\"\n    else:\n        match y:\n            _: 2\n",
+        ] {
+            let tokens = TT::tokenize(source)?;
+            let text = Token::layout(&tokens)
+                .into_iter()
+                .map(|(level, line)| format!("{}{}\n", "    ".repeat(level), line))
+                .collect::<String>();
+            assert_eq!(TT::tokenize(&text)?, tokens, "{}", text);
+        }
+        assert_eq!(
+            Token::layout(&TT::tokenize("f <- max(1, [2])\n")?),
+            vec![(0, "f <- max(1, [2])".to_owned())]
+        );
+        Ok(())
+    }
 }
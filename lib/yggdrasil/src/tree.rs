@@ -2,22 +2,25 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use crate::{
-    bif::{tostr::ToStr, NativeFunc},
+    bif::{add_builtins, NativeFunc},
     graph::Graph,
     parser::TreeParser,
     path::{ConcretePath, PathComponent, ScriptPath},
     physical::Dimension2,
     script::Script,
-    value::Value,
+    state::{Pending, Stateful},
+    time::Timestamp,
+    tokenizer::Token,
+    value::{Value, ValueType},
 };
-use failure::{bail, ensure, Fallible};
+use failure::{bail, ensure, format_err, Error, Fallible};
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet},
     default::Default,
     fs,
     path::Path,
     str::FromStr,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
 };
 use tracing::{error, trace, trace_span, warn};
 
@@ -31,6 +34,9 @@ pub struct TreeBuilder {
     // Handle an import of the given name by supplying a tree rather than
     // searching in the filesystem.
     import_interceptors: HashMap<String, Tree>,
+
+    // The type of the values that each kind of source produces, if known.
+    source_types: HashMap<String, ValueType>,
 }
 
 impl Default for TreeBuilder {
@@ -39,6 +45,7 @@ impl Default for TreeBuilder {
             nifs: HashMap::new(),
             add_builtin_nifs: true,
             import_interceptors: HashMap::new(),
+            source_types: HashMap::new(),
         }
     }
 }
@@ -54,56 +61,175 @@ impl TreeBuilder {
     }
 
     pub fn intercept_import(mut self, name: &str, content: &str) -> Fallible<TreeBuilder> {
-        let tree = Tree {
-            root: NodeRef::new(Node::new(ConcretePath::new_root())),
-            generation: 0,
-        };
-        let tree = TreeParser::from_str(tree, content, &self.nifs, &HashMap::new())?;
+        let tree = Tree::new_empty();
+        let tree = TreeParser::from_str(tree, name, content, &self.nifs, &HashMap::new())?;
         self.import_interceptors.insert(name.to_owned(), tree);
         Ok(self)
     }
 
+    /// Sources of this kind are assumed to produce values of the given type
+    /// when type checking the tree. Otherwise sources take the type of their
+    /// default, if they have one.
+    pub fn declare_source_type(
+        mut self,
+        kind: &str,
+        value_type: ValueType,
+    ) -> Fallible<TreeBuilder> {
+        self.source_types.insert(kind.to_owned(), value_type);
+        Ok(self)
+    }
+
     pub fn without_builtins(mut self) -> Fallible<TreeBuilder> {
         self.add_builtin_nifs = false;
         Ok(self)
     }
 
     pub fn empty() -> Tree {
-        Tree {
-            root: NodeRef::new(Node::new(ConcretePath::new_root())),
-            generation: 0,
-        }
+        Tree::new_empty()
     }
 
     pub fn build_from_file(self, path: &Path) -> Fallible<Tree> {
         let contents = fs::read_to_string(path)?;
-        self.build_from_str(&contents)
+        self.build(&path.to_string_lossy(), &contents)
+    }
+
+    pub fn build_from_str(self, s: &str) -> Fallible<Tree> {
+        self.build("<string>", s)
     }
 
-    pub fn build_from_str(mut self, s: &str) -> Fallible<Tree> {
+    // The name is used to report errors and to find imports.
+    fn build(mut self, name: &str, s: &str) -> Fallible<Tree> {
         if self.add_builtin_nifs {
-            self.nifs.insert("str".to_owned(), Box::new(ToStr));
+            add_builtins(&mut self.nifs);
         }
 
-        let tree = Tree {
-            root: NodeRef::new(Node::new(ConcretePath::new_root())),
-            generation: 0,
-        };
+        let mut tree = Tree::new_empty();
+        tree.source_types = self.source_types;
 
-        let tree = TreeParser::from_str(tree, s, &self.nifs, &self.import_interceptors)?
+        let tree = TreeParser::from_str(tree, name, s, &self.nifs, &self.import_interceptors)?
             .link_and_validate_inputs()?
+            .check_types()?
             .map_inputs_to_outputs()?;
 
         Ok(tree)
     }
 }
 
+/// How a reloaded tree differs from the one it replaced, so that the
+/// embedding can connect to new devices and let go of old ones. Paths are
+/// grouped by source or sink kind; a node whose kind changed is both removed
+/// under the old kind and added under the new. So is a node whose settings
+/// changed, so that the device is set up again.
+#[derive(Clone, Debug, Default)]
+pub struct TreeChanges {
+    pub added_sources: HashMap<String, Vec<ConcretePath>>,
+    pub removed_sources: HashMap<String, Vec<ConcretePath>>,
+    pub added_sinks: HashMap<String, Vec<ConcretePath>>,
+    pub removed_sinks: HashMap<String, Vec<ConcretePath>>,
+
+    /// Sinks whose value differs from the one last emitted by the old tree,
+    /// including every new sink.
+    pub updates: HashMap<String, Vec<(ConcretePath, Value)>>,
+}
+
+// Sources or sinks with their kinds.
+type Endpoints = Vec<(String, ConcretePath)>;
+
+impl TreeChanges {
+    // Record each endpoint that is in one list but not the other, or that is
+    // in both but with different settings.
+    fn diff(
+        (old_tree, old): (&Tree, &Endpoints),
+        (new_tree, new): (&Tree, &Endpoints),
+        added: &mut HashMap<String, Vec<ConcretePath>>,
+        removed: &mut HashMap<String, Vec<ConcretePath>>,
+    ) {
+        let changed = |(_, path): &&(String, ConcretePath)| {
+            old_tree.endpoint_settings(path) != new_tree.endpoint_settings(path)
+        };
+        for (kind, path) in new.iter().filter(|e| !old.contains(e) || changed(e)) {
+            added
+                .entry(kind.to_owned())
+                .or_default()
+                .push(path.to_owned());
+        }
+        for (kind, path) in old.iter().filter(|e| !new.contains(e) || changed(e)) {
+            removed
+                .entry(kind.to_owned())
+                .or_default()
+                .push(path.to_owned());
+        }
+    }
+}
+
 pub struct Tree {
     root: NodeRef,
     generation: usize,
+
+    // Script nodes that call impure functions. These are recomputed on every
+    // event, since we cannot know which events they depend on.
+    volatile: Vec<NodeRef>,
+
+    // Nodes that keep state, in path order. Each is updated when its trigger
+    // sees an event, or for timers, when its input changes or its deadline
+    // passes.
+    stateful: Vec<NodeRef>,
+
+    // The time as of the last tick. Timers measure their delays from this.
+    now: Timestamp,
+
+    // All sink nodes in the tree.
+    sinks: Vec<NodeRef>,
+
+    // The dataflow graph. This starts out with every input each script could
+    // possibly read and is narrowed to the inputs it actually read each time
+    // the script is computed, so that computed path lookups only depend on
+    // the node they currently resolve to.
+    graph: Mutex<Graph>,
+
+    // Each computation that is in progress, innermost last, with the nodes
+    // that it has read so far.
+    reads: Mutex<Vec<(NodeRef, Vec<NodeRef>)>>,
+
+    // Declared types of sources, by kind.
+    source_types: HashMap<String, ValueType>,
+
+    // Problems found while building the tree that do not stop it from running.
+    warnings: Mutex<Vec<String>>,
+
+    // Sinks that may have changed because nodes were added since the last
+    // event. These are emitted along with the sinks downstream of the next.
+    reshaped: Vec<NodeRef>,
 }
 
 impl Tree {
+    fn new_empty() -> Self {
+        Tree {
+            root: NodeRef::new(Node::new(ConcretePath::new_root())),
+            generation: 0,
+            volatile: Vec::new(),
+            stateful: Vec::new(),
+            now: Timestamp::from_unix_millis(0),
+            sinks: Vec::new(),
+            graph: Mutex::new(Graph::new_empty()),
+            reads: Mutex::new(Vec::new()),
+            source_types: HashMap::new(),
+            warnings: Mutex::new(Vec::new()),
+            reshaped: Vec::new(),
+        }
+    }
+
+    /// Problems found while building the tree that do not stop it from
+    /// running, such as sources that feed no sinks or unreachable match arms.
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.lock().unwrap().clone()
+    }
+
+    pub(crate) fn warn(&self, warning: String) {
+        warn!("{}", warning);
+        self.warnings.lock().unwrap().push(warning);
+    }
+
     pub fn handle_event(
         &mut self,
         path: &ConcretePath,
@@ -113,13 +239,334 @@ impl Tree {
         value.set_generation(self.generation);
 
         let source = self.lookup_path(path)?;
-        source.handle_event(value)?; // cache the value
-        let sink_nodes = source.get_sink_nodes_observing()?;
+        let previous = source.compute(self).ok();
+        source.handle_event(value.clone())?; // cache the value
+
+        // Stateful nodes downstream have their memos cleared with the rest,
+        // so that a sample is taken of the values as of this event.
+        let changed = self.invalidate_downstream(&[source.to_owned()]);
+        // The source has taken the event by now, so a stateful node that
+        // cannot must not keep the sinks from hearing about it.
+        for node in &self.stateful {
+            if let Err(e) = node.fire(self, &source, previous.as_ref(), &value) {
+                error!("failed to update {}: {}", node.path_str(), e);
+            }
+        }
+        self.observe_timers(&changed);
+        let mut sink_nodes = Self::sinks_among(&changed);
+        for node in self.reshaped.drain(..) {
+            if !sink_nodes.iter().any(|sink| sink.is(&node)) {
+                sink_nodes.push(node);
+            }
+        }
+        self.collect_sink_values(&sink_nodes, false)
+    }
+
+    /// The earliest time at which a timer will change, if any are waiting.
+    /// The embedding should call `tick` once this has passed.
+    pub fn next_deadline(&self) -> Option<Timestamp> {
+        self.stateful
+            .iter()
+            .filter_map(|node| node.pending().map(|p| p.deadline))
+            .min()
+    }
+
+    /// Move the tree's clock forward to now, letting every timer whose
+    /// deadline has passed take its new value. Returns the sinks that
+    /// changed, as with `handle_event`. Timers measure their delays from the
+    /// last tick, so this should be called at least as often as the
+    /// resolution wanted of them, and before the first event.
+    pub fn tick(
+        &mut self,
+        now: Timestamp,
+    ) -> Fallible<HashMap<String, Vec<(ConcretePath, Value)>>> {
+        self.now = self.now.max(now);
+        let due = self
+            .stateful
+            .iter()
+            .filter(|node| node.pending().is_some_and(|p| p.deadline <= self.now))
+            .cloned()
+            .collect::<Vec<_>>();
+        if due.is_empty() {
+            return Ok(HashMap::new());
+        }
+        self.generation += 1;
+        for node in &due {
+            node.expire(self.generation);
+        }
+        let changed = self.invalidate_downstream(&due);
+        self.observe_timers(&changed);
+        self.collect_sink_values(&Self::sinks_among(&changed), false)
+    }
+
+    // Let timers whose inputs may have changed look at them again.
+    fn observe_timers(&self, changed: &[NodeRef]) {
+        for node in &self.stateful {
+            if node.is_timer() && changed.iter().any(|n| n.is(node)) {
+                if let Err(e) = node.observe(self) {
+                    error!("failed to update timer {}: {}", node.path_str(), e);
+                }
+            }
+        }
+    }
+
+    fn sinks_among(nodes: &[NodeRef]) -> Vec<NodeRef> {
+        nodes
+            .iter()
+            .filter(|node| node.maybe_sink_kind().is_some())
+            .cloned()
+            .collect()
+    }
+
+    /// The last value of every source that has seen an event, in path order,
+    /// so that the embedding can save them across restarts.
+    pub fn source_values(&self) -> Vec<(ConcretePath, Value)> {
+        let mut values = Vec::new();
+        self.root().find_source_values(&mut values);
+        values.sort_by_key(|(path, _)| path.to_string());
+        values
+    }
+
+    /// Put back source values saved by `source_values`, as if their events
+    /// had just been replayed, but without computing anything. Values for
+    /// paths that are no longer sources, or that no longer have the declared
+    /// type of their source, are dropped; their paths are returned.
+    pub fn restore_source_values(
+        &mut self,
+        values: Vec<(ConcretePath, Value)>,
+    ) -> Vec<ConcretePath> {
+        let mut dropped = Vec::new();
+        let mut restored = Vec::new();
+        for (path, value) in values {
+            let source = match self.lookup_path(&path) {
+                Ok(source) => source,
+                Err(_) => {
+                    dropped.push(path);
+                    continue;
+                }
+            };
+            let declared = source
+                .maybe_source_kind()
+                .map(|kind| self.source_types.get(&kind).copied());
+            match declared {
+                Some(Some(declared)) if !declared.accepts(ValueType::of(&value.data)) => {
+                    dropped.push(path);
+                    continue;
+                }
+                None => {
+                    dropped.push(path);
+                    continue;
+                }
+                _ => {}
+            }
+            // Later events must still count as newer than what we restore.
+            self.generation = self.generation.max(value.generation());
+            source.0.write().unwrap().cache = Some(value);
+            restored.push(source);
+        }
+        self.invalidate_downstream(&restored);
+        dropped
+    }
+
+    /// Swap in a tree built from a changed configuration. Source values are
+    /// carried over to the same paths where they still fit, as with
+    /// `restore_source_values`, and sinks that kept their path and kind
+    /// remember what was last emitted to them, so that only real changes are
+    /// sent on. Stateful nodes start over. Sinks that cannot be computed yet
+    /// are left out of the updates, as they are for events.
+    pub fn reload(&mut self, next: Tree) -> Fallible<TreeChanges> {
+        let mut next = next;
+        let mut changes = TreeChanges::default();
+        let (old_sources, old_sinks) = self.endpoints();
+        let (new_sources, new_sinks) = next.endpoints();
+        TreeChanges::diff(
+            (self, &old_sources),
+            (&next, &new_sources),
+            &mut changes.added_sources,
+            &mut changes.removed_sources,
+        );
+        TreeChanges::diff(
+            (self, &old_sinks),
+            (&next, &new_sinks),
+            &mut changes.added_sinks,
+            &mut changes.removed_sinks,
+        );
+
+        for path in next.restore_source_values(self.source_values()) {
+            trace!("not carrying over the value of {}", path);
+        }
+        next.generation = next.generation.max(self.generation);
+        next.now = self.now;
+        for sink in &next.sinks {
+            if let Ok(old) = self.lookup_path(&sink.path()) {
+                if old.maybe_sink_kind() == sink.maybe_sink_kind() {
+                    sink.0.write().unwrap().emitted = old.0.read().unwrap().emitted.clone();
+                }
+            }
+        }
+        let sink_nodes = next.sinks.clone();
+        changes.updates = next.collect_sink_values(&sink_nodes, false)?;
+        *self = next;
+        Ok(changes)
+    }
+
+    // What a device is set up from: the sigils of a source or sink and
+    // everything below it, and the plain nodes next to it, like the host of
+    // a redstone device. The script of a sink is its value, not a setting.
+    fn endpoint_settings(&self, path: &ConcretePath) -> String {
+        let mut out = String::new();
+        if let Ok(node) = self.lookup_path(path) {
+            node.write_settings(false, &mut out);
+            for sibling in node
+                .child_at("..")
+                .map(|p| p.children())
+                .unwrap_or_default()
+            {
+                let plain = sibling.maybe_source_kind().is_none()
+                    && sibling.maybe_sink_kind().is_none()
+                    && sibling.child_names().is_empty();
+                if plain && sibling.path() != node.path() {
+                    sibling.write_settings(true, &mut out);
+                }
+            }
+        }
+        out
+    }
+
+    // Every source and sink, with its kind.
+    fn endpoints(&self) -> (Endpoints, Endpoints) {
+        let mut sources = Vec::new();
+        let mut sinks = Vec::new();
+        self.root().find_endpoints(&mut sources, &mut sinks);
+        (sources, sinks)
+    }
+
+    /// Add a source while the tree is running, for example when a new device
+    /// is found. Missing parents are created as plain nodes. Glob paths that
+    /// listed the children of a node we added to are recomputed, and pick up
+    /// the source once it has a value.
+    pub fn add_source(&mut self, path: &ConcretePath, kind: &str) -> Fallible<NodeRef> {
+        ensure!(
+            self.lookup_path(path).is_err(),
+            "runtime error: cannot add a source at {}; there is already a node there",
+            path
+        );
+        let mut node = self.root();
+        for name in &path.components {
+            node = match node.child_at(name) {
+                Some(child) => child,
+                None => {
+                    let changed = self.invalidate_downstream(&[node.to_owned()]);
+                    self.reshaped.extend(Self::sinks_among(&changed));
+                    node.add_child(name)?
+                }
+            };
+        }
+        node.set_source(kind)?;
+        Ok(node)
+    }
+
+    // Drop memoized values downstream of the given nodes and of all volatile
+    // nodes, returning every node that we found along the way. Those are the
+    // only nodes that may have changed.
+    fn invalidate_downstream(&self, sources: &[NodeRef]) -> Vec<NodeRef> {
+        let graph = self.graph.lock().unwrap();
+        let mut visited = HashSet::new();
+        let mut changed = Vec::new();
+        let mut pending = sources.to_vec();
+        pending.extend(self.volatile.iter().cloned());
+        while let Some(node) = pending.pop() {
+            if !visited.insert(node.path_str()) {
+                continue;
+            }
+            node.clear_memo();
+            pending.extend(graph.dependents_of(&node));
+            changed.push(node);
+        }
+        changed
+    }
+
+    // Computations note every node they read in the innermost frame.
+    fn record_read(&self, node: &NodeRef) {
+        if let Some((_, frame)) = self.reads.lock().unwrap().last_mut() {
+            frame.push(node.to_owned());
+        }
+    }
 
+    // Fails if the node is already being computed, listing the cycle of
+    // computations that led back to it.
+    fn begin_reads(&self, node: &NodeRef) -> Fallible<()> {
+        let mut reads = self.reads.lock().unwrap();
+        if let Some(start) = reads.iter().position(|(n, _)| n.is(node)) {
+            let mut cycle = reads[start..]
+                .iter()
+                .map(|(n, _)| n.path_str())
+                .collect::<Vec<_>>();
+            cycle.push(node.path_str());
+            bail!(
+                "runtime error: found a cycle between computed values, each reading the next: {}",
+                cycle.join(" -> ")
+            );
+        }
+        reads.push((node.to_owned(), Vec::new()));
+        Ok(())
+    }
+
+    fn end_reads(&self) -> Vec<NodeRef> {
+        self.reads
+            .lock()
+            .unwrap()
+            .pop()
+            .map(|(_, frame)| frame)
+            .unwrap_or_default()
+    }
+
+    // After a successful computation, the node's inputs are exactly what it
+    // read. A failed computation may not have reached all of its inputs, so
+    // we only add to what we knew before.
+    fn update_inputs(&self, node: &NodeRef, reads: &[NodeRef], complete: bool) {
+        let mut graph = self.graph.lock().unwrap();
+        if complete {
+            graph.set_inputs(node, reads);
+        } else {
+            for input in reads {
+                graph.add_node(input);
+                graph.add_edge(input, node);
+            }
+        }
+    }
+
+    /// Compute every sink in the tree, whether or not it has changed since it
+    /// was last emitted. Use this to bring sinks back in sync with the tree.
+    pub fn all_sink_values(&mut self) -> Fallible<HashMap<String, Vec<(ConcretePath, Value)>>> {
+        self.reshaped.clear();
+        let sink_nodes = self.sinks.clone();
+        self.collect_sink_values(&sink_nodes, true)
+    }
+
+    // Group sink values by sink kind. Unless forced, sinks whose value is the
+    // same as the one we last emitted for them are left out.
+    fn collect_sink_values(
+        &self,
+        sink_nodes: &[NodeRef],
+        force: bool,
+    ) -> Fallible<HashMap<String, Vec<(ConcretePath, Value)>>> {
         let mut groups = HashMap::new();
-        for node in &sink_nodes {
-            let next_value = node.compute(self)?;
+        for node in sink_nodes {
+            // A sink that fails to compute must not hold back the others. It
+            // keeps its last emitted value, so it is sent once it recovers.
+            let next_value = match node.compute(self) {
+                Ok(value) => value,
+                Err(e) => {
+                    error!("failed to compute sink {}: {}", node.path_str(), e);
+                    continue;
+                }
+            };
             let kind = node.sink_kind()?;
+            if !node.record_emitted(&next_value) && !force {
+                trace!("sink {} is unchanged", node.path_str());
+                continue;
+            }
             let value = (node.path(), next_value);
             match groups.entry(kind) {
                 Entry::Vacant(e) => {
@@ -151,6 +598,49 @@ impl Tree {
             .lookup_dynamic_path(gen, &path.components[0..], self)
     }
 
+    /// Find every node a glob path matches that has a value, in path order,
+    /// along with the generation of the lookups in the path. Every node we
+    /// pass through is read, so that the computation is redone if the nodes
+    /// under it change.
+    pub fn lookup_glob_path(
+        &self,
+        gen: usize,
+        path: &ScriptPath,
+    ) -> Fallible<(Vec<NodeRef>, usize)> {
+        let mut gen = gen;
+        let mut matches = vec![self.root()];
+        for component in &path.components {
+            let mut next = Vec::new();
+            for node in &matches {
+                self.record_read(node);
+                match component {
+                    PathComponent::Name(name) => next.extend(node.child_at(name)),
+                    PathComponent::Lookup(p) => {
+                        let (lookup, sub_gen) = self.lookup_dynamic_path(gen, p)?;
+                        let value = lookup.compute(self)?;
+                        gen = gen.max(sub_gen).max(value.generation());
+                        next.extend(node.child_at(&value.as_path_component()?));
+                    }
+                    PathComponent::Wildcard => next.extend(node.children()),
+                    PathComponent::Recursive => node.find_descendants(&mut next),
+                    PathComponent::Sink(kind) => next.extend(
+                        node.children()
+                            .into_iter()
+                            .filter(|child| child.maybe_sink_kind().as_ref() == Some(kind)),
+                    ),
+                }
+            }
+            matches = NodeRef::sorted_unique(next);
+        }
+
+        // Sources that have not seen an event yet are left out until they do.
+        for node in &matches {
+            self.record_read(node);
+        }
+        matches.retain(|node| node.has_value(self));
+        Ok((matches, gen))
+    }
+
     // After the tree has been built, visit all nodes looking up references and
     // storing those references directly in the inputs list per script.
     fn link_and_validate_inputs(self) -> Fallible<Tree> {
@@ -158,15 +648,65 @@ impl Tree {
         Ok(self)
     }
 
-    fn map_inputs_to_outputs(self) -> Fallible<Tree> {
+    // Infer the type of every node, so that scripts that can only fail are
+    // rejected before we start handling events.
+    fn check_types(self) -> Fallible<Tree> {
+        self.root.check_types(&self)?;
+        Ok(self)
+    }
+
+    fn map_inputs_to_outputs(mut self) -> Fallible<Tree> {
         let mut graph = Graph::new_empty();
+        let mut definite = Graph::new_empty();
         let mut sinks = Vec::new();
-        self.root().populate_flow_graph(&mut graph)?;
+        self.root()
+            .populate_flow_graph(&self, &mut graph, &mut definite)?;
+
+        // Computing any node on a cycle would recurse forever. This includes
+        // cycles through a latch: the latch picks the newer of its operands
+        // by generation, but it has to compute both of them to find out.
+        // Cycles that depend on how a path lookup resolves are caught when
+        // computing instead.
+        let cycles = definite.find_cycles();
+        if let Some(first) = cycles.first() {
+            let error = format_err!(
+                "dataflow error: found {} cycle(s) between computed values, each reading the next:\n    {}",
+                cycles.len(),
+                cycles
+                    .iter()
+                    .map(|cycle| cycle.iter().rev().cloned().collect::<Vec<_>>().join(" -> "))
+                    .collect::<Vec<_>>()
+                    .join("\n    ")
+            );
+            // Point at the first script along the cycle as it is printed.
+            for path in first.iter().rev() {
+                let node = self.lookup(path)?;
+                if node.has_script() {
+                    return Err(node.annotate(error));
+                }
+            }
+            return Err(error);
+        }
         self.root().find_all_sinks(&mut sinks)?;
-        self.root().flow_input_to_output(&sinks, &graph)?;
+        self.root().flow_input_to_output(&self, &sinks, &graph)?;
+        self.root().find_volatile(&mut self.volatile);
+        self.root().find_stateful(&mut self.stateful);
+        self.stateful = NodeRef::sorted_unique(self.stateful);
+        self.sinks = sinks;
+        self.graph = Mutex::new(graph);
         Ok(self)
     }
 
+    /// Write the tree back out as .ygg text, in a canonical layout: children
+    /// in the order they were written, sigils in a fixed order, and four
+    /// spaces per level. Templates and imports are written where they were
+    /// defined and used, rather than what they expand to. Comments are lost.
+    pub fn to_ygg_string(&self) -> String {
+        let mut out = String::new();
+        self.root().write_ygg(0, &mut out);
+        out
+    }
+
     pub fn find_sinks(&self, name: &str) -> Vec<ConcretePath> {
         let mut matching = Vec::new();
         self.root().find_sinks(name, &mut matching);
@@ -178,6 +718,17 @@ impl Tree {
         self.root().find_sources(name, &mut matching);
         matching
     }
+
+    /// Every sink in the tree and its kind, in path order.
+    pub fn sinks(&self) -> Vec<(ConcretePath, String)> {
+        let (_, sinks) = self.endpoints();
+        let mut sinks = sinks
+            .into_iter()
+            .map(|(kind, path)| (path, kind))
+            .collect::<Vec<_>>();
+        sinks.sort_by_key(|(path, _)| path.to_string());
+        sinks
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -233,12 +784,17 @@ impl NodeRef {
                     value.generation().max(sub_gen.max(gen)),
                 )
             }
+            glob => bail!(
+                "runtime error: {} matches many nodes; use lookup_glob_path @ {}",
+                glob,
+                self.path_str()
+            ),
         };
         if let Some(child) = self.child_at(&child_name) {
             if parts.len() == 1 {
                 return Ok((child, child_gen.max(gen)));
             }
-            return Ok(child.lookup_dynamic_path(child_gen.max(gen), &parts[1..], tree)?);
+            return child.lookup_dynamic_path(child_gen.max(gen), &parts[1..], tree);
         }
         bail!(format!(
             "invalid path: did not find path component '{}' @ {}",
@@ -261,6 +817,29 @@ impl NodeRef {
         }
     }
 
+    fn find_source_values(&self, values: &mut Vec<(ConcretePath, Value)>) {
+        if self.is_source() {
+            if let Some(ref value) = self.0.read().unwrap().cache {
+                values.push((self.path(), value.to_owned()));
+            }
+        }
+        for child in self.children() {
+            child.find_source_values(values);
+        }
+    }
+
+    fn find_endpoints(&self, sources: &mut Endpoints, sinks: &mut Endpoints) {
+        if let Some(kind) = self.maybe_source_kind() {
+            sources.push((kind, self.path()));
+        }
+        if let Some(kind) = self.maybe_sink_kind() {
+            sinks.push((kind, self.path()));
+        }
+        for child in self.children() {
+            child.find_endpoints(sources, sinks);
+        }
+    }
+
     fn find_sources(&self, source_name: &str, matching: &mut Vec<ConcretePath>) {
         if let Some(name) = self.maybe_source_kind() {
             if name == source_name {
@@ -303,6 +882,31 @@ impl NodeRef {
             .collect::<Vec<_>>()
     }
 
+    /// The children of this node, in name order.
+    pub fn children(&self) -> Vec<NodeRef> {
+        let mut names = self.child_names();
+        names.sort();
+        names
+            .iter()
+            .filter_map(|name| self.child_at(name))
+            .collect()
+    }
+
+    /// This node and every node below it.
+    pub fn find_descendants(&self, out: &mut Vec<NodeRef>) {
+        out.push(self.to_owned());
+        for child in self.children() {
+            child.find_descendants(out);
+        }
+    }
+
+    /// Sort nodes by path, dropping repeats.
+    pub(crate) fn sorted_unique(mut nodes: Vec<NodeRef>) -> Vec<NodeRef> {
+        nodes.sort_by_key(NodeRef::path_str);
+        nodes.dedup_by(|a, b| a.is(b));
+        nodes
+    }
+
     pub fn child(&self, name: &str) -> Fallible<NodeRef> {
         ensure!(
             self.0.read().unwrap().children.contains_key(name),
@@ -324,6 +928,22 @@ impl NodeRef {
         }
         self.0.write().unwrap().linked_and_validated = true;
 
+        if let Some(NodeInput::State(ref stateful)) = self.0.read().unwrap().input {
+            for input in stateful.inputs() {
+                tree.lookup_path(input).map_err(|e| stateful.annotate(e))?;
+            }
+            if let Some(trigger) = stateful.trigger() {
+                if !tree.lookup_path(trigger)?.is_source() {
+                    return Err(stateful.annotate(format_err!(
+                        "dataflow error: the trigger of {} at {} must be a source, but {} is not",
+                        stateful.kind(),
+                        path,
+                        trigger
+                    )));
+                }
+            }
+        }
+
         // Note: this pattern is a little funky! Normally we'd match to test
         // these conditions, but if we did that the borrow would last over the
         // body, which would disallow us from re-borrowing mutably inside.
@@ -368,6 +988,94 @@ impl NodeRef {
         Ok(())
     }
 
+    fn check_types(&self, tree: &Tree) -> Fallible<()> {
+        self.value_type(tree)?;
+        let mut children = self
+            .0
+            .read()
+            .unwrap()
+            .children
+            .iter()
+            .filter(|(name, _)| *name != "." && *name != "..")
+            .map(|(name, child)| (name.to_owned(), child.to_owned()))
+            .collect::<Vec<_>>();
+        children.sort_by(|a, b| a.0.cmp(&b.0));
+        for (_, child) in &children {
+            child.check_types(tree)?;
+        }
+        Ok(())
+    }
+
+    /// The type of the node's value, inferred when the tree is built.
+    pub(crate) fn value_type(&self, tree: &Tree) -> Fallible<ValueType> {
+        if let Some(value_type) = self.0.read().unwrap().value_type {
+            return Ok(value_type);
+        }
+
+        // A computed path may refer back to the node it is in, so assume
+        // nothing about a node while we are inferring it.
+        self.0.write().unwrap().value_type = Some(ValueType::Any);
+        let value_type = self.infer_type(tree)?;
+        self.0.write().unwrap().value_type = Some(value_type);
+        Ok(value_type)
+    }
+
+    /// Every value the node can take, if its script only ever yields
+    /// constants. Used to check that match blocks cover their inputs.
+    pub(crate) fn possible_values(
+        &self,
+        tree: &Tree,
+        visiting: &mut Vec<String>,
+    ) -> Fallible<Option<Vec<Value>>> {
+        let path = self.path_str();
+        if visiting.contains(&path) {
+            return Ok(None);
+        }
+        visiting.push(path);
+        let values = match self.0.read().unwrap().input {
+            Some(NodeInput::Script(ref script)) => script.possible_values(tree, visiting)?,
+            _ => None,
+        };
+        visiting.pop();
+        Ok(values)
+    }
+
+    fn infer_type(&self, tree: &Tree) -> Fallible<ValueType> {
+        match self.0.read().unwrap().input {
+            Some(NodeInput::Script(ref script)) => return script.value_type(tree),
+            Some(NodeInput::State(ref stateful)) => {
+                return stateful.value_type(tree).map_err(|e| stateful.annotate(e))
+            }
+            _ => {}
+        }
+        let kind = match self.maybe_source_kind() {
+            Some(kind) => kind,
+            None => return Ok(ValueType::Any),
+        };
+        let declared = tree.source_types.get(&kind).copied();
+        let default = match self.child_at("default") {
+            Some(default) => Some((default.value_type(tree)?, default)),
+            None => None,
+        };
+        Ok(match (declared, default) {
+            (Some(declared), Some((default_type, default))) => {
+                if !declared.accepts(default_type) {
+                    return Err(default.annotate(format_err!(
+                        "type error: the default of ^{} source {} must be {}, not {}",
+                        kind,
+                        self.path_str(),
+                        declared,
+                        default_type
+                    )));
+                }
+                declared
+            }
+            (Some(declared), None) => declared,
+            (None, Some((default_type, _))) => default_type,
+            (None, None) => ValueType::Any,
+        })
+    }
+
     fn find_all_sinks(&self, sinks: &mut Vec<NodeRef>) -> Fallible<()> {
         for (name, child) in &self.0.read().unwrap().children {
             if name == "." || name == ".." {
@@ -381,58 +1089,164 @@ impl NodeRef {
         Ok(())
     }
 
-    fn populate_flow_graph(&self, graph: &mut Graph) -> Fallible<()> {
+    fn populate_flow_graph(
+        &self,
+        tree: &Tree,
+        graph: &mut Graph,
+        definite: &mut Graph,
+    ) -> Fallible<()> {
         graph.add_node(self);
+        definite.add_node(self);
         for (name, child) in &self.0.read().unwrap().children {
             if name == "." || name == ".." {
                 continue;
             }
-            child.populate_flow_graph(graph)?;
+            child.populate_flow_graph(tree, graph, definite)?;
         }
 
-        if let Some(NodeInput::Script(ref script)) = self.0.read().unwrap().input {
-            script.populate_flow_graph(self, graph)?;
+        match self.0.read().unwrap().input {
+            Some(NodeInput::Script(ref script)) => {
+                script.populate_flow_graph(self, graph, definite)?
+            }
+            Some(NodeInput::State(ref stateful)) => {
+                for input in stateful.inputs() {
+                    let src_node = tree.lookup_path(input)?;
+                    graph.add_edge(&src_node, self);
+                    definite.add_edge(&src_node, self);
+                }
+            }
+            _ => {}
+        }
+
+        // A source that has not seen an event yet reads from its default.
+        if self.is_source() {
+            if let Some(default) = self.child_at("default") {
+                graph.add_edge(&default, self);
+                definite.add_edge(&default, self);
+            }
         }
 
         Ok(())
     }
 
-    fn flow_input_to_output(&self, sinks: &[NodeRef], graph: &Graph) -> Fallible<()> {
+    fn find_volatile(&self, volatile: &mut Vec<NodeRef>) {
+        for (name, child) in &self.0.read().unwrap().children {
+            if name == "." || name == ".." {
+                continue;
+            }
+            child.find_volatile(volatile);
+        }
+        if let Some(NodeInput::Script(ref script)) = self.0.read().unwrap().input {
+            if script.is_volatile() {
+                volatile.push(self.to_owned());
+            }
+        }
+    }
+
+    fn find_stateful(&self, stateful: &mut Vec<NodeRef>) {
         for (name, child) in &self.0.read().unwrap().children {
             if name == "." || name == ".." {
                 continue;
             }
-            child.flow_input_to_output(sinks, graph)?;
+            child.find_stateful(stateful);
         }
+        if let Some(NodeInput::State(_)) = self.0.read().unwrap().input {
+            stateful.push(self.to_owned());
+        }
+    }
 
-        let mut maybe_connected_sinks = None;
-        if let Some(NodeInput::Source(_, _)) = self.0.read().unwrap().input {
-            let connected = graph.connected_nodes(self, sinks)?;
-            if connected.is_empty() {
-                warn!(
-                    "dataflow warning: source at {} is not connected to any sinks",
-                    self.path_str()
-                );
+    // Update the state of a stateful node if the event is at its trigger.
+    fn fire(
+        &self,
+        tree: &Tree,
+        source: &NodeRef,
+        previous: Option<&Value>,
+        event: &Value,
+    ) -> Fallible<()> {
+        let next = match self.0.read().unwrap().input {
+            Some(NodeInput::State(ref stateful)) if stateful.trigger() == Some(&source.path()) => {
+                let state = self.0.read().unwrap().state.clone();
+                stateful
+                    .fire(state.as_ref(), previous, event, tree)
+                    .map_err(|e| stateful.annotate(e))?
             }
-            maybe_connected_sinks = Some(connected);
+            _ => return Ok(()),
         };
+        self.0.write().unwrap().state = next;
+        Ok(())
+    }
 
-        if let Some(mut connected_sinks) = maybe_connected_sinks {
-            if let Some(NodeInput::Source(_, ref mut sinks)) = self.0.write().unwrap().input {
-                assert!(
-                    sinks.is_empty(),
-                    "dataflow error: found connected sinks at {}, but sinks already set",
-                    self.path_str()
-                );
-                sinks.append(&mut connected_sinks);
-            } else {
-                panic!("expected source to not mutate")
+    fn is_timer(&self) -> bool {
+        match self.0.read().unwrap().input {
+            Some(NodeInput::State(ref stateful)) => stateful.kind().is_timer(),
+            _ => false,
+        }
+    }
+
+    fn pending(&self) -> Option<Pending> {
+        self.0.read().unwrap().pending.clone()
+    }
+
+    // A timer looks at its input after something upstream of it changed.
+    fn observe(&self, tree: &Tree) -> Fallible<()> {
+        let (mut state, mut pending) = {
+            let node = self.0.read().unwrap();
+            (node.state.clone(), node.pending.clone())
+        };
+        if let Some(NodeInput::State(ref stateful)) = self.0.read().unwrap().input {
+            stateful
+                .observe(&mut state, &mut pending, tree.now, tree)
+                .map_err(|e| stateful.annotate(e))?;
+        }
+        let mut node = self.0.write().unwrap();
+        node.state = state;
+        node.pending = pending;
+        Ok(())
+    }
+
+    // A timer whose deadline has passed takes the value it was waiting for.
+    fn expire(&self, generation: usize) {
+        let mut node = self.0.write().unwrap();
+        if let Some(pending) = node.pending.take() {
+            node.state = Some(pending.value.with_generation(generation));
+        }
+    }
+
+    fn clear_memo(&self) {
+        self.0.write().unwrap().memo = None;
+    }
+
+    fn flow_input_to_output(&self, tree: &Tree, sinks: &[NodeRef], graph: &Graph) -> Fallible<()> {
+        for (name, child) in &self.0.read().unwrap().children {
+            if name == "." || name == ".." {
+                continue;
             }
+            child.flow_input_to_output(tree, sinks, graph)?;
+        }
+
+        if self.is_source() && graph.connected_nodes(self, sinks)?.is_empty() {
+            tree.warn(format!(
+                "dataflow warning: source at {} is not connected to any sinks",
+                self.path_str()
+            ));
         }
 
         Ok(())
     }
 
+    // Pin a build error about this node to its script, if it has one.
+    fn annotate(&self, error: Error) -> Error {
+        match self.0.read().unwrap().input {
+            Some(NodeInput::Script(ref script)) => script.annotate(error),
+            Some(NodeInput::State(ref stateful)) => stateful.annotate(error),
+            _ => error,
+        }
+    }
+
+    pub(crate) fn has_input(&self) -> bool {
+        self.0.read().unwrap().input.is_some()
+    }
+
     fn has_script(&self) -> bool {
         if let Some(NodeInput::Script(_)) = self.0.read().unwrap().input {
             return true;
@@ -449,6 +1263,11 @@ impl NodeRef {
             .map(|v| v.to_owned())
     }
 
+    // True if both refer to the same node.
+    pub fn is(&self, other: &NodeRef) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
     pub fn path(&self) -> ConcretePath {
         self.0.read().unwrap().path.clone()
     }
@@ -496,7 +1315,7 @@ impl NodeRef {
             "parse error: input was set twice @ {}",
             self.0.read().unwrap().path
         );
-        self.0.write().unwrap().input = Some(NodeInput::Source(from.to_owned(), Vec::new()));
+        self.0.write().unwrap().input = Some(NodeInput::Source(from.to_owned()));
         Ok(())
     }
 
@@ -510,6 +1329,191 @@ impl NodeRef {
         Ok(())
     }
 
+    // What was written on this node and below it, with templates applied,
+    // leaving out the input of this node unless asked for.
+    fn write_settings(&self, with_input: bool, out: &mut String) {
+        {
+            let node = self.0.read().unwrap();
+            out.push_str(&format!(
+                "{} {:?} {:?} {:?}",
+                node.path, node.location, node.dimensions, node.sink
+            ));
+            match node.input.as_ref().filter(|_| with_input) {
+                Some(NodeInput::Source(kind)) => out.push_str(&format!(" ^{}", kind)),
+                Some(NodeInput::Script(script)) => {
+                    out.push_str(&format!(" <- {:?}", script.source()))
+                }
+                Some(NodeInput::State(stateful)) => {
+                    out.push_str(&format!(" <- {:?}", stateful.source()))
+                }
+                None => {}
+            }
+            out.push('\n');
+        }
+        for child in self.children() {
+            child.write_settings(true, out);
+        }
+    }
+
+    // Write the body of this node, with children at the given depth. The
+    // node's own line has already been written by its parent.
+    fn write_ygg(&self, depth: usize, out: &mut String) {
+        let indent = "    ".repeat(depth);
+        let (body, mut written) = {
+            let node = self.0.read().unwrap();
+            let templated = node
+                .template_uses
+                .iter()
+                .flat_map(|template_use| template_use.children.iter().cloned())
+                .collect::<Vec<_>>();
+            (node.body.clone(), templated)
+        };
+        for entry in &body {
+            match entry {
+                BodyEntry::Child(name) => {
+                    if let Some(child) = self.child_at(name) {
+                        child.write_ygg_line(depth, out);
+                    }
+                    written.push(name.to_owned());
+                }
+                BodyEntry::Import(filename, children) => {
+                    out.push_str(&format!("{}import({})\n", indent, filename));
+                    written.extend(children.iter().cloned());
+                }
+                BodyEntry::Template(token, template_body) => {
+                    out.push_str(&format!("{}{}\n", indent, token));
+                    for (level, text) in Token::layout(template_body) {
+                        out.push_str(&format!("{}{}\n", "    ".repeat(depth + 1 + level), text));
+                    }
+                }
+            }
+        }
+
+        // Children that were not parsed, like sources added at runtime.
+        for child in self.children() {
+            if !written.contains(&child.name()) {
+                child.write_ygg_line(depth, out);
+            }
+        }
+    }
+
+    // The name line of this node with its inline sigils, then its block.
+    fn write_ygg_line(&self, depth: usize, out: &mut String) {
+        let mut line = "    ".repeat(depth);
+        let mut block = Vec::new();
+        {
+            let node = self.0.read().unwrap();
+            // Sigils set by a template are written as the use of the template.
+            let templated = node
+                .template_uses
+                .iter()
+                .fold(Sigils::default(), |sigils, template_use| {
+                    sigils.union(template_use.sigils)
+                });
+            line.push_str(&node.name);
+            if let Some(location) = node.location.as_ref().filter(|_| !templated.location) {
+                line.push_str(&format!(" @{}", location));
+            }
+            if let Some(dimensions) = node.dimensions.as_ref().filter(|_| !templated.dimensions) {
+                line.push_str(&format!(" <>{}", dimensions));
+            }
+            let input = node.input.as_ref().filter(|_| !templated.input);
+            if let Some(NodeInput::Source(kind)) = input {
+                line.push_str(&format!(" ^{}", kind));
+            }
+            if let Some(kind) = node.sink.as_ref().filter(|_| !templated.sink) {
+                line.push_str(&format!(" ${}", kind));
+            }
+            for template_use in &node.template_uses {
+                line.push_str(&format!(" {}", template_use.token));
+            }
+            let source = match input {
+                Some(NodeInput::Script(script)) if script.is_block() => {
+                    block = Token::layout(script.source());
+                    &[][..]
+                }
+                Some(NodeInput::Script(script)) => script.source(),
+                Some(NodeInput::State(stateful)) => stateful.source(),
+                _ => &[][..],
+            };
+            if !source.is_empty() {
+                let text = Token::layout(source)
+                    .into_iter()
+                    .map(|(_, text)| text)
+                    .collect::<Vec<_>>();
+                line.push_str(&format!(" <- {}", text.join(" ")));
+            }
+        }
+
+        // A block script goes on the name line, unless there is more to
+        // the block that would have to follow it.
+        let mut rest = String::new();
+        self.write_ygg(depth + 1, &mut rest);
+        let mut script_depth = depth + 1;
+        if !block.is_empty() {
+            if rest.is_empty() {
+                line.push_str(" <-\\");
+            } else {
+                out.push_str(&line);
+                out.push('\n');
+                line = format!("{}<-\\", "    ".repeat(depth + 1));
+                script_depth += 1;
+            }
+        }
+        out.push_str(&line);
+        out.push('\n');
+        for (level, text) in block {
+            out.push_str(&format!(
+                "{}{}\n",
+                "    ".repeat(script_depth + level),
+                text
+            ));
+        }
+        out.push_str(&rest);
+    }
+
+    pub(crate) fn record_child(&self, name: &str) {
+        self.0
+            .write()
+            .unwrap()
+            .body
+            .push(BodyEntry::Child(name.to_owned()));
+    }
+
+    pub(crate) fn record_import(&self, filename: &str, children: Vec<String>) {
+        self.0
+            .write()
+            .unwrap()
+            .body
+            .push(BodyEntry::Import(filename.to_owned(), children));
+    }
+
+    pub(crate) fn record_template(&self, token: Token, body: Vec<Token>) {
+        self.0
+            .write()
+            .unwrap()
+            .body
+            .push(BodyEntry::Template(token, body));
+    }
+
+    pub(crate) fn record_template_use(&self, token: Token, children: Vec<String>, sigils: Sigils) {
+        self.0.write().unwrap().template_uses.push(TemplateUse {
+            token,
+            children,
+            sigils,
+        });
+    }
+
+    pub(crate) fn sigils(&self) -> Sigils {
+        let node = self.0.read().unwrap();
+        Sigils {
+            location: node.location.is_some(),
+            dimensions: node.dimensions.is_some(),
+            input: node.input.is_some(),
+            sink: node.sink.is_some(),
+        }
+    }
+
     pub fn insert_subtree(&self, subtree: &NodeRef) -> Fallible<()> {
         for (name, child) in &subtree.0.read().unwrap().children {
             self.0
@@ -521,25 +1525,15 @@ impl NodeRef {
         Ok(())
     }
 
-    pub fn apply_template(&self, template: &NodeRef) -> Fallible<()> {
-        // FIXME: -> copy children... probably needs to be lexical?
-
-        // Simple sigils.
-        // location: Option<Dimension2>,
-        // dimensions: Option<Dimension2>,
-
-        // Input data binding can either be an external system or a computed value
-        // pulling inputs from external systems and other computed values. Or
-        // nothing; it's fine for a node to just be structural.
-        // input: Option<NodeInput>,
-        // _cache: Option<Sample>,
-
-        // Optional output data binding.
-        // sink: Option<(String, SinkRef)>,
-
-        if let Some(dim) = template.location() {
-            self.set_location(dim)?;
-        }
+    pub fn set_state(&self, stateful: Stateful) -> Fallible<()> {
+        ensure!(
+            self.0.read().unwrap().input.is_none(),
+            "parse error: input was set twice at {}",
+            self.0.read().unwrap().path
+        );
+        let mut node = self.0.write().unwrap();
+        node.state = stateful.initial();
+        node.input = Some(NodeInput::State(stateful));
         Ok(())
     }
 
@@ -557,21 +1551,58 @@ impl NodeRef {
         let span = trace_span!("compute", "{}", self.path_str());
         let _ = span.enter();
 
-        // FIXME: we need to make this entire path mut, so that we can write back the
-        // FIXME: computed entries as we compute them. For now, we'll be re-computing
-        // FIXME: intermediate nodes. Note: The cache *is* populated for source nodes
-        // FIXME: by handle_event, which is mut.
+        // The cache is populated for source nodes by handle_event. Script
+        // nodes memoize their value until an upstream event invalidates it.
+        tree.record_read(self);
         if let Some(ref cached_value) = self.0.read().unwrap().cache {
             assert!(self.is_source());
             return Ok(cached_value.to_owned());
         }
+        if let Some(ref memo) = self.0.read().unwrap().memo {
+            return Ok(memo.to_owned());
+        }
+        if let Some(ref state) = self.0.read().unwrap().state {
+            return Ok(state.to_owned());
+        }
+
+        tree.begin_reads(self)?;
+        let result = self.compute_input(tree);
+        let reads = tree.end_reads();
+        tree.update_inputs(self, &reads, result.is_ok());
+
+        let value = result?;
+        if self.has_script() {
+            self.0.write().unwrap().memo = Some(value.clone());
+        }
+        if self.is_timer() {
+            self.0.write().unwrap().state = Some(value.clone());
+        }
+        Ok(value)
+    }
 
+    fn compute_input(&self, tree: &Tree) -> Fallible<Value> {
         let path = self.path_str();
         trace!("computing @ {}", path);
         match self.0.read().unwrap().input {
             None => bail!("runtime error: computing a non-input path @ {}", path),
             Some(NodeInput::Script(ref script)) => script.compute(tree),
-            Some(NodeInput::Source(_, _)) => match tree.lookup_path(&(self.path() / "default")) {
+            // A sample and hold that has not sampled yet passes its input
+            // through. It still depends on its trigger. Timers start out
+            // with the first value of their input.
+            Some(NodeInput::State(ref stateful)) => {
+                if let Some(trigger) = stateful.trigger() {
+                    tree.record_read(&tree.lookup_path(trigger)?);
+                }
+                match stateful.input() {
+                    Some(input) => tree.lookup_path(input)?.compute(tree),
+                    None => bail!(
+                        "runtime error: {} at {} has no value",
+                        stateful.kind(),
+                        path
+                    ),
+                }
+            }
+            Some(NodeInput::Source(_)) => match tree.lookup_path(&(self.path() / "default")) {
                 Ok(default_node) => default_node.compute(tree),
                 Err(_) => {
                     error!("source '{}' not ready and no default set", self.path_str());
@@ -581,14 +1612,15 @@ impl NodeRef {
         }
     }
 
-    pub fn get_sink_nodes_observing(&self) -> Fallible<Vec<NodeRef>> {
-        if let Some(NodeInput::Source(_, ref sinks)) = self.0.read().unwrap().input {
-            return Ok(sinks.to_owned());
+    // Remember the value last sent to this sink. Returns false if it is the
+    // same as the previous value.
+    fn record_emitted(&self, value: &Value) -> bool {
+        let mut node = self.0.write().unwrap();
+        if node.emitted.as_ref() == Some(value) {
+            return false;
         }
-        bail!(
-            "runtime: invalid event; occurred on node {} with no source",
-            self.path_str()
-        )
+        node.emitted = Some(value.to_owned());
+        true
     }
 
     pub fn sink_kind(&self) -> Fallible<String> {
@@ -608,15 +1640,27 @@ impl NodeRef {
         None
     }
 
+    // A source has a value once it has seen an event or if it has a default.
+    fn has_value(&self, tree: &Tree) -> bool {
+        match self.0.read().unwrap().input {
+            None => false,
+            Some(NodeInput::Source(_)) => {
+                self.0.read().unwrap().cache.is_some()
+                    || tree.lookup_path(&(self.path() / "default")).is_ok()
+            }
+            Some(NodeInput::Script(_)) | Some(NodeInput::State(_)) => true,
+        }
+    }
+
     pub fn is_source(&self) -> bool {
-        if let Some(NodeInput::Source(_, _)) = self.0.read().unwrap().input {
+        if let Some(NodeInput::Source(_)) = self.0.read().unwrap().input {
             return true;
         }
         false
     }
 
     pub fn maybe_source_kind(&self) -> Option<String> {
-        if let Some(NodeInput::Source(ref kind, _)) = self.0.read().unwrap().input {
+        if let Some(NodeInput::Source(ref kind)) = self.0.read().unwrap().input {
             return Some(kind.to_owned());
         }
         None
@@ -625,8 +1669,58 @@ impl NodeRef {
 
 #[derive(Debug)]
 enum NodeInput {
-    Source(String, Vec<NodeRef>),
+    Source(String),
     Script(Script),
+    State(Stateful),
+}
+
+// Something written in the body of a node, kept in source order so that the
+// tree can be written back out the way it was written.
+#[derive(Clone, Debug)]
+enum BodyEntry {
+    Child(String),
+    // The file imported and the children that it added.
+    Import(String, Vec<String>),
+    // The template token and the body of the template.
+    Template(Token, Vec<Token>),
+}
+
+// A template applied to a node, with what it added there.
+#[derive(Clone, Debug)]
+struct TemplateUse {
+    token: Token,
+    children: Vec<String>,
+    sigils: Sigils,
+}
+
+/// Which of the sigils of a node are set.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Sigils {
+    location: bool,
+    dimensions: bool,
+    input: bool,
+    sink: bool,
+}
+
+impl Sigils {
+    /// The sigils that are set here, but were not set before.
+    pub(crate) fn since(self, before: Sigils) -> Sigils {
+        Sigils {
+            location: self.location && !before.location,
+            dimensions: self.dimensions && !before.dimensions,
+            input: self.input && !before.input,
+            sink: self.sink && !before.sink,
+        }
+    }
+
+    fn union(self, other: Sigils) -> Sigils {
+        Sigils {
+            location: self.location || other.location,
+            dimensions: self.dimensions || other.dimensions,
+            input: self.input || other.input,
+            sink: self.sink || other.sink,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -647,8 +1741,26 @@ pub struct Node {
     input: Option<NodeInput>,
     cache: Option<Value>,
 
-    // Optional output data binding.
+    // The state of a stateful node, once it has one, and the change that a
+    // timer is waiting to make.
+    state: Option<Value>,
+    pending: Option<Pending>,
+
+    // The last computed value of a script node. Events clear this on every
+    // node downstream of them in the dataflow graph.
+    memo: Option<Value>,
+
+    // The type of the value, once inferred.
+    value_type: Option<ValueType>,
+
+    // Optional output data binding, and the last value we emitted to it.
     sink: Option<String>,
+    emitted: Option<Value>,
+
+    // What was written in the body of this node, and the templates applied
+    // to it, for writing the tree back out.
+    body: Vec<BodyEntry>,
+    template_uses: Vec<TemplateUse>,
 }
 
 impl Node {
@@ -662,7 +1774,14 @@ impl Node {
             dimensions: None,
             input: None,
             cache: None,
+            state: None,
+            pending: None,
+            memo: None,
+            value_type: None,
             sink: None,
+            emitted: None,
+            body: Vec::new(),
+            template_uses: Vec::new(),
         }
     }
 
@@ -676,6 +1795,35 @@ impl Node {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bif::Arity;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Passes through its argument, counting how often it is called.
+    #[derive(Clone, Debug)]
+    struct Count {
+        calls: Arc<AtomicUsize>,
+        pure: bool,
+    }
+
+    impl NativeFunc for Count {
+        fn arity(&self) -> Arity {
+            Arity::Exactly(1)
+        }
+
+        fn compute(&self, args: &[Value], _tree: &Tree) -> Fallible<Value> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(args[0].to_owned())
+        }
+
+        fn box_clone(&self) -> Box<dyn NativeFunc + Send + Sync> {
+            Box::new((*self).clone())
+        }
+
+        fn is_pure(&self) -> bool {
+            self.pure
+        }
+    }
 
     #[test]
     fn test_build_tree() -> Fallible<()> {
@@ -735,6 +1883,24 @@ bar
         Ok(())
     }
 
+    #[test]
+    fn test_tree_sinks() -> Fallible<()> {
+        let s = r#"
+b $text <- "b"
+a ^switch
+    c $hue <- 1
+    d $text <- "d"
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        let sinks = tree
+            .sinks()
+            .into_iter()
+            .map(|(path, kind)| format!("{} {}", path, kind))
+            .collect::<Vec<_>>();
+        assert_eq!(sinks, vec!["/a/c hue", "/a/d text", "/b text"]);
+        Ok(())
+    }
+
     #[test]
     fn test_tree_import_str() -> Fallible<()> {
         let test_ygg = r#"
@@ -777,4 +1943,1126 @@ foo <- /mnt/a/b/c
         );
         Ok(())
     }
+
+    #[test]
+    fn test_tree_memoize_shared_inputs() -> Fallible<()> {
+        let s = r#"
+a ^src
+    default <- 1
+b ^src
+    default <- "x"
+shared <- count(/a) * 10
+x $sink <- /shared + 1
+y $sink <- /shared + 2
+z $sink <- /b
+"#;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let count = Count {
+            calls: calls.clone(),
+            pure: true,
+        };
+        let mut tree = TreeBuilder::default()
+            .add_native_function("count", Box::new(count))?
+            .build_from_str(s)?;
+
+        let updates = tree.handle_event(&ConcretePath::from_str("/a")?, Value::from_integer(2))?;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(updates["sink"].len(), 2);
+        for (path, value) in &updates["sink"] {
+            let expect = if path.to_string() == "/x" { 21 } else { 22 };
+            assert_eq!(value, &Value::from_integer(expect));
+        }
+
+        // Unrelated events do not recompute the shared node.
+        let updates = tree.handle_event(&ConcretePath::from_str("/b")?, Value::new_str("y"))?;
+        assert_eq!(
+            updates["sink"],
+            vec![(ConcretePath::from_str("/z")?, Value::new_str("y"))]
+        );
+        assert_eq!(tree.lookup("/x")?.compute(&tree)?, Value::from_integer(21));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // But a new value on its input does.
+        tree.handle_event(&ConcretePath::from_str("/a")?, Value::from_integer(3))?;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(tree.lookup("/y")?.compute(&tree)?, Value::from_integer(32));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_memoize_invalidates_defaults() -> Fallible<()> {
+        let s = r#"
+a ^src
+    default <- /b
+b ^src
+    default <- 0
+c $sink <- /a + 1
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(tree.lookup("/c")?.compute(&tree)?, Value::from_integer(1));
+        let updates = tree.handle_event(&ConcretePath::from_str("/b")?, Value::from_integer(5))?;
+        assert_eq!(updates["sink"][0].1, Value::from_integer(6));
+        tree.handle_event(&ConcretePath::from_str("/a")?, Value::from_integer(1))?;
+        assert_eq!(tree.lookup("/c")?.compute(&tree)?, Value::from_integer(2));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_memoize_dynamic_lookup() -> Fallible<()> {
+        let s = r#"
+room ^room
+    default <- "bed"
+level ^level
+    default <- 1
+rooms
+    bed
+        v <- /level * 10
+    den
+        v <- /level * 20
+out $sink <- /rooms/{/room}/v
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(
+            tree.lookup("/out")?.compute(&tree)?,
+            Value::from_integer(10)
+        );
+        let updates =
+            tree.handle_event(&ConcretePath::from_str("/room")?, Value::new_str("den"))?;
+        assert_eq!(updates["sink"][0].1, Value::from_integer(20));
+        let updates =
+            tree.handle_event(&ConcretePath::from_str("/level")?, Value::from_integer(2))?;
+        assert_eq!(updates["sink"][0].1, Value::from_integer(40));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_impure_recomputed_per_event() -> Fallible<()> {
+        let s = r#"
+a ^src
+    default <- 1
+b ^src
+    default <- 1
+c $sink <- /a + 1
+d $sink <- count(/a) + /b
+"#;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let count = Count {
+            calls: calls.clone(),
+            pure: false,
+        };
+        let mut tree = TreeBuilder::default()
+            .add_native_function("count", Box::new(count))?
+            .build_from_str(s)?;
+        tree.handle_event(&ConcretePath::from_str("/b")?, Value::from_integer(2))?;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        tree.handle_event(&ConcretePath::from_str("/b")?, Value::from_integer(3))?;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_emit_only_changed_sinks() -> Fallible<()> {
+        let s = r#"
+level ^level
+    default <- 0
+on $light <- /level > 50
+dim $light <- /level * 2
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let level = ConcretePath::from_str("/level")?;
+        let updates = tree.handle_event(&level, Value::from_integer(10))?;
+        assert_eq!(updates["light"].len(), 2);
+
+        let updates = tree.handle_event(&level, Value::from_integer(20))?;
+        assert_eq!(
+            updates["light"],
+            vec![(ConcretePath::from_str("/dim")?, Value::from_integer(40))]
+        );
+
+        let updates = tree.handle_event(&level, Value::from_integer(20))?;
+        assert!(updates.is_empty());
+
+        let updates = tree.all_sink_values()?;
+        assert_eq!(updates["light"].len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_emit_despite_failing_sink() -> Fallible<()> {
+        let s = r#"
+level ^level
+    default <- 0
+dim $light <- /level * 2
+huge $light <- 9223372036854775800 + /level
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let level = ConcretePath::from_str("/level")?;
+        let dim = ConcretePath::from_str("/dim")?;
+        let huge = ConcretePath::from_str("/huge")?;
+        let updates = tree.handle_event(&level, Value::from_integer(10))?;
+        assert_eq!(
+            updates["light"],
+            vec![(dim.clone(), Value::from_integer(20))]
+        );
+
+        let updates = tree.all_sink_values()?;
+        assert_eq!(
+            updates["light"],
+            vec![(dim.clone(), Value::from_integer(20))]
+        );
+
+        let mut updates = tree.handle_event(&level, Value::from_integer(1))?;
+        updates
+            .get_mut("light")
+            .unwrap()
+            .sort_by_key(|(path, _)| path.to_string());
+        assert_eq!(
+            updates["light"],
+            vec![
+                (dim, Value::from_integer(2)),
+                (huge, Value::from_integer(9223372036854775801)),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_dynamic_dependencies() -> Fallible<()> {
+        let s = r#"
+mode ^mode
+    default <- "day"
+palette
+    day ^day
+        default <- 1
+    night ^night
+        default <- 2
+light $sink <- count(/palette/{/mode}) * 10
+"#;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let count = Count {
+            calls: calls.clone(),
+            pure: true,
+        };
+        let mut tree = TreeBuilder::default()
+            .add_native_function("count", Box::new(count))?
+            .build_from_str(s)?;
+        let updates = tree.all_sink_values()?;
+        assert_eq!(updates["sink"][0].1, Value::from_integer(10));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // The light only reads the palette entry that the mode points at.
+        let night = ConcretePath::from_str("/palette/night")?;
+        let updates = tree.handle_event(&night, Value::from_integer(3))?;
+        assert!(updates.is_empty());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Until the mode re-targets the lookup.
+        let updates =
+            tree.handle_event(&ConcretePath::from_str("/mode")?, Value::new_str("night"))?;
+        assert_eq!(updates["sink"][0].1, Value::from_integer(30));
+        let updates = tree.handle_event(
+            &ConcretePath::from_str("/palette/day")?,
+            Value::from_integer(4),
+        )?;
+        assert!(updates.is_empty());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        let updates = tree.handle_event(&night, Value::from_integer(5))?;
+        assert_eq!(updates["sink"][0].1, Value::from_integer(50));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_glob_paths() -> Fallible<()> {
+        let s = r#"
+rooms
+    bed
+        on ^switch
+            default <- false
+        lamp $hue <- 10
+    kitchen
+        on ^switch
+            default <- false
+        shelf
+            lamp $hue <- 20
+        fan $power <- 1
+hall $light <- any(/rooms/*/on)
+lit $count <- count(/rooms/*/on)
+hues $list <- /rooms/**/$hue
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(tree.lookup("/hues")?.value_type(&tree)?, ValueType::List);
+        let updates = tree.all_sink_values()?;
+        assert_eq!(updates["light"][0].1, Value::from_boolean(false));
+        assert_eq!(
+            updates["list"][0].1,
+            Value::from_list(vec![Value::from_integer(10), Value::from_integer(20)])
+        );
+
+        let kitchen = ConcretePath::from_str("/rooms/kitchen/on")?;
+        let updates = tree.handle_event(&kitchen, Value::from_boolean(true))?;
+        assert_eq!(updates["light"][0].1, Value::from_boolean(true));
+        assert_eq!(updates["count"][0].1, Value::from_integer(1));
+        let updates = tree.handle_event(&kitchen, Value::from_boolean(false))?;
+        assert_eq!(updates["light"][0].1, Value::from_boolean(false));
+
+        // A room added at runtime is matched once its switch reports.
+        let attic = ConcretePath::from_str("/rooms/attic/on")?;
+        tree.add_source(&attic, "switch")?;
+        assert!(tree.add_source(&attic, "switch").is_err());
+        let updates = tree.handle_event(&attic, Value::from_boolean(true))?;
+        assert_eq!(updates["light"][0].1, Value::from_boolean(true));
+        assert_eq!(updates["count"][0].1, Value::from_integer(1));
+        let updates = tree.handle_event(&attic, Value::from_boolean(false))?;
+        assert_eq!(updates["light"][0].1, Value::from_boolean(false));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_restore_source_values() -> Fallible<()> {
+        let s = r#"
+level ^clock
+    default <- 1
+mode ^mode
+    default <- "day"
+doubled $level <- /level * 2
+"#;
+        let build = || {
+            TreeBuilder::default()
+                .declare_source_type("clock", ValueType::Integer)?
+                .build_from_str(s)
+        };
+        let mut tree = build()?;
+        assert!(tree.source_values().is_empty());
+        let level = ConcretePath::from_str("/level")?;
+        let mode = ConcretePath::from_str("/mode")?;
+        tree.handle_event(&mode, Value::new_str("night"))?;
+        tree.handle_event(&level, Value::from_integer(5))?;
+        let saved = tree.source_values();
+        assert_eq!(
+            saved,
+            vec![
+                (level.clone(), Value::from_integer(5)),
+                (mode.clone(), Value::new_str("night")),
+            ]
+        );
+        assert_eq!(saved[0].1.generation(), 2);
+
+        let mut restored = build()?;
+        let mut extra = saved.clone();
+        extra.push((ConcretePath::from_str("/gone")?, Value::from_integer(1)));
+        extra.push((ConcretePath::from_str("/doubled")?, Value::from_integer(1)));
+        let mut mistyped = Value::new_str("five");
+        mistyped.set_generation(9);
+        extra.push((level.clone(), mistyped));
+        let dropped = restored.restore_source_values(extra);
+        assert_eq!(
+            dropped.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+            vec!["/gone", "/doubled", "/level"]
+        );
+        assert_eq!(restored.source_values(), saved);
+        let updates = restored.all_sink_values()?;
+        assert_eq!(updates["level"][0].1, Value::from_integer(10));
+
+        // Events after the restore are newer than the restored values.
+        restored.handle_event(&mode, Value::new_str("day"))?;
+        assert_eq!(
+            restored.source_values()[1].1.generation(),
+            saved[0].1.generation() + 1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_to_ygg_string() -> Fallible<()> {
+        let rooms = r#"
+bed
+    lamp $hue <- /mode
+"#;
+        let s = r#"
+template light(level)
+    $hue
+    <- ${level} * 2   # doubled
+    brightness <- ${level}
+mode ^switch
+    default <- "day"
+button ^button
+house @1'x2' <>10mx6'6"
+    import(rooms.ygg)
+    porch @1'x1' !light(level=10)
+    lit <- toggle(/button)
+    label $text <- "{/mode}: {{{len([1, 2.5])}}}"
+    color <-\
+        let m = /mode
+        if m == "day" && !false:
+            "on"
+        else:
+            match m:
+                "night": "moonlight"
+                _: "off"
+"#;
+        let builder = || TreeBuilder::default().intercept_import("rooms.ygg", rooms);
+        let mut tree = builder()?.build_from_str(s)?;
+        let text = tree.to_ygg_string();
+        assert_eq!(
+            text,
+            r#"template light(level)
+    $hue
+    <- ${level} * 2
+    brightness <- ${level}
+mode ^switch
+    default <- "day"
+button ^button
+house @1'x2' <>10mx6'6"
+    import(rooms.ygg)
+    porch @1'x1' !light(level=10)
+    lit <- toggle(/button)
+    label $text <- "{/mode}: {{{len([1, 2.5])}}}"
+    color <-\
+        let m = /mode
+        if m == "day" && !false:
+            "on"
+        else:
+            match m:
+                "night": "moonlight"
+                _: "off"
+"#
+        );
+
+        let mut reparsed = builder()?.build_from_str(&text)?;
+        assert_eq!(reparsed.to_ygg_string(), text);
+        assert_eq!(sink_values(&mut reparsed), sink_values(&mut tree));
+        assert_eq!(
+            reparsed.lookup("/house/color")?.compute(&reparsed)?,
+            Value::new_str("on")
+        );
+        assert_eq!(
+            reparsed
+                .lookup("/house/porch/brightness")?
+                .compute(&reparsed)?,
+            Value::from_integer(10)
+        );
+
+        // Children that were not written in the file go last.
+        tree.add_source(&ConcretePath::from_str("/house/bell")?, "button")?;
+        assert!(tree
+            .to_ygg_string()
+            .ends_with("                _: \"off\"\n    bell ^button\n"));
+        Ok(())
+    }
+
+    // Random configurations that parse and type check, covering each sigil
+    // and most of the expression syntax.
+    struct RandomConfig {
+        rng: StdRng,
+        nodes: usize,
+    }
+
+    impl RandomConfig {
+        fn chance(&mut self, percent: u32) -> bool {
+            self.rng.gen_range(0, 100) < percent
+        }
+
+        fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+            items[self.rng.gen_range(0, items.len())]
+        }
+
+        fn length(&mut self) -> String {
+            match self.rng.gen_range(0, 4) {
+                0 => format!("{}m", self.rng.gen_range(-20, 20)),
+                1 => format!("{}.5", self.rng.gen_range(0, 20)),
+                2 => format!("{}'", self.rng.gen_range(0, 20)),
+                _ => format!(
+                    "{}'{}\"",
+                    self.rng.gen_range(0, 20),
+                    self.rng.gen_range(1, 12)
+                ),
+            }
+        }
+
+        fn int(&mut self, depth: usize, vars: &[&str]) -> String {
+            if depth == 0 || self.chance(30) {
+                if !vars.is_empty() && self.chance(30) {
+                    return self.pick(vars).to_owned();
+                }
+                return self.rng.gen_range(-100, 100).to_string();
+            }
+            match self.rng.gen_range(0, 7) {
+                0 => format!("({})", self.int(depth - 1, vars)),
+                1 => format!("-{}", self.int(depth - 1, vars)),
+                2 => format!("len({})", self.string(depth - 1, vars)),
+                3 => format!("max({}, {})", self.int(depth - 1, vars), self.int(0, vars)),
+                4 => format!("abs({})", self.int(depth - 1, vars)),
+                _ => {
+                    let op = self.pick(&["+", "-", "*", "%"]);
+                    let rhs = if op == "%" {
+                        self.rng.gen_range(1, 9).to_string()
+                    } else {
+                        self.int(depth - 1, vars)
+                    };
+                    format!("{} {} {}", self.int(depth - 1, vars), op, rhs)
+                }
+            }
+        }
+
+        fn boolean(&mut self, depth: usize, vars: &[&str]) -> String {
+            if depth == 0 || self.chance(20) {
+                return self.pick(&["true", "false"]).to_owned();
+            }
+            match self.rng.gen_range(0, 4) {
+                0 => format!("! ({})", self.boolean(depth - 1, vars)),
+                1 => {
+                    let op = self.pick(&["&&", "||"]);
+                    let lhs = self.boolean(depth - 1, vars);
+                    format!("{} {} {}", lhs, op, self.boolean(depth - 1, vars))
+                }
+                2 => format!("{} == {}", self.string(0, vars), self.string(0, vars)),
+                _ => {
+                    let op = self.pick(&["<", "<=", ">", ">=", "==", "!="]);
+                    let lhs = self.int(depth - 1, vars);
+                    format!("{} {} {}", lhs, op, self.int(depth - 1, vars))
+                }
+            }
+        }
+
+        fn string(&mut self, depth: usize, vars: &[&str]) -> String {
+            let text = (0..self.rng.gen_range(0, 6))
+                .map(|_| self.pick(&["a", "B", " ", "\\\"", "{{", "}}", "-", "é"]))
+                .collect::<String>();
+            if depth == 0 || self.chance(40) {
+                return format!("\"{}\"", text);
+            }
+            match self.rng.gen_range(0, 3) {
+                0 => format!("\"{}{{{}}}{}\"", text, self.int(depth - 1, vars), text),
+                1 => format!("upper({})", self.string(depth - 1, vars)),
+                _ => format!("split(\"a,b\", \",\", {})", self.rng.gen_range(0, 2)),
+            }
+        }
+
+        fn expr(&mut self, depth: usize, vars: &[&str]) -> String {
+            match self.rng.gen_range(0, 6) {
+                0 => self.boolean(depth, vars),
+                1 => self.string(depth, vars),
+                2 => format!("{}.25", self.rng.gen_range(-10, 10)),
+                3 => format!(
+                    "{}h{}m + 500ms",
+                    self.rng.gen_range(0, 3),
+                    self.rng.gen_range(1, 59)
+                ),
+                4 => format!("[{}, {}]", self.int(depth, vars), self.string(0, vars)),
+                _ => self.int(depth, vars),
+            }
+        }
+
+        fn block(&mut self, indent: &str) -> Vec<String> {
+            let mut lines = vec![format!("{}let x = {}", indent, self.int(2, &[]))];
+            if self.chance(50) {
+                lines.push(format!("{}{}", indent, self.int(3, &["x"])));
+                return lines;
+            }
+            lines.push(format!("{}if {}:", indent, self.boolean(2, &["x"])));
+            lines.push(format!("{}    {}", indent, self.int(2, &["x"])));
+            if self.chance(50) {
+                lines.push(format!("{}elif {}:", indent, self.boolean(2, &["x"])));
+                lines.push(format!("{}    {}", indent, self.int(2, &["x"])));
+            }
+            lines.push(format!("{}else:", indent));
+            lines.push(format!("{}    match x:", indent));
+            lines.push(format!(
+                "{}        {}: {}",
+                indent,
+                self.rng.gen_range(-5, 5),
+                self.int(2, &["x"])
+            ));
+            lines.push(format!("{}        _: {}", indent, self.int(2, &["x"])));
+            lines
+        }
+
+        fn node(&mut self, depth: usize, out: &mut Vec<String>) {
+            let indent = "    ".repeat(depth);
+            self.nodes += 1;
+            let mut line = format!("{}n{}", indent, self.nodes);
+            if self.chance(20) {
+                line += &format!(" @{}x{}", self.length(), self.length());
+            }
+            if self.chance(20) {
+                line += &format!(" <>{}x{}", self.length(), self.length());
+            }
+            let children = if depth < 3 {
+                self.rng.gen_range(0, 4)
+            } else {
+                0
+            };
+            let mut block = Vec::new();
+            match self.rng.gen_range(0, 6) {
+                0 => line += &format!(" ^{}", self.pick(&["switch", "button"])),
+                1 => line += &format!(" <- {}(/trigger)", self.pick(&["toggle", "counter"])),
+                2 | 3 => {
+                    if self.chance(50) {
+                        line += &format!(" ${}", self.pick(&["hue", "text"]));
+                    }
+                    line += &format!(" <- {}", self.expr(3, &[]));
+                }
+                4 => {
+                    if self.chance(50) {
+                        line += " $hue";
+                    }
+                    // With children, the script goes on its own line first.
+                    if children == 0 {
+                        line += " <-\\";
+                        block = self.block(&format!("{}    ", indent));
+                    } else {
+                        block.push(format!("{}    <-\\", indent));
+                        block.extend(self.block(&format!("{}        ", indent)));
+                    }
+                }
+                _ => {}
+            }
+            out.push(line);
+            out.extend(block);
+            for _ in 0..children {
+                self.node(depth + 1, out);
+            }
+        }
+
+        fn generate(seed: u64) -> String {
+            let mut gen = RandomConfig {
+                rng: StdRng::seed_from_u64(seed),
+                nodes: 0,
+            };
+            let mut lines = vec!["trigger ^button".to_owned()];
+            for _ in 0..gen.rng.gen_range(1, 5) {
+                gen.node(0, &mut lines);
+            }
+            lines.join("\n") + "\n"
+        }
+    }
+
+    // What a tree computes, in a form that can be compared.
+    fn sink_values(tree: &mut Tree) -> String {
+        let mut values = match tree.all_sink_values() {
+            Ok(values) => values.into_values().flatten().collect::<Vec<_>>(),
+            Err(e) => return e.to_string(),
+        };
+        values.sort_by_key(|(path, _)| path.to_string());
+        format!("{:?}", values)
+    }
+
+    #[test]
+    fn test_tree_to_ygg_string_round_trip() -> Fallible<()> {
+        for seed in 0..300 {
+            let config = RandomConfig::generate(seed);
+            let mut tree = TreeBuilder::default()
+                .build_from_str(&config)
+                .map_err(|e| format_err!("seed {}: {}\n{}", seed, e, config))?;
+            let text = tree.to_ygg_string();
+            let mut reparsed = TreeBuilder::default()
+                .build_from_str(&text)
+                .map_err(|e| format_err!("seed {}: {}\n{}", seed, e, text))?;
+            assert_eq!(reparsed.to_ygg_string(), text, "seed {}", seed);
+            assert_eq!(
+                sink_values(&mut reparsed),
+                sink_values(&mut tree),
+                "seed {}",
+                seed
+            );
+            let mut nodes = Vec::new();
+            reparsed.root().find_descendants(&mut nodes);
+            for node in nodes {
+                let original = tree.lookup_path(&node.path())?;
+                assert_eq!(node.maybe_source_kind(), original.maybe_source_kind());
+                assert_eq!(node.maybe_sink_kind(), original.maybe_sink_kind());
+                assert_eq!(node.location(), original.location());
+                assert_eq!(node.dimensions(), original.dimensions());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_reload() -> Fallible<()> {
+        let before = r#"
+switch ^switch
+    default <- "off"
+fan ^switch
+    default <- "off"
+lamp $hue <- /switch
+heater $power <- /fan
+"#;
+        let after = r#"
+switch ^switch
+    default <- "off"
+fan ^button
+lamp $hue <- /switch
+spot $hue <- /switch == "on"
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(before)?;
+        tree.all_sink_values()?;
+        let switch = ConcretePath::from_str("/switch")?;
+        tree.handle_event(&switch, Value::new_str("on"))?;
+
+        let changes = tree.reload(TreeBuilder::default().build_from_str(after)?)?;
+        let paths = |m: &HashMap<String, Vec<ConcretePath>>, kind: &str| {
+            m.get(kind)
+                .map(|v| v.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+                .unwrap_or_default()
+        };
+        assert_eq!(paths(&changes.added_sources, "button"), vec!["/fan"]);
+        assert_eq!(paths(&changes.removed_sources, "switch"), vec!["/fan"]);
+        assert_eq!(paths(&changes.added_sinks, "hue"), vec!["/spot"]);
+        assert_eq!(paths(&changes.removed_sinks, "power"), vec!["/heater"]);
+        assert!(!changes.added_sources.contains_key("switch"));
+
+        // The lamp already showed the switch, so only the new sink is sent.
+        assert_eq!(
+            changes.updates["hue"],
+            vec![(ConcretePath::from_str("/spot")?, Value::from_boolean(true))]
+        );
+        assert_eq!(tree.source_values(), vec![(switch, Value::new_str("on"))]);
+
+        // Sinks that cannot be computed yet are left out of the updates.
+        let waiting = TreeBuilder::default().build_from_str("a ^a\nb $b <- /a\nc $b <- 1\n")?;
+        let changes = tree.reload(waiting)?;
+        assert_eq!(
+            changes.updates["b"],
+            vec![(ConcretePath::from_str("/c")?, Value::from_integer(1))]
+        );
+        assert!(tree.lookup("/lamp").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_reload_changed_settings() -> Fallible<()> {
+        let before = r#"
+tic ^clock
+    interval <- "minute"
+lamp
+    host <- "lamp.local"
+    power $redstone <- true
+    mode $redstone <- 1
+fan $redstone <- true
+"#;
+        let after = r#"
+tic ^clock
+    interval <- "second"
+lamp
+    host <- "lamp2.local"
+    power $redstone <- true
+    mode $redstone <- 1
+fan $redstone <- false
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(before)?;
+        let changes = tree.reload(TreeBuilder::default().build_from_str(after)?)?;
+        let paths = |m: &HashMap<String, Vec<ConcretePath>>, kind: &str| {
+            let mut paths = m
+                .get(kind)
+                .map(|v| v.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+                .unwrap_or_default();
+            paths.sort();
+            paths
+        };
+        assert_eq!(paths(&changes.removed_sources, "clock"), vec!["/tic"]);
+        assert_eq!(paths(&changes.added_sources, "clock"), vec!["/tic"]);
+        let devices = vec!["/lamp/mode", "/lamp/power"];
+        assert_eq!(paths(&changes.removed_sinks, "redstone"), devices);
+        assert_eq!(paths(&changes.added_sinks, "redstone"), devices);
+
+        let changes = tree.reload(TreeBuilder::default().build_from_str(after)?)?;
+        assert!(changes.added_sources.is_empty() && changes.removed_sources.is_empty());
+        assert!(changes.added_sinks.is_empty() && changes.removed_sinks.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_event_despite_failing_stateful_node() -> Fallible<()> {
+        let s = r#"
+motion ^motion
+arrived $alert <- edge(/motion)
+lamp $hue <- /motion
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        let motion = ConcretePath::from_str("/motion")?;
+        tree.handle_event(&motion, Value::from_boolean(false))?;
+
+        // The edge cannot take a string, but the lamp still hears of it.
+        let updates = tree.handle_event(&motion, Value::new_str("yes"))?;
+        assert_eq!(
+            updates["hue"],
+            vec![(ConcretePath::from_str("/lamp")?, Value::new_str("yes"))]
+        );
+        let updates = tree.handle_event(&motion, Value::from_boolean(true))?;
+        assert_eq!(updates["alert"][0].1, Value::from_boolean(true));
+        assert_eq!(updates["hue"][0].1, Value::from_boolean(true));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_stateful_nodes() -> Fallible<()> {
+        let s = r#"
+button ^button
+motion ^motion
+    default <- false
+level ^level
+    default <- 10
+lamp $light <- toggle(/button)
+presses $count <- counter(/button)
+arrived $alert <- edge(/motion)
+held $level <- sample_hold(/button, /level)
+doubled $double <- /held * 2
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        for (path, value_type) in &[
+            ("/lamp", ValueType::Boolean),
+            ("/presses", ValueType::Integer),
+            ("/arrived", ValueType::Boolean),
+            ("/held", ValueType::Integer),
+        ] {
+            assert_eq!(tree.lookup(path)?.value_type(&tree)?, *value_type);
+        }
+        let updates = tree.all_sink_values()?;
+        assert_eq!(updates["light"][0].1, Value::from_boolean(false));
+        assert_eq!(updates["count"][0].1, Value::from_integer(0));
+        assert_eq!(updates["level"][0].1, Value::from_integer(10));
+
+        // Until the button is pressed, the sample follows the level.
+        let button = ConcretePath::from_str("/button")?;
+        let level = ConcretePath::from_str("/level")?;
+        let updates = tree.handle_event(&level, Value::from_integer(20))?;
+        assert_eq!(updates["double"][0].1, Value::from_integer(40));
+        let updates = tree.handle_event(&button, Value::from_boolean(true))?;
+        assert_eq!(updates["light"][0].1, Value::from_boolean(true));
+        assert_eq!(updates["count"][0].1, Value::from_integer(1));
+        assert!(!updates.contains_key("level"));
+
+        // Then it holds, and the level does not affect it.
+        let updates = tree.handle_event(&level, Value::from_integer(30))?;
+        assert!(updates.is_empty());
+        let updates = tree.handle_event(&button, Value::from_boolean(false))?;
+        assert!(updates.is_empty());
+        let updates = tree.handle_event(&button, Value::from_boolean(true))?;
+        assert_eq!(updates["light"][0].1, Value::from_boolean(false));
+        assert_eq!(updates["count"][0].1, Value::from_integer(2));
+        assert_eq!(updates["double"][0].1, Value::from_integer(60));
+
+        // Only rises in motion set the edge.
+        let motion = ConcretePath::from_str("/motion")?;
+        let updates = tree.handle_event(&motion, Value::from_boolean(true))?;
+        assert_eq!(updates["alert"][0].1, Value::from_boolean(true));
+        let updates = tree.handle_event(&motion, Value::from_boolean(true))?;
+        assert_eq!(updates["alert"][0].1, Value::from_boolean(false));
+        let updates = tree.handle_event(&motion, Value::from_boolean(false))?;
+        assert!(updates.is_empty());
+        let updates = tree.handle_event(&motion, Value::from_boolean(true))?;
+        assert_eq!(updates["alert"][0].1, Value::from_boolean(true));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_timers() -> Fallible<()> {
+        let s = r#"
+motion ^motion
+    default <- false
+scene ^scene
+    default <- "day"
+timeout <- 10m
+occupied $light <- hold(/motion, /timeout)
+settled $scene <- debounce(/scene, 2s)
+lingering $alert <- after(/motion, 30s)
+"#;
+        let at = |secs: i64| Timestamp::from_unix_millis(secs * 1000);
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        tree.tick(at(0))?;
+        let updates = tree.all_sink_values()?;
+        assert_eq!(updates["light"][0].1, Value::from_boolean(false));
+        assert_eq!(updates["scene"][0].1, Value::new_str("day"));
+        assert_eq!(tree.next_deadline(), None);
+
+        // Rises of motion are taken at once by hold, but after waits.
+        let motion = ConcretePath::from_str("/motion")?;
+        let updates = tree.handle_event(&motion, Value::from_boolean(true))?;
+        assert_eq!(updates["light"][0].1, Value::from_boolean(true));
+        assert!(!updates.contains_key("alert"));
+        assert_eq!(tree.next_deadline(), Some(at(30)));
+        let updates = tree.tick(at(30))?;
+        assert_eq!(updates["alert"][0].1, Value::from_boolean(true));
+
+        // The light stays on for the timeout after motion stops, unless the
+        // motion comes back first.
+        tree.tick(at(100))?;
+        let updates = tree.handle_event(&motion, Value::from_boolean(false))?;
+        assert!(!updates.contains_key("light"));
+        assert_eq!(updates["alert"][0].1, Value::from_boolean(false));
+        assert_eq!(tree.next_deadline(), Some(at(700)));
+        tree.tick(at(200))?;
+        tree.handle_event(&motion, Value::from_boolean(true))?;
+        tree.handle_event(&motion, Value::from_boolean(false))?;
+        assert_eq!(tree.next_deadline(), Some(at(800)));
+        assert!(tree.tick(at(700))?.is_empty());
+        let updates = tree.tick(at(800))?;
+        assert_eq!(updates["light"][0].1, Value::from_boolean(false));
+        assert_eq!(tree.next_deadline(), None);
+
+        // Debounce only takes values that stay put for its delay.
+        let scene = ConcretePath::from_str("/scene")?;
+        tree.handle_event(&scene, Value::new_str("night"))?;
+        tree.tick(at(801))?;
+        tree.handle_event(&scene, Value::new_str("evening"))?;
+        assert!(tree.tick(at(802))?.is_empty());
+        let updates = tree.tick(at(803))?;
+        assert_eq!(updates["scene"][0].1, Value::new_str("evening"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_stateful_errors() {
+        for (s, error) in &[
+            (
+                "a <- 1
+b <- toggle(/a)",
+                "dataflow error: the trigger of toggle at /b must be a source, but /a is not",
+            ),
+            (
+                "b <- counter(/a)",
+                "runtime error: lookup on path that does not exist",
+            ),
+            (
+                "a ^clock
+b <- edge(/a)",
+                "type error: edge needs a boolean trigger, but /a is integer",
+            ),
+            (
+                "a ^button
+b <- toggle(/a) || true",
+                "parse error: toggle keeps state, so must be the only thing after <- on its node",
+            ),
+            (
+                "a ^button
+b <-\
+    1 + counter(/a)",
+                "parse error: counter keeps state, so must be the only thing after <- on its node",
+            ),
+        ] {
+            let err = TreeBuilder::default()
+                .declare_source_type("clock", ValueType::Integer)
+                .unwrap()
+                .build_from_str(s)
+                .err()
+                .unwrap();
+            assert!(err.to_string().starts_with(error), "{}: {}", s, err);
+        }
+    }
+
+    #[test]
+    fn test_tree_reject_cycles() {
+        for (s, cycle) in &[
+            ("a <- ./b\nb <- ./a", "/a -> /b -> /a"),
+            ("a <- ./a + 1", "/a -> /a"),
+            ("a <- /b :: 0\nb <- /c\nc <- /a * 2", "/a -> /b -> /c -> /a"),
+            (
+                "a ^src\n    default <- /b\nb <- /a",
+                "/a -> /a/default -> /b -> /a",
+            ),
+        ] {
+            let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+            assert!(err.to_string().contains(cycle), "{}: {}", s, err);
+        }
+    }
+
+    #[test]
+    fn test_tree_dynamic_cycle_fails_at_compute() -> Fallible<()> {
+        let s = r#"
+k ^src
+    default <- "y"
+x <- /{/k}
+y <- 1
+"#;
+        let mut tree = TreeBuilder::default().build_from_str(s)?;
+        assert_eq!(tree.lookup("/x")?.compute(&tree)?, Value::from_integer(1));
+        tree.handle_event(&ConcretePath::from_str("/k")?, Value::new_str("x"))?;
+        let err = tree.lookup("/x")?.compute(&tree).err().unwrap();
+        assert!(err.to_string().contains("/x -> /x"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_link_error_location() {
+        let s = "a\n    b <- 1 + /nope";
+        let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+        assert!(
+            err.to_string().ends_with(
+                r#"
+ --> <string>:2:10
+  |
+2 |     b <- 1 + /nope
+  |          ^"#
+            ),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_build_examples() -> Fallible<()> {
+        let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("../../examples");
+        // Declared as the daemon declares them.
+        for name in &["eyrie.ygg", "test1.ygg"] {
+            TreeBuilder::default()
+                .declare_source_type("legacy-mcu", ValueType::String)?
+                .build_from_file(&examples.join(name))?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_import_file() -> Fallible<()> {
+        let dir = std::env::temp_dir().join(format!("yggdrasil-import-{}", std::process::id()));
+        fs::create_dir_all(dir.join("rooms"))?;
+        fs::write(dir.join("main.ygg"), "house\n    import(rooms/all.ygg)\n")?;
+        fs::write(dir.join("rooms/all.ygg"), "import(bed.ygg)\n")?;
+        fs::write(dir.join("rooms/bed.ygg"), "bed\n    level <- 1 +\n")?;
+        let err = TreeBuilder::default()
+            .build_from_file(&dir.join("main.ygg"))
+            .err()
+            .unwrap()
+            .to_string();
+        fs::write(dir.join("rooms/bed.ygg"), "bed\n    level <- 1 + 1\n")?;
+        let tree = TreeBuilder::default().build_from_file(&dir.join("main.ygg"))?;
+        fs::remove_dir_all(&dir)?;
+
+        let main = dir.join("main.ygg");
+        let rooms = dir.join("rooms");
+        assert_eq!(
+            err,
+            format!(
+                r#"parse error: unexpected end of expression
+ --> {}:2:16
+  |
+2 |     level <- 1 +
+  |                ^
+  = imported from {}:1:1
+  = imported from {}:2:5"#,
+                rooms.join("bed.ygg").display(),
+                rooms.join("all.ygg").display(),
+                main.display()
+            )
+        );
+        assert_eq!(
+            tree.lookup("/house/bed/level")?.compute(&tree)?,
+            Value::from_integer(2)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_import_file_cycle() -> Fallible<()> {
+        let dir = std::env::temp_dir().join(format!("yggdrasil-cycle-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("a.ygg"), "import(b.ygg)\n")?;
+        fs::write(dir.join("b.ygg"), "import(a.ygg)\n")?;
+        let err = TreeBuilder::default()
+            .build_from_file(&dir.join("a.ygg"))
+            .err()
+            .unwrap();
+        fs::remove_dir_all(&dir)?;
+        assert!(err.to_string().contains("import cycle"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tree_type_errors() {
+        for (s, error) in &[
+            (
+                r#"a <- "on" == 1"#,
+                "type error: Equals is not a valid operation between string and integer",
+            ),
+            (
+                "a <-\\\n    if 1:\n        2\n    else:\n        3",
+                "type error: if statement conditions must be boolean, not integer",
+            ),
+            (
+                "a <- 1\nb <- upper(/a)",
+                "type error: argument 1 to upper must be string, not integer",
+            ),
+            (
+                "a <- 1.5\nb <- /c/{/a}\nc\n    d <- 1",
+                "type error: a float value cannot be used as a path component in /c/{/a}",
+            ),
+            (
+                "a ^src\n    default <- 1\nb <- /a + \"x\"",
+                "type error: Add is not a valid operation between integer and string",
+            ),
+            (
+                "a ^clock\n    default <- \"x\"",
+                "type error: the default of ^clock source /a must be integer, not string",
+            ),
+            (
+                "a ^clock\nb <- /a && true",
+                "type error: And is not a valid operation between integer and boolean",
+            ),
+        ] {
+            let err = TreeBuilder::default()
+                .declare_source_type("clock", ValueType::Integer)
+                .unwrap()
+                .build_from_str(s)
+                .err()
+                .unwrap();
+            assert!(err.to_string().starts_with(error), "{}: {}", s, err);
+        }
+    }
+
+    #[test]
+    fn test_tree_type_error_location() {
+        let s = "a <- 1\nb\n    c <- 2 + lower(/a)";
+        let err = TreeBuilder::default().build_from_str(s).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            r#"type error: argument 1 to lower must be string, not integer
+ --> <string>:3:10
+  |
+3 |     c <- 2 + lower(/a)
+  |          ^"#
+        );
+    }
+
+    #[test]
+    fn test_tree_type_inference() -> Fallible<()> {
+        let s = r#"
+src ^src
+level ^clock
+    default <- 3
+unknown <- /src + 1
+latched <- /src :: 2
+mixed <-\
+    if /level > 2:
+        "high"
+    else:
+        4
+colors
+    0 <- rgb(0, 0, 0)
+    1 <- bhs(0, 0, 0)
+parity <- /level % 2
+dark <- /colors/{/parity} * 0.5
+names
+    a <- 1
+    b <- "b"
+any <- /names/{/src}
+"#;
+        let tree = TreeBuilder::default().build_from_str(s)?;
+        for (path, value_type) in &[
+            ("/src", ValueType::Any),
+            ("/level", ValueType::Integer),
+            ("/unknown", ValueType::Any),
+            ("/latched", ValueType::Integer),
+            ("/mixed", ValueType::Any),
+            ("/dark", ValueType::Color),
+            ("/any", ValueType::Any),
+        ] {
+            assert_eq!(
+                tree.lookup(path)?.value_type(&tree)?,
+                *value_type,
+                "{}",
+                path
+            );
+        }
+        Ok(())
+    }
 }
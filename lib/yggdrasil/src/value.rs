@@ -2,25 +2,157 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use crate::{
+    color::{Color, RGB},
     float::Float,
-    path::{ConcretePath, ScriptPath},
+    path::{ConcretePath, PathComponent, ScriptPath},
+    time::{Duration, Timestamp},
     tokenizer::Token,
     tree::Tree,
 };
-use failure::{bail, ensure, Fallible};
+use failure::{bail, ensure, format_err, Fallible};
 use std::{convert::From, fmt};
 use tracing::trace;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum ValueData {
     Boolean(bool),
+    Color(Color),
+    Duration(Duration),
     Float(Float),
     Integer(i64),
+    List(Vec<Value>),
     Path(ScriptPath),
     String(String),
+    Timestamp(Timestamp),
     InputFlag, // Our Any type
 }
 
+/// The type of a value, as far as it is known before the value is computed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ValueType {
+    Any,
+    Boolean,
+    Color,
+    Duration,
+    Float,
+    Integer,
+    List,
+    String,
+    Timestamp,
+}
+
+impl ValueType {
+    pub fn of(data: &ValueData) -> Self {
+        match data {
+            ValueData::Boolean(_) => ValueType::Boolean,
+            ValueData::Color(_) => ValueType::Color,
+            ValueData::Duration(_) => ValueType::Duration,
+            ValueData::Float(_) => ValueType::Float,
+            ValueData::Integer(_) => ValueType::Integer,
+            ValueData::List(_) => ValueType::List,
+            ValueData::String(_) => ValueType::String,
+            ValueData::Timestamp(_) => ValueType::Timestamp,
+            ValueData::Path(_) | ValueData::InputFlag => ValueType::Any,
+        }
+    }
+
+    /// The type of a value that may come from either side.
+    pub fn join(self, other: ValueType) -> ValueType {
+        if self == other {
+            self
+        } else {
+            ValueType::Any
+        }
+    }
+
+    /// True if a value of this type may turn out to be of the given type.
+    pub fn accepts(self, other: ValueType) -> bool {
+        self == ValueType::Any || other == ValueType::Any || self == other
+    }
+
+    // The type of `lhs tok rhs`. Rather than keep a second copy of the rules
+    // in `Value::apply`, we apply the operator to a sample of each type.
+    pub(super) fn apply(tok: &Token, lhs: ValueType, rhs: ValueType) -> Fallible<ValueType> {
+        if lhs == ValueType::Any || rhs == ValueType::Any {
+            return Ok(match tok {
+                Token::Equals
+                | Token::NotEquals
+                | Token::LessThan
+                | Token::LessThanOrEquals
+                | Token::GreaterThan
+                | Token::GreaterThanOrEquals
+                | Token::And
+                | Token::Or => ValueType::Boolean,
+                // A latch gives one of its operands, so takes the known type.
+                Token::Latch if lhs == ValueType::Any => rhs,
+                Token::Latch => lhs,
+                _ => ValueType::Any,
+            });
+        }
+        let result = lhs.sample()?.apply(tok, &rhs.sample()?).map_err(|_| {
+            format_err!(
+                "type error: {:?} is not a valid operation between {} and {}",
+                tok,
+                lhs,
+                rhs
+            )
+        })?;
+        Ok(ValueType::of(&result.data))
+    }
+
+    pub(super) fn apply_unary(tok: &Token, operand: ValueType) -> Fallible<ValueType> {
+        if operand == ValueType::Any {
+            return Ok(match tok {
+                Token::Not => ValueType::Boolean,
+                _ => ValueType::Any,
+            });
+        }
+        let result = operand.sample()?.apply_unary(tok).map_err(|_| {
+            format_err!(
+                "type error: {:?} is not a valid operation on {}",
+                tok,
+                operand
+            )
+        })?;
+        Ok(ValueType::of(&result.data))
+    }
+
+    // A value of this type, chosen so that no operation on it can fail
+    // because of its particular value.
+    fn sample(self) -> Fallible<Value> {
+        Ok(match self {
+            ValueType::Any => bail!("type error: there is no sample of an unknown type"),
+            ValueType::Boolean => Value::from_boolean(true),
+            ValueType::Color => Value::from_color(Color::RGB(RGB::new(1, 1, 1)?)),
+            ValueType::Duration => Value::from_duration(Duration::from_millis(1000)),
+            ValueType::Float => Value::from_float(Float::new(1.0)?),
+            ValueType::Integer => Value::from_integer(1),
+            ValueType::List => Value::from_list(Vec::new()),
+            ValueType::String => Value::new_str("a"),
+            ValueType::Timestamp => {
+                Value::from_timestamp(Timestamp::from_unix_millis(1_000_000_000_000))
+            }
+        })
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ValueType::Any => "any",
+            ValueType::Boolean => "boolean",
+            ValueType::Color => "color",
+            ValueType::Duration => "duration",
+            ValueType::Float => "float",
+            ValueType::Integer => "integer",
+            ValueType::List => "list",
+            ValueType::String => "string",
+            ValueType::Timestamp => "timestamp",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 fn latch<T>(lhs: &Value, rhs: &Value, a: T, b: T) -> T {
     trace!("latch {} :: {}", lhs.generation, rhs.generation);
     if lhs.generation() >= rhs.generation() {
@@ -30,7 +162,7 @@ fn latch<T>(lhs: &Value, rhs: &Value, a: T, b: T) -> T {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug)]
 pub struct Value {
     pub data: ValueData,
     generation: usize,
@@ -44,6 +176,20 @@ impl Value {
         }
     }
 
+    pub fn from_color(c: Color) -> Self {
+        Self {
+            data: ValueData::Color(c),
+            generation: 0,
+        }
+    }
+
+    pub fn from_duration(d: Duration) -> Self {
+        Self {
+            data: ValueData::Duration(d),
+            generation: 0,
+        }
+    }
+
     pub fn from_integer(i: i64) -> Self {
         Self {
             data: ValueData::Integer(i),
@@ -58,6 +204,15 @@ impl Value {
         }
     }
 
+    /// A list is as new as the newest of its items.
+    pub fn from_list(items: Vec<Value>) -> Self {
+        let generation = items.iter().map(Value::generation).max().unwrap_or(0);
+        Self {
+            data: ValueData::List(items),
+            generation,
+        }
+    }
+
     pub fn from_string(s: String) -> Self {
         Self {
             data: ValueData::String(s),
@@ -72,6 +227,13 @@ impl Value {
         }
     }
 
+    pub fn from_timestamp(t: Timestamp) -> Self {
+        Self {
+            data: ValueData::Timestamp(t),
+            generation: 0,
+        }
+    }
+
     pub fn input_flag() -> Self {
         Self {
             data: ValueData::InputFlag,
@@ -99,8 +261,34 @@ impl Value {
         self
     }
 
+    /// The value as it would be written in a .ygg file.
+    pub fn literal(&self) -> String {
+        match self.data {
+            ValueData::Integer(i) => Token::IntegerTerm(i).to_string(),
+            ValueData::Float(f) => Token::FloatTerm(f).to_string(),
+            ValueData::String(ref s) => Token::StringTerm(s.to_owned()).to_string(),
+            ValueData::List(ref items) => format!(
+                "[{}]",
+                items
+                    .iter()
+                    .map(Value::literal)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            _ => self.to_string(),
+        }
+    }
+
     pub(super) fn compute(&self, tree: &Tree) -> Fallible<Value> {
         if let ValueData::Path(ref p) = self.data {
+            if p.is_glob() {
+                let (nodes, path_gen) = tree.lookup_glob_path(self.generation, p)?;
+                let values = nodes
+                    .iter()
+                    .map(|node| node.compute(tree))
+                    .collect::<Fallible<Vec<_>>>()?;
+                return Ok(Value::from_list(values).with_generation(path_gen));
+            }
             let (noderef, path_gen) = tree.lookup_dynamic_path(self.generation, p)?;
             return Ok(noderef.compute(tree)?.with_generation(path_gen));
         }
@@ -116,15 +304,69 @@ impl Value {
             !other.is_path(),
             "runtime error: attempting to apply a non-path"
         );
+        // Integers are promoted when they meet a float.
+        match (&self.data, &other.data) {
+            (ValueData::Integer(_), ValueData::Float(_)) => {
+                return Self::apply_float(tok, &self.to_float()?, other);
+            }
+            (ValueData::Float(_), ValueData::Integer(_)) => {
+                return Self::apply_float(tok, self, &other.to_float()?);
+            }
+            _ => {}
+        }
+        // Scaling is commutative, but colors and durations know how to scale
+        // themselves.
+        if *tok == Token::Multiply {
+            if other.is_color() {
+                return Self::apply_color(tok, other, self);
+            }
+            if other.is_duration() {
+                return Self::apply_duration(tok, other, self);
+            }
+        }
         Ok(match self.data {
             ValueData::Boolean(_) => Self::apply_boolean(tok, self, other)?,
+            ValueData::Color(_) => Self::apply_color(tok, self, other)?,
+            ValueData::Duration(_) => Self::apply_duration(tok, self, other)?,
+            ValueData::Timestamp(_) => Self::apply_timestamp(tok, self, other)?,
             ValueData::Integer(_) => Self::apply_integer(tok, self, other)?,
             ValueData::Float(_) => Self::apply_float(tok, self, other)?,
+            ValueData::List(_) => Self::apply_list(tok, self, other)?,
             ValueData::String(_) => Self::apply_string(tok, self, other)?,
             _ => bail!("runtime error: apply reached a path node"),
         })
     }
 
+    fn to_float(&self) -> Fallible<Value> {
+        Ok(Value {
+            data: ValueData::Float(Float::new(self.as_integer()? as f64)?),
+            generation: self.generation,
+        })
+    }
+
+    pub(super) fn apply_unary(&self, tok: &Token) -> Fallible<Value> {
+        let data = match (tok, &self.data) {
+            (Token::Not, ValueData::Boolean(b)) => ValueData::Boolean(!b),
+            (Token::Subtract, ValueData::Integer(i)) => ValueData::Integer(
+                i.checked_neg()
+                    .ok_or_else(|| format_err!("numerical error: integer overflow in -{}", i))?,
+            ),
+            (Token::Subtract, ValueData::Float(f)) => ValueData::Float(f.checked_neg()?),
+            (Token::Subtract, ValueData::Duration(d)) => {
+                ValueData::Duration(Duration::from_millis(0).checked_sub(*d)?)
+            }
+            _ => bail!(
+                "runtime error: {:?} is not a valid operation on {}",
+                tok,
+                self
+            ),
+        };
+        Ok(Value {
+            data,
+            generation: self.generation,
+        })
+    }
+
     pub(super) fn apply_boolean(tok: &Token, lhs: &Value, rhs: &Value) -> Fallible<Value> {
         let a = lhs.as_boolean()?;
         let b = rhs.as_boolean()?;
@@ -142,15 +384,110 @@ impl Value {
         Ok(Value::from_boolean(next).with_generation(lhs.generation().max(rhs.generation())))
     }
 
+    pub(super) fn apply_color(tok: &Token, lhs: &Value, rhs: &Value) -> Fallible<Value> {
+        let a = lhs.as_color()?;
+        let data = match tok {
+            Token::Multiply => {
+                let factor = match rhs.data {
+                    ValueData::Integer(i) => i as f64,
+                    ValueData::Float(f) => f.value,
+                    _ => bail!("runtime error: a color may only be scaled by a number"),
+                };
+                ValueData::Color(a.scale(factor)?)
+            }
+            Token::Latch => ValueData::Color(latch(lhs, rhs, a, rhs.as_color()?)),
+            Token::Equals => ValueData::Boolean(a == rhs.as_color()?),
+            Token::NotEquals => ValueData::Boolean(a != rhs.as_color()?),
+            _ => bail!(
+                "runtime error: {:?} is not a valid operation on a color",
+                tok
+            ),
+        };
+        Ok(Value {
+            data,
+            generation: lhs.generation().max(rhs.generation()),
+        })
+    }
+
+    fn as_factor(&self) -> Fallible<f64> {
+        Ok(match self.data {
+            ValueData::Integer(i) => i as f64,
+            ValueData::Float(f) => f.value,
+            _ => bail!("runtime error: a duration may only be scaled by a number"),
+        })
+    }
+
+    pub(super) fn apply_duration(tok: &Token, lhs: &Value, rhs: &Value) -> Fallible<Value> {
+        let a = lhs.as_duration()?;
+        let data = match (tok, &rhs.data) {
+            (Token::Add, ValueData::Timestamp(t)) => ValueData::Timestamp(t.checked_add(a)?),
+            (Token::Add, _) => ValueData::Duration(a.checked_add(rhs.as_duration()?)?),
+            (Token::Subtract, _) => ValueData::Duration(a.checked_sub(rhs.as_duration()?)?),
+            (Token::Multiply, _) => ValueData::Duration(a.checked_mul(rhs.as_factor()?)?),
+            (Token::Divide, ValueData::Duration(b)) => ValueData::Float(Float::new(a.ratio(*b)?)?),
+            (Token::Divide, _) => ValueData::Duration(a.checked_div(rhs.as_factor()?)?),
+            (Token::Modulo, _) => ValueData::Duration(a.checked_rem(rhs.as_duration()?)?),
+            (Token::Latch, _) => ValueData::Duration(latch(lhs, rhs, a, rhs.as_duration()?)),
+            (Token::Equals, _) => ValueData::Boolean(a == rhs.as_duration()?),
+            (Token::NotEquals, _) => ValueData::Boolean(a != rhs.as_duration()?),
+            (Token::GreaterThan, _) => ValueData::Boolean(a > rhs.as_duration()?),
+            (Token::LessThan, _) => ValueData::Boolean(a < rhs.as_duration()?),
+            (Token::GreaterThanOrEquals, _) => ValueData::Boolean(a >= rhs.as_duration()?),
+            (Token::LessThanOrEquals, _) => ValueData::Boolean(a <= rhs.as_duration()?),
+            _ => bail!(
+                "runtime error: {:?} is not a valid operation on a duration",
+                tok
+            ),
+        };
+        Ok(Value {
+            data,
+            generation: lhs.generation().max(rhs.generation()),
+        })
+    }
+
+    pub(super) fn apply_timestamp(tok: &Token, lhs: &Value, rhs: &Value) -> Fallible<Value> {
+        let a = lhs.as_timestamp()?;
+        let data = match (tok, &rhs.data) {
+            (Token::Add, _) => ValueData::Timestamp(a.checked_add(rhs.as_duration()?)?),
+            (Token::Subtract, ValueData::Timestamp(b)) => ValueData::Duration(a.since(*b)?),
+            (Token::Subtract, _) => ValueData::Timestamp(a.checked_sub(rhs.as_duration()?)?),
+            (Token::Latch, _) => ValueData::Timestamp(latch(lhs, rhs, a, rhs.as_timestamp()?)),
+            (Token::Equals, _) => ValueData::Boolean(a == rhs.as_timestamp()?),
+            (Token::NotEquals, _) => ValueData::Boolean(a != rhs.as_timestamp()?),
+            (Token::GreaterThan, _) => ValueData::Boolean(a > rhs.as_timestamp()?),
+            (Token::LessThan, _) => ValueData::Boolean(a < rhs.as_timestamp()?),
+            (Token::GreaterThanOrEquals, _) => ValueData::Boolean(a >= rhs.as_timestamp()?),
+            (Token::LessThanOrEquals, _) => ValueData::Boolean(a <= rhs.as_timestamp()?),
+            _ => bail!(
+                "runtime error: {:?} is not a valid operation on a timestamp",
+                tok
+            ),
+        };
+        Ok(Value {
+            data,
+            generation: lhs.generation().max(rhs.generation()),
+        })
+    }
+
     pub(super) fn apply_integer(tok: &Token, lhs: &Value, rhs: &Value) -> Fallible<Value> {
         let a = lhs.as_integer()?;
         let b = rhs.as_integer()?;
+        let checked = |result: Option<i64>| {
+            result.ok_or_else(|| {
+                format_err!("numerical error: integer overflow in {} {:?} {}", a, tok, b)
+            })
+        };
         let data = match tok {
-            Token::Add => ValueData::Integer(a + b),
-            Token::Subtract => ValueData::Integer(a - b),
-            Token::Multiply => ValueData::Integer(a * b),
-            Token::Divide => ValueData::Float(Float::new(a as f64)? / Float::new(b as f64)?),
-            Token::Modulo => ValueData::Integer(a % b),
+            Token::Add => ValueData::Integer(checked(a.checked_add(b))?),
+            Token::Subtract => ValueData::Integer(checked(a.checked_sub(b))?),
+            Token::Multiply => ValueData::Integer(checked(a.checked_mul(b))?),
+            Token::Divide => {
+                ValueData::Float(Float::new(a as f64)?.checked_div(Float::new(b as f64)?)?)
+            }
+            Token::Modulo => {
+                ensure!(b != 0, "numerical error: remainder by zero");
+                ValueData::Integer(checked(a.checked_rem(b))?)
+            }
             Token::Latch => ValueData::Integer(latch(lhs, rhs, a, b)),
             Token::Equals => ValueData::Boolean(a == b),
             Token::NotEquals => ValueData::Boolean(a != b),
@@ -173,10 +510,11 @@ impl Value {
         let a = lhs.as_float()?;
         let b = rhs.as_float()?;
         let data = match tok {
-            Token::Add => ValueData::Float(a + b),
-            Token::Subtract => ValueData::Float(a - b),
-            Token::Multiply => ValueData::Float(a * b),
-            Token::Divide => ValueData::Float(a / b),
+            Token::Add => ValueData::Float(a.checked_add(b)?),
+            Token::Subtract => ValueData::Float(a.checked_sub(b)?),
+            Token::Multiply => ValueData::Float(a.checked_mul(b)?),
+            Token::Divide => ValueData::Float(a.checked_div(b)?),
+            Token::Modulo => ValueData::Float(a.checked_rem(b)?),
             Token::Latch => ValueData::Float(latch(lhs, rhs, a, b)),
             Token::Equals => ValueData::Boolean(a == b),
             Token::NotEquals => ValueData::Boolean(a != b),
@@ -195,12 +533,36 @@ impl Value {
         })
     }
 
+    pub(super) fn apply_list(tok: &Token, lhs: &Value, rhs: &Value) -> Fallible<Value> {
+        let a = lhs.as_list()?;
+        let b = rhs.as_list()?;
+        let data = match tok {
+            Token::Add => ValueData::List(a.iter().chain(b).cloned().collect()),
+            Token::Equals => ValueData::Boolean(a == b),
+            Token::NotEquals => ValueData::Boolean(a != b),
+            Token::Latch => ValueData::List(latch(lhs, rhs, a, b).to_vec()),
+            _ => bail!(
+                "runtime error: {:?} is not a valid operation on a list",
+                tok
+            ),
+        };
+        Ok(Value {
+            data,
+            generation: lhs.generation().max(rhs.generation()),
+        })
+    }
+
     pub(super) fn apply_string(tok: &Token, lhs: &Value, rhs: &Value) -> Fallible<Value> {
         let a = lhs.as_string()?;
         let b = rhs.as_string()?;
         let data = match tok {
             Token::Add => ValueData::String(a + &b),
             Token::Equals => ValueData::Boolean(a == b),
+            Token::NotEquals => ValueData::Boolean(a != b),
+            Token::GreaterThan => ValueData::Boolean(a > b),
+            Token::LessThan => ValueData::Boolean(a < b),
+            Token::GreaterThanOrEquals => ValueData::Boolean(a >= b),
+            Token::LessThanOrEquals => ValueData::Boolean(a <= b),
             Token::Latch => ValueData::String(latch(lhs, rhs, a, b)),
             _ => bail!(
                 "runtime error: {:?} is not a valid operation on a string",
@@ -227,6 +589,20 @@ impl Value {
         false
     }
 
+    pub fn is_color(&self) -> bool {
+        if let ValueData::Color(_) = self.data {
+            return true;
+        }
+        false
+    }
+
+    pub fn is_duration(&self) -> bool {
+        if let ValueData::Duration(_) = self.data {
+            return true;
+        }
+        false
+    }
+
     pub fn is_integer(&self) -> bool {
         if let ValueData::Integer(_) = self.data {
             return true;
@@ -241,6 +617,13 @@ impl Value {
         false
     }
 
+    pub fn is_list(&self) -> bool {
+        if let ValueData::List(_) = self.data {
+            return true;
+        }
+        false
+    }
+
     pub fn is_string(&self) -> bool {
         if let ValueData::String(_) = self.data {
             return true;
@@ -248,6 +631,13 @@ impl Value {
         false
     }
 
+    pub fn is_timestamp(&self) -> bool {
+        if let ValueData::Timestamp(_) = self.data {
+            return true;
+        }
+        false
+    }
+
     pub fn is_input_flag(&self) -> bool {
         self.data == ValueData::InputFlag
     }
@@ -259,6 +649,20 @@ impl Value {
         bail!("runtime error: attempted to use a non-boolean value in boolean context")
     }
 
+    pub fn as_color(&self) -> Fallible<Color> {
+        if let ValueData::Color(c) = self.data {
+            return Ok(c);
+        }
+        bail!("runtime error: attempted to use a non-color value in color context")
+    }
+
+    pub fn as_duration(&self) -> Fallible<Duration> {
+        if let ValueData::Duration(d) = self.data {
+            return Ok(d);
+        }
+        bail!("runtime error: attempted to use a non-duration value in duration context")
+    }
+
     pub fn as_integer(&self) -> Fallible<i64> {
         if let ValueData::Integer(i) = self.data {
             return Ok(i);
@@ -273,6 +677,13 @@ impl Value {
         bail!("runtime error: attempted to use a non-float value in float context")
     }
 
+    pub fn as_list(&self) -> Fallible<&[Value]> {
+        if let ValueData::List(ref items) = self.data {
+            return Ok(items);
+        }
+        bail!("runtime error: attempted to use a non-list value in list context")
+    }
+
     pub fn as_string(&self) -> Fallible<String> {
         if let ValueData::String(ref s) = self.data {
             return Ok(s.to_owned());
@@ -280,6 +691,13 @@ impl Value {
         bail!("runtime error: attempted to use a non-stringvalue in string context")
     }
 
+    pub fn as_timestamp(&self) -> Fallible<Timestamp> {
+        if let ValueData::Timestamp(t) = self.data {
+            return Ok(t);
+        }
+        bail!("runtime error: attempted to use a non-timestamp value in timestamp context")
+    }
+
     pub fn as_path_component(&self) -> Fallible<String> {
         match self.data {
             ValueData::Integer(i) => Ok(i.to_string()),
@@ -288,11 +706,64 @@ impl Value {
             ValueData::Float(_) => {
                 bail!("runtime error: a float value cannot be used as a path component")
             }
+            ValueData::Color(_) => {
+                bail!("runtime error: a color value cannot be used as a path component")
+            }
+            ValueData::Duration(_) | ValueData::Timestamp(_) => {
+                bail!("runtime error: a time value cannot be used as a path component")
+            }
+            ValueData::List(_) => {
+                bail!("runtime error: a list value cannot be used as a path component")
+            }
             ValueData::Path(_) => bail!("runtime error: did not expect a path as path component"),
             ValueData::InputFlag => bail!("runtime error: input flag in as_path_component"),
         }
     }
 
+    // The type of this value, or of whatever it may refer to if it is a path.
+    pub fn value_type(&self, tree: &Tree) -> Fallible<ValueType> {
+        let path = match self.data {
+            ValueData::Path(ref path) => path,
+            ref data => return Ok(ValueType::of(data)),
+        };
+        for component in &path.components {
+            if let PathComponent::Lookup(lookup) = component {
+                let component_type = Value::from_path(lookup.to_owned()).value_type(tree)?;
+                ensure!(
+                    [
+                        ValueType::Any,
+                        ValueType::Boolean,
+                        ValueType::Integer,
+                        ValueType::String
+                    ]
+                    .contains(&component_type),
+                    "type error: a {} value cannot be used as a path component in {}",
+                    component_type,
+                    path
+                );
+            }
+        }
+
+        // A glob collects whatever it matches.
+        if path.is_glob() {
+            return Ok(ValueType::List);
+        }
+
+        // A computed path could resolve to any of the nodes it may refer to.
+        // Nodes without a value would fail at runtime, so are not counted.
+        let mut value_type = None;
+        for concrete in path.devirtualize(tree)? {
+            if let Ok(node) = tree.lookup_path(&concrete) {
+                if node.has_input() {
+                    let node_type = node.value_type(tree)?;
+                    value_type =
+                        Some(value_type.map_or(node_type, |t: ValueType| t.join(node_type)));
+                }
+            }
+        }
+        Ok(value_type.unwrap_or(ValueType::Any))
+    }
+
     // Devirtualize and return all concrete paths, if this is a path.
     pub fn find_all_possible_inputs(
         &self,
@@ -318,6 +789,11 @@ impl Value {
                 .filter(|path| tree.lookup_path(path).is_ok())
                 .collect::<Vec<ConcretePath>>();
 
+            // A glob also depends on the shape of the tree where it matched.
+            if path.is_glob() {
+                out.append(&mut path.explode_glob(tree)?.1);
+            }
+
             // Collect both direct and indirect inputs at this value.
             out.append(&mut direct_inputs);
             out.append(&mut concrete_inputs);
@@ -326,7 +802,17 @@ impl Value {
     }
 }
 
-impl<'a> From<&'a str> for Value {
+// The generation records when a value was produced, not what it is, so it
+// does not participate in equality.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        self.data == other.data
+    }
+}
+
+impl Eq for Value {}
+
+impl From<&str> for Value {
     fn from(t: &str) -> Value {
         Value {
             data: ValueData::String(t.to_owned()),
@@ -339,11 +825,231 @@ impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.data {
             ValueData::Boolean(b) => write!(f, "{}", b),
+            ValueData::Color(c) => write!(f, "{}", c),
+            ValueData::Duration(d) => write!(f, "{}", d),
+            ValueData::Timestamp(t) => write!(f, "{}", t),
             ValueData::Integer(i) => write!(f, "{}i64", i),
             ValueData::Float(v) => write!(f, "{}f64", v),
             ValueData::String(ref s) => write!(f, "\"{}\"", s),
+            ValueData::List(ref items) => write!(
+                f,
+                "[{}]",
+                items
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
             ValueData::Path(ref p) => write!(f, "{}", p),
             ValueData::InputFlag => write!(f, "InputFlag"),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const OPERATORS: &[Token] = &[
+        Token::Add,
+        Token::And,
+        Token::Subtract,
+        Token::Divide,
+        Token::Multiply,
+        Token::Modulo,
+        Token::Equals,
+        Token::NotEquals,
+        Token::LessThan,
+        Token::LessThanOrEquals,
+        Token::GreaterThan,
+        Token::GreaterThanOrEquals,
+        Token::Or,
+        Token::Latch,
+    ];
+
+    const TYPES: &[ValueType] = &[
+        ValueType::Boolean,
+        ValueType::Color,
+        ValueType::Duration,
+        ValueType::Float,
+        ValueType::Integer,
+        ValueType::List,
+        ValueType::String,
+        ValueType::Timestamp,
+    ];
+
+    // The type of `lhs op rhs` for every combination that is allowed.
+    fn result_type(lhs: ValueType, tok: &Token, rhs: ValueType) -> Option<ValueType> {
+        use ValueType::*;
+        let comparison = [
+            Token::Equals,
+            Token::NotEquals,
+            Token::LessThan,
+            Token::LessThanOrEquals,
+            Token::GreaterThan,
+            Token::GreaterThanOrEquals,
+        ]
+        .contains(tok);
+        let equality = [Token::Equals, Token::NotEquals].contains(tok);
+        let numeric = |t| t == Integer || t == Float;
+        Some(match (lhs, tok, rhs) {
+            (Boolean, Token::And, Boolean) | (Boolean, Token::Or, Boolean) => Boolean,
+            (Boolean, _, Boolean) | (Color, _, Color) | (List, _, List) if equality => Boolean,
+            (Duration, _, Duration) | (Timestamp, _, Timestamp) | (String, _, String)
+                if comparison =>
+            {
+                Boolean
+            }
+            (a, _, b) if numeric(a) && numeric(b) && comparison => Boolean,
+            (a, Token::Latch, b) if a == b => a,
+            (Integer, Token::Latch, Float) | (Float, Token::Latch, Integer) => Float,
+            (Integer, Token::Divide, Integer) => Float,
+            (Integer, _, Integer)
+                if [Token::Add, Token::Subtract, Token::Multiply, Token::Modulo].contains(tok) =>
+            {
+                Integer
+            }
+            (a, _, b)
+                if numeric(a)
+                    && numeric(b)
+                    && [
+                        Token::Add,
+                        Token::Subtract,
+                        Token::Multiply,
+                        Token::Divide,
+                        Token::Modulo,
+                    ]
+                    .contains(tok) =>
+            {
+                Float
+            }
+            (String, Token::Add, String) => String,
+            (List, Token::Add, List) => List,
+            (Color, Token::Multiply, n) | (n, Token::Multiply, Color) if numeric(n) => Color,
+            (Duration, Token::Multiply, n) | (n, Token::Multiply, Duration) if numeric(n) => {
+                Duration
+            }
+            (Duration, Token::Divide, n) if numeric(n) => Duration,
+            (Duration, Token::Divide, Duration) => Float,
+            (Duration, Token::Add, Duration)
+            | (Duration, Token::Subtract, Duration)
+            | (Duration, Token::Modulo, Duration) => Duration,
+            (Duration, Token::Add, Timestamp)
+            | (Timestamp, Token::Add, Duration)
+            | (Timestamp, Token::Subtract, Duration) => Timestamp,
+            (Timestamp, Token::Subtract, Timestamp) => Duration,
+            _ => return None,
+        })
+    }
+
+    #[test]
+    fn test_operator_matrix() -> Fallible<()> {
+        for lhs in TYPES {
+            for tok in OPERATORS {
+                for rhs in TYPES {
+                    let result = lhs.sample()?.apply(tok, &rhs.sample()?);
+                    let inferred = ValueType::apply(tok, *lhs, *rhs);
+                    match result_type(*lhs, tok, *rhs) {
+                        Some(expect) => {
+                            let value = result
+                                .map_err(|e| format_err!("{} {:?} {}: {}", lhs, tok, rhs, e))?;
+                            assert_eq!(
+                                ValueType::of(&value.data),
+                                expect,
+                                "{} {:?} {}",
+                                lhs,
+                                tok,
+                                rhs
+                            );
+                            assert_eq!(inferred?, expect, "{} {:?} {}", lhs, tok, rhs);
+                        }
+                        None => {
+                            assert!(result.is_err(), "{} {:?} {}", lhs, tok, rhs);
+                            assert!(inferred.is_err(), "{} {:?} {}", lhs, tok, rhs);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_operator_values() -> Fallible<()> {
+        let int = Value::from_integer;
+        let float = |f| Value::from_float(Float::new(f).unwrap());
+        let string = Value::new_str;
+        let boolean = Value::from_boolean;
+        for (lhs, tok, rhs, expect) in vec![
+            (int(7), Token::Modulo, int(3), int(1)),
+            (int(-7), Token::Modulo, int(3), int(-1)),
+            (float(7.5), Token::Modulo, int(2), float(1.5)),
+            (int(1), Token::Add, float(0.5), float(1.5)),
+            (float(3.0), Token::Divide, int(2), float(1.5)),
+            (int(1), Token::Divide, int(4), float(0.25)),
+            (int(2), Token::Equals, float(2.0), boolean(true)),
+            (int(2), Token::LessThan, float(2.5), boolean(true)),
+            (string("a"), Token::NotEquals, string("b"), boolean(true)),
+            (string("a"), Token::LessThan, string("b"), boolean(true)),
+            (
+                string("b"),
+                Token::GreaterThanOrEquals,
+                string("b"),
+                boolean(true),
+            ),
+            (string("a"), Token::Add, string("b"), string("ab")),
+        ] {
+            assert_eq!(lhs.apply(&tok, &rhs)?, expect, "{} {:?} {}", lhs, tok, rhs);
+        }
+        for (lhs, tok, rhs) in vec![
+            (int(i64::MAX), Token::Add, int(1)),
+            (int(i64::MIN), Token::Subtract, int(1)),
+            (int(i64::MAX), Token::Multiply, int(2)),
+            (int(i64::MIN), Token::Modulo, int(-1)),
+            (int(1), Token::Modulo, int(0)),
+            (int(1), Token::Divide, int(0)),
+            (float(1.0), Token::Divide, float(0.0)),
+            (float(1.0), Token::Modulo, int(0)),
+            (float(f64::MAX), Token::Multiply, float(2.0)),
+        ] {
+            let err = lhs.apply(&tok, &rhs).err().unwrap();
+            assert!(
+                err.to_string().starts_with("numerical error:"),
+                "{} {:?} {}: {}",
+                lhs,
+                tok,
+                rhs,
+                err
+            );
+        }
+        assert!(int(i64::MIN).apply_unary(&Token::Subtract).is_err());
+        assert_eq!(int(3).apply_unary(&Token::Subtract)?, int(-3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_operator_generation() -> Fallible<()> {
+        let lhs = Value::from_integer(1).with_generation(3);
+        let rhs = Value::from_float(Float::new(2.0)?).with_generation(5);
+        assert_eq!(lhs.apply(&Token::Add, &rhs)?.generation(), 5);
+        assert_eq!(lhs.apply_unary(&Token::Subtract)?.generation(), 3);
+        assert_eq!(
+            lhs.apply(&Token::Latch, &rhs)?,
+            Value::from_float(Float::new(2.0)?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal() -> Fallible<()> {
+        assert_eq!(Value::from_integer(-1).literal(), "-1");
+        assert_eq!(Value::from_float(Float::new(3.0)?).literal(), "3.0");
+        assert_eq!(Value::from_float(Float::new(3.5)?).literal(), "3.5");
+        assert_eq!(Value::new_str("a \"{b}\"").literal(), r#""a \"{{b}}\"""#);
+        assert_eq!(
+            Value::from_list(vec![Value::from_integer(1), Value::from_boolean(true)]).literal(),
+            "[1, true]"
+        );
+        Ok(())
+    }
+}
@@ -5,16 +5,21 @@ mod oh;
 
 use crate::oh::RedstoneServer;
 use failure::Fallible;
+use futures::future::{select, Either};
 use oh::{ClockServer, HueServer, LegacyMcu, TreeServer, UpdateServer};
-use std::{net::IpAddr, path::PathBuf};
+use std::{net::IpAddr, path::PathBuf, sync::Arc};
 use structopt::StructOpt;
-use tokio::signal;
-use tracing::{info, Level};
+use tokio::signal::{
+    ctrl_c,
+    unix::{signal, SignalKind},
+};
+use tracing::{error, info, Level};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "open_house")]
 struct Opt {
+    #[allow(dead_code)]
     #[structopt(short = "d", long = "debug")]
     debug: bool,
 
@@ -30,6 +35,17 @@ struct Opt {
     #[structopt(short = "c", long = "config", parse(from_os_str))]
     config: PathBuf,
 
+    #[structopt(
+        short = "s",
+        long = "state",
+        parse(from_os_str),
+        help = "Where to save source values across restarts [default: next to the config]"
+    )]
+    state: Option<PathBuf>,
+
+    #[structopt(long = "no-state", help = "Do not save or restore source values")]
+    no_state: bool,
+
     #[structopt(
         short = "C",
         long = "no-cache",
@@ -42,6 +58,11 @@ struct Opt {
 async fn main() -> Fallible<()> {
     let opt = Opt::from_args();
     let config = opt.config;
+    let state = if opt.no_state {
+        None
+    } else {
+        Some(opt.state.unwrap_or_else(|| config.with_extension("state")))
+    };
     let host = opt
         .host
         .unwrap_or_else(|| "127.0.0.1".to_string())
@@ -59,7 +80,7 @@ async fn main() -> Fallible<()> {
         .finish();
     tracing::subscriber::set_global_default(subscriber)?; //.expect("setting defualt subscriber failed");
 
-    let tree_server = TreeServer::launch(&config).await?;
+    let tree_server = TreeServer::launch(&config, state.as_deref()).await?;
     let update_server = UpdateServer::launch().await?;
     let hue_server = HueServer::launch(!opt.clear_cache, tree_server.mailbox()).await?;
     let redstone_server =
@@ -72,11 +93,57 @@ async fn main() -> Fallible<()> {
         .mailbox()
         .set_hue(hue_server.mailbox())
         .await?;
+
+    // Events only report sinks that changed, so push everything once to get
+    // devices in sync with the tree.
+    update_server
+        .mailbox()
+        .apply_updates(tree_server.mailbox().all_sink_values().await?)
+        .await?;
+
     let clock_server = ClockServer::launch(update_server.mailbox(), tree_server.mailbox()).await?;
     let legacy_mcu =
         LegacyMcu::launch(host, port, update_server.mailbox(), tree_server.mailbox()).await?;
 
-    signal::ctrl_c().await?;
+    // SIGHUP rebuilds the tree from the changed configuration and tells each
+    // subsystem which of its sources and sinks came or went.
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        match select(Box::pin(ctrl_c()), Box::pin(hangup.recv())).await {
+            Either::Left((result, _)) => {
+                result?;
+                break;
+            }
+            Either::Right(_) => {
+                info!("SIGHUP received, reloading {}", config.display());
+                if let Some(changes) = tree_server.mailbox().reload().await? {
+                    let changes = Arc::new(changes);
+                    // A subsystem that has stopped should not take the rest down with it.
+                    let sent = [
+                        ("clock", clock_server.mailbox().tree_changed(&changes).await),
+                        (
+                            "legacy-mcu",
+                            legacy_mcu.mailbox().tree_changed(&changes).await,
+                        ),
+                        ("hue", hue_server.mailbox().tree_changed(&changes).await),
+                        (
+                            "redstone",
+                            redstone_server.mailbox().tree_changed(&changes).await,
+                        ),
+                    ];
+                    for (subsystem, result) in &sent {
+                        if let Err(e) = result {
+                            error!("{} did not take the reloaded tree: {}", subsystem, e);
+                        }
+                    }
+                    update_server
+                        .mailbox()
+                        .apply_updates(changes.updates.clone())
+                        .await?;
+                }
+            }
+        }
+    }
     info!("ctrl-c received, shutting down cleanly");
 
     tree_server.mailbox().finish().await?;
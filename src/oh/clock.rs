@@ -5,14 +5,14 @@ use crate::oh::{TreeMailbox, UpdateMailbox};
 use chrono::{DateTime, Datelike, Local, Timelike};
 use failure::{bail, Fallible};
 use futures::future::{select, Either};
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Arc};
 use tokio::{
     sync::mpsc::{channel, Sender},
     task::{spawn, JoinHandle},
     time::{delay_for, Duration},
 };
-use tracing::trace;
-use yggdrasil::Value;
+use tracing::{error, trace};
+use yggdrasil::{ConcretePath, Timestamp, TreeChanges, Value};
 
 /**
  * Example usage:
@@ -23,6 +23,13 @@ use yggdrasil::Value;
  *                ^clock
  *                interval <- "second"
  *                wrap <- "yearly"
+ *        now
+ *            ^clock
+ *            interval <- "minute"
+ *            wrap <- "timestamp"
+ *
+ * The timestamp wrap produces timestamp values, rounded down to the interval,
+ * rather than an integer count of intervals.
  */
 
 #[derive(Clone, Debug)]
@@ -42,13 +49,17 @@ impl ClockInterval {
         })
     }
 
-    fn convert_seconds(&self, seconds: i64) -> i64 {
+    fn seconds(&self) -> i64 {
         match self {
-            ClockInterval::Second => seconds,
-            ClockInterval::Minute => seconds / 60,
-            ClockInterval::Hour => seconds / (60 * 60),
+            ClockInterval::Second => 1,
+            ClockInterval::Minute => 60,
+            ClockInterval::Hour => 60 * 60,
         }
     }
+
+    fn convert_seconds(&self, seconds: i64) -> i64 {
+        seconds / self.seconds()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -60,6 +71,7 @@ enum ClockWrap {
     Daily,
     Hourly,
     Minutly,
+    Timestamp,
 }
 
 impl ClockWrap {
@@ -72,6 +84,7 @@ impl ClockWrap {
             "daily" => ClockWrap::Daily,
             "hourly" => ClockWrap::Hourly,
             "minutly" => ClockWrap::Minutly,
+            "timestamp" => ClockWrap::Timestamp,
             _ => bail!("unknown wrap mode for clock: {}", s),
         })
     }
@@ -83,18 +96,18 @@ impl ClockWrap {
             ClockWrap::Hourly => i64::from(now.time().minute() * 60 + now.time().second()),
             ClockWrap::Daily => i64::from(now.time().num_seconds_from_midnight()),
             ClockWrap::Weekly => {
-                let day = now.date().weekday().num_days_from_sunday(); // day of week, 0 on sunday
+                let day = now.date_naive().weekday().num_days_from_sunday(); // day of week, 0 on sunday
                 i64::from(day * SECS_PER_DAY + now.time().num_seconds_from_midnight())
             }
             ClockWrap::Monthly => {
-                let day = now.date().day0(); // day of month, 0 based
+                let day = now.date_naive().day0(); // day of month, 0 based
                 i64::from(day * SECS_PER_DAY + now.time().num_seconds_from_midnight())
             }
             ClockWrap::Yearly => {
-                let day = now.date().ordinal() - 1; // day of year, 1 based
+                let day = now.date_naive().ordinal() - 1; // day of year, 1 based
                 i64::from(day * SECS_PER_DAY + now.time().num_seconds_from_midnight())
             }
-            ClockWrap::Never => now.timestamp(),
+            ClockWrap::Never | ClockWrap::Timestamp => now.timestamp(),
         }
     }
 }
@@ -119,14 +132,23 @@ impl ClockDef {
         self.interval.convert_seconds(self.wrap.seconds(now))
     }
 
-    fn tick(&mut self, now: &DateTime<Local>) -> Option<i64> {
+    fn tick(&mut self, now: &DateTime<Local>) -> Option<Value> {
         let next_value = self.value(now);
         if next_value != self.last_value {
             self.last_value = next_value;
-            return Some(next_value);
+            return Some(self.to_value(next_value));
         }
         None
     }
+
+    fn to_value(&self, v: i64) -> Value {
+        match self.wrap {
+            ClockWrap::Timestamp => Value::from_timestamp(Timestamp::from_unix_millis(
+                v * self.interval.seconds() * 1000,
+            )),
+            _ => Value::from_integer(v),
+        }
+    }
 }
 
 pub struct ClockServer {
@@ -135,15 +157,19 @@ pub struct ClockServer {
 }
 
 impl ClockServer {
+    async fn read_clock_def(tree: &mut TreeMailbox, path: &ConcretePath) -> Fallible<ClockDef> {
+        let interval = tree.compute(&(path / "interval")).await?.as_string()?;
+        let wrap = tree.compute(&(path / "wrap")).await?.as_string()?;
+        Ok(ClockDef::new(
+            ClockInterval::from_str(&interval)?,
+            ClockWrap::from_str(&wrap)?,
+        ))
+    }
+
     pub async fn launch(mut update: UpdateMailbox, mut tree: TreeMailbox) -> Fallible<Self> {
         let mut clock_map = HashMap::new();
         for path in &tree.find_sources("clock").await? {
-            let interval = tree.compute(&(path / "interval")).await?.as_string()?;
-            let wrap = tree.compute(&(path / "wrap")).await?.as_string()?;
-            let clock_def = ClockDef::new(
-                ClockInterval::from_str(&interval)?,
-                ClockWrap::from_str(&wrap)?,
-            );
+            let clock_def = Self::read_clock_def(&mut tree, path).await?;
             clock_map.insert(path.to_owned(), clock_def);
         }
 
@@ -151,32 +177,48 @@ impl ClockServer {
         let task = spawn(async move {
             let mut mailbox_recv = Box::pin(mailbox_receiver.recv());
             loop {
-                match select(delay_for(Duration::from_secs(1)), mailbox_recv).await {
-                    Either::Right((maybe_message, _delay)) => {
-                        if let Some(message) = maybe_message {
-                            match message {
-                                ClockServerProtocol::Finish => {
-                                    // Note: we borrowed the mailbox above by storing the recv across the loop,
-                                    // so we can't reborrow here to close it. Luckily this system can only take
-                                    // a single Finish message, so there's not much point closing cleanly.
-                                    break;
-                                }
-                            }
-                        } else {
-                            break;
-                        }
-                    }
+                let message = match select(delay_for(Duration::from_secs(1)), mailbox_recv).await {
+                    Either::Right((maybe_message, _delay)) => maybe_message,
                     Either::Left(((), mailbox_unrecv)) => {
                         mailbox_recv = mailbox_unrecv;
                         let now = Local::now();
                         for (path, clock_def) in clock_map.iter_mut() {
                             if let Some(v) = clock_def.tick(&now) {
                                 trace!("{} timed out", path.to_string());
-                                let updates =
-                                    tree.handle_event(path, Value::from_integer(v)).await?;
+                                let updates = tree.handle_event(path, v).await?;
                                 update.apply_updates(updates).await?;
                             }
                         }
+
+                        // Timers in the tree wait out their delays to the second.
+                        let now = Timestamp::from_unix_millis(now.timestamp_millis());
+                        let updates = tree.tick(now).await?;
+                        if !updates.is_empty() {
+                            update.apply_updates(updates).await?;
+                        }
+                        continue;
+                    }
+                };
+                match message {
+                    Some(ClockServerProtocol::TreeChanged(changes)) => {
+                        for path in changes.removed_sources.get("clock").into_iter().flatten() {
+                            clock_map.remove(path);
+                        }
+                        for path in changes.added_sources.get("clock").into_iter().flatten() {
+                            match Self::read_clock_def(&mut tree, path).await {
+                                Ok(clock_def) => {
+                                    clock_map.insert(path.to_owned(), clock_def);
+                                }
+                                Err(e) => error!("failed to read clock {}: {}", path, e),
+                            }
+                        }
+                        mailbox_recv = Box::pin(mailbox_receiver.recv());
+                    }
+                    Some(ClockServerProtocol::Finish) | None => {
+                        // Note: we borrowed the mailbox above by storing the recv across the loop,
+                        // so we can't reborrow here to close it. Luckily this system can only take
+                        // a single Finish message, so there's not much point closing cleanly.
+                        break;
                     }
                 }
             }
@@ -200,6 +242,7 @@ impl ClockServer {
 
 #[derive(Debug)]
 enum ClockServerProtocol {
+    TreeChanged(Arc<TreeChanges>),
     Finish,
 }
 
@@ -209,8 +252,35 @@ pub struct ClockMailbox {
 }
 
 impl ClockMailbox {
+    pub async fn tree_changed(&mut self, changes: &Arc<TreeChanges>) -> Fallible<()> {
+        self.mailbox
+            .send(ClockServerProtocol::TreeChanged(changes.clone()))
+            .await?;
+        Ok(())
+    }
+
     pub async fn finish(&mut self) -> Fallible<()> {
         self.mailbox.send(ClockServerProtocol::Finish).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_timestamp_clock() -> Fallible<()> {
+        let mut clock = ClockDef::new(ClockInterval::Minute, ClockWrap::Timestamp);
+        let now = Local.timestamp_opt(1_580_493_630, 0).unwrap();
+        assert_eq!(
+            clock.tick(&now),
+            Some(Value::from_timestamp(Timestamp::parse(
+                "2020-01-31T18:00:00Z"
+            )?))
+        );
+        assert_eq!(clock.tick(&now), None);
+        Ok(())
+    }
+}
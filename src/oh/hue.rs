@@ -2,25 +2,24 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 use crate::oh::{
-    color::{Color, Mired, BHS},
     json_helpers::{ObjectHelper, ValueHelper},
     TreeMailbox,
 };
 use bytes::BytesMut;
-use failure::{ensure, Fallible};
+use failure::{bail, ensure, Fallible};
 use hyper::{
     body::HttpBody,
     client::{Client, HttpConnector},
     Body, Request, Response, Uri,
 };
 use json::{object, parse, stringify, JsonValue};
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, sync::Arc};
 use tokio::{
     sync::mpsc::{channel, Sender},
     task::{spawn, JoinHandle},
 };
-use tracing::{error, info, trace};
-use yggdrasil::{ConcretePath, Value};
+use tracing::{error, info, trace, warn};
+use yggdrasil::{Color, ConcretePath, Mired, TreeChanges, Value, BHS};
 
 pub struct HueServer {
     task: JoinHandle<Fallible<()>>,
@@ -54,6 +53,7 @@ impl HueServer {
                             }
                         }
                     }
+                    HueServerProtocol::TreeChanged(changes) => bridge.handle_tree_changed(&changes),
                     HueServerProtocol::Finish => mailbox_receiver.close(),
                 }
             }
@@ -78,6 +78,7 @@ impl HueServer {
 #[derive(Debug)]
 enum HueServerProtocol {
     ValuesUpdated(Vec<(ConcretePath, Value)>),
+    TreeChanged(Arc<TreeChanges>),
     Finish,
 }
 
@@ -92,6 +93,13 @@ impl HueMailbox {
         Ok(())
     }
 
+    pub async fn tree_changed(&mut self, changes: &Arc<TreeChanges>) -> Fallible<()> {
+        self.mailbox
+            .send(HueServerProtocol::TreeChanged(changes.clone()))
+            .await?;
+        Ok(())
+    }
+
     pub async fn values_updated(&mut self, values: &[(ConcretePath, Value)]) -> Fallible<()> {
         self.mailbox
             .send(HueServerProtocol::ValuesUpdated(values.to_vec()))
@@ -154,6 +162,23 @@ impl HueBridge {
         })
     }
 
+    // Lights are found by name on the hub, so new sinks only need mapping.
+    fn handle_tree_changed(&mut self, changes: &TreeChanges) {
+        for path in changes.removed_sinks.get("hue").into_iter().flatten() {
+            self.path_map.remove(path);
+        }
+        for path in changes.added_sinks.get("hue").into_iter().flatten() {
+            info!("hue: now tracking {}", path);
+            self.path_map
+                .insert(path.to_owned(), path.basename().to_owned());
+        }
+        if changes.added_sinks.contains_key("hue-bridge")
+            || changes.removed_sinks.contains_key("hue-bridge")
+        {
+            warn!("hue: the bridge changed; restart to connect to it");
+        }
+    }
+
     fn show_configuration(body: &JsonValue) -> Fallible<()> {
         let config = body.to_object()?.fetch("config")?.to_object()?;
         let props = vec![
@@ -222,12 +247,12 @@ impl HueBridge {
 
     fn group_by_value(
         values: &[(ConcretePath, Value)],
-    ) -> Fallible<HashMap<String, Vec<ConcretePath>>> {
+    ) -> Fallible<HashMap<LightState, Vec<ConcretePath>>> {
         let mut by_value = HashMap::new();
         for (path, value) in values {
             by_value
-                .entry(value.as_string()?)
-                .or_insert_with(|| vec![])
+                .entry(LightState::from_value(value)?)
+                .or_insert_with(std::vec::Vec::new)
                 .push(path.to_owned());
         }
         Ok(by_value)
@@ -253,7 +278,7 @@ impl HueBridge {
             }
             let group_name = self.group_map[&lights];
 
-            self.update_group(group_name, &value).await?;
+            self.update_group(group_name, value).await?;
         }
         Ok(())
     }
@@ -270,9 +295,9 @@ impl HueBridge {
         Ok(())
     }
 
-    async fn update_group(&self, group: u32, light_value: &str) -> Fallible<()> {
+    async fn update_group(&self, group: u32, state: &LightState) -> Fallible<()> {
         let url = format!("/groups/{}/action", group);
-        let obj = HueBridgeClient::light_state_for_value(light_value)?;
+        let obj = HueBridgeClient::light_state_for_value(state);
         let put_data = stringify(obj);
         match self.client.put(&url, put_data).await {
             Ok(status) => trace!("update_group succeeded with {:?}", status),
@@ -284,6 +309,37 @@ impl HueBridge {
     }
 }
 
+// What a hue sink asks of its lights: a color, or the string "none" for off.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+enum LightState {
+    Off,
+    On(Color),
+}
+
+impl LightState {
+    fn from_value(value: &Value) -> Fallible<Self> {
+        if value.is_color() {
+            return Ok(LightState::On(value.as_color()?));
+        }
+        if value.is_string() && value.as_string()? == "none" {
+            return Ok(LightState::Off);
+        }
+        bail!(
+            "hue: expected a color or \"none\", but found {}; build colors with rgb(), bhs(), mired() or kelvin()",
+            value
+        )
+    }
+}
+
+impl fmt::Display for LightState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LightState::Off => write!(f, "off"),
+            LightState::On(color) => write!(f, "{}", color),
+        }
+    }
+}
+
 struct HueBridgeClient {
     address: String,
     username: String,
@@ -296,7 +352,6 @@ impl HueBridgeClient {
             address: address.to_owned(),
             username: username.to_owned(),
             client: Client::builder()
-                .keep_alive(true)
                 .http1_writev(false) // always flatten so we send fewer packets
                 .retry_canceled_requests(true)
                 .set_host(true)
@@ -306,27 +361,27 @@ impl HueBridgeClient {
         })
     }
 
-    fn light_state_for_value(value: &str) -> Fallible<JsonValue> {
-        if value == "none" {
-            return Ok(object! {"on" => false});
-        }
-        let color = Color::parse(value)?;
+    fn light_state_for_value(state: &LightState) -> JsonValue {
+        let color = match state {
+            LightState::Off => return object! {"on" => false},
+            LightState::On(color) => color,
+        };
         let mut obj = match color {
-            Color::Mired(Mired { color_temp: ct }) => object! {"ct" => ct},
+            Color::Mired(Mired { color_temp: ct }) => object! {"ct" => *ct},
             Color::RGB(rgb) => {
-                let bhs = BHS::from_rgb(&rgb);
+                let bhs = BHS::from_rgb(rgb);
                 object! {"bri" => bhs.brightness, "hue" => bhs.hue, "sat" => bhs.saturation}
             }
             Color::BHS(BHS {
                 brightness,
                 hue,
                 saturation,
-            }) => object! {"bri" => brightness, "hue" => hue, "sat" => saturation},
+            }) => object! {"bri" => *brightness, "hue" => *hue, "sat" => *saturation},
         };
         obj["on"] = true.into();
         // FIXME: support transition time
         obj["transitiontime"] = 10.into();
-        Ok(obj)
+        obj
     }
 
     fn url(&self, path: &str) -> Fallible<Uri> {
@@ -389,3 +444,28 @@ impl HueBridgeClient {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_light_state_for_value() -> Fallible<()> {
+        let off = LightState::from_value(&Value::new_str("none"))?;
+        assert_eq!(off, LightState::Off);
+        assert_eq!(HueBridgeClient::light_state_for_value(&off)["on"], false);
+
+        let color = Color::parse("bhs(254, 34495, 128)")?;
+        let on = LightState::from_value(&Value::from_color(color))?;
+        assert_eq!(on, LightState::On(color));
+        let obj = HueBridgeClient::light_state_for_value(&on);
+        assert_eq!(obj["on"], true);
+        assert_eq!(obj["bri"], 254);
+        assert_eq!(obj["hue"], 34495);
+        assert_eq!(obj["sat"], 128);
+
+        assert!(LightState::from_value(&Value::new_str("bhs(254, 0, 254)")).is_err());
+        assert!(LightState::from_value(&Value::from_integer(1)).is_err());
+        Ok(())
+    }
+}
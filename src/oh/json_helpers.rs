@@ -8,6 +8,7 @@ pub trait ValueHelper {
     fn to_object(&self) -> Fallible<&Object>;
     fn to_array(&self) -> Fallible<&Array>;
     fn to_str(&self) -> Fallible<&str>;
+    #[allow(dead_code)]
     fn to_int(&self) -> Fallible<i64>;
     fn to_bool(&self) -> Fallible<bool>;
 }
@@ -14,13 +14,14 @@ use std::{
     collections::HashMap,
     convert::Infallible,
     net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
 };
 use tokio::{
     sync::mpsc::{channel, Sender},
     task::{spawn, JoinHandle},
 };
 use tracing::{error, info, trace, warn};
-use yggdrasil::Value;
+use yggdrasil::{ConcretePath, TreeChanges, Value};
 
 async fn read_body(mut req: Request<Body>) -> String {
     let mut data = BytesMut::new();
@@ -38,6 +39,14 @@ pub struct LegacyMcu {
 }
 
 impl LegacyMcu {
+    async fn read_ip(tree: &mut TreeMailbox, path: &ConcretePath) -> Fallible<IpAddr> {
+        Ok(tree
+            .compute(&(path / "ip"))
+            .await?
+            .as_string()?
+            .parse::<IpAddr>()?)
+    }
+
     pub async fn launch(
         host: IpAddr,
         port: u16,
@@ -46,22 +55,28 @@ impl LegacyMcu {
     ) -> Fallible<Self> {
         let (mailbox, mut mailbox_receiver) = channel(16);
         let task = spawn(async move {
-            let mut path_map = HashMap::new();
+            // Shared with the server, which looks up each connection's path.
+            let path_map = Arc::new(RwLock::new(HashMap::new()));
             for source_path in &tree.find_sources("legacy-mcu").await? {
-                let ip_addr = tree
-                    .compute(&(source_path / "ip"))
-                    .await?
-                    .as_string()?
-                    .parse::<IpAddr>()?;
+                let ip_addr = Self::read_ip(&mut tree, source_path).await?;
                 trace!("Mapping {} => {}", ip_addr, source_path);
-                path_map.insert(ip_addr, source_path.to_owned());
+                path_map
+                    .write()
+                    .unwrap()
+                    .insert(ip_addr, source_path.to_owned());
             }
 
+            let server_tree = tree.clone();
+            let server_path_map = path_map.clone();
             let make_svc = make_service_fn(move |socket: &AddrStream| {
                 let update = update.clone();
-                let tree = tree.clone();
+                let tree = server_tree.clone();
                 let remote_addr = socket.remote_addr();
-                let maybe_path = path_map.get(&remote_addr.ip()).cloned();
+                let maybe_path = server_path_map
+                    .read()
+                    .unwrap()
+                    .get(&remote_addr.ip())
+                    .cloned();
                 if maybe_path.is_none() {
                     warn!("Missing path info on connection: {:?}", socket);
                 }
@@ -75,7 +90,7 @@ impl LegacyMcu {
                                 let command = read_body(req).await;
                                 info!("handling event {} for {}", command, path);
                                 if let Ok(updates) =
-                                    tree.handle_event(&path, Value::from_string(command)).await
+                                    tree.handle_event(path, Value::from_string(command)).await
                                 {
                                     trace!("updates available for {} systems", updates.len());
                                     trace!("Sending to: {:?}", update);
@@ -102,6 +117,26 @@ impl LegacyMcu {
 
             while let Some(message) = mailbox_receiver.recv().await {
                 match message {
+                    LegacyMcuProtocol::TreeChanged(changes) => {
+                        let removed = changes.removed_sources.get("legacy-mcu");
+                        for path in removed.into_iter().flatten() {
+                            path_map.write().unwrap().retain(|_, p| p != path);
+                        }
+                        for path in changes
+                            .added_sources
+                            .get("legacy-mcu")
+                            .into_iter()
+                            .flatten()
+                        {
+                            match Self::read_ip(&mut tree, path).await {
+                                Ok(ip_addr) => {
+                                    trace!("Mapping {} => {}", ip_addr, path);
+                                    path_map.write().unwrap().insert(ip_addr, path.to_owned());
+                                }
+                                Err(e) => error!("failed to map legacy-mcu {}: {}", path, e),
+                            }
+                        }
+                    }
                     LegacyMcuProtocol::Finish => mailbox_receiver.close(),
                 }
             }
@@ -126,6 +161,7 @@ impl LegacyMcu {
 
 #[derive(Debug)]
 enum LegacyMcuProtocol {
+    TreeChanged(Arc<TreeChanges>),
     Finish,
 }
 
@@ -135,6 +171,13 @@ pub struct LegacyMcuMailbox {
 }
 
 impl LegacyMcuMailbox {
+    pub async fn tree_changed(&mut self, changes: &Arc<TreeChanges>) -> Fallible<()> {
+        self.mailbox
+            .send(LegacyMcuProtocol::TreeChanged(changes.clone()))
+            .await?;
+        Ok(())
+    }
+
     pub async fn finish(&mut self) -> Fallible<()> {
         self.mailbox.send(LegacyMcuProtocol::Finish).await?;
         Ok(())
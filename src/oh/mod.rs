@@ -2,15 +2,15 @@
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
 mod clock;
-mod color;
 mod hue;
 mod json_helpers;
 mod legacy_mcu;
 mod redstone;
+mod state_file;
 mod tree_server;
 mod update;
 
-pub use self::clock::{ClockMailbox, ClockServer};
+pub use self::clock::ClockServer;
 pub use self::hue::{HueMailbox, HueServer};
 pub use self::legacy_mcu::LegacyMcu;
 pub use self::redstone::{RedstoneMailbox, RedstoneServer};
@@ -18,6 +18,7 @@ use json::{object, JsonValue};
 use std::{
     boxed::Box,
     collections::HashMap,
+    sync::Arc,
     time::{Duration, Instant},
 };
 use tokio::{
@@ -30,7 +31,7 @@ use tokio_tungstenite::{connect_async, WebSocketStream};
 use tracing::{error, info, trace, warn};
 use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Message};
 use url::Url;
-use yggdrasil::{ConcretePath, Float, Value};
+use yggdrasil::{ConcretePath, Float, TreeChanges, Value};
 
 #[derive(Debug, Clone)]
 enum PropertyKind {
@@ -73,7 +74,7 @@ struct RedstoneHttpClient {
 impl RedstoneHttpClient {
     fn new(base_url: &Url) -> Fallible<Self> {
         let client = Client::builder()
-            .keep_alive(false)
+            .pool_max_idle_per_host(0)
             .http1_writev(false) // always flatten so we send fewer packets
             .retry_canceled_requests(true)
             .set_host(true)
@@ -121,20 +122,20 @@ impl RedstoneHttpClient {
 fn value_from_json(json: &JsonValue) -> Fallible<Value> {
     Ok(match json {
         JsonValue::Boolean(b) => Value::from_boolean(*b),
-        JsonValue::Short(s) => Value::new_str(&s),
-        JsonValue::String(s) => Value::new_str(&s),
+        JsonValue::Short(s) => Value::new_str(s),
+        JsonValue::String(s) => Value::new_str(s),
         JsonValue::Number(n) => {
             let (sign, mantissa, exponent) = n.as_parts();
             match (sign, exponent) {
                 (true, 0) => {
-                    if mantissa < i64::max_value() as u64 {
+                    if mantissa < i64::MAX as u64 {
                         Value::from_integer(mantissa as i64)
                     } else {
                         Value::from_float(Float::new((*n).into())?)
                     }
                 }
                 (false, 0) => {
-                    if mantissa < (-i64::min_value()) as u64 {
+                    if mantissa < i64::MIN.unsigned_abs() {
                         Value::from_integer(-(mantissa as i64))
                     } else {
                         Value::from_float(Float::new((*n).into())?)
@@ -143,6 +144,9 @@ fn value_from_json(json: &JsonValue) -> Fallible<Value> {
                 _ => Value::from_float(Float::new((*n).into())?),
             }
         }
+        JsonValue::Array(items) => {
+            Value::from_list(items.iter().map(value_from_json).collect::<Fallible<_>>()?)
+        }
         _ => bail!("non-value float in value_from_json"),
     })
 }
@@ -160,6 +164,18 @@ fn value_to_json(value: &Value) -> Fallible<JsonValue> {
     if value.is_string() {
         return Ok(JsonValue::String(value.as_string()?));
     }
+    if value.is_color() {
+        return Ok(JsonValue::String(value.as_color()?.to_string()));
+    }
+    if value.is_list() {
+        return Ok(JsonValue::Array(
+            value
+                .as_list()?
+                .iter()
+                .map(value_to_json)
+                .collect::<Fallible<_>>()?,
+        ));
+    }
     bail!("cannot format value {} as json", value)
 }
 
@@ -379,7 +395,7 @@ impl DeviceServer {
                 return Ok(LoopStatus::Finished);
             }
         }
-        return Ok(LoopStatus::Okay);
+        Ok(LoopStatus::Okay)
     }
 
     async fn wait_for_retry_connect(
@@ -507,12 +523,16 @@ impl RedstoneServer {
         Ok(())
     }
 
-    pub async fn launch(update: UpdateMailbox, mut tree: TreeMailbox) -> Fallible<Self> {
-        let (mailbox, mut mailbox_receiver) = channel(16);
-        let task = spawn(async move {
-            info!("redstone webthings gateway starting up");
-            let mut devices = HashMap::new();
-            for source_path in &tree.find_sources("redstone").await? {
+    // Group redstone sources and sinks into devices by their parent path,
+    // keeping only the given devices if there are any.
+    async fn build_devices(
+        tree: &mut TreeMailbox,
+        only: Option<&[ConcretePath]>,
+    ) -> Fallible<HashMap<Url, RedstoneDevice>> {
+        let wanted = |path: &ConcretePath| only.is_none_or(|only| only.contains(&path.parent()));
+        let mut devices = HashMap::new();
+        for source_path in &tree.find_sources("redstone").await? {
+            if wanted(source_path) {
                 Self::build_device(
                     PropertyKind::Source,
                     source_path,
@@ -521,18 +541,38 @@ impl RedstoneServer {
                 )
                 .await?;
             }
-            for sink_path in &tree.find_sinks("redstone").await? {
+        }
+        for sink_path in &tree.find_sinks("redstone").await? {
+            if wanted(sink_path) {
                 Self::build_device(PropertyKind::Sink, sink_path, tree.clone(), &mut devices)
                     .await?;
             }
+        }
+        Ok(devices)
+    }
 
+    async fn track_devices(
+        mut devices: HashMap<Url, RedstoneDevice>,
+        update: &UpdateMailbox,
+        tree: &TreeMailbox,
+        device_servers: &mut HashMap<ConcretePath, DeviceServer>,
+    ) -> Fallible<()> {
+        for (_, device) in devices.drain() {
+            let path = device.path.clone();
+            let device_server =
+                DeviceServer::track_device(device, update.clone(), tree.clone()).await?;
+            device_servers.insert(path, device_server);
+        }
+        Ok(())
+    }
+
+    pub async fn launch(update: UpdateMailbox, mut tree: TreeMailbox) -> Fallible<Self> {
+        let (mailbox, mut mailbox_receiver) = channel(16);
+        let task = spawn(async move {
+            info!("redstone webthings gateway starting up");
+            let devices = Self::build_devices(&mut tree, None).await?;
             let mut device_servers = HashMap::new();
-            for (_, device) in devices.drain() {
-                let path = device.path.clone();
-                let device_server =
-                    DeviceServer::track_device(device, update.clone(), tree.clone()).await?;
-                device_servers.insert(path, device_server);
-            }
+            Self::track_devices(devices, &update, &tree, &mut device_servers).await?;
 
             'message_loop: loop {
                 trace!("server: entering mainloop");
@@ -556,6 +596,61 @@ impl RedstoneServer {
                                     );
                                 }
                             }
+                            Some(RedstoneProtocol::TreeChanged(changes)) => {
+                                // Properties are set up when a device connects, so
+                                // restart each device that gained or lost one.
+                                let mut affected = Vec::new();
+                                for paths in &[
+                                    &changes.added_sources,
+                                    &changes.removed_sources,
+                                    &changes.added_sinks,
+                                    &changes.removed_sinks,
+                                ] {
+                                    for path in paths.get("redstone").into_iter().flatten() {
+                                        let device_path = path.parent();
+                                        if !affected.contains(&device_path) {
+                                            affected.push(device_path);
+                                        }
+                                    }
+                                }
+                                // A device that fails to restart is left out, so
+                                // that the others keep running.
+                                for device_path in &affected {
+                                    if let Some(server) = device_servers.remove(device_path) {
+                                        info!("server: restarting redstone device {}", device_path);
+                                        let stopped = async {
+                                            server.mailbox().finish().await?;
+                                            server.join().await
+                                        };
+                                        if let Err(e) = stopped.await {
+                                            error!(
+                                                "server: failed to stop redstone device {}: {}",
+                                                device_path, e
+                                            );
+                                        }
+                                    }
+                                    let only = [device_path.to_owned()];
+                                    let tracked =
+                                        match Self::build_devices(&mut tree, Some(&only)).await {
+                                            Ok(devices) => {
+                                                Self::track_devices(
+                                                    devices,
+                                                    &update,
+                                                    &tree,
+                                                    &mut device_servers,
+                                                )
+                                                .await
+                                            }
+                                            Err(e) => Err(e),
+                                        };
+                                    if let Err(e) = tracked {
+                                        error!(
+                                            "server: failed to set up redstone device {}: {}",
+                                            device_path, e
+                                        );
+                                    }
+                                }
+                            }
                             Some(RedstoneProtocol::Finish) | None => {
                                 for (_, server) in device_servers.drain() {
                                     server.mailbox().finish().await?;
@@ -596,6 +691,7 @@ impl RedstoneServer {
 #[derive(Debug)]
 enum RedstoneProtocol {
     SetProperty(ConcretePath, Value),
+    TreeChanged(Arc<TreeChanges>),
     Finish,
 }
 
@@ -612,6 +708,13 @@ impl RedstoneMailbox {
         Ok(())
     }
 
+    pub async fn tree_changed(&mut self, changes: &Arc<TreeChanges>) -> Fallible<()> {
+        self.mailbox
+            .send(RedstoneProtocol::TreeChanged(changes.clone()))
+            .await?;
+        Ok(())
+    }
+
     pub async fn finish(&mut self) -> Fallible<()> {
         self.mailbox.send(RedstoneProtocol::Finish).await?;
         Ok(())
@@ -0,0 +1,281 @@
+// This Source Code Form is subject to the terms of the GNU General Public
+// License, version 3. If a copy of the GPL was not distributed with this file,
+// You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::oh::json_helpers::{ObjectHelper, ValueHelper};
+use failure::{bail, err_msg, Fallible};
+use json::{object, JsonValue};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::{info, warn};
+use yggdrasil::{Color, ConcretePath, Duration, Float, Timestamp, Tree, Value};
+
+/**
+ * Source values saved across restarts, so that devices do not fall back to
+ * their defaults until they happen to report again. The file is JSON:
+ *
+ * {
+ *     "version": 1,
+ *     "sources": [
+ *         {"path": "/hall/switch", "generation": 12, "value": {"type": "string", "value": "on"}}
+ *     ]
+ * }
+ *
+ * Values are tagged with their type, since colors, durations and timestamps
+ * are not distinguishable from strings and numbers otherwise.
+ */
+const VERSION: i64 = 1;
+
+pub struct StateFile {
+    path: PathBuf,
+
+    // What we last wrote, to avoid rewriting the file on repeated values.
+    saved: Vec<(ConcretePath, Value)>,
+}
+
+impl StateFile {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            saved: Vec::new(),
+        }
+    }
+
+    /// Put the saved source values back into the tree, if there are any.
+    pub fn restore(&mut self, tree: &mut Tree) -> Fallible<()> {
+        if !self.path.exists() {
+            info!("no saved source values at {}", self.path.display());
+            return Ok(());
+        }
+        let values = load(&self.path)?;
+        let count = values.len();
+        for path in tree.restore_source_values(values) {
+            info!("dropping saved value for {}: no longer a source", path);
+        }
+        self.saved = tree.source_values();
+        info!(
+            "restored {} of {} saved source values from {}",
+            self.saved.len(),
+            count,
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    /// Write the tree's source values, if any have changed since we last did.
+    pub fn save_if_changed(&mut self, tree: &Tree) -> Fallible<()> {
+        let values = tree.source_values();
+        if unchanged(&values, &self.saved) {
+            return Ok(());
+        }
+        save(&self.path, &values)?;
+        self.saved = values;
+        Ok(())
+    }
+}
+
+// Values compare equal regardless of generation, but the generation decides
+// which side of a latch wins, so a value that is sent again must be saved.
+fn unchanged(values: &[(ConcretePath, Value)], saved: &[(ConcretePath, Value)]) -> bool {
+    values.len() == saved.len()
+        && values
+            .iter()
+            .zip(saved)
+            .all(|((p0, v0), (p1, v1))| p0 == p1 && v0 == v1 && v0.generation() == v1.generation())
+}
+
+fn load(path: &Path) -> Fallible<Vec<(ConcretePath, Value)>> {
+    let json = json::parse(&fs::read_to_string(path)?)?;
+    let root = json.to_object()?;
+    let version = root.fetch("version")?.as_i64();
+    if version != Some(VERSION) {
+        bail!(
+            "state file {} has version {:?}; expected {}",
+            path.display(),
+            version,
+            VERSION
+        );
+    }
+    let mut values = Vec::new();
+    for entry in root.fetch("sources")?.to_array()? {
+        let entry = entry.to_object()?;
+        let source = ConcretePath::from_str(entry.fetch("path")?.to_str()?)?;
+        let mut value = value_from_json(entry.fetch("value")?)?;
+        let generation = entry
+            .fetch("generation")?
+            .as_usize()
+            .ok_or_else(|| err_msg("generation is not a count"))?;
+        value.set_generation(generation);
+        values.push((source, value));
+    }
+    Ok(values)
+}
+
+// Write to the side and move into place, so that a crash while writing
+// leaves the last good file.
+fn save(path: &Path, values: &[(ConcretePath, Value)]) -> Fallible<()> {
+    let mut sources = JsonValue::new_array();
+    for (source, value) in values {
+        match value_to_json(value) {
+            Ok(json) => sources.push(object! {
+                "path" => source.to_string(),
+                "generation" => value.generation(),
+                "value" => json,
+            })?,
+            Err(e) => warn!("not saving the value of {}: {}", source, e),
+        }
+    }
+    let json = object! {
+        "version" => VERSION,
+        "sources" => sources,
+    };
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, json.pretty(2))?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn value_to_json(value: &Value) -> Fallible<JsonValue> {
+    let (kind, json) = if value.is_boolean() {
+        ("boolean", JsonValue::Boolean(value.as_boolean()?))
+    } else if value.is_integer() {
+        ("integer", value.as_integer()?.into())
+    } else if value.is_float() {
+        ("float", value.as_float()?.value.into())
+    } else if value.is_string() {
+        ("string", value.as_string()?.into())
+    } else if value.is_color() {
+        ("color", value.as_color()?.to_string().into())
+    } else if value.is_duration() {
+        ("duration", value.as_duration()?.as_millis().into())
+    } else if value.is_timestamp() {
+        ("timestamp", value.as_timestamp()?.as_unix_millis().into())
+    } else if value.is_list() {
+        let mut items = JsonValue::new_array();
+        for item in value.as_list()? {
+            items.push(value_to_json(item)?)?;
+        }
+        ("list", items)
+    } else {
+        bail!("cannot save {}", value);
+    };
+    Ok(object! { "type" => kind, "value" => json })
+}
+
+fn value_from_json(json: &JsonValue) -> Fallible<Value> {
+    let tagged = json.to_object()?;
+    let json = tagged.fetch("value")?;
+    let millis = || {
+        json.as_i64()
+            .ok_or_else(|| err_msg("expected milliseconds"))
+    };
+    Ok(match tagged.fetch("type")?.to_str()? {
+        "boolean" => Value::from_boolean(json.to_bool()?),
+        "integer" => Value::from_integer(
+            json.as_i64()
+                .ok_or_else(|| err_msg("expected an integer"))?,
+        ),
+        "float" => Value::from_float(Float::new(
+            json.as_f64().ok_or_else(|| err_msg("expected a float"))?,
+        )?),
+        "string" => Value::new_str(json.to_str()?),
+        "color" => Value::from_color(Color::parse(json.to_str()?)?),
+        "duration" => Value::from_duration(Duration::from_millis(millis()?)),
+        "timestamp" => Value::from_timestamp(Timestamp::from_unix_millis(millis()?)),
+        "list" => Value::from_list(
+            json.to_array()?
+                .iter()
+                .map(value_from_json)
+                .collect::<Fallible<_>>()?,
+        ),
+        kind => bail!("unknown value type {} in state file", kind),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use yggdrasil::{TreeBuilder, ValueType};
+
+    #[test]
+    fn test_value_round_trip() -> Fallible<()> {
+        for value in &[
+            Value::from_boolean(true),
+            Value::from_integer(i64::MAX),
+            Value::from_float(Float::new(0.25)?),
+            Value::new_str("on"),
+            Value::from_color(Color::parse("bhs(254, 34495, 254)")?),
+            Value::from_duration(Duration::from_millis(90_500)),
+            Value::from_timestamp(Timestamp::parse("2020-01-31T18:00:00Z")?),
+            Value::from_list(vec![Value::from_integer(1), Value::new_str("a")]),
+        ] {
+            let json = value_to_json(value)?;
+            assert_eq!(value_from_json(&json::parse(&json.dump())?)?, *value);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_and_restore() -> Fallible<()> {
+        let config = r#"
+switch ^legacy-mcu
+    default <- "off"
+level ^level
+    default <- 1
+light $light <- /switch
+"#;
+        let build = || {
+            TreeBuilder::default()
+                .declare_source_type("legacy-mcu", ValueType::String)?
+                .build_from_str(config)
+        };
+        let path = std::env::temp_dir().join(format!("oh-state-{}.json", std::process::id()));
+        let mut tree = build()?;
+        let mut state = StateFile::new(&path);
+        tree.handle_event(&ConcretePath::from_str("/switch")?, Value::new_str("on"))?;
+        state.save_if_changed(&tree)?;
+
+        let mut restored = build()?;
+        StateFile::new(&path).restore(&mut restored)?;
+        fs::remove_file(&path)?;
+        assert_eq!(restored.source_values(), tree.source_values());
+        let updates = restored.all_sink_values()?;
+        assert_eq!(updates["light"][0].1, Value::new_str("on"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_resent_value() -> Fallible<()> {
+        let config = r#"
+x ^switch
+    default <- "off"
+y ^switch
+    default <- "off"
+light $light <- /x :: /y
+"#;
+        let build = || TreeBuilder::default().build_from_str(config);
+        let path = std::env::temp_dir().join(format!("oh-latch-{}.json", std::process::id()));
+        let mut tree = build()?;
+        let mut state = StateFile::new(&path);
+        for (source, value) in &[("/x", "on"), ("/y", "off"), ("/x", "on")] {
+            tree.handle_event(&ConcretePath::from_str(source)?, Value::new_str(value))?;
+            state.save_if_changed(&tree)?;
+        }
+        let light = ConcretePath::from_str("/light")?;
+        assert_eq!(
+            tree.lookup_path(&light)?.compute(&tree)?,
+            Value::new_str("on")
+        );
+
+        let mut restored = build()?;
+        StateFile::new(&path).restore(&mut restored)?;
+        fs::remove_file(&path)?;
+        assert_eq!(
+            restored.lookup_path(&light)?.compute(&restored)?,
+            Value::new_str("on")
+        );
+        Ok(())
+    }
+}
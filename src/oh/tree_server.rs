@@ -1,14 +1,15 @@
 // This Source Code Form is subject to the terms of the GNU General Public
 // License, version 3. If a copy of the GPL was not distributed with this file,
 // You can obtain one at https://www.gnu.org/licenses/gpl.txt.
+use crate::oh::state_file::StateFile;
 use failure::{bail, Fallible};
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, panic, path::Path};
 use tokio::{
     sync::{mpsc, mpsc::Receiver, oneshot},
     task::{spawn, JoinHandle},
 };
-use tracing::error;
-use yggdrasil::{ConcretePath, Tree, TreeBuilder, Value};
+use tracing::{error, info, warn};
+use yggdrasil::{ConcretePath, Timestamp, Tree, TreeBuilder, TreeChanges, Value, ValueType};
 
 #[derive(Debug)]
 pub struct TreeServer {
@@ -17,27 +18,38 @@ pub struct TreeServer {
 }
 
 impl TreeServer {
-    pub async fn launch(filename: &Path) -> Fallible<Self> {
+    /// Build the tree from the configuration, restoring any source values
+    /// saved in the state file by a previous run before accepting messages.
+    pub async fn launch(filename: &Path, state_filename: Option<&Path>) -> Fallible<Self> {
         let filename = filename.to_path_buf();
+        let mut state_file = state_filename.map(StateFile::new);
         let (mailbox, mut mailbox_receiver) = mpsc::channel(16);
         let task = spawn(async move {
-            let mut tree = match TreeBuilder::default().build_from_file(&filename) {
-                Ok(tree) => tree,
-                Err(e) => {
-                    error!("Failed to parse configuration:");
-                    error!("{:?}", e.backtrace());
-                    bail!("failed to parse configuration")
+            let mut tree = Self::build(&filename)?;
+            if let Some(state_file) = state_file.as_mut() {
+                if let Err(e) = state_file.restore(&mut tree) {
+                    warn!("failed to restore saved source values: {}", e);
                 }
-            };
+            }
 
             while let Some(message) = mailbox_receiver.recv().await {
-                let result = Self::handle_message(message, &mut mailbox_receiver, &mut tree);
+                let result =
+                    Self::handle_message(message, &mut mailbox_receiver, &filename, &mut tree);
                 if let Err(e) = result {
                     error!("Error: {}", e);
                     error!("{}", e.backtrace());
                 }
+                if let Some(state_file) = state_file.as_mut() {
+                    if let Err(e) = state_file.save_if_changed(&tree) {
+                        error!("failed to save source values: {}", e);
+                    }
+                }
             }
 
+            // Whatever is left is saved on shutdown.
+            if let Some(state_file) = state_file.as_mut() {
+                state_file.save_if_changed(&tree)?;
+            }
             Ok(())
         });
 
@@ -47,9 +59,33 @@ impl TreeServer {
         })
     }
 
+    fn build(filename: &Path) -> Fallible<Tree> {
+        let builder =
+            TreeBuilder::default().declare_source_type("legacy-mcu", ValueType::String)?;
+        match builder.build_from_file(filename) {
+            Ok(tree) => Ok(tree),
+            Err(e) => {
+                error!("Failed to parse configuration:");
+                error!("{}", e);
+                error!("{:?}", e.backtrace());
+                bail!("failed to parse configuration")
+            }
+        }
+    }
+
+    // A reload must not take down the running tree, even if building the new
+    // one hits a bug in the parser rather than a mistake in the configuration.
+    fn build_for_reload(filename: &Path) -> Fallible<Tree> {
+        match panic::catch_unwind(|| Self::build(filename)) {
+            Ok(result) => result,
+            Err(_) => bail!("building {} panicked", filename.display()),
+        }
+    }
+
     fn handle_message<T>(
         message: TreeServerProtocol,
         mailbox_receiver: &mut Receiver<T>,
+        filename: &Path,
         tree: &mut Tree,
     ) -> Fallible<()> {
         match message {
@@ -63,7 +99,7 @@ impl TreeServer {
                 tx.send(tree.lookup_path(&path).is_ok()).ok();
             }
             TreeServerProtocol::Compute(path, tx) => {
-                tx.send(tree.lookup_path(&path)?.compute(&tree)?).ok();
+                tx.send(tree.lookup_path(&path)?.compute(tree)?).ok();
             }
             TreeServerProtocol::HandleEvent(path, value, tx) => {
                 match tree.handle_event(&path, value) {
@@ -76,6 +112,38 @@ impl TreeServer {
                     }
                 }
             }
+            TreeServerProtocol::Tick(now, tx) => match tree.tick(now) {
+                Ok(result) => {
+                    tx.send(result).ok();
+                }
+                Err(e) => {
+                    error!("failed to tick timers: {}", e);
+                    tx.send(HashMap::new()).ok();
+                }
+            },
+            TreeServerProtocol::AllSinkValues(tx) => match tree.all_sink_values() {
+                Ok(result) => {
+                    tx.send(result).ok();
+                }
+                Err(e) => {
+                    error!("failed to compute all sink values: {}", e);
+                    tx.send(HashMap::new()).ok();
+                }
+            },
+            TreeServerProtocol::Reload(tx) => {
+                // A configuration that does not build leaves the running tree alone.
+                let changes = Self::build_for_reload(filename).and_then(|next| tree.reload(next));
+                match changes {
+                    Ok(changes) => {
+                        info!("reloaded configuration from {}", filename.display());
+                        tx.send(Some(changes)).ok();
+                    }
+                    Err(e) => {
+                        error!("keeping the old configuration: {}", e);
+                        tx.send(None).ok();
+                    }
+                }
+            }
             TreeServerProtocol::Finish => {
                 mailbox_receiver.close();
             }
@@ -109,6 +177,12 @@ enum TreeServerProtocol {
         Value,
         oneshot::Sender<HashMap<String, Vec<(ConcretePath, Value)>>>,
     ),
+    Tick(
+        Timestamp,
+        oneshot::Sender<HashMap<String, Vec<(ConcretePath, Value)>>>,
+    ),
+    AllSinkValues(oneshot::Sender<HashMap<String, Vec<(ConcretePath, Value)>>>),
+    Reload(oneshot::Sender<Option<TreeChanges>>),
     Finish,
 }
 
@@ -157,8 +231,66 @@ impl TreeMailbox {
         Ok(rx.await?)
     }
 
+    /// Advance the tree's timers to now, returning the sinks that changed.
+    pub async fn tick(
+        &mut self,
+        now: Timestamp,
+    ) -> Fallible<HashMap<String, Vec<(ConcretePath, Value)>>> {
+        let (tx, rx) = oneshot::channel();
+        self.mailbox.send(TreeServerProtocol::Tick(now, tx)).await?;
+        Ok(rx.await?)
+    }
+
+    /// Every sink's current value, including those that have not changed.
+    pub async fn all_sink_values(
+        &mut self,
+    ) -> Fallible<HashMap<String, Vec<(ConcretePath, Value)>>> {
+        let (tx, rx) = oneshot::channel();
+        self.mailbox
+            .send(TreeServerProtocol::AllSinkValues(tx))
+            .await?;
+        Ok(rx.await?)
+    }
+
+    /// Rebuild the tree from the configuration file. Returns None if the new
+    /// configuration was rejected, in which case the old tree keeps running.
+    pub async fn reload(&mut self) -> Fallible<Option<TreeChanges>> {
+        let (tx, rx) = oneshot::channel();
+        self.mailbox.send(TreeServerProtocol::Reload(tx)).await?;
+        Ok(rx.await?)
+    }
+
     pub async fn finish(&mut self) -> Fallible<()> {
         self.mailbox.send(TreeServerProtocol::Finish).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{fs, str::FromStr};
+
+    #[tokio::test]
+    async fn test_reload_keeps_tree_on_bad_config() -> Fallible<()> {
+        let path = std::env::temp_dir().join(format!("oh-reload-{}.ygg", std::process::id()));
+        fs::write(&path, "a <- 1 + 2\n")?;
+        let server = TreeServer::launch(&path, None).await?;
+        let mut tree = server.mailbox();
+        let a = ConcretePath::from_str("/a")?;
+        assert_eq!(tree.compute(&a).await?, Value::from_integer(3));
+
+        fs::write(&path, "a <- (1 + 2\n")?;
+        assert!(tree.reload().await?.is_none());
+        assert_eq!(tree.compute(&a).await?, Value::from_integer(3));
+
+        fs::write(&path, "a <- 4\n")?;
+        assert!(tree.reload().await?.is_some());
+        assert_eq!(tree.compute(&a).await?, Value::from_integer(4));
+
+        tree.finish().await?;
+        server.join().await?;
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}